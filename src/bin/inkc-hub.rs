@@ -0,0 +1,168 @@
+extern crate chrono;
+extern crate clap;
+extern crate ctrlc;
+#[macro_use]
+extern crate failure;
+extern crate fern;
+extern crate libc;
+#[macro_use]
+extern crate log;
+extern crate INKC;
+
+#[macro_use]
+extern crate may;
+
+use std::fs;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc;
+use std::time::Duration;
+
+use clap::{App, Arg};
+use may::coroutine;
+use network::hub::{self, WSS};
+use network::peer_manager;
+use network::tls;
+use network::WsServer;
+use INKC::*;
+
+fn log_init(settings: &config::Settings) -> Result<()> {
+    let mut dispatch = fern::Dispatch::new()
+        .format(|out, message, record| {
+            out.finish(format_args!(
+                "{}[{}][{}] {}",
+                chrono::Local::now().format("[%Y-%m-%d][%H:%M:%S%.3f]"),
+                record.target(),
+                record.level(),
+                message
+            ))
+        })
+        // the actual level is controlled by log::set_max_level so that it can be reloaded
+        .level(log::LevelFilter::Trace)
+        .chain(std::io::stdout());
+    if let Some(ref log_file) = settings.log_file {
+        dispatch = dispatch.chain(fern::log_file(log_file)?);
+    }
+    dispatch.apply().map_err(|e| format_err!("{}", e))?;
+    log::set_max_level(settings.get_log_level()?);
+
+    info!("log init done!");
+    Ok(())
+}
+
+fn write_pidfile(pidfile: &str) -> Result<()> {
+    fs::write(pidfile, format!("{}\n", std::process::id()))?;
+    Ok(())
+}
+
+// start the node, must be called in coroutine context
+fn start_hub(settings: &config::Settings) -> Result<()> {
+    {
+        let db = db::DB_POOL.get_connection();
+        joint_storage::init_genesis_joint(&db)?;
+    }
+    info!("my witnesses = {:?}", *my_witness::MY_WITNESSES);
+
+    let tls_config = match (&settings.tls_cert_file, &settings.tls_key_file) {
+        (&Some(ref cert_file), &Some(ref key_file)) => {
+            Some(tls::load_server_config(cert_file, key_file)?)
+        }
+        _ => None,
+    };
+    let scheme = if tls_config.is_some() { "wss" } else { "ws" };
+    let _server = WsServer::start(settings.listen_address.as_str(), tls_config, |c| {
+        WSS.add_inbound(c);
+    });
+    info!(
+        "websocket server running on {}://{}",
+        scheme, settings.listen_address
+    );
+
+    hub::start_purge_junk_unhandled_joints();
+
+    peer_manager::start();
+    Ok(())
+}
+
+// set by SIGHUP, the settings are reloaded out of the signal handler
+static RELOAD_SETTINGS: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn on_sighup(_: libc::c_int) {
+    RELOAD_SETTINGS.store(true, Ordering::Relaxed);
+}
+
+// SIGHUP reloads the settings instead of stopping the node,
+// must be called after ctrlc::set_handler which also takes SIGHUP
+fn handle_sighup() -> Result<()> {
+    let handler = on_sighup as extern "C" fn(libc::c_int) as libc::sighandler_t;
+    if unsafe { libc::signal(libc::SIGHUP, handler) } == libc::SIG_ERR {
+        bail!("failed to set the SIGHUP handler");
+    }
+    go!(|| loop {
+        if RELOAD_SETTINGS.swap(false, Ordering::Relaxed) {
+            match config::reload_settings() {
+                Ok(()) => info!("settings reloaded"),
+                Err(e) => error!("failed to reload settings, err = {}", e),
+            }
+        }
+        coroutine::sleep(Duration::from_secs(1));
+    });
+    Ok(())
+}
+
+fn main() -> Result<()> {
+    let matches = App::new("inkc-hub")
+        .version(env!("CARGO_PKG_VERSION"))
+        .about("INKC full node serving as a hub or relay")
+        .arg(
+            Arg::with_name("config")
+                .short("c")
+                .long("config")
+                .value_name("FILE")
+                .default_value("settings.json")
+                .help("the settings file"),
+        )
+        .arg(
+            Arg::with_name("pidfile")
+                .short("p")
+                .long("pidfile")
+                .value_name("FILE")
+                .help("write the process id to the file"),
+        )
+        .get_matches();
+
+    config::init_settings(matches.value_of("config").unwrap())?;
+    let settings = config::get_settings();
+    log_init(&settings)?;
+    info!("settings = {:?}", settings);
+
+    let pidfile = matches.value_of("pidfile");
+    if let Some(pidfile) = pidfile {
+        write_pidfile(pidfile)?;
+    }
+
+    may::config().set_stack_size(0x2000);
+    signature::init_secp256k1()?;
+
+    // run the network stuff in coroutine context
+    go!(move || start_hub(&settings))
+        .join()
+        .map_err(|_| format_err!("start hub panicked"))??;
+
+    // block until SIGINT or SIGTERM
+    let (tx, rx) = mpsc::channel();
+    ctrlc::set_handler(move || {
+        let _ = tx.send(());
+    })?;
+    handle_sighup()?;
+    rx.recv()?;
+
+    info!("shutting down");
+    WSS.close_all();
+    if let Some(pidfile) = pidfile {
+        if let Err(e) = fs::remove_file(pidfile) {
+            error!("failed to remove pidfile {}, err = {}", pidfile, e);
+        }
+    }
+    info!("bye from main!\n\n");
+    Ok(())
+}
@@ -0,0 +1,153 @@
+use std::collections::{HashMap, VecDeque};
+use std::hash::Hash;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CacheStats {
+    pub hits: usize,
+    pub misses: usize,
+    pub size: usize,
+    pub capacity: usize,
+}
+
+// a bounded key-value cache, the oldest inserted entry is evicted first
+// hit/miss counters are atomic so that lookups only need a shared reference
+pub struct Cache<K, V> {
+    capacity: usize,
+    map: HashMap<K, V>,
+    // insertion order of the keys, may contain keys that are already removed
+    order: VecDeque<K>,
+    hits: AtomicUsize,
+    misses: AtomicUsize,
+}
+
+impl<K: Clone + Hash + Eq, V: Clone> Cache<K, V> {
+    pub fn new(capacity: usize) -> Self {
+        Cache {
+            capacity,
+            map: HashMap::new(),
+            order: VecDeque::new(),
+            hits: AtomicUsize::new(0),
+            misses: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn get(&self, key: &K) -> Option<V> {
+        match self.map.get(key) {
+            Some(v) => {
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                Some(v.clone())
+            }
+            None => {
+                self.misses.fetch_add(1, Ordering::Relaxed);
+                None
+            }
+        }
+    }
+
+    pub fn contains_key(&self, key: &K) -> bool {
+        self.map.contains_key(key)
+    }
+
+    pub fn insert(&mut self, key: K, value: V) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        if self.map.insert(key.clone(), value).is_none() {
+            self.order.push_back(key);
+        }
+
+        while self.map.len() > self.capacity {
+            match self.order.pop_front() {
+                Some(k) => {
+                    self.map.remove(&k);
+                }
+                None => break,
+            }
+        }
+
+        // drop the stale keys left by remove() once they dominate the queue
+        if self.order.len() > self.capacity * 2 {
+            let map = &self.map;
+            self.order.retain(|k| map.contains_key(k));
+        }
+    }
+
+    pub fn remove(&mut self, key: &K) -> Option<V> {
+        self.map.remove(key)
+    }
+
+    pub fn clear(&mut self) {
+        self.map.clear();
+        self.order.clear();
+    }
+
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+
+    pub fn get_stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            size: self.map.len(),
+            capacity: self.capacity,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cache_evict_oldest() {
+        let mut cache = Cache::new(2);
+        cache.insert("a", 1);
+        cache.insert("b", 2);
+        cache.insert("c", 3);
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.get(&"a"), None);
+        assert_eq!(cache.get(&"b"), Some(2));
+        assert_eq!(cache.get(&"c"), Some(3));
+
+        // update an existing key doesn't change the order
+        cache.insert("b", 4);
+        cache.insert("d", 5);
+        assert_eq!(cache.get(&"b"), None);
+        assert_eq!(cache.get(&"c"), Some(3));
+        assert_eq!(cache.get(&"d"), Some(5));
+    }
+
+    #[test]
+    fn test_cache_remove_and_stats() {
+        let mut cache = Cache::new(2);
+        cache.insert("a", 1);
+        cache.insert("b", 2);
+        assert_eq!(cache.remove(&"a"), Some(1));
+        cache.insert("c", 3);
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.get(&"b"), Some(2));
+        assert_eq!(cache.get(&"a"), None);
+
+        let stats = cache.get_stats();
+        assert_eq!(stats.hits, 1);
+        assert_eq!(stats.misses, 1);
+        assert_eq!(stats.size, 2);
+
+        cache.clear();
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn test_cache_zero_capacity() {
+        let mut cache = Cache::new(0);
+        cache.insert("a", 1);
+        assert_eq!(cache.get(&"a"), None);
+    }
+}
@@ -1,6 +1,8 @@
-use error::Result;
+use std::time::Instant;
+
+use error::{INKCError, Result};
 use joint::Joint;
-use may::sync::Mutex;
+use may::sync::{Mutex, RwLock};
 use rusqlite::Connection;
 use storage;
 use witness_proof;
@@ -8,13 +10,86 @@ use witness_proof;
 lazy_static! {
     static ref CATCHUP_MUTEX: Mutex<()> = Mutex::new(());
     static ref HASHTREE_MUTEX: Mutex<()> = Mutex::new(());
+    static ref CATCHUP_STATE: RwLock<Option<CatchupState>> = RwLock::new(None);
+}
+
+struct CatchupState {
+    start_mci: u32,
+    start_time: Instant,
+    target_mci: Option<u32>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CatchupProgress {
+    pub current_mci: u32,
+    // the last mci of the peer we are catching up from
+    pub target_mci: Option<u32>,
+    pub chain_balls_pending: u32,
+    pub hash_tree_balls_pending: u32,
+    // stabilized mcis per second
+    pub rate: f64,
+}
+
+pub fn start_catchup_progress(db: &Connection) -> Result<()> {
+    let start_mci = storage::read_last_stable_mc_index(db)?;
+    let mut g = CATCHUP_STATE.write().unwrap();
+    *g = Some(CatchupState {
+        start_mci,
+        start_time: Instant::now(),
+        target_mci: None,
+    });
+    Ok(())
+}
+
+// the target is known once the peer sends its catchup chain
+pub fn set_catchup_target_mci(target_mci: Option<u32>) {
+    let mut g = CATCHUP_STATE.write().unwrap();
+    if let Some(ref mut state) = *g {
+        state.target_mci = target_mci;
+    }
+}
+
+pub fn finish_catchup_progress() {
+    let mut g = CATCHUP_STATE.write().unwrap();
+    *g = None;
+}
+
+// return None if not catching up
+pub fn get_catchup_progress(db: &Connection) -> Result<Option<CatchupProgress>> {
+    let g = CATCHUP_STATE.read().unwrap();
+    let state = match *g {
+        Some(ref state) => state,
+        None => return Ok(None),
+    };
+
+    let current_mci = storage::read_last_stable_mc_index(db)?;
+    let mut stmt = db.prepare_cached("SELECT COUNT(*) FROM catchup_chain_balls")?;
+    let chain_balls_pending = stmt.query_row(&[], |row| row.get::<_, u32>(0))?;
+    let mut stmt = db.prepare_cached("SELECT COUNT(*) FROM hash_tree_balls")?;
+    let hash_tree_balls_pending = stmt.query_row(&[], |row| row.get::<_, u32>(0))?;
+
+    let elapsed = state.start_time.elapsed();
+    let secs = elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) / 1e9;
+    let rate = if secs > 0.0 {
+        f64::from(current_mci.saturating_sub(state.start_mci)) / secs
+    } else {
+        0.0
+    };
+
+    Ok(Some(CatchupProgress {
+        current_mci,
+        target_mci: state.target_mci,
+        chain_balls_pending,
+        hash_tree_balls_pending,
+        rate,
+    }))
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct CatchupReq {
-    last_stable_mci: u32,
-    last_known_mci: u32,
-    witnesses: Vec<String>,
+    pub last_stable_mci: u32,
+    pub last_known_mci: u32,
+    pub witnesses: Vec<String>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -29,6 +104,16 @@ pub struct CatchupChain {
     pub witness_change_and_definition: Vec<Joint>,
 }
 
+impl CatchupChain {
+    /// the last mci of the peer, its unstable main chain units come first
+    pub fn get_last_mci(&self) -> Option<u32> {
+        self.unstable_mc_joints
+            .iter()
+            .filter_map(|joint| joint.unit.main_chain_index)
+            .max()
+    }
+}
+
 pub fn prepare_catchup_chain(db: &Connection, catchup_req: CatchupReq) -> Result<CatchupChain> {
     let CatchupReq {
         last_stable_mci,
@@ -39,7 +124,7 @@ pub fn prepare_catchup_chain(db: &Connection, catchup_req: CatchupReq) -> Result
     let mut stable_last_ball_joints = Vec::new();
 
     ensure!(
-        last_stable_mci >= last_known_mci,
+        last_stable_mci < last_known_mci || (last_stable_mci == 0 && last_known_mci == 0),
         "last_stable_mci >= last_known_mci"
     );
     ensure!(witnesses.len() == 12, "invalide witness list");
@@ -61,7 +146,21 @@ pub fn prepare_catchup_chain(db: &Connection, catchup_req: CatchupReq) -> Result
         });
     }
 
-    let witness_proof = witness_proof::prepare_witness_proof(db, witnesses, last_stable_mci)?;
+    let witness_proof = match witness_proof::prepare_witness_proof(db, witnesses, last_stable_mci) {
+        Ok(witness_proof) => witness_proof,
+        Err(e) => match e.downcast::<INKCError>() {
+            Ok(INKCError::CatchupAlreadyCurrent) => {
+                return Ok(CatchupChain {
+                    status: Some("current".to_owned()),
+                    unstable_mc_joints: Vec::new(),
+                    stable_last_ball_joints: Vec::new(),
+                    witness_change_and_definition: Vec::new(),
+                })
+            }
+            Ok(e) => return Err(e.into()),
+            Err(e) => return Err(e),
+        },
+    };
     let mut last_ball_unit = witness_proof.last_ball_unit;
 
     loop {
@@ -88,7 +187,11 @@ pub fn prepare_catchup_chain(db: &Connection, catchup_req: CatchupReq) -> Result
 }
 
 // return true if alreay current, or else flase
-pub fn process_catchup_chain(db: &Connection, catchup_chain: CatchupChain) -> Result<bool> {
+pub fn process_catchup_chain(
+    db: &Connection,
+    catchup_chain: CatchupChain,
+    witnesses: &[String],
+) -> Result<bool> {
     if let Some(s) = catchup_chain.status {
         if s.as_str() == "current" {
             return Ok(true);
@@ -102,6 +205,7 @@ pub fn process_catchup_chain(db: &Connection, catchup_chain: CatchupChain) -> Re
 
     let witness_proof = witness_proof::process_witness_proof(
         db,
+        witnesses,
         catchup_chain.unstable_mc_joints,
         catchup_chain.witness_change_and_definition,
         true,
@@ -119,14 +223,13 @@ pub fn process_catchup_chain(db: &Connection, catchup_chain: CatchupChain) -> Re
         "first stable unit is not last ball unit of any unstable unit"
     );
 
-    let last_stable_mc_unit_props;
     let mut last_ball = &assoc_last_ball_by_last_ball_unit[last_ball_unit];
     ensure!(
         first_stable_joint.ball.as_ref() == Some(last_ball),
         "last ball and last ball unit do not match"
     );
 
-    let mut chain_balls = Vec::<&String>::new();
+    let mut chain_balls = Vec::new();
     for joint in catchup_chain.stable_last_ball_joints.iter() {
         ensure!(joint.ball.is_some(), "stable but no ball");
         ensure!(joint.has_valid_hashes(), "invalid hash");
@@ -143,7 +246,7 @@ pub fn process_catchup_chain(db: &Connection, catchup_chain: CatchupChain) -> Re
             last_ball_unit = unit.last_ball_unit.as_ref().unwrap();
         }
 
-        chain_balls.push(joint.ball.as_ref().unwrap());
+        chain_balls.push(joint.ball.clone().unwrap());
     }
     // FIXME: use a dqueue to avoid reverse
     chain_balls.reverse();
@@ -151,15 +254,31 @@ pub fn process_catchup_chain(db: &Connection, catchup_chain: CatchupChain) -> Re
     // FIXME: is this lock too earlier?
     let _g = CATCHUP_MUTEX.lock().unwrap();
     let mut stmt = db.prepare_cached("SELECT 1 FROM catchup_chain_balls LIMIT 1")?;
-    ensure!(!stmt.exists(&[])?, "duplicate catchup_chain_balls");
+    if stmt.exists(&[])? {
+        // left by an interrupted catchup, the new chain supersedes it
+        info!("catchup chain already exists, replace it");
+        let mut stmt = db.prepare_cached("DELETE FROM catchup_chain_balls")?;
+        stmt.execute(&[])?;
+    }
 
-    // adjust first chain ball if necessary and make sure it is the only stable unit in the entire chain
+    adjust_first_chain_ball(db, &mut chain_balls)?;
+
+    // validation complete, now write the chain for future downloading of hash trees
+    let mut stmt = db.prepare_cached("INSERT INTO catchup_chain_balls (ball) VALUES (?)")?;
+    for ball in &chain_balls {
+        stmt.execute(&[ball])?;
+    }
+    Ok(false)
+}
+
+// adjust first chain ball if necessary and make sure it is the only stable unit in the entire chain
+fn adjust_first_chain_ball(db: &Connection, chain_balls: &mut Vec<String>) -> Result<()> {
     let mut stmt = db.prepare_cached(
         "SELECT is_stable, is_on_main_chain, main_chain_index \
          FROM balls JOIN units USING(unit) WHERE ball=?",
     )?;
 
-    let mut rows = stmt.query_map(&[chain_balls[0]], |row| {
+    let mut rows = stmt.query_map(&[&chain_balls[0]], |row| {
         (
             row.get::<_, u32>(0),
             row.get::<_, u32>(1),
@@ -168,8 +287,8 @@ pub fn process_catchup_chain(db: &Connection, catchup_chain: CatchupChain) -> Re
     })?;
     let (is_stable, is_on_main_chain, main_chain_index) = match rows.next() {
         None => {
-            if storage::is_genesis_ball(chain_balls[0]) {
-                return Ok(false);
+            if storage::is_genesis_ball(&chain_balls[0]) {
+                return Ok(());
             }
             bail!("first chain ball {} is not known", chain_balls[0]);
         }
@@ -187,26 +306,26 @@ pub fn process_catchup_chain(db: &Connection, catchup_chain: CatchupChain) -> Re
         chain_balls[0]
     );
 
-    last_stable_mc_unit_props = storage::read_last_stable_mc_unit_props(db)?;
+    let last_stable_mc_unit_props = storage::read_last_stable_mc_unit_props(db)?;
     let last_stable_mci = last_stable_mc_unit_props.main_chain_index;
     if main_chain_index > last_stable_mci {
         bail!("first chain ball {} mci is too large", chain_balls[0]);
     }
     if last_stable_mci == main_chain_index {
-        return Ok(false);
+        return Ok(());
     }
 
     // replace to avoid receiving duplicates
-    chain_balls[0] = &last_stable_mc_unit_props.ball;
-    if chain_balls.len() > 1 {
-        return Ok(false);
+    chain_balls[0] = last_stable_mc_unit_props.ball;
+    if chain_balls.len() == 1 {
+        return Ok(());
     }
 
     let mut stmt =
         db.prepare_cached("SELECT is_stable FROM balls JOIN units USING(unit) WHERE ball=?")?;
-    let mut rows = stmt.query_map(&[chain_balls[1]], |row| row.get::<_, u32>(0))?;
+    let mut rows = stmt.query_map(&[&chain_balls[1]], |row| row.get::<_, u32>(0))?;
     let second_ball_is_stable = match rows.next() {
-        None => return Ok(false),
+        None => return Ok(()),
         Some(row) => row?,
     };
 
@@ -215,16 +334,7 @@ pub fn process_catchup_chain(db: &Connection, catchup_chain: CatchupChain) -> Re
         "second chain ball {} must not be stable",
         chain_balls[1]
     );
-
-    // validation complete, now write the chain for future downloading of hash trees
-    let ball_str = chain_balls
-        .iter()
-        .map(|s| format!("('{}')", s))
-        .collect::<Vec<_>>()
-        .join(", ");
-    let sql = format!("INSERT INTO catchup_chain_balls (ball) VALUES {}", ball_str);
-    db.execute(&sql, &[])?;
-    Ok(false)
+    Ok(())
 }
 
 #[derive(Serialize, Deserialize)]
@@ -235,15 +345,16 @@ pub struct HashTreeReq {
 
 #[derive(Serialize, Deserialize)]
 pub struct BallProps {
-    unit: String,
-    ball: Option<String>, // this should not be an option
+    pub unit: String,
+    pub ball: Option<String>, // this should not be an option
     #[serde(skip_serializing_if = "Option::is_none")]
-    content_hash: Option<String>,
-    is_nonserial: bool,
-    #[serde(skip_serializing_if = "Vec::is_empty")]
-    parent_balls: Vec<String>,
-    #[serde(skip_serializing_if = "Vec::is_empty")]
-    skiplist_balls: Vec<String>,
+    pub content_hash: Option<String>,
+    #[serde(default)]
+    pub is_nonserial: bool,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub parent_balls: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub skiplist_balls: Vec<String>,
 }
 
 pub fn read_hash_tree(db: &Connection, hash_tree_req: HashTreeReq) -> Result<Vec<BallProps>> {
@@ -398,7 +509,7 @@ pub fn process_hash_tree(balls: Vec<BallProps>) -> Result<()> {
             let mut stmt = tx.prepare_cached(
                 "INSERT OR IGNORE INTO hash_tree_balls (ball, unit) VALUES(?,?)",
             )?;
-            stmt.insert(&[ball, &ball_prop.unit])?;
+            stmt.execute(&[ball, &ball_prop.unit])?;
             Ok(())
         };
 
@@ -411,7 +522,7 @@ pub fn process_hash_tree(balls: Vec<BallProps>) -> Result<()> {
             let sql = format!(
                 "SELECT ball FROM hash_tree_balls \
                  WHERE ball IN({}) UNION SELECT ball FROM balls WHERE ball IN({})",
-                parent_balls_set, skiplist_balls_set
+                skiplist_balls_set, skiplist_balls_set
             );
             let mut stmt = tx.prepare(&sql)?;
             let rows = stmt.query_map(&[], |row| row.get::<_, String>(0))?;
@@ -425,7 +536,8 @@ pub fn process_hash_tree(balls: Vec<BallProps>) -> Result<()> {
             add_ball()
         };
 
-        if !ball_prop.parent_balls.is_empty() {
+        // genesis
+        if ball_prop.parent_balls.is_empty() {
             check_skiplist_ball_exist()?;
             continue;
         }
@@ -487,27 +599,39 @@ pub fn process_hash_tree(balls: Vec<BallProps>) -> Result<()> {
         check_skiplist_ball_exist()?;
     }
 
-    let mut stmt = tx.prepare_cached(
-        "SELECT ball, main_chain_index \
-         FROM catchup_chain_balls LEFT JOIN balls USING(ball) LEFT JOIN units USING(unit) \
-         ORDER BY member_index LIMIT 2",
-    )?;
+    {
+        let mut stmt = tx.prepare_cached(
+            "SELECT ball, main_chain_index \
+             FROM catchup_chain_balls LEFT JOIN balls USING(ball) LEFT JOIN units USING(unit) \
+             ORDER BY member_index LIMIT 2",
+        )?;
 
-    let rows = stmt.query_map(&[], |row| (row.get::<_, String>(0), row.get::<_, u32>(1)))?;
-    let mut rows_data = Vec::new();
-    for row in rows {
-        rows_data.push(row?);
-    }
-    if rows_data.len() != 2 {
-        bail!("expecting to have 2 elements in the chain");
-    }
-    if rows_data[1].0 != last_ball {
-        bail!("tree root doesn't match second chain element");
-    }
+        let rows = stmt.query_map(&[], |row| {
+            (row.get::<_, String>(0), row.get::<_, Option<u32>>(1))
+        })?;
+        let mut rows_data = Vec::new();
+        for row in rows {
+            rows_data.push(row?);
+        }
+        if rows_data.len() != 2 {
+            bail!("expecting to have 2 elements in the chain");
+        }
+        if rows_data[1].0 != last_ball {
+            bail!("tree root doesn't match second chain element");
+        }
+        if rows_data[0].1 != Some(max_mci) {
+            bail!(
+                "max mci doesn't match first chain element: max mci = {}, first mci = {:?}",
+                max_mci,
+                rows_data[0].1
+            );
+        }
 
-    let mut stmt = tx.prepare_cached("DELETE FROM catchup_chain_balls WHERE ball=?")?;
-    stmt.execute(&[&rows_data[0].0])?;
-    purge_handled_balls_from_hash_tree(&tx)?;
+        let mut stmt = tx.prepare_cached("DELETE FROM catchup_chain_balls WHERE ball=?")?;
+        stmt.execute(&[&rows_data[0].0])?;
+        purge_handled_balls_from_hash_tree(&tx)?;
+    }
+    tx.commit()?;
 
     Ok(())
 }
@@ -525,3 +649,87 @@ pub fn purge_handled_balls_from_hash_tree(db: &Connection) -> Result<()> {
     }
     Ok(())
 }
+
+#[cfg(test)]
+fn read_catchup_chain_balls(db: &Connection) -> Vec<String> {
+    let mut stmt = db
+        .prepare("SELECT ball FROM catchup_chain_balls ORDER BY member_index")
+        .unwrap();
+    let balls = stmt
+        .query_map(&[], |row| row.get(0))
+        .unwrap()
+        .collect::<::std::result::Result<Vec<_>, _>>()
+        .unwrap();
+    balls
+}
+
+#[test]
+fn test_prepare_catchup_chain() {
+    let dag = ::test_utils::TestDag::new("catchup prepare");
+    let req = |last_stable_mci, last_known_mci| CatchupReq {
+        last_stable_mci,
+        last_known_mci,
+        witnesses: dag.get_witness_addresses(),
+    };
+
+    assert!(prepare_catchup_chain(&dag.db, req(5, 5)).is_err());
+    assert!(prepare_catchup_chain(&dag.db, req(6, 5)).is_err());
+    // we don't know mci 5 yet
+    let chain = prepare_catchup_chain(&dag.db, req(3, 5)).unwrap();
+    assert_eq!(chain.status, Some(String::from("current")));
+}
+
+#[test]
+fn test_process_catchup_chain() {
+    use serde_json;
+    use test_utils::TestDag;
+
+    // the nodes have the same tag so that the client DAG is a prefix of the server DAG
+    let mut server = TestDag::new("catchup");
+    server.add_units(40);
+    let witnesses = server.get_witness_addresses();
+    let prepare = |client: &TestDag| {
+        let req = CatchupReq {
+            last_stable_mci: storage::read_last_stable_mc_index(&client.db).unwrap(),
+            last_known_mci: storage::read_last_main_chain_index(&client.db).unwrap(),
+            witnesses: witnesses.clone(),
+        };
+        let chain = prepare_catchup_chain(&server.db, req).unwrap();
+        assert!(chain.status.is_none());
+        let mut balls = chain
+            .stable_last_ball_joints
+            .iter()
+            .map(|joint| joint.ball.clone().unwrap())
+            .collect::<Vec<_>>();
+        balls.reverse();
+        // as sent over the network
+        let chain: CatchupChain =
+            serde_json::from_value(serde_json::to_value(chain).unwrap()).unwrap();
+        assert_eq!(
+            chain.get_last_mci(),
+            Some(storage::read_last_main_chain_index(&server.db).unwrap())
+        );
+        (chain, balls)
+    };
+
+    // a new node that only has the genesis
+    let client = TestDag::new("catchup");
+    let (chain, balls) = prepare(&client);
+    assert!(balls.len() >= 2);
+    assert!(storage::is_genesis_ball(&balls[0]));
+    assert!(!process_catchup_chain(&client.db, chain, &witnesses).unwrap());
+    assert_eq!(read_catchup_chain_balls(&client.db), balls);
+
+    // a node that is behind, the chain starts from its last stable ball
+    let mut client = TestDag::new("catchup");
+    client.add_units(15);
+    let last_stable_ball = storage::read_last_stable_mc_unit_props(&client.db)
+        .unwrap()
+        .ball;
+    let (chain, balls) = prepare(&client);
+    assert!(balls.len() >= 2);
+    assert!(!process_catchup_chain(&client.db, chain, &witnesses).unwrap());
+    let chain_balls = read_catchup_chain_balls(&client.db);
+    assert_eq!(chain_balls[0], last_stable_ball);
+    assert_eq!(chain_balls[1..], balls[1..]);
+}
@@ -1,25 +1,390 @@
 extern crate config;
 
+use std::collections::HashMap;
+
 use self::config::*;
+use error::Result;
 use may::sync::RwLock;
 
-pub const WS_PORT: u16 = 8080;
 pub const COUNT_WITNESSES: usize = 12;
 pub const MAJORITY_OF_WITNESSES: usize = 7;
+pub const MAX_WITNESS_LIST_MUTATIONS: usize = 1;
 pub const GENESIS_UNIT: &str = "rg1RzwKwnfRHjBojGol3gZaC5w7kR++rOR6O61JRsrQ=";
 pub const VERSION: &str = "1.0";
 pub const ALT: &str = "1";
-pub const STALLED_TIMEOUT: usize = 10;
+pub const MAX_GET_JOINT_TRIES: usize = 3;
+pub const MAX_CATCHUP_TRIES: usize = 3;
+// seconds of the exponential backoff when reconnecting to a peer
+pub const MIN_RECONNECT_DELAY: u64 = 1;
+pub const MAX_RECONNECT_DELAY: u64 = 300;
 pub const MAX_MESSAGES_PER_UNIT: usize = 128;
+pub const MAX_PARENTS_PER_UNIT: usize = 16;
+pub const MAX_AUTHORS_PER_UNIT: usize = 16;
+pub const MAX_AUTHENTIFIER_LENGTH: usize = 4096;
+pub const MAX_COMPLEXITY: usize = 100;
+pub const MAX_OPS: usize = 300;
+pub const HASH_LENGTH: usize = 44;
+pub const PUBKEY_LENGTH: usize = 44;
+pub const MAX_DATA_FEED_NAME_LENGTH: usize = 64;
+pub const MAX_DATA_FEED_VALUE_LENGTH: usize = 64;
+pub const MAX_CHOICES_PER_POLL: usize = 128;
+pub const MAX_CHOICE_LENGTH: usize = 64;
+pub const MAX_INPUTS_PER_PAYMENT_MESSAGE: usize = 128;
+pub const MAX_OUTPUTS_PER_PAYMENT_MESSAGE: usize = 128;
+pub const TOTAL_WHITEBYTES: i64 = 1_000_000_000_000_000;
 
 pub const COUNT_MC_BALLS_FOR_PAID_WITNESSING: u32 = 100;
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NodeMode {
+    // serve light clients and accept hub logins
+    Hub,
+    // only relay joints between full nodes
+    Relay,
+}
+
+// the config crate can't deserialize an enum from a string, so go through the string
+impl<'de> ::serde::Deserialize<'de> for NodeMode {
+    fn deserialize<D>(deserializer: D) -> ::std::result::Result<Self, D::Error>
+    where
+        D: ::serde::Deserializer<'de>,
+    {
+        use serde::de::Error;
+        let mode = String::deserialize(deserializer)?;
+        match mode.as_str() {
+            "hub" => Ok(NodeMode::Hub),
+            "relay" => Ok(NodeMode::Relay),
+            _ => Err(D::Error::unknown_variant(&mode, &["hub", "relay"])),
+        }
+    }
+}
+
+/// node settings loaded from settings.json, each field can be overridden by
+/// an `INKC_` prefixed environment variable, e.g. `INKC_LISTEN_ADDRESS`, the
+/// lists and maps are comma separated, e.g. `INKC_TLS_PINNED_CERTS=host=fingerprint`
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct Settings {
+    pub debug: bool,
+    pub mode: NodeMode,
+    pub listen_address: String,
+    pub data_dir: String,
+    // 0 means decided by the number of cpus
+    pub db_pool_size: usize,
+    pub peers: Vec<String>,
+    // number of outbound connections the peer manager tries to keep
+    pub max_outbound_connections: usize,
+    // seconds to wait for a response or progress before giving up
+    pub stalled_timeout: u64,
+    // seconds before an unhandled joint is purged
+    pub unhandled_joints_timeout: u64,
+    pub log_level: String,
+    // logs are also appended to this file if set
+    pub log_file: Option<String>,
+    pub program: String,
+    pub program_version: String,
+    pub genesis_joint: String,
+    // witnesses used when my_witnesses is empty on first start
+    pub initial_witnesses: Vec<String>,
+    pub max_cached_units: usize,
+    pub max_cached_unstable_units: usize,
+    // seconds of the sliding window that the peer reputation is counted over
+    pub peer_events_window: u64,
+    // a peer is not judged until it has sent this many events in the window
+    pub min_peer_events: u32,
+    // ban the peer once the ratio of invalid and known bad events reaches it
+    pub max_bad_peer_ratio: f64,
+    // seconds that a bad peer is banned
+    pub peer_ban_duration: u64,
+    // pem files of the server cert chain and private key, the server
+    // listens on wss if both are set, or else on plain ws
+    pub tls_cert_file: Option<String>,
+    pub tls_key_file: Option<String>,
+    // pem file of the ca certs to verify wss peers, the well known roots if not set
+    pub tls_ca_file: Option<String>,
+    // peer host => base64 sha256 of its der cert, a pinned peer is only
+    // verified by the fingerprint, e.g. for a self-signed cert
+    pub tls_pinned_certs: HashMap<String, String>,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            debug: false,
+            mode: NodeMode::Hub,
+            listen_address: String::from("0.0.0.0:8080"),
+            data_dir: String::from("db"),
+            db_pool_size: 0,
+            peers: Vec::new(),
+            max_outbound_connections: 5,
+            stalled_timeout: 10,
+            unhandled_joints_timeout: 3600,
+            log_level: String::from("debug"),
+            log_file: None,
+            program: String::from("rust-INKC-hub"),
+            program_version: String::from("0.1.0"),
+            genesis_joint: String::from("genesis.json"),
+            initial_witnesses: Vec::new(),
+            max_cached_units: 100_000,
+            max_cached_unstable_units: 10_000,
+            peer_events_window: 3600,
+            min_peer_events: 10,
+            max_bad_peer_ratio: 0.5,
+            peer_ban_duration: 3600,
+            tls_cert_file: None,
+            tls_key_file: None,
+            tls_ca_file: None,
+            tls_pinned_certs: HashMap::new(),
+        }
+    }
+}
+
+impl Settings {
+    pub fn is_hub(&self) -> bool {
+        self.mode == NodeMode::Hub
+    }
+
+    pub fn get_db_path(&self) -> String {
+        format!("{}/INKC.sqlite", self.data_dir)
+    }
+
+    pub fn get_db_pool_size(&self) -> usize {
+        match self.db_pool_size {
+            0 => ::num_cpus::get() * 4,
+            n => n,
+        }
+    }
+
+    pub fn get_log_level(&self) -> Result<::log::LevelFilter> {
+        use std::str::FromStr;
+        ::log::LevelFilter::from_str(&self.log_level)
+            .map_err(|_| format_err!("invalid log_level {}", self.log_level))
+    }
+
+    pub fn check(&self) -> Result<()> {
+        use std::net::SocketAddr;
+
+        if self.listen_address.parse::<SocketAddr>().is_err() {
+            bail!("invalid listen_address {}", self.listen_address);
+        }
+        ensure!(!self.data_dir.is_empty(), "data_dir can't be empty");
+        for peer in &self.peers {
+            ensure!(
+                peer.starts_with("ws://") || peer.starts_with("wss://"),
+                "peer {} is not a websocket url",
+                peer
+            );
+            ::url::Url::parse(peer).map_err(|e| format_err!("invalid peer {}: {}", peer, e))?;
+        }
+        ensure!(
+            self.max_outbound_connections > 0,
+            "max_outbound_connections must be positive"
+        );
+        ensure!(self.stalled_timeout > 0, "stalled_timeout must be positive");
+        ensure!(
+            self.unhandled_joints_timeout > 0,
+            "unhandled_joints_timeout must be positive"
+        );
+        self.get_log_level()?;
+        ensure!(
+            self.peer_events_window > 0,
+            "peer_events_window must be positive"
+        );
+        ensure!(
+            self.max_bad_peer_ratio > 0.0 && self.max_bad_peer_ratio <= 1.0,
+            "max_bad_peer_ratio must be in (0, 1]"
+        );
+        ensure!(
+            self.tls_cert_file.is_some() == self.tls_key_file.is_some(),
+            "tls_cert_file and tls_key_file must be set together"
+        );
+        ensure!(
+            self.initial_witnesses.is_empty() || self.initial_witnesses.len() == COUNT_WITNESSES,
+            "initial_witnesses must contain {} addresses",
+            COUNT_WITNESSES
+        );
+        Ok(())
+    }
+}
+
+fn split_env_list(value: &str) -> Vec<String> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+// the environment source only gives strings, so the lists and maps are parsed
+// from comma separated values and override what it gives
+fn merge_env_lists<F>(conf: &mut Config, get_var: F) -> Result<()>
+where
+    F: Fn(&str) -> Option<String>,
+{
+    for key in &["peers", "initial_witnesses"] {
+        if let Some(value) = get_var(&format!("INKC_{}", key.to_uppercase())) {
+            conf.set(key, split_env_list(&value))?;
+        }
+    }
+    if let Some(value) = get_var("INKC_TLS_PINNED_CERTS") {
+        let mut certs = HashMap::new();
+        for item in split_env_list(&value) {
+            // the base64 fingerprint may end with '='
+            let mut parts = item.splitn(2, '=');
+            match (parts.next(), parts.next()) {
+                (Some(host), Some(fingerprint)) if !host.is_empty() => {
+                    certs.insert(host.to_owned(), fingerprint.to_owned());
+                }
+                _ => bail!("invalid INKC_TLS_PINNED_CERTS item {}", item),
+            }
+        }
+        conf.set("tls_pinned_certs", certs)?;
+    }
+    Ok(())
+}
+
+fn load_settings() -> Result<Settings> {
+    let file_name = SETTINGS_FILE.read().unwrap().clone();
+    let mut conf = Config::default();
+    conf.merge(File::with_name(&file_name).required(false))?
+        .merge(Environment::with_prefix("INKC"))?;
+    merge_env_lists(&mut conf, |name| ::std::env::var(name).ok())?;
+    let settings: Settings = conf.try_into()?;
+    settings.check()?;
+    Ok(settings)
+}
+
 lazy_static! {
-    pub static ref CONFIG: RwLock<Config> = RwLock::new({
-        let mut settings = Config::default();
-        settings
-            .merge(File::with_name("settings.json"))
-            .expect("failed to load config");
-        settings
-    });
+    static ref GENESIS: RwLock<String> = RwLock::new(GENESIS_UNIT.to_owned());
+    static ref SETTINGS_FILE: RwLock<String> = RwLock::new(String::from("settings.json"));
+    static ref SETTINGS: RwLock<Settings> =
+        RwLock::new(load_settings().expect("failed to load settings"));
+}
+
+/// load the settings from the given file, must be called at startup
+/// before anything reads the settings
+pub fn init_settings(file_name: &str) -> Result<()> {
+    *SETTINGS_FILE.write().unwrap() = file_name.to_owned();
+    let settings = load_settings()?;
+    *SETTINGS.write().unwrap() = settings;
+    Ok(())
+}
+
+/// the genesis unit of the network, `GENESIS_UNIT` unless changed by `set_genesis_unit`
+pub fn get_genesis_unit() -> String {
+    GENESIS.read().unwrap().clone()
+}
+
+/// run on the network of another genesis, e.g. a testnet,
+/// must be called at startup before any unit is handled
+pub fn set_genesis_unit(unit: &str) {
+    *GENESIS.write().unwrap() = unit.to_owned();
+}
+
+/// return a snapshot of the current settings
+pub fn get_settings() -> Settings {
+    SETTINGS.read().unwrap().clone()
+}
+
+/// reload settings.json, only the peers, timeouts, log level and reputation
+/// limits take effect,
+/// the others need a restart
+pub fn reload_settings() -> Result<()> {
+    let new = load_settings()?;
+    let mut settings = SETTINGS.write().unwrap();
+    if new.listen_address != settings.listen_address
+        || new.data_dir != settings.data_dir
+        || new.db_pool_size != settings.db_pool_size
+        || new.mode != settings.mode
+    {
+        warn!("listen_address, data_dir, db_pool_size and mode changes need a restart");
+    }
+
+    settings.peers = new.peers;
+    settings.max_outbound_connections = new.max_outbound_connections;
+    settings.stalled_timeout = new.stalled_timeout;
+    settings.unhandled_joints_timeout = new.unhandled_joints_timeout;
+    settings.log_level = new.log_level;
+    settings.debug = new.debug;
+    settings.peer_events_window = new.peer_events_window;
+    settings.min_peer_events = new.min_peer_events;
+    settings.max_bad_peer_ratio = new.max_bad_peer_ratio;
+    settings.peer_ban_duration = new.peer_ban_duration;
+    ::log::set_max_level(settings.get_log_level()?);
+    Ok(())
+}
+
+#[test]
+fn test_settings_check() {
+    let settings = Settings::default();
+    assert!(settings.check().is_ok());
+
+    let mut bad = settings.clone();
+    bad.listen_address = String::from("localhost");
+    assert!(bad.check().is_err());
+
+    let mut bad = settings.clone();
+    bad.peers = vec![String::from("127.0.0.1:6655")];
+    assert!(bad.check().is_err());
+
+    let mut bad = settings.clone();
+    bad.log_level = String::from("verbose");
+    assert!(bad.check().is_err());
+
+    let mut bad = settings.clone();
+    bad.max_bad_peer_ratio = 1.5;
+    assert!(bad.check().is_err());
+
+    let mut bad = settings.clone();
+    bad.tls_cert_file = Some(String::from("hub.crt"));
+    assert!(bad.check().is_err());
+}
+
+#[test]
+fn test_load_node_mode() {
+    let mut conf = Config::default();
+    conf.merge(File::from_str(r#"{"mode": "relay"}"#, FileFormat::Json))
+        .unwrap();
+    let settings: Settings = conf.try_into().unwrap();
+    assert_eq!(settings.mode, NodeMode::Relay);
+
+    let mut conf = Config::default();
+    conf.merge(File::from_str(r#"{"mode": "full"}"#, FileFormat::Json))
+        .unwrap();
+    assert!(conf.try_into::<Settings>().is_err());
+}
+
+#[test]
+fn test_merge_env_lists() {
+    let mut vars = HashMap::new();
+    vars.insert("INKC_PEERS", "ws://127.0.0.1:6615, ws://127.0.0.1:6616");
+    vars.insert("INKC_INITIAL_WITNESSES", "");
+    vars.insert(
+        "INKC_TLS_PINNED_CERTS",
+        "localhost=a8JOBilgPU9iFMoLit57ZzielMvlC9fl09SmHHUt+so=",
+    );
+    let get_var = |name: &str| vars.get(name).map(|v| v.to_string());
+
+    let mut conf = Config::default();
+    conf.merge(File::from_str(
+        r#"{"peers": ["ws://127.0.0.1:6655"], "initial_witnesses": ["A"]}"#,
+        FileFormat::Json,
+    ))
+    .unwrap();
+    merge_env_lists(&mut conf, &get_var).unwrap();
+    let settings: Settings = conf.try_into().unwrap();
+    assert_eq!(
+        settings.peers,
+        vec!["ws://127.0.0.1:6615", "ws://127.0.0.1:6616"]
+    );
+    assert!(settings.initial_witnesses.is_empty());
+    assert_eq!(
+        settings.tls_pinned_certs["localhost"],
+        "a8JOBilgPU9iFMoLit57ZzielMvlC9fl09SmHHUt+so="
+    );
+
+    vars.insert("INKC_TLS_PINNED_CERTS", "localhost");
+    let get_var = |name: &str| vars.get(name).map(|v| v.to_string());
+    assert!(merge_env_lists(&mut Config::default(), get_var).is_err());
 }
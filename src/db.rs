@@ -1,6 +1,7 @@
+use std::fs;
 use std::ops::{Deref, DerefMut};
 
-use num_cpus;
+use config;
 use rusqlite::{Connection, OpenFlags};
 
 use may;
@@ -8,10 +9,49 @@ use may::sync::mpmc::{self, Receiver, Sender};
 
 use error::Result;
 
+// each migration upgrades the schema by one version, the version is kept in user_version
+// never change a released migration, append a new one instead
+const MIGRATIONS: &[&str] = &[
+    include_str!("schema.sql"),
+    include_str!("migrations/002_peers.sql"),
+    include_str!("migrations/003_peer_events.sql"),
+    include_str!("migrations/004_unit_authors_index.sql"),
+    include_str!("migrations/005_polls.sql"),
+];
+
 lazy_static! {
     pub static ref DB_POOL: DatabasePool = DatabasePool::new();
 }
 
+// create the database file if not exist and bring the schema up to date
+fn init_database(settings: &config::Settings) -> Result<()> {
+    fs::create_dir_all(&settings.data_dir)?;
+    let mut db = Connection::open_with_flags(
+        settings.get_db_path(),
+        OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_CREATE,
+    )?;
+    migrate(&mut db)
+}
+
+pub fn migrate(db: &mut Connection) -> Result<()> {
+    let version = db.query_row("PRAGMA user_version", &[], |row| row.get::<_, i64>(0))? as usize;
+    ensure!(
+        version <= MIGRATIONS.len(),
+        "database version {} is newer than the supported version {}",
+        version,
+        MIGRATIONS.len()
+    );
+
+    for (i, sql) in MIGRATIONS.iter().enumerate().skip(version) {
+        info!("upgrading database to version {}", i + 1);
+        let tx = db.transaction()?;
+        tx.execute_batch(sql)?;
+        tx.execute_batch(&format!("PRAGMA user_version={}", i + 1))?;
+        tx.commit()?;
+    }
+    Ok(())
+}
+
 pub struct DatabasePool {
     db_rx: Receiver<Connection>,
     db_tx: Sender<Connection>,
@@ -19,13 +59,19 @@ pub struct DatabasePool {
 
 impl DatabasePool {
     pub fn new() -> Self {
+        let settings = config::get_settings();
+        if let Err(e) = init_database(&settings) {
+            error!("init database failed, err = {}", e);
+            ::std::process::abort();
+        }
+
         // create the connection pool
         let (db_tx, db_rx) = mpmc::channel();
         may::coroutine::scope(|s| {
-            for _ in 0..(num_cpus::get() * 4) {
+            for _ in 0..settings.get_db_pool_size() {
                 go!(s, || {
                     let conn = match Connection::open_with_flags(
-                        "db/INKC.sqlite",
+                        settings.get_db_path(),
                         OpenFlags::SQLITE_OPEN_READ_WRITE,
                     ) {
                         Ok(conn) => conn,
@@ -92,8 +138,44 @@ impl Database {
         Ok(names)
     }
 
-    // TODO:
-    pub fn insert_witnesses(&self) -> Result<()> {
-        unimplemented!();
+    pub fn insert_witnesses(&self, witnesses: &[String]) -> Result<()> {
+        ensure!(
+            witnesses.len() == config::COUNT_WITNESSES,
+            "attempting to insert wrong number of witnesses: {}",
+            witnesses.len()
+        );
+
+        let values = witnesses
+            .iter()
+            .map(|s| format!("('{}')", s))
+            .collect::<Vec<_>>()
+            .join(", ");
+        let sql = format!("INSERT INTO my_witnesses (address) VALUES {}", values);
+        self.execute(&sql, &[])?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_migrate() {
+        let mut db = Connection::open_in_memory().unwrap();
+        migrate(&mut db).unwrap();
+        let version = db
+            .query_row("PRAGMA user_version", &[], |row| row.get::<_, i64>(0))
+            .unwrap();
+        assert_eq!(version as usize, MIGRATIONS.len());
+
+        // migrate again is a no-op
+        migrate(&mut db).unwrap();
+        let count = db
+            .query_row("SELECT COUNT(*) FROM units", &[], |row| {
+                row.get::<_, i64>(0)
+            })
+            .unwrap();
+        assert_eq!(count, 0);
     }
 }
@@ -1,7 +1,1180 @@
+use std::collections::HashMap;
+
+use config;
 use error::Result;
-use serde_json::Value;
+use merkle;
+use object_hash;
+use rusqlite::types::ToSql;
+use rusqlite::Connection;
+use serde_json::{self, Value};
+use signature;
+use spec::{Payload, Unit};
+use storage;
+use validation::ValidationState;
+
+const RELATIONS: [&str; 6] = ["=", ">", "<", ">=", "<=", "!="];
+
+/// check if the definition references other units or addresses
+pub fn has_references(definition: &Value) -> Result<bool> {
+    let (op, args) = parse_op(definition)?;
+
+    match op {
+        "or" | "and" => {
+            for arg in args.as_array().unwrap_or(&Vec::new()) {
+                if has_references(arg)? {
+                    return Ok(true);
+                }
+            }
+            Ok(false)
+        }
+        "r of set" => {
+            for arg in args["set"].as_array().unwrap_or(&Vec::new()) {
+                if has_references(arg)? {
+                    return Ok(true);
+                }
+            }
+            Ok(false)
+        }
+        "weighted and" => {
+            for arg in args["set"].as_array().unwrap_or(&Vec::new()) {
+                if has_references(&arg["value"])? {
+                    return Ok(true);
+                }
+            }
+            Ok(false)
+        }
+        "sig" | "hash" | "cosigned by" => Ok(false),
+        "not" => has_references(args),
+        "address"
+        | "definition template"
+        | "seen address"
+        | "seen"
+        | "in data feed"
+        | "in merkle"
+        | "mci"
+        | "age"
+        | "has"
+        | "has one"
+        | "has equal"
+        | "has one equal"
+        | "sum"
+        | "attested" => Ok(true),
+        _ => bail!("unknown op: {}", op),
+    }
+}
+
+/// validate the structure and the complexity of the definition,
+/// authentifier_paths is None when there is no signing paths and all nested addresses are checked
+pub fn validate_definition(
+    db: &Connection,
+    definition: &Value,
+    unit: &Unit,
+    state: &ValidationState,
+    authentifier_paths: Option<&[String]>,
+    is_asset_condition: bool,
+) -> Result<()> {
+    if state.no_references && has_references(definition)? {
+        bail!("no references allowed in address definition");
+    }
+
+    let mut validator = DefinitionValidator {
+        db,
+        unit,
+        state,
+        authentifier_paths,
+        is_asset_condition,
+        complexity: 0,
+        count_ops: 0,
+    };
+
+    let has_sig = validator.evaluate(definition, "r", false)?;
+    ensure!(
+        has_sig || is_asset_condition,
+        "each branch must have a signature"
+    );
+    ensure!(
+        validator.complexity <= config::MAX_COMPLEXITY,
+        "complexity exceeded"
+    );
+    Ok(())
+}
+
+/// evaluate the definition against the unit and the authentifiers of the author
+pub fn validate_authentifiers(
+    db: &Connection,
+    address: &String,
+    definition: &Value,
+    unit: &Unit,
+    state: &ValidationState,
+    authentifiers: &HashMap<String, String>,
+) -> Result<bool> {
+    // we need to re-validate the definition every time, because a referenced address
+    // might be redefined and the complexity or loops could change
+    let authentifier_paths = authentifiers.keys().cloned().collect::<Vec<_>>();
+    validate_definition(
+        db,
+        definition,
+        unit,
+        state,
+        Some(&authentifier_paths),
+        false,
+    )?;
+
+    let mut evaluator = AuthentifierEvaluator {
+        db,
+        address,
+        unit,
+        state,
+        authentifiers,
+        used_paths: Vec::new(),
+        augmented_payments: None,
+    };
+
+    let res = evaluator.evaluate(definition, "r")?;
+    ensure!(
+        evaluator.used_paths.len() == authentifiers.len(),
+        "some authentifiers are not used, res={}, used={:?}, passed={:?}",
+        res,
+        evaluator.used_paths,
+        authentifiers
+    );
+
+    Ok(res)
+}
+
+/// replace all the "$name" strings in the template with the params
+pub fn replace_in_template(template: &Value, params: &Value) -> Result<Value> {
+    match *template {
+        Value::String(ref s) if s.starts_with('$') => match params.get(&s[1..]) {
+            Some(v) => Ok(v.clone()),
+            None => bail!("variable {} not specified", &s[1..]),
+        },
+        Value::Array(ref arr) => {
+            let mut ret = Vec::new();
+            for v in arr {
+                ret.push(replace_in_template(v, params)?);
+            }
+            Ok(Value::Array(ret))
+        }
+        Value::Object(ref obj) => {
+            let mut ret = serde_json::Map::new();
+            for (k, v) in obj {
+                ret.insert(k.clone(), replace_in_template(v, params)?);
+            }
+            Ok(Value::Object(ret))
+        }
+        _ => Ok(template.clone()),
+    }
+}
+
+// a definition is a two element array of [op, args]
+fn parse_op(definition: &Value) -> Result<(&str, &Value)> {
+    let arr = match definition.as_array() {
+        Some(arr) if arr.len() == 2 => arr,
+        _ => bail!("expression must be 2-element array"),
+    };
+
+    match arr[0].as_str() {
+        Some(op) => Ok((op, &arr[1])),
+        None => bail!("op must be string"),
+    }
+}
+
+fn has_fields_except(obj: &Value, fields: &[&str]) -> bool {
+    match obj.as_object() {
+        Some(obj) => obj.keys().any(|k| !fields.contains(&k.as_str())),
+        None => true,
+    }
+}
+
+fn is_positive_integer(v: &Value) -> bool {
+    v.as_u64().map(|n| n > 0).unwrap_or(false)
+}
+
+fn is_valid_address(v: &Value) -> bool {
+    match v.as_str() {
+        Some(address) if address.len() == 32 => {
+            object_hash::is_chash_valid(address.to_owned()).unwrap_or(false)
+        }
+        _ => false,
+    }
+}
+
+// same as /^-?\d+\.?\d*$/
+fn is_numeric_string(s: &str) -> bool {
+    let s = if s.starts_with('-') { &s[1..] } else { s };
+    let mut parts = s.splitn(2, '.');
+    let int_part = parts.next().unwrap_or("");
+    let frac_part = parts.next().unwrap_or("");
+    !int_part.is_empty()
+        && int_part.chars().all(|c| c.is_ascii_digit())
+        && frac_part.chars().all(|c| c.is_ascii_digit())
+}
+
+// the string values of the list, they are bound to the placeholders of the query
+fn to_str_list(values: &Value) -> Vec<&str> {
+    match values.as_array() {
+        Some(values) => values.iter().filter_map(|v| v.as_str()).collect(),
+        None => Vec::new(),
+    }
+}
+
+fn to_sql_placeholders(count: usize) -> String {
+    vec!["?"; count].join(", ")
+}
+
+fn validate_filter(filter: &Value, is_asset_condition: bool) -> Result<()> {
+    ensure!(filter.is_object(), "no filter");
+    ensure!(
+        !has_fields_except(
+            filter,
+            &[
+                "what",
+                "asset",
+                "type",
+                "own_funds",
+                "address",
+                "amount",
+                "amount_at_least",
+                "amount_at_most",
+            ],
+        ),
+        "unknown fields in filter"
+    );
+
+    let what = filter["what"].as_str().unwrap_or("");
+    ensure!(what == "input" || what == "output", "invalid what={}", what);
+
+    if let Some(asset) = filter.get("asset") {
+        let asset = asset.as_str().unwrap_or("");
+        ensure!(
+            !(is_asset_condition && asset == "this asset"),
+            "asset condition cannot reference this asset"
+        );
+        ensure!(
+            asset.len() == config::HASH_LENGTH || asset == "base" || asset == "this asset",
+            "invalid asset: {}",
+            asset
+        );
+    }
+
+    if what == "output" {
+        ensure!(filter.get("type").is_none(), "output canot have type");
+        ensure!(
+            filter.get("own_funds").is_none(),
+            "output canot have own_funds"
+        );
+    }
+    if let Some(kind) = filter.get("type") {
+        ensure!(
+            kind == "issue" || kind == "transfer",
+            "invalid type: {}",
+            kind
+        );
+    }
+    if let Some(own_funds) = filter.get("own_funds") {
+        ensure!(own_funds.is_boolean(), "own_funds must be boolean");
+    }
+    if let Some(address) = filter.get("address") {
+        ensure!(
+            !(is_asset_condition && address == "this address"),
+            "asset condition cannot reference this address"
+        );
+        ensure!(
+            is_valid_address(address) || address == "this address",
+            "invalid address: {}",
+            address
+        );
+    }
+
+    let mut count_amount_filters = 0;
+    for key in &["amount", "amount_at_least", "amount_at_most"] {
+        if let Some(amount) = filter.get(*key) {
+            ensure!(is_positive_integer(amount), "{} must be positive int", key);
+            count_amount_filters += 1;
+        }
+    }
+    ensure!(
+        count_amount_filters <= 1,
+        "can't have more than one amount filter"
+    );
+
+    Ok(())
+}
+
+// read the definition of an inner address, it could be defined in the unit itself
+fn read_inner_definition(
+    db: &Connection,
+    address: &str,
+    unit: &Unit,
+    last_ball_mci: u32,
+) -> Result<Option<Value>> {
+    let address = address.to_owned();
+    if let Some(definition) =
+        storage::read_definition_by_address(db, &address, Some(last_ball_mci))?
+    {
+        return Ok(Some(serde_json::from_str(&definition)?));
+    }
+
+    let mut defining_authors = unit.authors.iter().filter(|author| {
+        author.address == address
+            && !author.definition.is_null()
+            && object_hash::get_chash(&author.definition).ok() == Some(address.clone())
+    });
+    Ok(defining_authors
+        .next()
+        .map(|author| author.definition.clone()))
+}
+
+// ["definition template", ["unit", {param1: "value1"}]]
+fn read_filled_template(db: &Connection, args: &Value, last_ball_mci: u32) -> Result<Value> {
+    let template_unit = match args.as_array() {
+        Some(arr) if arr.len() == 2 && arr[0].is_string() => arr[0].as_str().unwrap().to_owned(),
+        _ => bail!("definition template must be 2-element array"),
+    };
+
+    let mut stmt = db.prepare_cached(
+        "SELECT payload FROM messages JOIN units USING(unit) \
+         WHERE unit=? AND app='definition_template' AND main_chain_index<=? \
+         AND +sequence='good' AND is_stable=1",
+    )?;
+    let rows = stmt
+        .query_map(&[&template_unit, &last_ball_mci], |row| {
+            row.get::<_, String>(0)
+        })?
+        .collect::<::std::result::Result<Vec<_>, _>>()?;
+    ensure!(rows.len() == 1, "template not found or too many");
+
+    let template: Value = serde_json::from_str(&rows[0])?;
+    replace_in_template(&template, &args[1])
+}
+
+struct DefinitionValidator<'a> {
+    db: &'a Connection,
+    unit: &'a Unit,
+    state: &'a ValidationState,
+    authentifier_paths: Option<&'a [String]>,
+    is_asset_condition: bool,
+    complexity: usize,
+    count_ops: usize,
+}
+
+impl<'a> DefinitionValidator<'a> {
+    // evaluate if at least one of the signing paths is in the nested address
+    fn need_to_evaluate_nested_address(&self, path: &str) -> bool {
+        match self.authentifier_paths {
+            None => true,
+            Some(paths) => {
+                let prefix = format!("{}.", path);
+                paths.iter().any(|p| p == path || p.starts_with(&prefix))
+            }
+        }
+    }
+
+    // return true if the expression has a signature
+    fn evaluate(&mut self, definition: &Value, path: &str, in_negation: bool) -> Result<bool> {
+        self.complexity += 1;
+        self.count_ops += 1;
+        ensure!(
+            self.complexity <= config::MAX_COMPLEXITY,
+            "complexity exceeded at {}",
+            path
+        );
+        ensure!(
+            self.count_ops <= config::MAX_OPS,
+            "number of ops exceeded at {}",
+            path
+        );
+
+        let (op, args) = parse_op(definition)?;
+
+        match op {
+            "or" | "and" => {
+                let args = match args.as_array() {
+                    Some(args) => args,
+                    None => bail!("{} args must be array", op),
+                };
+                ensure!(args.len() >= 2, "{} must have at least 2 options", op);
+
+                let mut count_options_with_sig = 0;
+                for (i, arg) in args.iter().enumerate() {
+                    if self.evaluate(arg, &format!("{}.{}", path, i), in_negation)? {
+                        count_options_with_sig += 1;
+                    }
+                }
+                Ok(op == "and" && count_options_with_sig > 0
+                    || op == "or" && count_options_with_sig == args.len())
+            }
+            "r of set" => {
+                ensure!(
+                    !has_fields_except(args, &["required", "set"]),
+                    "unknown fields in {}",
+                    op
+                );
+                ensure!(
+                    is_positive_integer(&args["required"]),
+                    "required must be positive"
+                );
+                let set = match args["set"].as_array() {
+                    Some(set) => set,
+                    None => bail!("set must be array"),
+                };
+                ensure!(set.len() >= 2, "set must have at least 2 options");
+                let required = args["required"].as_u64().unwrap() as usize;
+                ensure!(required <= set.len(), "required must be <= than set length");
+
+                let mut count_options_with_sig = 0;
+                for (i, arg) in set.iter().enumerate() {
+                    if self.evaluate(arg, &format!("{}.{}", path, i), in_negation)? {
+                        count_options_with_sig += 1;
+                    }
+                }
+                Ok(set.len() - count_options_with_sig < required)
+            }
+            "weighted and" => {
+                ensure!(
+                    !has_fields_except(args, &["required", "set"]),
+                    "unknown fields in {}",
+                    op
+                );
+                ensure!(
+                    is_positive_integer(&args["required"]),
+                    "required must be positive"
+                );
+                let set = match args["set"].as_array() {
+                    Some(set) => set,
+                    None => bail!("set must be array"),
+                };
+                ensure!(set.len() >= 2, "set must have at least 2 options");
+
+                let mut weight_of_options_with_sig = 0;
+                let mut total_weight = 0;
+                for (i, arg) in set.iter().enumerate() {
+                    ensure!(
+                        !has_fields_except(arg, &["value", "weight"]),
+                        "unknown fields in weighted value"
+                    );
+                    ensure!(
+                        is_positive_integer(&arg["weight"]),
+                        "weight must be positive int"
+                    );
+                    let weight = arg["weight"].as_u64().unwrap();
+                    total_weight += weight;
+                    if self.evaluate(&arg["value"], &format!("{}.{}", path, i), in_negation)? {
+                        weight_of_options_with_sig += weight;
+                    }
+                }
+
+                let required = args["required"].as_u64().unwrap();
+                ensure!(
+                    required <= total_weight,
+                    "required must be <= than total weight"
+                );
+                Ok(total_weight - weight_of_options_with_sig < required)
+            }
+            "sig" => {
+                ensure!(!in_negation, "{} cannot be negated", op);
+                ensure!(
+                    !self.is_asset_condition,
+                    "asset condition cannot have {}",
+                    op
+                );
+                ensure!(
+                    !has_fields_except(args, &["algo", "pubkey"]),
+                    "unknown fields in {}",
+                    op
+                );
+                if let Some(algo) = args.get("algo") {
+                    ensure!(
+                        algo != "secp256k1",
+                        "default algo must not be explicitly specified"
+                    );
+                    bail!("unsupported sig algo");
+                }
+                ensure!(
+                    args["pubkey"].as_str().map(|s| s.len()) == Some(config::PUBKEY_LENGTH),
+                    "wrong pubkey length"
+                );
+                Ok(true)
+            }
+            "hash" => {
+                ensure!(!in_negation, "{} cannot be negated", op);
+                ensure!(
+                    !self.is_asset_condition,
+                    "asset condition cannot have {}",
+                    op
+                );
+                ensure!(
+                    !has_fields_except(args, &["algo", "hash"]),
+                    "unknown fields in {}",
+                    op
+                );
+                if let Some(algo) = args.get("algo") {
+                    ensure!(
+                        algo != "sha256",
+                        "default algo must not be explicitly specified"
+                    );
+                    bail!("unsupported hash algo");
+                }
+                ensure!(
+                    args["hash"].as_str().map(|s| s.len()) == Some(config::HASH_LENGTH),
+                    "wrong base64 hash"
+                );
+                Ok(true)
+            }
+            "address" => {
+                ensure!(!in_negation, "{} cannot be negated", op);
+                ensure!(
+                    !self.is_asset_condition,
+                    "asset condition cannot have {}",
+                    op
+                );
+                ensure!(is_valid_address(args), "invalid address");
+
+                let other_address = args.as_str().unwrap();
+                let definition = read_inner_definition(
+                    self.db,
+                    other_address,
+                    self.unit,
+                    self.state.last_ball_mci,
+                )?;
+                match definition {
+                    Some(ref definition) if self.need_to_evaluate_nested_address(path) => {
+                        self.evaluate(definition, path, in_negation)
+                    }
+                    // unresolved inner definitions are allowed
+                    _ => Ok(true),
+                }
+            }
+            "definition template" => {
+                ensure!(!in_negation, "{} cannot be negated", op);
+                ensure!(
+                    !self.is_asset_condition,
+                    "asset condition cannot have {}",
+                    op
+                );
+                let definition = read_filled_template(self.db, args, self.state.last_ball_mci)?;
+                self.evaluate(&definition, path, in_negation)
+            }
+            "seen address" => {
+                ensure!(is_valid_address(args), "invalid seen address");
+                Ok(false)
+            }
+            "cosigned by" => {
+                ensure!(!in_negation, "{} cannot be negated", op);
+                ensure!(is_valid_address(args), "invalid cosigned address");
+                Ok(false)
+            }
+            "not" => {
+                self.evaluate(args, path, true)?;
+                Ok(false)
+            }
+            "in data feed" | "in merkle" => {
+                // ["in data feed", [["BASE32"], "data feed name", "=", "expected value"]]
+                // ["in merkle", [["BASE32"], "data feed name", "expected value"]]
+                let args = match args.as_array() {
+                    Some(args) => args,
+                    None => bail!("{} arguments must be array", op),
+                };
+                let min_args = if op == "in data feed" { 4 } else { 3 };
+                ensure!(
+                    args.len() == min_args || args.len() == min_args + 1,
+                    "{} must have {} or {} args",
+                    op,
+                    min_args,
+                    min_args + 1
+                );
+
+                let addresses = match args[0].as_array() {
+                    Some(addresses) if !addresses.is_empty() => addresses,
+                    _ => bail!("no addresses in {}", op),
+                };
+                for address in addresses {
+                    ensure!(is_valid_address(address), "address {} not valid", address);
+                }
+
+                let feed_name = args[1].as_str().unwrap_or("");
+                ensure!(!feed_name.is_empty(), "no feed_name");
+                ensure!(
+                    feed_name.len() <= config::MAX_DATA_FEED_NAME_LENGTH,
+                    "feed_name too long"
+                );
+
+                let value = if op == "in data feed" {
+                    let relation = args[2].as_str().unwrap_or("");
+                    ensure!(
+                        RELATIONS.contains(&relation),
+                        "invalid relation: {}",
+                        relation
+                    );
+                    &args[3]
+                } else {
+                    &args[2]
+                };
+                match *value {
+                    Value::String(ref s) => {
+                        ensure!(!s.is_empty(), "no value");
+                        ensure!(
+                            s.len() <= config::MAX_DATA_FEED_VALUE_LENGTH,
+                            "value too long"
+                        );
+                    }
+                    Value::Number(ref n) if op == "in data feed" => {
+                        ensure!(
+                            n.is_i64() || n.is_u64(),
+                            "fractional numbers not allowed in data feeds"
+                        );
+                    }
+                    _ => bail!("invalid value"),
+                }
+
+                if let Some(min_mci) = args.get(min_args) {
+                    ensure!(min_mci.is_u64(), "{}: invalid min_mci", op);
+                }
+                Ok(false)
+            }
+            "mci" | "age" => {
+                let relation = args[0].as_str().unwrap_or("");
+                ensure!(
+                    RELATIONS.contains(&relation),
+                    "invalid relation: {}",
+                    relation
+                );
+                ensure!(args[1].is_u64(), "{} must be a non-negative integer", op);
+                Ok(false)
+            }
+            "has" | "has one" => {
+                validate_filter(args, self.is_asset_condition)?;
+                Ok(false)
+            }
+            "sum" => {
+                ensure!(
+                    !has_fields_except(args, &["filter", "equals", "at_least", "at_most"]),
+                    "unknown fields in {}",
+                    op
+                );
+                let filter = &args["filter"];
+                validate_filter(filter, self.is_asset_condition)?;
+                ensure!(
+                    filter.get("amount").is_none()
+                        && filter.get("amount_at_least").is_none()
+                        && filter.get("amount_at_most").is_none(),
+                    "sum filter cannot restrict amounts"
+                );
+
+                if let Some(equals) = args.get("equals") {
+                    ensure!(equals.is_u64(), "equals must be nonnegative int");
+                    ensure!(
+                        args.get("at_least").is_none() && args.get("at_most").is_none(),
+                        "can't have equals and at_least/at_most at the same time"
+                    );
+                } else {
+                    ensure!(
+                        args.get("at_least").is_some() || args.get("at_most").is_some(),
+                        "at least one of equals, at_least, at_most must be specified"
+                    );
+                }
+                for key in &["at_least", "at_most"] {
+                    if let Some(v) = args.get(*key) {
+                        ensure!(is_positive_integer(v), "{} must be positive int", key);
+                    }
+                }
+                Ok(false)
+            }
+            _ => bail!("unknown op: {}", op),
+        }
+    }
+}
+
+// payment inputs and outputs with the address and amount looked up
+#[derive(Debug)]
+struct AugmentedObject {
+    kind: Option<String>,
+    unit: Option<String>,
+    address: Option<String>,
+    amount: Option<i64>,
+}
+
+#[derive(Debug)]
+struct AugmentedPayment {
+    asset: Option<String>,
+    inputs: Vec<AugmentedObject>,
+    outputs: Vec<AugmentedObject>,
+}
+
+struct AuthentifierEvaluator<'a> {
+    db: &'a Connection,
+    address: &'a String,
+    unit: &'a Unit,
+    state: &'a ValidationState,
+    authentifiers: &'a HashMap<String, String>,
+    used_paths: Vec<String>,
+    augmented_payments: Option<Vec<AugmentedPayment>>,
+}
+
+impl<'a> AuthentifierEvaluator<'a> {
+    fn evaluate(&mut self, definition: &Value, path: &str) -> Result<bool> {
+        let (op, args) = parse_op(definition)?;
+
+        match op {
+            "or" => {
+                // ok if at least one of the args is ok
+                let mut res = false;
+                for (i, arg) in args.as_array().unwrap_or(&Vec::new()).iter().enumerate() {
+                    res = self.evaluate(arg, &format!("{}.{}", path, i))? || res;
+                }
+                Ok(res)
+            }
+            "and" => {
+                // ok if all the args are ok
+                let mut res = true;
+                for (i, arg) in args.as_array().unwrap_or(&Vec::new()).iter().enumerate() {
+                    res = self.evaluate(arg, &format!("{}.{}", path, i))? && res;
+                }
+                Ok(res)
+            }
+            "r of set" => {
+                let mut count = 0;
+                for (i, arg) in args["set"]
+                    .as_array()
+                    .unwrap_or(&Vec::new())
+                    .iter()
+                    .enumerate()
+                {
+                    if self.evaluate(arg, &format!("{}.{}", path, i))? {
+                        count += 1;
+                    }
+                }
+                Ok(count >= args["required"].as_u64().unwrap_or(0))
+            }
+            "weighted and" => {
+                let mut weight = 0;
+                for (i, arg) in args["set"]
+                    .as_array()
+                    .unwrap_or(&Vec::new())
+                    .iter()
+                    .enumerate()
+                {
+                    if self.evaluate(&arg["value"], &format!("{}.{}", path, i))? {
+                        weight += arg["weight"].as_u64().unwrap_or(0);
+                    }
+                }
+                Ok(weight >= args["required"].as_u64().unwrap_or(0))
+            }
+            "sig" => {
+                let sig = match self.authentifiers.get(path) {
+                    Some(sig) => sig,
+                    None => return Ok(false),
+                };
+                self.used_paths.push(path.to_owned());
+
+                let pubkey = args["pubkey"].as_str().unwrap_or("");
+                if signature::verify(&self.state.unit_hash_to_sign, sig, pubkey).is_err() {
+                    bail!("bad signature at path {}", path);
+                }
+                Ok(true)
+            }
+            "hash" => {
+                let preimage = match self.authentifiers.get(path) {
+                    Some(preimage) => preimage,
+                    None => return Ok(false),
+                };
+                self.used_paths.push(path.to_owned());
+
+                Ok(Some(object_hash::get_base64_hash(preimage)?.as_str()) == args["hash"].as_str())
+            }
+            "address" => {
+                let other_address = args.as_str().unwrap_or("");
+                let definition = read_inner_definition(
+                    self.db,
+                    other_address,
+                    self.unit,
+                    self.state.last_ball_mci,
+                )?;
+                match definition {
+                    Some(ref definition) => self.evaluate(definition, path),
+                    None => Ok(false),
+                }
+            }
+            "definition template" => {
+                let definition = read_filled_template(self.db, args, self.state.last_ball_mci)?;
+                self.evaluate(&definition, path)
+            }
+            "seen address" => {
+                let mut stmt = self.db.prepare_cached(
+                    "SELECT 1 FROM unit_authors CROSS JOIN units USING(unit) \
+                     WHERE address=? AND main_chain_index<=? AND sequence='good' AND is_stable=1 \
+                     LIMIT 1",
+                )?;
+                Ok(stmt.exists(&[&args.as_str().unwrap_or(""), &self.state.last_ball_mci])?)
+            }
+            "cosigned by" => {
+                let cosigner_address = args.as_str().unwrap_or("");
+                Ok(self
+                    .unit
+                    .authors
+                    .iter()
+                    .any(|author| author.address == cosigner_address))
+            }
+            "not" => Ok(!self.evaluate(args, path)?),
+            "in data feed" => {
+                // ["in data feed", [["BASE32"], "data feed name", "=", "expected value"]]
+                let addresses = to_str_list(&args[0]);
+                let feed_name = args[1].as_str().unwrap_or("").to_owned();
+                let relation = args[2].as_str().unwrap_or("=");
+                let min_mci = args[4].as_u64().unwrap_or(0) as u32;
+
+                let mut str_value = None;
+                let mut int_value = None;
+                let value_condition = match args[3] {
+                    Value::String(ref value) if is_numeric_string(value) => {
+                        let plus_0 = if ["<", "<=", ">", ">="].contains(&relation) {
+                            "+0"
+                        } else {
+                            ""
+                        };
+                        format!(
+                            "(value{}{}{} OR int_value{}{})",
+                            plus_0, relation, value, relation, value
+                        )
+                    }
+                    Value::String(ref value) => {
+                        str_value = Some(value.clone());
+                        format!("value{}?", relation)
+                    }
+                    ref value => {
+                        int_value = value.as_i64();
+                        format!("int_value{}?", relation)
+                    }
+                };
+
+                let sql = format!(
+                    "SELECT 1 FROM data_feeds CROSS JOIN units USING(unit) \
+                     CROSS JOIN unit_authors USING(unit) \
+                     WHERE address IN({}) AND feed_name=? AND {} \
+                     AND main_chain_index<=? AND main_chain_index>=? \
+                     AND sequence='good' AND is_stable=1 LIMIT 1",
+                    to_sql_placeholders(addresses.len()),
+                    value_condition
+                );
+
+                let mut params = addresses.iter().map(|a| a as &ToSql).collect::<Vec<_>>();
+                params.push(&feed_name);
+                if let Some(ref value) = str_value {
+                    params.push(value);
+                }
+                if let Some(ref value) = int_value {
+                    params.push(value);
+                }
+                params.push(&self.state.last_ball_mci);
+                params.push(&min_mci);
+
+                let mut stmt = self.db.prepare(&sql)?;
+                Ok(stmt.exists(&params)?)
+            }
+            "in merkle" => {
+                // ["in merkle", [["BASE32"], "data feed name", "expected value"]]
+                let serialized_proof = match self.authentifiers.get(path) {
+                    Some(proof) => proof,
+                    None => return Ok(false),
+                };
+                self.used_paths.push(path.to_owned());
+
+                let element = args[2].as_str().unwrap_or("");
+                let proof = merkle::deserialize_merkle_proof(serialized_proof)?;
+                if !merkle::verify_merkle_proof(element, &proof) {
+                    bail!("bad merkle proof at path {}", path);
+                }
+
+                let addresses = to_str_list(&args[0]);
+                let feed_name = args[1].as_str().unwrap_or("");
+                let min_mci = args[3].as_u64().unwrap_or(0) as u32;
+                let sql = format!(
+                    "SELECT 1 FROM data_feeds CROSS JOIN units USING(unit) \
+                     JOIN unit_authors USING(unit) \
+                     WHERE address IN({}) AND feed_name=? AND value=? \
+                     AND main_chain_index<=? AND main_chain_index>=? \
+                     AND sequence='good' AND is_stable=1 LIMIT 1",
+                    to_sql_placeholders(addresses.len())
+                );
+                let mut params = addresses.iter().map(|a| a as &ToSql).collect::<Vec<_>>();
+                params.push(&feed_name);
+                params.push(&proof.root);
+                params.push(&self.state.last_ball_mci);
+                params.push(&min_mci);
+                let mut stmt = self.db.prepare(&sql)?;
+                if !stmt.exists(&params)? {
+                    bail!("merkle proof at path {} not found", path);
+                }
+                Ok(true)
+            }
+            "mci" => {
+                let relation = args[0].as_str().unwrap_or("");
+                let mci = args[1].as_u64().unwrap_or(0);
+                let last_ball_mci = u64::from(self.state.last_ball_mci);
+                Ok(match relation {
+                    ">" => last_ball_mci > mci,
+                    ">=" => last_ball_mci >= mci,
+                    "<" => last_ball_mci < mci,
+                    "<=" => last_ball_mci <= mci,
+                    "=" => last_ball_mci == mci,
+                    "!=" => last_ball_mci != mci,
+                    _ => bail!("unknown relation in mci: {}", relation),
+                })
+            }
+            "age" => {
+                let relation = args[0].as_str().unwrap_or("=");
+                let age = args[1].as_u64().unwrap_or(0) as i64;
+
+                let address = self.address;
+                let mut src_units = Vec::new();
+                for payment in self.get_augmented_payments()? {
+                    for input in &payment.inputs {
+                        // assume age is satisfied for issue, headers commission and witnessing
+                        if input.kind.as_ref().map(|s| s.as_str()) != Some("transfer") {
+                            continue;
+                        }
+                        if input.address.as_ref() == Some(address) {
+                            if let Some(ref unit) = input.unit {
+                                if !src_units.contains(unit) {
+                                    src_units.push(unit.clone());
+                                }
+                            }
+                        }
+                    }
+                }
+                if src_units.is_empty() {
+                    return Ok(false);
+                }
+
+                let sql = format!(
+                    "SELECT 1 FROM units \
+                     WHERE unit IN({}) AND ?{}main_chain_index AND main_chain_index<=? \
+                     AND +sequence='good' AND is_stable=1",
+                    to_sql_placeholders(src_units.len()),
+                    relation
+                );
+                let last_ball_mci = i64::from(self.state.last_ball_mci);
+                let age_mci = last_ball_mci - age;
+                let mut params = src_units.iter().map(|u| u as &ToSql).collect::<Vec<_>>();
+                params.push(&age_mci);
+                params.push(&last_ball_mci);
+                let mut stmt = self.db.prepare(&sql)?;
+                let count = stmt.query_map(&params, |_| ())?.count();
+                Ok(count == src_units.len())
+            }
+            "has" | "has one" => {
+                let address = self.address.clone();
+                let found = filter_objects(self.get_augmented_payments()?, args, &address);
+                if op == "has" {
+                    Ok(!found.is_empty())
+                } else {
+                    Ok(found.len() == 1)
+                }
+            }
+            "sum" => {
+                let address = self.address.clone();
+                let found =
+                    filter_objects(self.get_augmented_payments()?, &args["filter"], &address);
+                let sum = found.iter().map(|obj| obj.amount.unwrap_or(0)).sum::<i64>();
+
+                if let Some(equals) = args["equals"].as_i64() {
+                    return Ok(sum == equals);
+                }
+                let at_least_ok = args["at_least"].as_i64().map_or(true, |v| sum >= v);
+                let at_most_ok = args["at_most"].as_i64().map_or(true, |v| sum <= v);
+                Ok(at_least_ok && at_most_ok)
+            }
+            _ => bail!("unknown op: {}", op),
+        }
+    }
+
+    fn get_augmented_payments(&mut self) -> Result<&Vec<AugmentedPayment>> {
+        if self.augmented_payments.is_none() {
+            let payments = self.augment_payments()?;
+            self.augmented_payments = Some(payments);
+        }
+        Ok(self.augmented_payments.as_ref().unwrap())
+    }
+
+    fn augment_payments(&self) -> Result<Vec<AugmentedPayment>> {
+        let mut payments = Vec::new();
+        for message in &self.unit.messages {
+            let payload = match message.payload {
+                Some(Payload::Payment(ref payload)) if message.app == "payment" => payload,
+                _ => continue,
+            };
+
+            let mut inputs = Vec::new();
+            for input in &payload.inputs {
+                let mut obj = AugmentedObject {
+                    kind: Some(input.kind.clone().unwrap_or_else(|| "transfer".to_owned())),
+                    unit: input.unit.clone(),
+                    address: input.address.clone(),
+                    amount: input.amount,
+                };
+                match input.kind.as_ref().map(|s| s.as_str()) {
+                    Some("issue") if obj.address.is_none() => {
+                        obj.address = Some(self.unit.authors[0].address.clone())
+                    }
+                    None => {
+                        let mut stmt = self.db.prepare_cached(
+                            "SELECT amount, address FROM outputs \
+                             WHERE unit=? AND message_index=? AND output_index=?",
+                        )?;
+                        let rows = stmt
+                            .query_map(
+                                &[&input.unit, &input.message_index, &input.output_index],
+                                |row| (row.get::<_, i64>(0), row.get::<_, String>(1)),
+                            )?
+                            .collect::<::std::result::Result<Vec<_>, _>>()?;
+                        if let Some(&(amount, ref address)) = rows.first() {
+                            obj.amount = Some(amount);
+                            obj.address = Some(address.clone());
+                        }
+                    }
+                    _ => {}
+                }
+                inputs.push(obj);
+            }
+
+            let outputs = payload
+                .outputs
+                .iter()
+                .map(|output| AugmentedObject {
+                    kind: None,
+                    unit: None,
+                    address: Some(output.address.clone()),
+                    amount: Some(output.amount),
+                })
+                .collect();
+
+            payments.push(AugmentedPayment {
+                asset: payload.asset.clone(),
+                inputs,
+                outputs,
+            });
+        }
+        Ok(payments)
+    }
+}
+
+fn filter_objects<'a>(
+    payments: &'a [AugmentedPayment],
+    filter: &Value,
+    address: &String,
+) -> Vec<&'a AugmentedObject> {
+    let mut found = Vec::new();
+    for payment in payments {
+        match filter["asset"].as_str() {
+            None => {}
+            Some("base") => {
+                if payment.asset.is_some() {
+                    continue;
+                }
+            }
+            // there is no asset condition in this context
+            Some("this asset") => continue,
+            Some(asset) => {
+                if payment.asset.as_ref().map(|s| s.as_str()) != Some(asset) {
+                    continue;
+                }
+            }
+        }
+
+        let objects = if filter["what"] == "input" {
+            &payment.inputs
+        } else {
+            &payment.outputs
+        };
+        for obj in objects {
+            if let Some(kind) = filter["type"].as_str() {
+                if obj.kind.as_ref().map(|s| s.as_str()) != Some(kind) {
+                    continue;
+                }
+            }
+            if let Some(own_funds) = filter["own_funds"].as_bool() {
+                if (obj.address.as_ref() == Some(address)) != own_funds {
+                    continue;
+                }
+            }
+            if let Some(filter_address) = filter["address"].as_str() {
+                let filter_address = if filter_address == "this address" {
+                    address.as_str()
+                } else {
+                    filter_address
+                };
+                if obj.address.as_ref().map(|s| s.as_str()) != Some(filter_address) {
+                    continue;
+                }
+            }
+            let amount = obj.amount.unwrap_or(0);
+            if filter["amount"].as_i64().map_or(false, |v| amount != v) {
+                continue;
+            }
+            if filter["amount_at_least"]
+                .as_i64()
+                .map_or(false, |v| amount < v)
+            {
+                continue;
+            }
+            if filter["amount_at_most"]
+                .as_i64()
+                .map_or(false, |v| amount > v)
+            {
+                continue;
+            }
+            found.push(obj);
+        }
+    }
+    found
+}
+
+#[test]
+fn test_evaluate_in_data_feed() {
+    use test_utils::*;
+
+    let mut dag = TestDag::new("definition data feed");
+    dag.add_units(3);
+    let feed = inline_message(
+        "data_feed",
+        Payload::Other(json!({ "price": 100, "name": "inkc" })),
+    );
+    let joint = dag.compose(&[&dag.witnesses[3]], dag.read_free_units(), vec![feed]);
+    dag.save(&joint).unwrap();
+    // the data feed gets stable
+    dag.add_units(30);
 
-// TODO: implement definition
-pub fn has_references(_definition: &Value) -> Result<bool> {
-    Ok(false)
+    let mut state = ValidationState::new();
+    state.last_ball_mci = storage::read_last_stable_mc_index(&dag.db).unwrap();
+    let authentifiers = HashMap::new();
+    let mut evaluator = AuthentifierEvaluator {
+        db: &dag.db,
+        address: &dag.witnesses[0].address,
+        unit: &joint.unit,
+        state: &state,
+        authentifiers: &authentifiers,
+        used_paths: Vec::new(),
+        augmented_payments: None,
+    };
+    let oracle = dag.witnesses[3].address.as_str();
+    let other = dag.witnesses[4].address.as_str();
+    let cases = vec![
+        (json!([oracle]), "price", "=", json!(100), true),
+        (json!([oracle]), "price", ">", json!("99"), true),
+        (json!([oracle]), "name", "=", json!("inkc"), true),
+        (json!([oracle]), "name", "=", json!("other"), false),
+        (json!([other]), "price", "=", json!(100), false),
+        // the addresses are bound, not spliced into the query
+        (json!(["x') OR ('1'='1"]), "price", "=", json!(100), false),
+    ];
+    for (oracles, feed_name, relation, value, expected) in cases {
+        let definition = json!(["in data feed", [oracles, feed_name, relation, value]]);
+        assert_eq!(
+            evaluator.evaluate(&definition, "r").unwrap(),
+            expected,
+            "{}",
+            definition
+        );
+    }
 }
@@ -2,6 +2,7 @@ use error::Result;
 use rusqlite::Connection;
 use storage;
 
+#[derive(Clone, Debug)]
 pub struct UnitProps {
     pub unit: String,
     pub level: u32,
@@ -122,10 +123,10 @@ pub fn compare_unit_props(
     };
 
     // can be negative if main_chain_index == None but that doesn't matter
-    let earlier_unit_delta = earlier_unit.main_chain_index.unwrap_or(0)
-        - earlier_unit.latest_included_mc_index.unwrap_or(0);
-    let later_unit_delta =
-        later_unit.main_chain_index.unwrap_or(0) - later_unit.latest_included_mc_index.unwrap_or(0);
+    let earlier_unit_delta = earlier_unit.main_chain_index.unwrap_or(0) as i64
+        - earlier_unit.latest_included_mc_index.unwrap_or(0) as i64;
+    let later_unit_delta = later_unit.main_chain_index.unwrap_or(0) as i64
+        - later_unit.latest_included_mc_index.unwrap_or(0) as i64;
 
     let mut start_units = Vec::new();
     if later_unit_delta > earlier_unit_delta {
@@ -241,16 +242,14 @@ pub fn determine_if_included(
 
     ensure!(later_units_props.len() > 0, "no later unit props were read");
 
-    //spec::UnitProps.latest_included_mc_index and spec::UnitProps.main_chain_index is not Option
     let max_later_limci = later_units_props
         .iter()
         .max_by_key(|props| props.latest_included_mc_index)
         .unwrap()
         .latest_included_mc_index;
-    if
-    /*earlier_unit_props.main_chain_index.is_some()
-        &&*/
-    max_later_limci >= earlier_unit_props.main_chain_index {
+    if earlier_unit_props.main_chain_index.is_some()
+        && max_later_limci >= earlier_unit_props.main_chain_index
+    {
         return Ok(true);
     }
 
@@ -1,7 +1,6 @@
 use std::collections::HashMap;
 
 use error::Result;
-use may::sync::Mutex;
 use rusqlite::Connection;
 
 struct ChildInfo {
@@ -9,15 +8,12 @@ struct ChildInfo {
     next_mc_unit: String,
 }
 
-lazy_static! {
-    static ref MAX_SPENDABLE_MCI: Mutex<Option<u32>> = Mutex::new(None);
-}
-
 fn get_max_spendable_mci(db: &Connection) -> Result<u32> {
     let mut stmt = db.prepare_cached(
         "SELECT MAX(main_chain_index) AS max_spendable_mci FROM headers_commission_outputs",
     )?;
-    let mci = stmt.query_row(&[], |row| row.get(0)).unwrap_or(0);
+    let mci = stmt.query_row(&[], |row| row.get::<_, Option<u32>>(0))?;
+    let mci = mci.unwrap_or(0);
     Ok(mci)
 }
 
@@ -38,12 +34,9 @@ fn get_winner_info<'a>(children: &'a mut Vec<ChildInfo>) -> Result<&'a ChildInfo
 }
 
 pub fn calc_headers_commissions(db: &Connection) -> Result<()> {
-    // here for multi-thread we need a mutex to protect db without dup insertions
-    let mut max_spendable_mci = MAX_SPENDABLE_MCI.lock().unwrap();
-    if max_spendable_mci.is_none() {
-        *max_spendable_mci = Some(get_max_spendable_mci(db)?);
-    }
-    let since_mc_index = max_spendable_mci.unwrap();
+    // read within the transaction of the caller who holds the writer lock,
+    // so that a rolled back save doesn't leave a wrong mci behind
+    let since_mc_index = get_max_spendable_mci(db)?;
 
     // chunits is any child unit and contender for headers commission, punits is hc-payer unit
     let sql =
@@ -194,8 +187,7 @@ pub fn calc_headers_commissions(db: &Connection) -> Result<()> {
             value_list
         );
 
-        let mut stmt = db.prepare(&sql)?;
-        stmt.insert(&[])?;
+        db.execute(&sql, &[])?;
     }
 
     let mut stmt = db.prepare_cached(
@@ -203,12 +195,10 @@ pub fn calc_headers_commissions(db: &Connection) -> Result<()> {
                 SELECT main_chain_index, address, SUM(amount) FROM headers_commission_contributions JOIN units USING(unit) \
                 WHERE main_chain_index>? \
                 GROUP BY main_chain_index, address")?;
-    stmt.insert(&[&since_mc_index])?;
-
-    *max_spendable_mci = Some(get_max_spendable_mci(db)?);
+    stmt.execute(&[&since_mc_index])?;
     Ok(())
 }
 
-pub fn get_max_spendable_mci_for_last_ball_mci(last_ball_mci: u32) -> u32 {
-    last_ball_mci - 1
+pub fn get_max_spendable_mci_for_last_ball_mci(last_ball_mci: u32) -> Option<u32> {
+    last_ball_mci.checked_sub(1)
 }
@@ -3,14 +3,16 @@ use std::collections::HashSet;
 use db;
 use definition;
 use error::Result;
+use main_chain;
 use may::sync::Mutex;
 use object_hash::get_chash;
 use rusqlite::Transaction;
-use serde_json;
+use serde_json::{self, Value};
 use spec::*;
+use validation::ValidationState;
 
 lazy_static! {
-    static ref WRITER_MUTEX: Mutex<()> = Mutex::new(());
+    pub(crate) static ref WRITER_MUTEX: Mutex<()> = Mutex::new(());
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -122,23 +124,27 @@ impl Joint {
         let unit_hash = self.get_unit_hash();
         for author in &self.unit.authors {
             let definition = &author.definition;
-            let definition_chash = get_chash(definition)?;
-            let mut stmt = tx.prepare_cached(
-                "INSERT OR IGNORE INTO definitions \
-                 (definition_chash, definition, has_references) \
-                 VALUES (?, ?, ?)",
-            )?;
-            let definition_json = serde_json::to_string(definition)?;
-            let has_references = definition::has_references(definition)? as u8;
-            stmt.insert(&[&definition_chash, &definition_json, &has_references])?;
+            let mut definition_chash = None;
+            if !definition.is_null() {
+                let chash = get_chash(definition)?;
+                let mut stmt = tx.prepare_cached(
+                    "INSERT OR IGNORE INTO definitions \
+                     (definition_chash, definition, has_references) \
+                     VALUES (?, ?, ?)",
+                )?;
+                let definition_json = serde_json::to_string(definition)?;
+                let has_references = definition::has_references(definition)? as u8;
+                stmt.execute(&[&chash, &definition_json, &has_references])?;
+                definition_chash = Some(chash);
+            }
 
-            // TODO: we ingore unit.content_hash here
-            if definition_chash == author.address {
+            if definition_chash.as_ref() == Some(&author.address) || self.unit.content_hash.is_some()
+            {
                 let mut stmt = tx.prepare_cached(
                     "INSERT OR IGNORE INTO addresses (address) \
                      VALUES (?)",
                 )?;
-                stmt.insert(&[&author.address])?;
+                stmt.execute(&[&author.address])?;
             }
 
             let mut stmt = tx.prepare_cached(
@@ -164,7 +170,10 @@ impl Joint {
         let unit_hash = self.get_unit_hash();
         for (i, message) in self.unit.messages.iter().enumerate() {
             let text_payload = match message.app.as_str() {
-                "text" => unimplemented!(),
+                "text" => match message.payload {
+                    Some(Payload::Text(ref text)) => Some(text.clone()),
+                    _ => bail!("text payload must be a string"),
+                },
                 "data" | "profile" | "attestation" | "definition_template" => {
                     let payload = serde_json::to_string(&message.payload)?;
                     Some(payload)
@@ -191,8 +200,14 @@ impl Joint {
 
             if message.payload_location.as_str() == "inline" {
                 match message.app.as_str() {
-                    "payment" => {}
-                    _ => unimplemented!(),
+                    "address_definition_change" => {
+                        self.save_address_definition_change(tx, i as u32, message)?
+                    }
+                    "data_feed" => self.save_data_feed(tx, i as u32, message)?,
+                    "poll" => self.save_poll(tx, i as u32, message)?,
+                    "vote" => self.save_vote(tx, i as u32, message)?,
+                    // payments are saved in save_inline_payment, the rest are kept as text
+                    _ => {}
                 }
             }
 
@@ -212,45 +227,163 @@ impl Joint {
         Ok(())
     }
 
-    #[allow(dead_code)]
-    fn save_header_earnings(&self, _tx: &Transaction) -> Result<()> {
-        // TODO: unimplemented!()
-
-        /*
-        if ("earned_headers_commission_recipients" in objUnit){
-			for (var i=0; i<objUnit.earned_headers_commission_recipients.length; i++){
-				var recipient = objUnit.earned_headers_commission_recipients[i];
-				conn.addQuery(arrQueries, 
-					"INSERT INTO earned_headers_commission_recipients (unit, address, earned_headers_commission_share) VALUES(?,?,?)", 
-					[objUnit.unit, recipient.address, recipient.earned_headers_commission_share]);
-			}
-		}
-        */
+    fn save_address_definition_change(
+        &self,
+        tx: &Transaction,
+        message_index: u32,
+        message: &Message,
+    ) -> Result<()> {
+        let payload = match message.payload {
+            Some(Payload::Other(ref payload)) => payload,
+            _ => bail!("address_definition_change payload must be an object"),
+        };
+        let definition_chash = match payload["definition_chash"].as_str() {
+            Some(chash) => chash,
+            None => bail!("no definition_chash in address_definition_change"),
+        };
+        // the address may be omitted when single-authored
+        let address = match payload["address"].as_str() {
+            Some(address) => address,
+            None => self.unit.authors[0].address.as_str(),
+        };
+
+        let mut stmt = tx.prepare_cached(
+            "INSERT INTO address_definition_changes \
+             (unit, message_index, address, definition_chash) VALUES(?,?,?,?)",
+        )?;
+        stmt.insert(&[
+            self.get_unit_hash(),
+            &message_index,
+            &address,
+            &definition_chash,
+        ])?;
         Ok(())
     }
 
-    fn update_best_parent(&self, tx: &Transaction) -> Result<String> {
-        let unit = &self.unit;
-        let parents_set = unit.parent_units
-            .iter()
-            .map(|s| format!("'{}'", s))
-            .collect::<Vec<_>>()
-            .join(", ");
-        // TODO: witness list is fixed
-        let sql = format!(
-            "SELECT unit \
-             FROM units AS parent_units \
-             WHERE unit IN({}) AND (witness_list_unit=?) \
-             ORDER BY \
-             witnessed_level DESC, \
-             level-witnessed_level ASC, \
-             unit ASC \
-             LIMIT 1",
-            parents_set
-        );
+    fn save_data_feed(&self, tx: &Transaction, message_index: u32, message: &Message) -> Result<()> {
+        let feeds = match message.payload {
+            Some(Payload::Other(Value::Object(ref feeds))) => feeds,
+            _ => bail!("data feed payload must be an object"),
+        };
+
+        for (feed_name, value) in feeds {
+            // strings and integers are kept in different columns
+            let (value, int_value) = match *value {
+                Value::String(ref s) => (Some(s.as_str()), None),
+                Value::Number(ref n) if n.is_i64() => (None, n.as_i64()),
+                _ => bail!("data feed {} must be a string or an integer", feed_name),
+            };
+            let mut stmt = tx.prepare_cached(
+                "INSERT INTO data_feeds \
+                 (unit, message_index, feed_name, value, int_value) VALUES(?,?,?,?,?)",
+            )?;
+            stmt.insert(&[
+                self.get_unit_hash(),
+                &message_index,
+                feed_name,
+                &value,
+                &int_value,
+            ])?;
+        }
+        Ok(())
+    }
+
+    fn save_poll(&self, tx: &Transaction, message_index: u32, message: &Message) -> Result<()> {
+        let payload = match message.payload {
+            Some(Payload::Other(ref payload)) => payload,
+            _ => bail!("poll payload must be an object"),
+        };
+        let question = match payload["question"].as_str() {
+            Some(question) => question,
+            None => bail!("no question in poll"),
+        };
+        let choices = match payload["choices"].as_array() {
+            Some(choices) => choices,
+            None => bail!("no choices in poll"),
+        };
+
+        let mut stmt =
+            tx.prepare_cached("INSERT INTO polls (unit, message_index, question) VALUES(?,?,?)")?;
+        stmt.insert(&[self.get_unit_hash(), &message_index, &question])?;
+
+        let mut stmt = tx.prepare_cached(
+            "INSERT INTO poll_choices (unit, choice_index, choice) VALUES(?,?,?)",
+        )?;
+        for (choice_index, choice) in choices.iter().enumerate() {
+            let choice = match choice.as_str() {
+                Some(choice) => choice,
+                None => bail!("poll choice must be a string"),
+            };
+            stmt.insert(&[self.get_unit_hash(), &(choice_index as u32), &choice])?;
+        }
+        Ok(())
+    }
+
+    fn save_vote(&self, tx: &Transaction, message_index: u32, message: &Message) -> Result<()> {
+        let payload = match message.payload {
+            Some(Payload::Other(ref payload)) => payload,
+            _ => bail!("vote payload must be an object"),
+        };
+        let (poll_unit, choice) = match (payload["unit"].as_str(), payload["choice"].as_str()) {
+            (Some(poll_unit), Some(choice)) => (poll_unit, choice),
+            _ => bail!("vote must have unit and choice"),
+        };
+
+        let mut stmt = tx.prepare_cached(
+            "INSERT INTO votes (unit, message_index, poll_unit, choice) VALUES(?,?,?,?)",
+        )?;
+        stmt.insert(&[self.get_unit_hash(), &message_index, &poll_unit, &choice])?;
+        Ok(())
+    }
+
+    fn save_header_earnings(&self, tx: &Transaction) -> Result<()> {
+        if let Some(ref recipients) = self.unit.earned_headers_commission_recipients {
+            let mut stmt = tx.prepare_cached(
+                "INSERT INTO earned_headers_commission_recipients \
+                 (unit, address, earned_headers_commission_share) VALUES(?,?,?)",
+            )?;
+            for recipient in recipients {
+                stmt.insert(&[
+                    self.get_unit_hash(),
+                    &recipient.address,
+                    &recipient.earned_headers_commission_share,
+                ])?;
+            }
+        }
+        Ok(())
+    }
 
-        let best_parent_unit: String =
-            tx.query_row(&sql, &[&unit.witness_list_unit], |row| row.get(0))?;
+    fn save_witnesses(&self, tx: &Transaction) -> Result<()> {
+        if let Some(ref witnesses) = self.unit.witnesses {
+            let mut stmt =
+                tx.prepare_cached("INSERT INTO unit_witnesses (unit, address) VALUES(?,?)")?;
+            for address in witnesses {
+                stmt.insert(&[self.get_unit_hash(), address])?;
+            }
+        }
+        Ok(())
+    }
+
+    fn read_witnesses(&self, tx: &Transaction) -> Result<Vec<String>> {
+        match self.unit.witnesses {
+            Some(ref witnesses) => Ok(witnesses.clone()),
+            None => match self.unit.witness_list_unit {
+                Some(ref witness_list_unit) => ::storage::read_witness_list(tx, witness_list_unit),
+                None => bail!("unit {} has no witnesses", self.get_unit_hash()),
+            },
+        }
+    }
+
+    fn update_best_parent(&self, tx: &Transaction, witnesses: &[String]) -> Result<String> {
+        let best_parent_unit = match ::storage::determine_best_parent(
+            tx,
+            &self.unit.parent_units,
+            self.unit.witness_list_unit.as_ref(),
+            witnesses,
+        )? {
+            Some(unit) => unit,
+            None => bail!("no compatible best parent of unit {}", self.get_unit_hash()),
+        };
 
         let mut stmt = tx.prepare_cached("UPDATE units SET best_parent_unit=? WHERE unit=?")?;
         stmt.execute(&[&best_parent_unit, self.get_unit_hash()])?;
@@ -301,7 +434,7 @@ impl Joint {
             }
 
             for address in authors {
-                if !witness_list.contains(&address) && !collected_witnesses.contains(&address) {
+                if witness_list.contains(&address) && !collected_witnesses.contains(&address) {
                     collected_witnesses.insert(address);
                 }
             }
@@ -311,23 +444,14 @@ impl Joint {
             }
 
             // search next best parent
-            best_parent_unit = props.best_parent_unit;
-        }
-    }
-
-    fn update_witness_level(&self, tx: &Transaction, best_parent_unit: String) -> Result<()> {
-        match self.unit.witnesses {
-            Some(ref witness_list) => {
-                self.update_witness_level_by_witness_list(tx, witness_list, best_parent_unit)
-            }
-            None => {
-                let witness_list = ::storage::read_witness_list(tx, self.get_unit_hash())?;
-                self.update_witness_level_by_witness_list(tx, &witness_list, best_parent_unit)
-            }
+            best_parent_unit = match props.best_parent_unit {
+                Some(unit) => unit,
+                None => bail!("no best parent of unit {}", best_parent_unit),
+            };
         }
     }
 
-    fn save_inline_payment(&self, tx: &Transaction) -> Result<()> {
+    fn save_inline_payment(&self, tx: &Transaction, state: &ValidationState) -> Result<()> {
         let mut author_addresses = vec![];
         for author in &self.unit.authors {
             author_addresses.push(&author.address);
@@ -338,15 +462,30 @@ impl Joint {
                 continue;
             }
 
-            let payload = message.payload.as_ref().expect("no payload found");
+            let payload = match message.payload {
+                Some(Payload::Payment(ref payment)) => payment,
+                _ => bail!("no payment payload found"),
+            };
             let denomination = payload.denomination.unwrap_or(1);
 
             for (j, input) in payload.inputs.iter().enumerate() {
                 let default_kind = String::from("transfer");
                 let kind = input.kind.as_ref().unwrap_or(&default_kind);
-                let src_unit = some_if!(kind == "transfer", input.unit.clone());
-                let src_message_index = some_if!(kind == "transfer", input.message_index);
-                let src_output_index = some_if!(kind == "transfer", input.output_index);
+                let src_unit = if kind == "transfer" {
+                    input.unit.clone()
+                } else {
+                    None
+                };
+                let src_message_index = if kind == "transfer" {
+                    input.message_index
+                } else {
+                    None
+                };
+                let src_output_index = if kind == "transfer" {
+                    input.output_index
+                } else {
+                    None
+                };
                 let from_main_chain_index = if kind == "witnessing" || kind == "headers_commission"
                 {
                     input.from_main_chain_index
@@ -363,19 +502,24 @@ impl Joint {
                     author_addresses[0].clone()
                 } else {
                     match kind.as_str() {
-                        "headers_commission" | "witnessing" | "issue" => unimplemented!(), //input.address.clone(),
+                        "headers_commission" | "witnessing" | "issue" => input
+                            .address
+                            .clone()
+                            .ok_or_else(|| format_err!("no address in {} input", kind))?,
                         _ => self.determine_input_address_from_output(
                             tx,
-                            payload.asset.as_ref().unwrap(),
+                            &payload.asset,
                             denomination,
                             &input,
                         )?,
                     }
                 };
 
-                // TODO: objValidationState.arrDoubleSpendInputs.some(...)
-                // here we give it a unique as default
-                let is_unique = 1;
+                let is_double_spend = state
+                    .double_spend_inputs
+                    .iter()
+                    .any(|d| d.message_index == i as u32 && d.input_index == j as u32);
+                let is_unique = some_if!(!is_double_spend, 1);
 
                 let mut stmt = tx.prepare_cached(
                     "INSERT INTO inputs \
@@ -396,6 +540,9 @@ impl Joint {
                     &src_output_index,
                     &from_main_chain_index,
                     &to_main_chain_index,
+                    &denomination,
+                    &input.amount,
+                    &input.serial_number,
                     &payload.asset,
                     &is_unique,
                     &address,
@@ -446,7 +593,7 @@ impl Joint {
     fn determine_input_address_from_output(
         &self,
         tx: &Transaction,
-        asset: &String,
+        asset: &Option<String>,
         denomination: u32,
         input: &Input,
     ) -> Result<String> {
@@ -457,7 +604,10 @@ impl Joint {
         let address = stmt.query_row(
             &[&input.unit, &input.message_index, &input.output_index],
             |row| {
-                ensure!(asset == &row.get::<_, String>(2), "asset doesn't match");
+                ensure!(
+                    asset == &row.get::<_, Option<String>>(2),
+                    "asset doesn't match"
+                );
                 ensure!(
                     denomination == row.get::<_, u32>(1),
                     "denomination not match"
@@ -469,37 +619,61 @@ impl Joint {
         Ok(address?)
     }
 
-    pub fn save(&self) -> Result<()> {
+    pub fn save(&self, state: ValidationState) -> Result<()> {
         // first construct all the sql within a mutex
         info!("saving unit = {:?}", self.unit);
         assert_eq!(self.unit.unit.is_some(), true);
         let _g = WRITER_MUTEX.lock()?;
+        ::storage::set_unit_is_uncommitted(self.get_unit_hash());
         // and then execute the transaction
         let mut db = db::DB_POOL.get_connection();
-        let tx = db.transaction()?;
+        let ret = (|| -> Result<()> {
+            let tx = db.transaction()?;
+            self.save_in_tx(&tx, &state)?;
+            // TODO: add precommit hook
+            tx.commit()?;
+            Ok(())
+        })();
+
+        if let Err(e) = ret {
+            // the unit is not saved, nothing about it stays cached
+            ::storage::forget_unit(self.get_unit_hash());
+            ::storage::refresh_unstable_units(&db)?;
+            return Err(e);
+        }
 
-        self.save_inline_payment(&tx)?;
+        ::storage::cache_saved_unit(&db, &self.unit)?;
+        ::storage::refresh_unstable_units(&db)?;
 
-        // TODO: add validation, default is good
-        let sequence = String::from("good");
+        // TODO: add sqlite optimization
+        Ok(())
+    }
 
-        self.save_unit(&tx, &sequence)?;
-        self.save_ball(&tx)?;
-        self.save_parents(&tx)?;
-        self.save_authors(&tx)?;
-        self.save_messages(&tx)?;
-        self.save_header_earnings(&tx)?;
-        let best_parent_unit = self.update_best_parent(&tx)?;
-        self.update_level(&tx)?;
-        self.update_witness_level(&tx, best_parent_unit)?;
-        // TODO: add update mainchain()
-        // main_chain::update_main_chain()?;
+    pub(crate) fn save_in_tx(&self, tx: &Transaction, state: &ValidationState) -> Result<()> {
+        for sql in &state.additional_queries {
+            tx.execute(sql, &[])?;
+        }
 
-        // TODO: add precommit hook
-        tx.commit()?;
+        // the unit and its authors go first, the rest refer to them by foreign keys
+        self.save_unit(tx, &state.sequence)?;
+        self.save_ball(tx)?;
+        self.save_parents(tx)?;
+        self.save_authors(tx)?;
+        self.save_messages(tx)?;
+        self.save_inline_payment(tx, state)?;
+        self.save_witnesses(tx)?;
+        self.save_header_earnings(tx)?;
+
+        // genesis is already stable and on the main chain after save_parents
+        if self.unit.is_genesis_unit() {
+            return Ok(());
+        }
 
-        // TODO: add sqlite optimization
-        Ok(())
+        let witnesses = self.read_witnesses(tx)?;
+        let best_parent_unit = self.update_best_parent(tx, &witnesses)?;
+        self.update_level(tx)?;
+        self.update_witness_level_by_witness_list(tx, &witnesses, best_parent_unit)?;
+        main_chain::update_main_chain(tx, self.get_unit_hash())
     }
 
     pub fn has_valid_hashes(&self) -> bool {
@@ -512,48 +686,6 @@ impl Joint {
     }
 
     pub fn get_joint_hash(&self) -> String {
-        use base64;
-        use sha2::{Digest, Sha256};
-        base64::encode(&Sha256::digest(&serde_json::to_vec(self).expect("joint to json failed")))
+        ::object_hash::get_base64_hash(self).expect("joint to json failed")
     }
 }
-
-#[test]
-fn test_write() {
-    let unit = Unit {
-        alt: String::from("1"),
-        authors: Vec::new(),
-        content_hash: None,
-        earned_headers_commission_recipients: None,
-        headers_commission: None,
-        last_ball: Some(String::from("oiIA6Y+87fk6/QyrbOlwqsQ/LLr82Rcuzcr1G/GoHlA=")),
-        last_ball_unit: Some(String::from("vxrlKyY517Z+BGMNG35ExiQsYv3ncp/KU414SqXKXTk=")),
-        main_chain_index: None,
-        messages: Vec::new(),
-        parent_units: vec![
-            "uPbobEuZL+FY1ujTNiYZnM9lgC3xysxuDIpSbvnmbac=".into(),
-            "vxrlKyY517Z+BGMNG35ExiQsYv3ncp/KU414SqXKXTk=".into(),
-        ],
-        payload_commission: None,
-        timestamp: None,
-        unit: Some(String::from("5CYeTTa4VQxgF4b1Tn33NBlKilJadddwBMLvtp1HIus=")),
-        version: String::from("1.0"),
-        witnesses: None,
-        witness_list_unit: Some(String::from("MtzrZeOHHjqVZheuLylf0DX7zhp10nBsQX5e/+cA3PQ=")),
-    };
-    let joint = Joint {
-        ball: None,
-        skiplist_units: None,
-        unit: unit,
-        unsigned: None,
-    };
-    let parents_set = joint
-        .unit
-        .parent_units
-        .iter()
-        .map(|s| format!("'{}'", s))
-        .collect::<Vec<_>>()
-        .join(", ");
-    println!("{}", parents_set);
-    // joint.save().unwrap();
-}
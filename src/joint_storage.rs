@@ -1,9 +1,22 @@
+use std::fs::File;
+use std::path::Path;
+
+use config;
 use error::Result;
 use joint::Joint;
+use may::sync::Mutex;
+use object_hash;
 use rusqlite::Connection;
+use serde_json;
 use storage;
+use validation::ValidationState;
 // use spec::Unit;
 
+lazy_static! {
+    // protect the unhandled_joints and dependencies tables
+    static ref DEPENDENCIES_MUTEX: Mutex<()> = Mutex::new(());
+}
+
 #[derive(Debug)]
 pub enum CheckNewResult {
     Known,
@@ -57,3 +70,290 @@ pub fn check_new_joint(db: &Connection, joint: &Joint) -> Result<CheckNewResult>
     }
     Ok(ret)
 }
+
+pub fn save_unhandled_joint_and_dependencies(
+    db: &mut Connection,
+    joint: &Joint,
+    missing_parent_units: &[String],
+    peer: &str,
+) -> Result<()> {
+    let unit = joint.unit.unit.as_ref().expect("miss unit hash in joint");
+    let _g = DEPENDENCIES_MUTEX.lock()?;
+    let tx = db.transaction()?;
+    {
+        // the joint may be queued already when it's still missing some parents after handled
+        let mut stmt = tx.prepare_cached(
+            "INSERT OR IGNORE INTO unhandled_joints (unit, json, peer) VALUES (?, ?, ?)",
+        )?;
+        stmt.execute(&[unit, &serde_json::to_string(joint)?, &peer])?;
+
+        let mut stmt = tx.prepare_cached(
+            "INSERT OR IGNORE INTO dependencies (unit, depends_on_unit) VALUES (?, ?)",
+        )?;
+        for missing_unit in missing_parent_units {
+            stmt.execute(&[unit, missing_unit])?;
+        }
+    }
+    tx.commit()?;
+    Ok(())
+}
+
+pub struct ReadyJoint {
+    pub joint: Joint,
+    pub peer: String,
+}
+
+// the joints that depend on the unit and have no other missing parents,
+// they stay in the unhandled queue until `remove_unhandled_joint` after handled
+pub fn read_dependent_joints_that_are_ready(
+    db: &Connection,
+    unit: &String,
+) -> Result<Vec<ReadyJoint>> {
+    let _g = DEPENDENCIES_MUTEX.lock()?;
+    let mut stmt = db.prepare_cached(
+        "SELECT dependencies.unit, \
+         SUM(CASE WHEN units.unit IS NULL THEN 1 ELSE 0 END) AS count_missing_parents \
+         FROM dependencies \
+         JOIN unhandled_joints ON dependencies.unit=unhandled_joints.unit \
+         LEFT JOIN units ON dependencies.depends_on_unit=units.unit \
+         WHERE dependencies.unit IN(SELECT unit FROM dependencies WHERE depends_on_unit=?) \
+         GROUP BY dependencies.unit \
+         HAVING count_missing_parents=0 \
+         ORDER BY NULL",
+    )?;
+    let units = stmt
+        .query_map(&[unit], |row| row.get::<_, String>(0))?
+        .collect::<::std::result::Result<Vec<_>, _>>()?;
+
+    let mut ready_joints = Vec::new();
+    for unit in units {
+        let mut stmt = db.prepare_cached("SELECT json, peer FROM unhandled_joints WHERE unit=?")?;
+        let (json, peer) = stmt.query_row(&[&unit], |row| {
+            (row.get::<_, String>(0), row.get::<_, String>(1))
+        })?;
+        ready_joints.push(ReadyJoint {
+            joint: serde_json::from_str(&json)?,
+            peer,
+        });
+    }
+
+    Ok(ready_joints)
+}
+
+// remove the handled joint from the unhandled queue, it's kept if it has been queued
+// again for other missing parents
+pub fn remove_unhandled_joint(db: &mut Connection, unit: &String) -> Result<()> {
+    let _g = DEPENDENCIES_MUTEX.lock()?;
+    let tx = db.transaction()?;
+    {
+        let mut stmt = tx.prepare_cached(
+            "DELETE FROM unhandled_joints WHERE unit=? AND NOT EXISTS ( \
+             SELECT 1 FROM dependencies LEFT JOIN units ON depends_on_unit=units.unit \
+             WHERE dependencies.unit=unhandled_joints.unit AND units.unit IS NULL)",
+        )?;
+        if stmt.execute(&[unit])? > 0 {
+            let mut stmt = tx.prepare_cached("DELETE FROM dependencies WHERE unit=?")?;
+            stmt.execute(&[unit])?;
+        }
+    }
+    tx.commit()?;
+    Ok(())
+}
+
+// remove the unhandled joints that have been waiting for their parents too long
+pub fn purge_old_unhandled_joints(db: &Connection) -> Result<()> {
+    let _g = DEPENDENCIES_MUTEX.lock()?;
+    let sql = format!(
+        "DELETE FROM unhandled_joints WHERE creation_date < datetime('now', '-{} seconds')",
+        config::get_settings().unhandled_joints_timeout
+    );
+    let count = db.execute(&sql, &[])?;
+    if count > 0 {
+        info!("purged {} old unhandled joints", count);
+        db.execute(
+            "DELETE FROM dependencies WHERE NOT EXISTS \
+             (SELECT 1 FROM unhandled_joints WHERE unhandled_joints.unit=dependencies.unit)",
+            &[],
+        )?;
+    }
+    Ok(())
+}
+
+// record the joint whose own structure is bad, the unit hash can't be trusted
+pub fn save_known_bad_joint(db: &Connection, joint: &Joint, error: &str) -> Result<()> {
+    let mut stmt =
+        db.prepare_cached("INSERT INTO known_bad_joints (joint, json, error) VALUES (?,?,?)")?;
+    stmt.insert(&[&joint.get_joint_hash(), &serde_json::to_string(joint)?, &error])?;
+    Ok(())
+}
+
+// mark the unit bad and purge all the unhandled joints that depend on it,
+// return the purged dependent units with the peers that sent them
+pub fn purge_joint_and_dependencies(
+    db: &mut Connection,
+    joint: &Joint,
+    error: &str,
+) -> Result<Vec<(String, String)>> {
+    let unit = joint.unit.unit.as_ref().expect("miss unit hash in joint");
+    let mut purged_units = Vec::new();
+
+    {
+        let _g = DEPENDENCIES_MUTEX.lock()?;
+        let tx = db.transaction()?;
+        {
+            // the unit may be purged again when another peer sends it
+            let mut stmt = tx.prepare_cached(
+                "INSERT OR IGNORE INTO known_bad_joints (unit, json, error) VALUES (?,?,?)",
+            )?;
+            stmt.execute(&[unit, &serde_json::to_string(joint)?, &error])?;
+            let mut stmt = tx.prepare_cached("DELETE FROM unhandled_joints WHERE unit=?")?;
+            stmt.execute(&[unit])?;
+            let mut stmt = tx.prepare_cached("DELETE FROM dependencies WHERE unit=?")?;
+            stmt.execute(&[unit])?;
+
+            let mut bad_units = vec![unit.clone()];
+            while let Some(bad_unit) = bad_units.pop() {
+                let mut stmt = tx.prepare_cached(
+                    "SELECT unit, peer FROM dependencies JOIN unhandled_joints USING(unit) \
+                     WHERE depends_on_unit=?",
+                )?;
+                let rows = stmt
+                    .query_map(&[&bad_unit], |row| {
+                        (row.get::<_, String>(0), row.get::<_, String>(1))
+                    })?
+                    .collect::<::std::result::Result<Vec<_>, _>>()?;
+
+                for (dependent_unit, peer) in rows {
+                    let error = format!("unit {} is bad: {}", bad_unit, error);
+                    let mut stmt = tx.prepare_cached(
+                        "INSERT OR IGNORE INTO known_bad_joints (unit, json, error) \
+                         SELECT unit, json, ? FROM unhandled_joints WHERE unit=?",
+                    )?;
+                    stmt.execute(&[&error, &dependent_unit])?;
+                    let mut stmt = tx.prepare_cached("DELETE FROM unhandled_joints WHERE unit=?")?;
+                    stmt.execute(&[&dependent_unit])?;
+                    let mut stmt = tx.prepare_cached("DELETE FROM dependencies WHERE unit=?")?;
+                    stmt.execute(&[&dependent_unit])?;
+
+                    bad_units.push(dependent_unit.clone());
+                    purged_units.push((dependent_unit, peer));
+                }
+            }
+        }
+        tx.commit()?;
+    }
+
+    storage::forget_unit(unit);
+    for &(ref purged_unit, _) in &purged_units {
+        storage::forget_unit(purged_unit);
+    }
+
+    Ok(purged_units)
+}
+
+// save the genesis joint from config when the database is empty
+pub fn init_genesis_joint(db: &Connection) -> Result<()> {
+    let mut stmt = db.prepare_cached("SELECT 1 FROM units LIMIT 1")?;
+    if stmt.exists(&[])? {
+        return Ok(());
+    }
+
+    let file_name = config::get_settings().genesis_joint;
+    if !Path::new(&file_name).exists() {
+        warn!(
+            "no genesis joint file {} found, the database stays empty",
+            file_name
+        );
+        return Ok(());
+    }
+
+    let mut joint: Joint = serde_json::from_reader(File::open(&file_name)?)?;
+    ensure!(
+        joint.unit.is_genesis_unit(),
+        "{} is not the genesis joint {}",
+        file_name,
+        config::get_genesis_unit()
+    );
+    ensure!(joint.has_valid_hashes(), "wrong unit hash of genesis joint");
+
+    let genesis_ball =
+        object_hash::get_ball_hash(joint.get_unit_hash(), &Vec::new(), &Vec::new(), false);
+    if joint.ball.is_none() {
+        joint.ball = Some(genesis_ball.clone());
+    }
+    ensure!(
+        joint.ball.as_ref() == Some(&genesis_ball),
+        "wrong ball of genesis joint"
+    );
+
+    info!("saving genesis joint {}", joint.get_unit_hash());
+    joint.save(ValidationState::new())
+}
+
+#[test]
+fn test_unhandled_joint_stays_queued_until_removed() {
+    use test_utils::TestDag;
+
+    let mut dag = TestDag::new("unhandled joints");
+    dag.add_units(3);
+    let parent = dag.compose(&[&dag.witnesses[3]], dag.read_free_units(), Vec::new());
+    let parent_unit = parent.get_unit_hash().clone();
+    let child = dag.compose(&[&dag.witnesses[4]], vec![parent_unit.clone()], Vec::new());
+    let child_unit = child.get_unit_hash().clone();
+
+    // queueing the joint again is fine
+    for _ in 0..2 {
+        save_unhandled_joint_and_dependencies(&mut dag.db, &child, &[parent_unit.clone()], "peer")
+            .unwrap();
+    }
+    assert!(read_dependent_joints_that_are_ready(&dag.db, &parent_unit)
+        .unwrap()
+        .is_empty());
+    // it's still missing the parent
+    remove_unhandled_joint(&mut dag.db, &child_unit).unwrap();
+
+    dag.save(&parent).unwrap();
+    let ready = read_dependent_joints_that_are_ready(&dag.db, &parent_unit).unwrap();
+    assert_eq!(ready.len(), 1);
+    assert_eq!(ready[0].joint.get_unit_hash(), &child_unit);
+    assert_eq!(ready[0].peer, "peer");
+    match check_new_unit(&dag.db, &child_unit).unwrap() {
+        CheckNewResult::KnownUnverified => {}
+        r => panic!("unexpected result {:?}", r),
+    }
+
+    remove_unhandled_joint(&mut dag.db, &child_unit).unwrap();
+    assert!(read_dependent_joints_that_are_ready(&dag.db, &parent_unit)
+        .unwrap()
+        .is_empty());
+    match check_new_unit(&dag.db, &child_unit).unwrap() {
+        CheckNewResult::New => {}
+        r => panic!("unexpected result {:?}", r),
+    }
+}
+
+#[test]
+fn test_purge_same_bad_joint_twice() {
+    use test_utils::TestDag;
+
+    let mut dag = TestDag::new("purge bad joints");
+    dag.add_units(3);
+    let bad = dag.compose(&[&dag.witnesses[3]], dag.read_free_units(), Vec::new());
+    let bad_unit = bad.get_unit_hash().clone();
+    let child = dag.compose(&[&dag.witnesses[4]], vec![bad_unit.clone()], Vec::new());
+    let child_unit = child.get_unit_hash().clone();
+
+    // the child is sent again after it was purged with the bad unit
+    for _ in 0..2 {
+        save_unhandled_joint_and_dependencies(&mut dag.db, &child, &[bad_unit.clone()], "peer")
+            .unwrap();
+        let purged = purge_joint_and_dependencies(&mut dag.db, &bad, "bad unit").unwrap();
+        assert_eq!(purged, vec![(child_unit.clone(), String::from("peer"))]);
+        for unit in &[&bad_unit, &child_unit] {
+            match check_new_unit(&dag.db, unit).unwrap() {
+                CheckNewResult::KnownBad => {}
+                r => panic!("unexpected result {:?}", r),
+            }
+        }
+    }
+}
@@ -4,11 +4,14 @@ extern crate log;
 extern crate may;
 extern crate num_cpus;
 extern crate rusqlite;
+extern crate rustls;
 extern crate serde;
 #[macro_use]
 extern crate serde_json;
 extern crate tungstenite;
 extern crate url;
+extern crate webpki;
+extern crate webpki_roots;
 
 #[macro_use]
 extern crate failure;
@@ -46,6 +49,7 @@ macro_rules! t {
     };
 }
 
+pub mod cache;
 pub mod config;
 pub mod db;
 #[macro_use]
@@ -63,10 +67,14 @@ pub mod catchup;
 mod definition;
 pub mod joint;
 pub mod joint_storage;
+pub mod main_chain;
+pub mod merkle;
 mod obj_ser;
 pub mod object_hash;
 pub mod signature;
 pub mod storage;
+#[cfg(test)]
+mod test_utils;
 pub mod time;
 pub mod validation;
 pub mod witness_proof;
@@ -0,0 +1,1123 @@
+use std::collections::HashSet;
+
+use config;
+use error::Result;
+use graph;
+use header_commissions;
+use object_hash;
+use paid_witnessing;
+use rusqlite::Connection;
+use storage;
+
+// pick the best free unit as the new tip of the main chain
+fn read_best_free_unit(db: &Connection) -> Result<String> {
+    let mut stmt = db.prepare_cached(
+        "SELECT unit FROM units WHERE is_free=1 \
+         ORDER BY witnessed_level DESC, \
+         level-witnessed_level ASC, \
+         unit ASC \
+         LIMIT 1",
+    )?;
+    let mut rows = stmt.query_map(&[], |row| row.get::<_, String>(0))?;
+    match rows.next() {
+        Some(row) => Ok(row?),
+        None => bail!("no free units?"),
+    }
+}
+
+fn read_best_parent_unit(db: &Connection, unit: &String) -> Result<String> {
+    let mut stmt = db.prepare_cached("SELECT best_parent_unit FROM units WHERE unit=?")?;
+    let best_parent_unit = stmt.query_row(&[unit], |row| row.get::<_, Option<String>>(0))?;
+    match best_parent_unit {
+        Some(unit) => Ok(unit),
+        None => bail!("best parent of unit {} is null", unit),
+    }
+}
+
+// walk up the best parents from the best free unit until we meet the current MC,
+// marking every new unit on the way as on MC
+fn go_up_from_free_units(db: &Connection, last_added_unit: &String) -> Result<()> {
+    let mut unit: Option<String> = None;
+    loop {
+        if let Some(ref unit) = unit {
+            if storage::is_genesis_unit(unit) {
+                check_not_rebuilding_stable_main_chain(db, 0, last_added_unit)?;
+                return go_down_and_update_main_chain_index(db, 0, last_added_unit);
+            }
+        }
+
+        let best_parent_unit = match unit {
+            None => read_best_free_unit(db)?,
+            Some(ref unit) => read_best_parent_unit(db, unit)?,
+        };
+
+        let mut stmt =
+            db.prepare_cached("SELECT is_on_main_chain, main_chain_index FROM units WHERE unit=?")?;
+        let (is_on_main_chain, main_chain_index) = stmt.query_row(&[&best_parent_unit], |row| {
+            (row.get::<_, u32>(0), row.get::<_, Option<u32>>(1))
+        })?;
+
+        if is_on_main_chain == 0 {
+            let mut stmt = db.prepare_cached(
+                "UPDATE units SET is_on_main_chain=1, main_chain_index=NULL WHERE unit=?",
+            )?;
+            stmt.execute(&[&best_parent_unit])?;
+            unit = Some(best_parent_unit);
+            continue;
+        }
+
+        let last_main_chain_index = match main_chain_index {
+            Some(mci) => mci,
+            None => bail!("MC unit {} has no main_chain_index", best_parent_unit),
+        };
+
+        // the tip didn't change, only need to update the new units' limci
+        if unit.is_none() {
+            return update_latest_included_mc_index(db, last_main_chain_index, false);
+        }
+
+        check_not_rebuilding_stable_main_chain(db, last_main_chain_index, last_added_unit)?;
+        return go_down_and_update_main_chain_index(db, last_main_chain_index, last_added_unit);
+    }
+}
+
+fn check_not_rebuilding_stable_main_chain(
+    db: &Connection,
+    last_main_chain_index: u32,
+    last_added_unit: &String,
+) -> Result<()> {
+    let mut stmt = db.prepare_cached(
+        "SELECT unit FROM units WHERE is_on_main_chain=1 AND main_chain_index>? AND is_stable=1",
+    )?;
+    let rows = stmt.query_map(&[&last_main_chain_index], |row| row.get::<_, String>(0))?;
+    let mut units = Vec::new();
+    for row in rows {
+        units.push(row?);
+    }
+
+    ensure!(
+        units.is_empty(),
+        "removing stable units {} from MC after adding {}",
+        units.join(", "),
+        last_added_unit
+    );
+    Ok(())
+}
+
+// retreat the old MC beyond the fork point and assign new MCIs to the new MC units
+// and all the units they newly include
+fn go_down_and_update_main_chain_index(
+    db: &Connection,
+    last_main_chain_index: u32,
+    last_added_unit: &String,
+) -> Result<()> {
+    let mut stmt = db.prepare_cached(
+        "UPDATE units SET is_on_main_chain=0, main_chain_index=NULL WHERE main_chain_index>?",
+    )?;
+    stmt.execute(&[&last_main_chain_index])?;
+
+    let mut stmt = db.prepare_cached(
+        "SELECT unit FROM units WHERE is_on_main_chain=1 AND main_chain_index IS NULL \
+         ORDER BY level",
+    )?;
+    let rows = stmt.query_map(&[], |row| row.get::<_, String>(0))?;
+    let mut mc_units = Vec::new();
+    for row in rows {
+        mc_units.push(row?);
+    }
+
+    ensure!(
+        !mc_units.is_empty(),
+        "no unindexed MC units after adding {}",
+        last_added_unit
+    );
+
+    let mut main_chain_index = last_main_chain_index;
+    for mc_unit in mc_units {
+        main_chain_index += 1;
+
+        let mut units = HashSet::new();
+        units.insert(mc_unit.clone());
+        let mut start_units = vec![mc_unit];
+
+        'go_up: loop {
+            let start_unit_list = start_units
+                .iter()
+                .map(|s| format!("'{}'", s))
+                .collect::<Vec<_>>()
+                .join(", ");
+
+            let sql = format!(
+                "SELECT DISTINCT unit \
+                 FROM parenthoods JOIN units ON parent_unit=unit \
+                 WHERE child_unit IN({}) AND main_chain_index IS NULL",
+                start_unit_list
+            );
+
+            let mut stmt = db.prepare(&sql)?;
+            let rows = stmt.query_map(&[], |row| row.get::<_, String>(0))?;
+            let mut new_start_units = Vec::new();
+            for row in rows {
+                let unit = row?;
+                if units.insert(unit.clone()) {
+                    new_start_units.push(unit);
+                }
+            }
+
+            if new_start_units.is_empty() {
+                break 'go_up;
+            }
+            start_units = new_start_units;
+        }
+
+        let unit_list = units
+            .iter()
+            .map(|s| format!("'{}'", s))
+            .collect::<Vec<_>>()
+            .join(", ");
+        let sql = format!(
+            "UPDATE units SET main_chain_index=? WHERE unit IN({})",
+            unit_list
+        );
+        db.execute(&sql, &[&main_chain_index])?;
+    }
+
+    update_latest_included_mc_index(db, last_main_chain_index, true)
+}
+
+fn update_latest_included_mc_index(
+    db: &Connection,
+    last_main_chain_index: u32,
+    rebuilt_mc: bool,
+) -> Result<()> {
+    let mut stmt = db.prepare_cached(
+        "UPDATE units SET latest_included_mc_index=NULL \
+         WHERE main_chain_index>? OR main_chain_index IS NULL",
+    )?;
+    let affected = stmt.execute(&[&last_main_chain_index])?;
+
+    // if these units have other parents, they cannot include later MC units
+    // (otherwise, the parents would've been redundant)
+    let mut stmt = db.prepare_cached(
+        "SELECT chunits.unit, punits.main_chain_index \
+         FROM units AS punits \
+         JOIN parenthoods ON punits.unit=parent_unit \
+         JOIN units AS chunits ON child_unit=chunits.unit \
+         WHERE punits.is_on_main_chain=1 \
+         AND (chunits.main_chain_index > ? OR chunits.main_chain_index IS NULL) \
+         AND chunits.latest_included_mc_index IS NULL",
+    )?;
+    let rows = stmt.query_map(&[&last_main_chain_index], |row| {
+        (row.get::<_, String>(0), row.get::<_, u32>(1))
+    })?;
+    let mut limcis = Vec::new();
+    for row in rows {
+        limcis.push(row?);
+    }
+
+    ensure!(
+        !limcis.is_empty() || !rebuilt_mc,
+        "no latest_included_mc_index updated, last_mci={}, affected={}",
+        last_main_chain_index,
+        affected
+    );
+
+    let mut stmt = db.prepare_cached("UPDATE units SET latest_included_mc_index=? WHERE unit=?")?;
+    for (unit, limci) in limcis {
+        stmt.execute(&[&limci, &unit])?;
+    }
+
+    propagate_latest_included_mc_index(db, last_main_chain_index)?;
+    check_all_latest_included_mc_indexes_are_set(db)
+}
+
+// push the limci down to children until nothing changes
+fn propagate_latest_included_mc_index(db: &Connection, last_main_chain_index: u32) -> Result<()> {
+    loop {
+        let mut stmt = db.prepare_cached(
+            "SELECT chunits.unit, punits.latest_included_mc_index \
+             FROM units AS punits \
+             JOIN parenthoods ON punits.unit=parent_unit \
+             JOIN units AS chunits ON child_unit=chunits.unit \
+             WHERE (chunits.main_chain_index > ? OR chunits.main_chain_index IS NULL) \
+             AND (chunits.latest_included_mc_index IS NULL \
+             OR chunits.latest_included_mc_index < punits.latest_included_mc_index)",
+        )?;
+        let rows = stmt.query_map(&[&last_main_chain_index], |row| {
+            (row.get::<_, String>(0), row.get::<_, Option<u32>>(1))
+        })?;
+        let mut limcis = Vec::new();
+        for row in rows {
+            // the parent is not set yet either, it is pushed down in a later round
+            if let (unit, Some(limci)) = row? {
+                limcis.push((unit, limci));
+            }
+        }
+
+        if limcis.is_empty() {
+            return Ok(());
+        }
+
+        let mut stmt =
+            db.prepare_cached("UPDATE units SET latest_included_mc_index=? WHERE unit=?")?;
+        for (unit, limci) in limcis {
+            stmt.execute(&[&limci, &unit])?;
+        }
+    }
+}
+
+fn check_all_latest_included_mc_indexes_are_set(db: &Connection) -> Result<()> {
+    let mut stmt = db.prepare_cached(
+        "SELECT unit FROM units WHERE latest_included_mc_index IS NULL AND level!=0",
+    )?;
+    let rows = stmt.query_map(&[], |row| row.get::<_, String>(0))?;
+    let mut units = Vec::new();
+    for row in rows {
+        units.push(row?);
+    }
+
+    ensure!(
+        units.is_empty(),
+        "{} units have latest_included_mc_index=NULL, e.g. unit {}",
+        units.len(),
+        units.first().map(|s| s.as_str()).unwrap_or("")
+    );
+    Ok(())
+}
+
+// collect the given units and all their best children down to the free units
+fn create_list_of_best_children(db: &Connection, parent_units: &[String]) -> Result<Vec<String>> {
+    let mut best_children = parent_units.to_vec();
+    let mut start_units = parent_units.to_vec();
+
+    while !start_units.is_empty() {
+        let start_unit_list = start_units
+            .iter()
+            .map(|s| format!("'{}'", s))
+            .collect::<Vec<_>>()
+            .join(", ");
+
+        let sql = format!(
+            "SELECT unit, is_free FROM units WHERE best_parent_unit IN({})",
+            start_unit_list
+        );
+
+        let mut stmt = db.prepare(&sql)?;
+        let rows = stmt.query_map(&[], |row| (row.get::<_, String>(0), row.get::<_, u32>(1)))?;
+
+        let mut new_start_units = Vec::new();
+        for row in rows {
+            let (unit, is_free) = row?;
+            best_children.push(unit.clone());
+            if is_free == 0 {
+                new_start_units.push(unit);
+            }
+        }
+        start_units = new_start_units;
+    }
+
+    Ok(best_children)
+}
+
+struct BestChild {
+    unit: String,
+    main_chain_index: Option<u32>,
+    level: u32,
+}
+
+// return the single MC best child of the unit and the roots of the alternative branches
+fn read_best_children(db: &Connection, unit: &String) -> Result<(BestChild, Vec<String>)> {
+    let mut stmt = db.prepare_cached(
+        "SELECT unit, is_on_main_chain, main_chain_index, level \
+         FROM units WHERE best_parent_unit=?",
+    )?;
+    let rows = stmt.query_map(&[unit], |row| {
+        (
+            row.get::<_, u32>(1),
+            BestChild {
+                unit: row.get(0),
+                main_chain_index: row.get(2),
+                level: row.get(3),
+            },
+        )
+    })?;
+
+    let mut mc_children = Vec::new();
+    let mut alt_branch_root_units = Vec::new();
+    for row in rows {
+        let (is_on_main_chain, child) = row?;
+        if is_on_main_chain == 1 {
+            mc_children.push(child);
+        } else {
+            alt_branch_root_units.push(child.unit);
+        }
+    }
+
+    ensure!(
+        !mc_children.is_empty() || !alt_branch_root_units.is_empty(),
+        "no best children of {}?",
+        unit
+    );
+    ensure!(mc_children.len() == 1, "not a single MC child?");
+
+    Ok((mc_children.pop().unwrap(), alt_branch_root_units))
+}
+
+// Compose a set S of units that increase WL, that is their own WL is greater than that of every parent.
+// In this set, find max L. Alt WL will never reach it. If min_mc_wl > L, next MC unit is stable.
+// Also filter the set S to include only those units that are conformant with the last ball and last ball unit
+fn read_max_alt_level(
+    db: &Connection,
+    alt_best_children: &[String],
+    witnesses: &[String],
+) -> Result<Option<u32>> {
+    let alt_best_children_list = alt_best_children
+        .iter()
+        .map(|s| format!("'{}'", s))
+        .collect::<Vec<_>>()
+        .join(", ");
+    let witness_list = witnesses
+        .iter()
+        .map(|s| format!("'{}'", s))
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    let sql = format!(
+        "SELECT MAX(units.level) AS max_alt_level \
+         FROM units \
+         LEFT JOIN parenthoods ON units.unit=child_unit \
+         LEFT JOIN units AS punits ON parent_unit=punits.unit \
+         AND punits.witnessed_level >= units.witnessed_level \
+         WHERE units.unit IN({}) AND punits.unit IS NULL AND ( \
+         SELECT COUNT(*) \
+         FROM unit_witnesses \
+         WHERE unit_witnesses.unit IN(units.unit, units.witness_list_unit) \
+         AND unit_witnesses.address IN({}) \
+         )>=?",
+        alt_best_children_list, witness_list
+    );
+    let min_matching_witnesses =
+        (config::COUNT_WITNESSES - config::MAX_WITNESS_LIST_MUTATIONS) as u32;
+    let max_alt_level = db.query_row(&sql, &[&min_matching_witnesses], |row| {
+        row.get::<_, Option<u32>>(0)
+    })?;
+    Ok(max_alt_level)
+}
+
+// return true if the first unstable MC unit can be marked as stable
+fn determine_if_first_unstable_mc_unit_is_stable(
+    db: &Connection,
+    witnesses: &[String],
+    first_unstable_mc_level: u32,
+    alt_branch_root_units: &[String],
+) -> Result<bool> {
+    let mut stmt = db.prepare_cached(
+        "SELECT witnessed_level FROM units WHERE is_free=1 AND is_on_main_chain=1",
+    )?;
+    let rows = stmt.query_map(&[], |row| row.get::<_, u32>(0))?;
+    let mut witnessed_levels = Vec::new();
+    for row in rows {
+        witnessed_levels.push(row?);
+    }
+    ensure!(witnessed_levels.len() == 1, "not a single mc wl");
+    // this is the level when we colect 7 witnesses if walking up the MC from its end
+    let mc_end_witnessed_level = witnessed_levels[0];
+
+    let witness_list = witnesses
+        .iter()
+        .map(|s| format!("'{}'", s))
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    // among these 7 witnesses, find min wl
+    // _left_ join enforces the best query plan in sqlite
+    let sql = format!(
+        "SELECT MIN(witnessed_level) AS min_mc_wl FROM units LEFT JOIN unit_authors USING(unit) \
+         WHERE is_on_main_chain=1 AND level>=? AND address IN({})",
+        witness_list
+    );
+    let min_mc_wl = db.query_row(&sql, &[&mc_end_witnessed_level], |row| {
+        row.get::<_, Option<u32>>(0)
+    })?;
+    let min_mc_wl = match min_mc_wl {
+        Some(wl) => wl,
+        None => return Ok(false),
+    };
+
+    // no alt branches
+    if alt_branch_root_units.is_empty() {
+        return Ok(min_mc_wl >= first_unstable_mc_level);
+    }
+
+    let alt_best_children = create_list_of_best_children(db, alt_branch_root_units)?;
+    let max_alt_level = read_max_alt_level(db, &alt_best_children, witnesses)?;
+    Ok(max_alt_level.map_or(true, |level| min_mc_wl > level))
+}
+
+// advance the last stable MCI as far as the witnesses allow
+fn update_stable_mc_flag(db: &Connection) -> Result<()> {
+    loop {
+        let last_stable_mc_unit = storage::read_last_stable_mc_unit(db)?;
+        info!("last stable mc unit {}", last_stable_mc_unit);
+        let witnesses = storage::read_witnesses(db, &last_stable_mc_unit)?;
+
+        let (first_unstable_mc, alt_branch_root_units) =
+            read_best_children(db, &last_stable_mc_unit)?;
+        let first_unstable_mc_index = match first_unstable_mc.main_chain_index {
+            Some(mci) => mci,
+            None => bail!(
+                "first unstable MC unit {} has no mci",
+                first_unstable_mc.unit
+            ),
+        };
+
+        if !determine_if_first_unstable_mc_unit_is_stable(
+            db,
+            &witnesses,
+            first_unstable_mc.level,
+            &alt_branch_root_units,
+        )? {
+            return Ok(());
+        }
+
+        mark_mc_index_stable(db, first_unstable_mc_index)?;
+    }
+}
+
+// walk up from the best parent of the later units until a majority of witnesses is collected,
+// return None if we went below the first unstable MC unit before that
+fn find_min_mc_witnessed_level(
+    db: &Connection,
+    later_units: &[String],
+    witnesses: &[String],
+    first_unstable_mc_level: u32,
+) -> Result<Option<u32>> {
+    let mut start_unit = match storage::determine_best_parent(db, later_units, None, witnesses)? {
+        Some(unit) => unit,
+        None => bail!("no best parent of later units"),
+    };
+
+    let mut collected_witnesses = Vec::new();
+    let mut min_mc_wl = ::std::u32::MAX;
+    loop {
+        let props = storage::read_static_unit_property(db, &start_unit)?;
+        if props.level < first_unstable_mc_level {
+            return Ok(None);
+        }
+
+        for address in storage::read_unit_authors(db, &start_unit)? {
+            if witnesses.contains(&address) && !collected_witnesses.contains(&address) {
+                collected_witnesses.push(address);
+                if props.witnessed_level < min_mc_wl {
+                    min_mc_wl = props.witnessed_level;
+                }
+            }
+        }
+
+        if collected_witnesses.len() >= config::MAJORITY_OF_WITNESSES {
+            return Ok(Some(min_mc_wl));
+        }
+
+        start_unit = match props.best_parent_unit {
+            Some(unit) => unit,
+            None => return Ok(None),
+        };
+    }
+}
+
+// also includes the alt branch roots that are included by the later units
+fn create_list_of_best_children_included_by_later_units(
+    db: &Connection,
+    alt_branch_root_units: &[String],
+    later_units: &[String],
+    max_later_limci: Option<u32>,
+) -> Result<Vec<String>> {
+    let mut best_children = Vec::new();
+    let mut start_units = Vec::new();
+    for unit in alt_branch_root_units {
+        if graph::determine_if_included_or_equal(db, unit, later_units)? {
+            best_children.push(unit.clone());
+            start_units.push(unit.clone());
+        }
+    }
+
+    while let Some(start_unit) = start_units.pop() {
+        let mut stmt = db.prepare_cached(
+            "SELECT unit, is_free, main_chain_index FROM units WHERE best_parent_unit=?",
+        )?;
+        let rows = stmt.query_map(&[&start_unit], |row| {
+            (
+                row.get::<_, String>(0),
+                row.get::<_, u32>(1),
+                row.get::<_, Option<u32>>(2),
+            )
+        })?;
+        let mut children = Vec::new();
+        for row in rows {
+            children.push(row?);
+        }
+
+        for (unit, is_free, main_chain_index) in children {
+            let is_included = match (main_chain_index, max_later_limci) {
+                (Some(mci), Some(limci)) if mci <= limci => true,
+                _ => graph::determine_if_included_or_equal(db, &unit, later_units)?,
+            };
+            if !is_included {
+                continue;
+            }
+
+            best_children.push(unit.clone());
+            if is_free == 0 && !later_units.contains(&unit) {
+                start_units.push(unit);
+            }
+        }
+    }
+
+    Ok(best_children)
+}
+
+/// check if the earlier MC unit is stable in view of the later units (e.g. the parents of a new unit)
+pub fn determine_if_stable_in_later_units(
+    db: &Connection,
+    earlier_unit: &String,
+    later_units: &[String],
+) -> Result<bool> {
+    if storage::is_genesis_unit(earlier_unit) {
+        return Ok(true);
+    }
+
+    let (earlier_unit_props, later_units_props) =
+        storage::read_props_of_units(db, earlier_unit, later_units)?;
+    if earlier_unit_props.is_free == 1 {
+        return Ok(false);
+    }
+
+    let max_later_limci = later_units_props
+        .iter()
+        .filter_map(|props| props.latest_included_mc_index)
+        .max();
+
+    let best_parent_unit =
+        match storage::read_static_unit_property(db, earlier_unit)?.best_parent_unit {
+            Some(unit) => unit,
+            None => bail!("unit {} has no best parent", earlier_unit),
+        };
+    let witnesses = storage::read_witnesses(db, &best_parent_unit)?;
+
+    let (first_unstable_mc, alt_branch_root_units) = read_best_children(db, &best_parent_unit)?;
+    ensure!(
+        &first_unstable_mc.unit == earlier_unit,
+        "first unstable MC unit is not our input unit"
+    );
+    let first_unstable_mc_level = first_unstable_mc.level;
+
+    let min_mc_wl =
+        match find_min_mc_witnessed_level(db, later_units, &witnesses, first_unstable_mc_level)? {
+            Some(wl) => wl,
+            None => return Ok(false),
+        };
+
+    let mut has_alt_branches = false;
+    for alt_root_unit in alt_branch_root_units.iter() {
+        if graph::determine_if_included_or_equal(db, alt_root_unit, later_units)? {
+            has_alt_branches = true;
+            break;
+        }
+    }
+
+    if !has_alt_branches {
+        return Ok(min_mc_wl >= first_unstable_mc_level);
+    }
+
+    let alt_best_children = create_list_of_best_children_included_by_later_units(
+        db,
+        &alt_branch_root_units,
+        later_units,
+        max_later_limci,
+    )?;
+    let max_alt_level = read_max_alt_level(db, &alt_best_children, &witnesses)?;
+    Ok(max_alt_level.map_or(true, |level| min_mc_wl > level))
+}
+
+/// mark all the MCIs up to the one of the given MC unit as stable,
+/// the caller must hold the writer lock and refresh the unstable units after commit
+pub fn mark_stable_up_to_unit(db: &Connection, unit: &String) -> Result<()> {
+    storage::forget_unstable_units();
+    let mut stmt = db.prepare_cached("SELECT main_chain_index FROM units WHERE unit=?")?;
+    let mci = match stmt.query_row(&[unit], |row| row.get::<_, Option<u32>>(0))? {
+        Some(mci) => mci,
+        None => bail!("unit {} has no main_chain_index", unit),
+    };
+
+    let last_stable_mci = storage::read_last_stable_mc_index(db)?;
+    for mci in (last_stable_mci + 1)..(mci + 1) {
+        mark_mc_index_stable(db, mci)?;
+    }
+    Ok(())
+}
+
+fn mark_mc_index_stable(db: &Connection, mci: u32) -> Result<()> {
+    info!("marking mci {} stable", mci);
+    let mut stmt =
+        db.prepare_cached("UPDATE units SET is_stable=1 WHERE is_stable=0 AND main_chain_index=?")?;
+    stmt.execute(&[&mci])?;
+
+    handle_nonserial_units(db, mci)?;
+    add_balls(db, mci)?;
+    calc_commissions(db)
+}
+
+// temp-bad units become final-bad if any conflicting unit is stable, otherwise good,
+// final-bad units only keep their content hash
+fn handle_nonserial_units(db: &Connection, mci: u32) -> Result<()> {
+    let mut stmt = db.prepare_cached(
+        "SELECT unit, content_hash, sequence FROM units \
+         WHERE main_chain_index=? AND sequence!='good' ORDER BY unit",
+    )?;
+    let rows = stmt
+        .query_map(&[&mci], |row| {
+            (
+                row.get::<_, String>(0),
+                row.get::<_, Option<String>>(1),
+                row.get::<_, String>(2),
+            )
+        })?
+        .collect::<::std::result::Result<Vec<_>, _>>()?;
+
+    for (unit, content_hash, sequence) in rows {
+        if sequence == "final-bad" {
+            if content_hash.is_none() {
+                set_content_hash(db, &unit)?;
+            }
+            continue;
+        }
+
+        ensure!(
+            sequence == "temp-bad",
+            "unexpected sequence {} of unit {}",
+            sequence,
+            unit
+        );
+        let conflicting_units = find_stable_conflicting_units(db, &unit)?;
+        let sequence = if conflicting_units.is_empty() {
+            "good"
+        } else {
+            "final-bad"
+        };
+        info!(
+            "unit {} has competitors {:?}, it becomes {}",
+            unit, conflicting_units, sequence
+        );
+
+        let mut stmt = db.prepare_cached("UPDATE units SET sequence=? WHERE unit=?")?;
+        stmt.execute(&[&sequence, &unit])?;
+        if sequence == "good" {
+            let mut stmt = db.prepare_cached("UPDATE inputs SET is_unique=1 WHERE unit=?")?;
+            stmt.execute(&[&unit])?;
+        } else {
+            set_content_hash(db, &unit)?;
+        }
+    }
+    Ok(())
+}
+
+// stable good units of the same authors that are neither included nor including the unit
+fn find_stable_conflicting_units(db: &Connection, unit: &String) -> Result<Vec<String>> {
+    let mut stmt = db.prepare_cached(
+        "SELECT unit, level, latest_included_mc_index, main_chain_index, is_on_main_chain, is_free \
+         FROM units WHERE unit=?",
+    )?;
+    let unit_props = stmt.query_row(&[unit], |row| graph::UnitProps {
+        unit: row.get(0),
+        level: row.get(1),
+        latest_included_mc_index: row.get(2),
+        main_chain_index: row.get(3),
+        is_on_main_chain: row.get(4),
+        is_free: row.get(5),
+    })?;
+
+    let mut stmt = db.prepare_cached(
+        "SELECT competitor_units.unit, competitor_units.level, \
+         competitor_units.latest_included_mc_index, competitor_units.main_chain_index, \
+         competitor_units.is_on_main_chain, competitor_units.is_free \
+         FROM unit_authors AS this_unit_authors \
+         JOIN unit_authors AS competitor_unit_authors USING(address) \
+         JOIN units AS competitor_units ON competitor_unit_authors.unit=competitor_units.unit \
+         JOIN units AS this_unit ON this_unit_authors.unit=this_unit.unit \
+         WHERE this_unit_authors.unit=? AND competitor_units.is_stable=1 \
+         AND +competitor_units.sequence='good' \
+         AND competitor_units.main_chain_index>this_unit.latest_included_mc_index \
+         AND competitor_units.main_chain_index<=this_unit.main_chain_index",
+    )?;
+    let competitors = stmt
+        .query_map(&[unit], |row| graph::UnitProps {
+            unit: row.get(0),
+            level: row.get(1),
+            latest_included_mc_index: row.get(2),
+            main_chain_index: row.get(3),
+            is_on_main_chain: row.get(4),
+            is_free: row.get(5),
+        })?
+        .collect::<::std::result::Result<Vec<_>, _>>()?;
+
+    let mut conflicting_units = Vec::new();
+    for competitor_props in competitors {
+        if graph::compare_unit_props(db, &competitor_props, &unit_props)?.is_none() {
+            conflicting_units.push(competitor_props.unit);
+        }
+    }
+    Ok(conflicting_units)
+}
+
+fn set_content_hash(db: &Connection, unit: &String) -> Result<()> {
+    let joint = storage::read_joint_directly(db, unit)?;
+    let content_hash = joint.unit.get_unit_content_hash();
+    let mut stmt = db.prepare_cached("UPDATE units SET content_hash=? WHERE unit=?")?;
+    stmt.execute(&[&content_hash, unit])?;
+    Ok(())
+}
+
+// MCIs of the skiplist units, e.g. mci 1200 links to 1190 and 1100
+fn get_similar_mcis(mci: u32) -> Vec<u32> {
+    let mut similar_mcis = Vec::new();
+    if mci == 0 {
+        return similar_mcis;
+    }
+
+    let mut divisor: u32 = 10;
+    while mci % divisor == 0 {
+        similar_mcis.push(mci - divisor);
+        divisor = match divisor.checked_mul(10) {
+            Some(d) => d,
+            None => break,
+        };
+    }
+    similar_mcis
+}
+
+fn add_balls(db: &Connection, mci: u32) -> Result<()> {
+    struct UnitBallProps {
+        unit: String,
+        ball: Option<String>,
+        is_on_main_chain: u32,
+        sequence: String,
+    }
+
+    let mut stmt = db.prepare_cached(
+        "SELECT units.unit, ball, is_on_main_chain, sequence \
+         FROM units LEFT JOIN balls USING(unit) \
+         WHERE main_chain_index=? ORDER BY level",
+    )?;
+    let rows = stmt.query_map(&[&mci], |row| UnitBallProps {
+        unit: row.get(0),
+        ball: row.get(1),
+        is_on_main_chain: row.get(2),
+        sequence: row.get(3),
+    })?;
+    let mut units = Vec::new();
+    for row in rows {
+        units.push(row?);
+    }
+    ensure!(!units.is_empty(), "no units on mci {}", mci);
+
+    for props in units {
+        let unit = &props.unit;
+
+        let mut stmt = db.prepare_cached(
+            "SELECT ball FROM parenthoods LEFT JOIN balls ON parent_unit=unit \
+             WHERE child_unit=? ORDER BY ball",
+        )?;
+        let rows = stmt.query_map(&[unit], |row| row.get::<_, Option<String>>(0))?;
+        let mut parent_balls = Vec::new();
+        for row in rows {
+            match row? {
+                Some(ball) => parent_balls.push(ball),
+                None => bail!("some parent balls not found for unit {}", unit),
+            }
+        }
+
+        let mut skiplist_units = Vec::new();
+        let mut skiplist_balls = Vec::new();
+        let similar_mcis = get_similar_mcis(mci);
+        if props.is_on_main_chain == 1 && !similar_mcis.is_empty() {
+            let similar_mci_list = similar_mcis
+                .iter()
+                .map(|mci| mci.to_string())
+                .collect::<Vec<_>>()
+                .join(", ");
+            let sql = format!(
+                "SELECT units.unit, ball FROM units LEFT JOIN balls USING(unit) \
+                 WHERE is_on_main_chain=1 AND main_chain_index IN({})",
+                similar_mci_list
+            );
+            let mut stmt = db.prepare(&sql)?;
+            let rows = stmt.query_map(&[], |row| {
+                (row.get::<_, String>(0), row.get::<_, Option<String>>(1))
+            })?;
+            for row in rows {
+                let (skiplist_unit, skiplist_ball) = row?;
+                match skiplist_ball {
+                    Some(ball) => skiplist_balls.push(ball),
+                    None => bail!("no skiplist ball"),
+                }
+                skiplist_units.push(skiplist_unit);
+            }
+        }
+        skiplist_balls.sort();
+
+        let ball = object_hash::get_ball_hash(
+            unit,
+            &parent_balls,
+            &skiplist_balls,
+            props.sequence == "final-bad",
+        );
+
+        // already inserted, e.g. received from a peer during catchup
+        if let Some(ref stored_ball) = props.ball {
+            ensure!(
+                stored_ball == &ball,
+                "stored and calculated ball hashes do not match, ball={}, unit={}",
+                ball,
+                unit
+            );
+            continue;
+        }
+
+        let mut stmt = db.prepare_cached("INSERT INTO balls (ball, unit) VALUES(?,?)")?;
+        stmt.insert(&[&ball, unit])?;
+
+        let mut stmt = db.prepare_cached("DELETE FROM hash_tree_balls WHERE ball=?")?;
+        stmt.execute(&[&ball])?;
+
+        let mut stmt =
+            db.prepare_cached("INSERT INTO skiplist_units (unit, skiplist_unit) VALUES (?, ?)")?;
+        for skiplist_unit in skiplist_units.iter() {
+            stmt.insert(&[unit, skiplist_unit])?;
+        }
+    }
+
+    Ok(())
+}
+
+fn calc_commissions(db: &Connection) -> Result<()> {
+    header_commissions::calc_headers_commissions(db)?;
+    paid_witnessing::update_paid_witnesses(db)
+}
+
+/// rebuild the main chain after a new unit is added and advance the stability point,
+/// must be called within the same transaction that saved `last_added_unit`,
+/// the caller must refresh the unstable units after commit
+pub fn update_main_chain(db: &Connection, last_added_unit: &String) -> Result<()> {
+    info!("will update MC after adding {}", last_added_unit);
+    storage::forget_unstable_units();
+    go_up_from_free_units(db, last_added_unit)?;
+    update_stable_mc_flag(db)?;
+    info!("done updating MC");
+    Ok(())
+}
+
+#[test]
+fn test_similar_mcis() {
+    assert_eq!(get_similar_mcis(0), Vec::<u32>::new());
+    assert_eq!(get_similar_mcis(15), Vec::<u32>::new());
+    assert_eq!(get_similar_mcis(20), vec![10]);
+    assert_eq!(get_similar_mcis(1200), vec![1190, 1100]);
+    assert_eq!(get_similar_mcis(3000), vec![2990, 2900, 2000]);
+}
+
+#[cfg(test)]
+fn read_unit_props(db: &Connection, unit: &str) -> (Option<u32>, u32, Option<u32>, u32) {
+    let mut stmt = db
+        .prepare(
+            "SELECT main_chain_index, is_on_main_chain, latest_included_mc_index, is_stable \
+             FROM units WHERE unit=?",
+        )
+        .unwrap();
+    stmt.query_row(&[&unit], |row| {
+        (row.get(0), row.get(1), row.get(2), row.get(3))
+    })
+    .unwrap()
+}
+
+#[test]
+fn test_update_main_chain_on_serial_units() {
+    let mut dag = ::test_utils::TestDag::new("main_chain serial");
+    let units = dag.add_units(30);
+
+    let last_stable_mci = storage::read_last_stable_mc_index(&dag.db).unwrap();
+    assert!(last_stable_mci > 0 && last_stable_mci < 30);
+    for (i, unit) in units.iter().enumerate() {
+        let mci = i as u32 + 1;
+        let is_stable = if mci <= last_stable_mci { 1 } else { 0 };
+        assert_eq!(
+            read_unit_props(&dag.db, unit),
+            (Some(mci), 1, Some(mci - 1), is_stable)
+        );
+    }
+}
+
+#[test]
+fn test_update_main_chain_on_forks() {
+    let mut dag = ::test_utils::TestDag::new("main_chain forks");
+    let base = dag.add_units(3).pop().unwrap();
+    let base_mci = read_unit_props(&dag.db, &base).0.unwrap();
+
+    // two branches of 3 units, one of them is left off the MC
+    let mut branches = Vec::new();
+    for first_witness in &[3, 6] {
+        let mut parent = base.clone();
+        let mut branch = Vec::new();
+        for i in 0..3 {
+            parent = dag.add_unit_on(first_witness + i, vec![parent]);
+            branch.push(parent.clone());
+        }
+        branches.push(branch);
+    }
+    let merge = dag.add_unit(9);
+    let (merge_mci, is_on_main_chain, _, _) = read_unit_props(&dag.db, &merge);
+    assert_eq!(is_on_main_chain, 1);
+    assert_eq!(merge_mci, Some(base_mci + 4));
+
+    let on_mc = branches
+        .iter()
+        .filter(|branch| read_unit_props(&dag.db, &branch[0]).1 == 1)
+        .collect::<Vec<_>>();
+    let off_mc = branches
+        .iter()
+        .filter(|branch| read_unit_props(&dag.db, &branch[0]).1 == 0)
+        .collect::<Vec<_>>();
+    assert_eq!((on_mc.len(), off_mc.len()), (1, 1));
+
+    for (i, unit) in on_mc[0].iter().enumerate() {
+        let mci = base_mci + i as u32 + 1;
+        assert_eq!(read_unit_props(&dag.db, unit).0, Some(mci));
+    }
+    // the off MC branch is first included by the merge unit, the limci is pushed down the branch
+    for unit in off_mc[0] {
+        let (mci, is_on_main_chain, limci, _) = read_unit_props(&dag.db, unit);
+        assert_eq!(
+            (mci, is_on_main_chain, limci),
+            (merge_mci, 0, Some(base_mci))
+        );
+    }
+    assert_eq!(read_unit_props(&dag.db, &merge).2, Some(base_mci + 3));
+}
+
+#[test]
+fn test_mark_stable_up_to_unit() {
+    let mut dag = ::test_utils::TestDag::new("main_chain mark stable");
+    let units = dag.add_units(30);
+    let last_stable_mci = storage::read_last_stable_mc_index(&dag.db).unwrap();
+    assert!(last_stable_mci < 25);
+
+    let unit = &units[24];
+    {
+        let tx = dag.db.transaction().unwrap();
+        mark_stable_up_to_unit(&tx, unit).unwrap();
+        tx.commit().unwrap();
+    }
+    assert_eq!(storage::read_last_stable_mc_index(&dag.db).unwrap(), 25);
+    for (i, unit) in units.iter().enumerate() {
+        let is_stable = if i < 25 { 1 } else { 0 };
+        assert_eq!(read_unit_props(&dag.db, unit).3, is_stable);
+    }
+    check_balls(&dag.db);
+
+    // already stable
+    let tx = dag.db.transaction().unwrap();
+    mark_stable_up_to_unit(&tx, &units[10]).unwrap();
+    assert_eq!(storage::read_last_stable_mc_index(&tx).unwrap(), 25);
+}
+
+// every stable unit has a ball made of its parent balls and the skiplist balls
+#[cfg(test)]
+fn check_balls(db: &Connection) {
+    let mut stmt = db
+        .prepare(
+            "SELECT unit, ball, main_chain_index, is_on_main_chain \
+             FROM units LEFT JOIN balls USING(unit) WHERE is_stable=1",
+        )
+        .unwrap();
+    let rows = stmt
+        .query_map(&[], |row| {
+            (
+                row.get::<_, String>(0),
+                row.get::<_, Option<String>>(1),
+                row.get::<_, u32>(2),
+                row.get::<_, u32>(3),
+            )
+        })
+        .unwrap()
+        .collect::<::std::result::Result<Vec<_>, _>>()
+        .unwrap();
+    assert!(!rows.is_empty());
+
+    for (unit, ball, mci, is_on_main_chain) in rows {
+        let mut stmt = db
+            .prepare(
+                "SELECT ball FROM parenthoods JOIN balls ON parent_unit=unit \
+                 WHERE child_unit=? ORDER BY ball",
+            )
+            .unwrap();
+        let parent_balls = stmt
+            .query_map(&[&unit], |row| row.get(0))
+            .unwrap()
+            .collect::<::std::result::Result<Vec<String>, _>>()
+            .unwrap();
+
+        let mut skiplist_units = Vec::new();
+        let mut skiplist_balls = Vec::new();
+        if is_on_main_chain == 1 {
+            for similar_mci in get_similar_mcis(mci) {
+                let mut stmt = db
+                    .prepare(
+                        "SELECT unit, ball FROM units JOIN balls USING(unit) \
+                         WHERE is_on_main_chain=1 AND main_chain_index=?",
+                    )
+                    .unwrap();
+                let (skiplist_unit, skiplist_ball) = stmt
+                    .query_row(&[&similar_mci], |row| {
+                        (row.get::<_, String>(0), row.get::<_, String>(1))
+                    })
+                    .unwrap();
+                skiplist_units.push(skiplist_unit);
+                skiplist_balls.push(skiplist_ball);
+            }
+        }
+        skiplist_units.sort();
+        skiplist_balls.sort();
+
+        let expected = object_hash::get_ball_hash(&unit, &parent_balls, &skiplist_balls, false);
+        assert_eq!(ball, Some(expected), "ball of unit {}", unit);
+
+        let mut stmt = db
+            .prepare("SELECT skiplist_unit FROM skiplist_units WHERE unit=? ORDER BY skiplist_unit")
+            .unwrap();
+        let stored_skiplist_units = stmt
+            .query_map(&[&unit], |row| row.get(0))
+            .unwrap()
+            .collect::<::std::result::Result<Vec<String>, _>>()
+            .unwrap();
+        assert_eq!(stored_skiplist_units, skiplist_units);
+    }
+}
+
+#[test]
+fn test_add_balls_and_skiplist() {
+    let mut dag = ::test_utils::TestDag::new("main_chain balls");
+    let units = dag.add_units(40);
+    assert!(storage::read_last_stable_mc_index(&dag.db).unwrap() >= 20);
+    check_balls(&dag.db);
+
+    // mci 20 skips to mci 10
+    let mut stmt = dag
+        .db
+        .prepare("SELECT skiplist_unit FROM skiplist_units WHERE unit=?")
+        .unwrap();
+    let skiplist_units = stmt
+        .query_map(&[&units[19]], |row| row.get(0))
+        .unwrap()
+        .collect::<::std::result::Result<Vec<String>, _>>()
+        .unwrap();
+    assert_eq!(skiplist_units, vec![units[9].clone()]);
+}
@@ -211,6 +211,7 @@ mod tests {
 
     #[test]
     fn test_map_lock() {
+        ::test_utils::init_coroutines();
         let lock = Arc::new(MapLock::new());
         let g = lock.lock(vec!["test"]);
         let g1 = lock.try_lock(vec!["test", "test1"]);
@@ -222,7 +223,6 @@ mod tests {
         let lock_1 = lock.clone();
         let j = go!(move || {
             let _g = lock_1.lock(vec!["test"]);
-            println!("comeback in coroutine");
         });
 
         drop(g2);
@@ -245,19 +245,18 @@ mod tests {
 
     #[test]
     fn test_map_unlock() {
+        ::test_utils::init_coroutines();
         let lock = Arc::new(MapLock::new());
         let g = lock.lock(vec!["test1", "test2"]);
 
         let lock_1 = lock.clone();
         let j1 = go!(move || {
             let _g = lock_1.lock(vec!["test1"]);
-            println!("comeback in coroutine1");
         });
 
         let lock_2 = lock.clone();
         let j2 = go!(move || {
             let _g = lock_2.lock(vec!["test2"]);
-            println!("comeback in coroutine2");
         });
 
         drop(g); // this will release both coroutine
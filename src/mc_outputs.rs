@@ -15,7 +15,7 @@ pub fn read_next_spendable_mc_index(
             .join(", ");
         format!(
             "SELECT to_main_chain_index FROM inputs CROSS JOIN units USING(unit) \
-             WHERE type={} AND address={} AND sequence='good' \
+             WHERE type='{}' AND address='{}' AND sequence='good' \
              AND unit NOT IN({}) \
              ORDER BY to_main_chain_index DESC LIMIT 1",
             kind, address, conflict_units_list
@@ -23,7 +23,7 @@ pub fn read_next_spendable_mc_index(
     } else {
         format!(
             "SELECT to_main_chain_index FROM inputs CROSS JOIN units USING(unit) \
-             WHERE type={} AND address={} AND sequence='good' \
+             WHERE type='{}' AND address='{}' AND sequence='good' \
              ORDER BY to_main_chain_index DESC LIMIT 1",
             kind, address
         )
@@ -156,8 +156,8 @@ pub fn calc_earnings(
     let mut stmt = db.prepare_cached(&sql)?;
     let total = stmt.query_row(
         &[&from_main_chain_index, &to_main_chain_index, address],
-        |row| row.get::<_, u32>(0),
-    ).unwrap_or(0);
+        |row| row.get::<_, Option<u32>>(0),
+    )?;
 
-    Ok(total)
+    Ok(total.unwrap_or(0))
 }
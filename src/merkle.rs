@@ -0,0 +1,124 @@
+use base64;
+use error::Result;
+use sha2::{Digest, Sha256};
+
+#[derive(Debug)]
+pub struct MerkleProof {
+    pub root: String,
+    pub siblings: Vec<String>,
+    pub index: usize,
+}
+
+fn hash(s: &str) -> String {
+    base64::encode(&Sha256::digest(s.as_bytes()))
+}
+
+pub fn get_merkle_root(elements: &[String]) -> String {
+    let mut hashes = elements.iter().map(|e| hash(e)).collect::<Vec<_>>();
+    while hashes.len() > 1 {
+        // hashes of hashes
+        let mut overlying_hashes = Vec::new();
+        for i in 0..(hashes.len() + 1) / 2 {
+            // for odd number of hashes
+            let hash2_index = if i * 2 + 1 < hashes.len() {
+                i * 2 + 1
+            } else {
+                i * 2
+            };
+            overlying_hashes.push(hash(&format!("{}{}", hashes[i * 2], hashes[hash2_index])));
+        }
+        hashes = overlying_hashes;
+    }
+    hashes.pop().unwrap_or_default()
+}
+
+pub fn get_merkle_proof(elements: &[String], element_index: usize) -> Result<MerkleProof> {
+    ensure!(element_index < elements.len(), "invalid index");
+
+    let mut hashes = elements.iter().map(|e| hash(e)).collect::<Vec<_>>();
+    let mut index = element_index;
+    let mut siblings = Vec::new();
+    while hashes.len() > 1 {
+        // hashes of hashes
+        let mut overlying_hashes = Vec::new();
+        let overlying_index = index / 2;
+        for i in 0..(hashes.len() + 1) / 2 {
+            // for odd number of hashes
+            let hash2_index = if i * 2 + 1 < hashes.len() {
+                i * 2 + 1
+            } else {
+                i * 2
+            };
+            if i == overlying_index {
+                let sibling_index = if index == i * 2 { hash2_index } else { i * 2 };
+                siblings.push(hashes[sibling_index].clone());
+            }
+            overlying_hashes.push(hash(&format!("{}{}", hashes[i * 2], hashes[hash2_index])));
+        }
+        hashes = overlying_hashes;
+        index = overlying_index;
+    }
+
+    Ok(MerkleProof {
+        root: hashes.pop().unwrap_or_default(),
+        siblings,
+        index: element_index,
+    })
+}
+
+/// serialized as "index-sibling1-sibling2-...-root"
+pub fn serialize_merkle_proof(proof: &MerkleProof) -> String {
+    let mut parts = vec![proof.index.to_string()];
+    parts.extend(proof.siblings.iter().cloned());
+    parts.push(proof.root.clone());
+    parts.join("-")
+}
+
+pub fn deserialize_merkle_proof(serialized_proof: &str) -> Result<MerkleProof> {
+    let mut parts = serialized_proof
+        .split('-')
+        .map(|s| s.to_owned())
+        .collect::<Vec<_>>();
+    ensure!(parts.len() >= 2, "bad merkle proof {}", serialized_proof);
+
+    let root = parts.pop().unwrap();
+    let index = parts.remove(0).parse::<usize>()?;
+    Ok(MerkleProof {
+        root,
+        siblings: parts,
+        index,
+    })
+}
+
+pub fn verify_merkle_proof(element: &str, proof: &MerkleProof) -> bool {
+    let mut index = proof.index;
+    let mut the_other_sibling = hash(element);
+    for sibling in &proof.siblings {
+        // this also works for duplicated trailing nodes
+        the_other_sibling = if index % 2 == 0 {
+            hash(&format!("{}{}", the_other_sibling, sibling))
+        } else {
+            hash(&format!("{}{}", sibling, the_other_sibling))
+        };
+        index /= 2;
+    }
+    the_other_sibling == proof.root
+}
+
+#[test]
+fn test_merkle_proof() {
+    let elements = ["a", "b", "c", "d", "e"]
+        .iter()
+        .map(|s| s.to_string())
+        .collect::<Vec<_>>();
+    let root = get_merkle_root(&elements);
+
+    for (i, element) in elements.iter().enumerate() {
+        let proof = get_merkle_proof(&elements, i).unwrap();
+        assert_eq!(proof.root, root);
+
+        let proof = deserialize_merkle_proof(&serialize_merkle_proof(&proof)).unwrap();
+        assert!(verify_merkle_proof(element, &proof));
+        assert!(!verify_merkle_proof("f", &proof));
+    }
+}
@@ -0,0 +1,12 @@
+-- peers that we know, either configured or learnt from other peers by get_peers
+CREATE TABLE peers (
+	peer VARCHAR(100) NOT NULL PRIMARY KEY,
+	peer_host VARCHAR(100) NOT NULL,
+	learnt_from_peer_host VARCHAR(100) NULL,
+	is_self TINYINT NOT NULL DEFAULT 0,
+	count_successes INT NOT NULL DEFAULT 0,
+	count_failures INT NOT NULL DEFAULT 0,
+	last_seen TIMESTAMP NULL,
+	creation_date TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+CREATE INDEX peersByLastSeen ON peers(last_seen);
@@ -0,0 +1,16 @@
+-- what the peers sent us, used to rate the peers
+CREATE TABLE peer_events (
+	peer_host VARCHAR(100) NOT NULL,
+	event_date TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+	event VARCHAR(20) NOT NULL CHECK (event IN ('new_good', 'know_good', 'know_bad', 'invalid'))
+);
+CREATE INDEX peerEventsByHostDate ON peer_events(peer_host, event_date);
+CREATE INDEX peerEventsByDate ON peer_events(event_date);
+
+-- peers that sent too many bad joints, no connections until expiry_date
+CREATE TABLE banned_peers (
+	peer_host VARCHAR(100) NOT NULL PRIMARY KEY,
+	reason VARCHAR(200) NOT NULL,
+	ban_date TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+	expiry_date TIMESTAMP NOT NULL
+);
@@ -0,0 +1,2 @@
+-- the witness proof looks up the definitions of the witnesses by this index
+CREATE INDEX unitAuthorsIndexByAddressDefinitionChash ON unit_authors(address, definition_chash);
@@ -0,0 +1,28 @@
+-- polls and votes are inline and kept in their own tables like data feeds
+CREATE TABLE polls (
+	unit CHAR(44) NOT NULL PRIMARY KEY,
+	message_index TINYINT NOT NULL,
+	question VARCHAR(4096) NOT NULL,
+	FOREIGN KEY (unit) REFERENCES units(unit)
+);
+
+CREATE TABLE poll_choices (
+	unit CHAR(44) NOT NULL,
+	choice_index TINYINT NOT NULL,
+	choice VARCHAR(64) NOT NULL,
+	PRIMARY KEY (unit, choice_index),
+	UNIQUE (unit, choice),
+	FOREIGN KEY (unit) REFERENCES polls(unit)
+);
+
+CREATE TABLE votes (
+	unit CHAR(44) NOT NULL,
+	message_index TINYINT NOT NULL,
+	poll_unit CHAR(44) NOT NULL,
+	choice VARCHAR(64) NOT NULL,
+	PRIMARY KEY (unit, message_index),
+	UNIQUE (unit, choice),
+	FOREIGN KEY (unit) REFERENCES units(unit),
+	CONSTRAINT votesByChoice FOREIGN KEY (poll_unit, choice) REFERENCES poll_choices(unit, choice)
+);
+CREATE INDEX votesIndexByPollUnitChoice ON votes(poll_unit, choice);
@@ -9,15 +9,21 @@ lazy_static! {
 fn read_my_witnesses() -> Result<Vec<String>> {
     // read from database
     let db = db::DB_POOL.get_connection();
-    let witnesses = db.get_my_witnesses()?;
+    let mut witnesses = db.get_my_witnesses()?;
 
-    // if the data base is empty we should wait until
-    if witnesses.len() == 0 {
-        // TODO: block until data available
-    } else {
-        assert_eq!(witnesses.len(), config::COUNT_WITNESSES);
+    // on first start the witnesses are seeded from config
+    if witnesses.is_empty() {
+        witnesses = config::get_settings().initial_witnesses;
+        witnesses.sort();
+        db.insert_witnesses(&witnesses)?;
+        info!("initialized my witnesses from config");
     }
 
+    ensure!(
+        witnesses.len() == config::COUNT_WITNESSES,
+        "wrong number of my witnesses: {}",
+        witnesses.len()
+    );
     Ok(witnesses)
 }
 
@@ -27,6 +33,7 @@ mod tests {
 
     #[test]
     fn get_witnesses() {
+        ::test_utils::init_coroutines();
         assert_eq!(MY_WITNESSES.len(), config::COUNT_WITNESSES);
     }
 }
@@ -1,9 +1,12 @@
-use std::net::ToSocketAddrs;
-use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering, ATOMIC_BOOL_INIT};
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use super::network::{Sender, Server, WsConnection};
+use super::peer_manager;
+use super::reputation::{self, PeerEvent};
+use super::tls::{self, WsStream};
+use catchup;
 use config;
 use db;
 use error::Result;
@@ -26,24 +29,40 @@ pub struct HubData {
     // indicate if this connection is a subscribed peer
     is_subscribed: AtomicBool,
     is_source: AtomicBool,
+    // set when the peer turns out to be ourselves
+    is_self: AtomicBool,
 }
 
 pub type HubConn = WsConnection<HubData>;
 
 // global data that record the internal state
+// set when the node is syncing history from a peer
+static IS_CATCHING_UP: AtomicBool = ATOMIC_BOOL_INIT;
+
+// the error of subscribe when the subscription id is our own
+const SELF_CONNECT_ERROR: &str = "self-connect";
+
 lazy_static! {
     // global Ws connections
     pub static ref WSS: WsConnections = WsConnections::new();
     // maybe this is too heavy, could use an optimized hashset<AtomicBool>
     static ref UNIT_IN_WORK: MapLock<String> = MapLock::new();
+    // units that are being requested from peers
+    static ref JOINT_IN_REQ: MapLock<String> = MapLock::new();
+    // sent in every subscribe, receiving it back means we connected to ourselves
+    static ref MY_SUBSCRIPTION_ID: String = ::object_hash::gen_random_string(30);
 }
 
 fn init_connection(ws: &Arc<HubConn>) {
     use rand::{thread_rng, Rng};
 
     t!(ws.send_version());
-    t!(ws.send_subscribe());
-    t!(ws.send_hub_challenge());
+    if let Err(e) = ws.send_subscribe() {
+        error!("subscribe to {} failed, err = {}", ws.get_peer(), e);
+        if ws.is_self_connection() {
+            return;
+        }
+    }
 
     let mut rng = thread_rng();
     let n: u64 = rng.gen_range(0, 1000);
@@ -66,6 +85,15 @@ fn init_connection(ws: &Arc<HubConn>) {
     });
 }
 
+// catch up from the peer once connected, the catchup chain tells if we are behind,
+// and an interrupted catchup continues from where it stopped
+fn start_catchup(ws: Arc<HubConn>) {
+    if IS_CATCHING_UP.load(Ordering::Relaxed) {
+        return;
+    }
+    go!(move || t!(ws.request_catchup()));
+}
+
 // global request has no specific ws connections, just find a proper one should be fine
 pub struct WsConnections {
     inbound: RwLock<Vec<Arc<HubConn>>>,
@@ -85,15 +113,44 @@ impl WsConnections {
     }
 
     pub fn add_inbound(&self, inbound: Arc<HubConn>) {
+        let host = reputation::get_peer_host(inbound.get_peer());
+        let is_banned = {
+            let db = db::DB_POOL.get_connection();
+            reputation::is_banned(&db, &host)
+        };
+        match is_banned {
+            Ok(true) => {
+                // the connection is closed when dropped
+                warn!("reject banned peer {}", inbound.get_peer());
+                return;
+            }
+            Ok(false) => {}
+            Err(e) => error!("failed to check ban of {}, err = {}", host, e),
+        }
+
         init_connection(&inbound);
+        // the connection to ourselves is closed when dropped
+        if inbound.is_self_connection() {
+            return;
+        }
+        // only hubs accept logins from light clients
+        if config::get_settings().is_hub() {
+            t!(inbound.send_hub_challenge());
+        }
         let mut g = self.inbound.write().unwrap();
         g.push(inbound);
     }
 
     pub fn add_outbound(&self, outbound: Arc<HubConn>) {
         init_connection(&outbound);
-        let mut g = self.outbound.write().unwrap();
-        g.push(outbound);
+        if outbound.is_self_connection() {
+            return;
+        }
+        {
+            let mut g = self.outbound.write().unwrap();
+            g.push(outbound.clone());
+        }
+        start_catchup(outbound);
     }
 
     pub fn close_all(&self) {
@@ -122,6 +179,89 @@ impl WsConnections {
         }
     }
 
+    // send the joint to all the subscribed peers except the one that sent it to us
+    pub fn forward_joint(&self, cur_ws: Option<&HubConn>, joint: &Joint) -> Result<()> {
+        let mut peers = Vec::new();
+        {
+            let g = self.outbound.read().unwrap();
+            peers.extend(g.iter().cloned());
+            let g = self.inbound.read().unwrap();
+            peers.extend(g.iter().cloned());
+        }
+
+        for peer in peers {
+            let is_sender = match cur_ws {
+                Some(ws) => peer.conn_eq(ws),
+                None => false,
+            };
+            if peer.is_subscribed() && !is_sender {
+                t!(peer.send_joint(joint));
+            }
+        }
+        Ok(())
+    }
+
+    // close all the connections from the host
+    pub fn close_by_host(&self, host: &str) {
+        let is_other = |c: &Arc<HubConn>| reputation::get_peer_host(c.get_peer()) != host;
+        let mut g = self.outbound.write().unwrap();
+        g.retain(&is_other);
+        let mut g = self.inbound.write().unwrap();
+        g.retain(&is_other);
+    }
+
+    pub fn contains_outbound(&self, conn: &HubConn) -> bool {
+        let g = self.outbound.read().unwrap();
+        g.iter().any(|c| c.conn_eq(conn))
+    }
+
+    pub fn get_outbound_count(&self) -> usize {
+        let g = self.outbound.read().unwrap();
+        g.len()
+    }
+
+    pub fn get_connection_by_name(&self, peer: &str) -> Option<Arc<HubConn>> {
+        let g = self.outbound.read().unwrap();
+        for conn in g.iter() {
+            if conn.get_peer() == peer {
+                return Some(conn.clone());
+            }
+        }
+
+        let g = self.inbound.read().unwrap();
+        for conn in g.iter() {
+            if conn.get_peer() == peer {
+                return Some(conn.clone());
+            }
+        }
+        None
+    }
+
+    // select a connection other than the given one, outbound is preferred
+    pub fn get_next_peer_except(&self, conn: &HubConn) -> Option<Arc<HubConn>> {
+        let mut peers = {
+            let g = self.outbound.read().unwrap();
+            g.iter()
+                .filter(|c| !c.conn_eq(conn))
+                .cloned()
+                .collect::<Vec<_>>()
+        };
+        if peers.is_empty() {
+            let g = self.inbound.read().unwrap();
+            peers = g
+                .iter()
+                .filter(|c| !c.conn_eq(conn))
+                .cloned()
+                .collect::<Vec<_>>();
+        }
+        if peers.is_empty() {
+            return None;
+        }
+
+        let idx = self.next_outbound.fetch_add(1, Ordering::Relaxed) % peers.len();
+        Some(peers.swap_remove(idx))
+    }
+
     pub fn get_next_inbound(&self) -> Arc<HubConn> {
         let g = self.inbound.read().unwrap();
         let len = g.len();
@@ -144,6 +284,7 @@ impl Server<HubData> for HubData {
         HubData {
             is_subscribed: AtomicBool::new(false),
             is_source: AtomicBool::new(false),
+            is_self: AtomicBool::new(false),
         }
     }
 
@@ -165,6 +306,10 @@ impl Server<HubData> for HubData {
         let response = match command.as_str() {
             "heartbeat" => ws.on_heartbeat(params)?,
             "subscribe" => ws.on_subscribe(params)?,
+            "get_joint" => ws.on_get_joint(params)?,
+            "catchup" => ws.on_catchup(params)?,
+            "get_hash_tree" => ws.on_get_hash_tree(params)?,
+            "get_peers" => ws.on_get_peers(params)?,
             command => bail!("on_request unkown command: {}", command),
         };
         Ok(response)
@@ -192,6 +337,16 @@ impl HubConn {
         let data = self.get_data();
         data.is_source.store(true, Ordering::Relaxed);
     }
+
+    pub fn is_self_connection(&self) -> bool {
+        let data = self.get_data();
+        data.is_self.load(Ordering::Relaxed)
+    }
+
+    fn set_self_connection(&self) {
+        let data = self.get_data();
+        data.is_self.store(true, Ordering::Relaxed);
+    }
 }
 
 // the server side impl
@@ -216,15 +371,60 @@ impl HubConn {
     }
 
     fn on_subscribe(&self, param: Value) -> Result<Value> {
-        // TODO: is it necessary to detect the self connection? (#63)
-        let _subscription_id = param["subscription_id"]
+        let subscription_id = param["subscription_id"]
             .as_str()
             .ok_or(format_err!("no subscription_id"))?;
+        // the connection is not closed here or the peer would miss the error,
+        // it's dropped once the response is sent
+        if subscription_id == MY_SUBSCRIPTION_ID.as_str() {
+            self.set_self_connection();
+            bail!(SELF_CONNECT_ERROR);
+        }
 
         self.set_subscribed();
         Ok(json!("subscribed"))
     }
 
+    fn on_get_joint(&self, param: Value) -> Result<Value> {
+        let unit: String = serde_json::from_value(param)?;
+        let db = db::DB_POOL.get_connection();
+
+        let mut stmt = db.prepare_cached("SELECT 1 FROM units WHERE unit=?")?;
+        if !stmt.exists(&[&unit])? {
+            return Ok(json!({ "joint_not_found": unit }));
+        }
+
+        let joint = storage::read_joint_with_ball(&db, &unit)?;
+        Ok(json!({ "joint": joint }))
+    }
+
+    fn on_catchup(&self, param: Value) -> Result<Value> {
+        ensure!(
+            self.is_subscribed(),
+            "not subscribed, will not serve catchup"
+        );
+        let catchup_req: catchup::CatchupReq = serde_json::from_value(param)?;
+        let db = db::DB_POOL.get_connection();
+        let catchup_chain = catchup::prepare_catchup_chain(&db, catchup_req)?;
+        Ok(serde_json::to_value(catchup_chain)?)
+    }
+
+    fn on_get_hash_tree(&self, param: Value) -> Result<Value> {
+        ensure!(
+            self.is_subscribed(),
+            "not subscribed, will not serve get_hash_tree"
+        );
+        let hash_tree_req: catchup::HashTreeReq = serde_json::from_value(param)?;
+        let db = db::DB_POOL.get_connection();
+        let balls = catchup::read_hash_tree(&db, hash_tree_req)?;
+        Ok(json!({ "balls": balls }))
+    }
+
+    fn on_get_peers(&self, _: Value) -> Result<Value> {
+        let db = db::DB_POOL.get_connection();
+        Ok(json!(peer_manager::read_good_peers(&db)?))
+    }
+
     fn on_hub_challenge(&self, param: Value) -> Result<()> {
         // this is hub, we do nothing here
         // only wallet would save the challenge and save the challenge
@@ -254,173 +454,476 @@ impl HubConn {
 impl HubConn {
     fn handle_online_joint(&self, mut joint: Joint, db: &mut Connection) -> Result<()> {
         use joint_storage::CheckNewResult;
-        use validation::{ValidationError, ValidationOk};
 
         // clear the main chain index
         joint.unit.main_chain_index = None;
-        let unit = joint.unit.unit.as_ref().unwrap();
-        // check if unit is in work, when g is dropped unlock the unit
-        let g = UNIT_IN_WORK.try_lock(vec![unit.to_owned()]);
-        if g.is_none() {
-            // the unit is in work, do nothing
+        let unit = joint.get_unit_hash().clone();
+        {
+            // check if unit is in work, when g is dropped unlock the unit
+            let g = UNIT_IN_WORK.try_lock(vec![unit.clone()]);
+            if g.is_none() {
+                // the unit is in work, do nothing
+                return Ok(());
+            }
+
+            match joint_storage::check_new_joint(db, &joint)? {
+                CheckNewResult::New => {
+                    // do nothing here, proceed to valide
+                }
+                CheckNewResult::Known => {
+                    if joint.unsigned == Some(true) {
+                        bail!("known unsigned");
+                    }
+                    self.send_result(json!({"unit": unit, "result": "known"}))?;
+                    return self.write_event(db, PeerEvent::KnownGood);
+                }
+                CheckNewResult::KnownBad => {
+                    self.send_result(json!({"unit": unit, "result": "known_bad"}))?;
+                    return self.write_event(db, PeerEvent::KnownBad);
+                }
+                CheckNewResult::KnownUnverified => {
+                    return self.send_result(json!({"unit": unit, "result": "known_unverified"}));
+                }
+            }
+
+            if !validate_and_save_joint(Some(self), self.get_peer(), &joint, db)? {
+                return Ok(());
+            }
+        }
+
+        // wake up other joints that depend on me
+        find_and_handle_joints_that_are_ready(&unit, db)
+    }
+
+    fn write_event(&self, db: &Connection, event: PeerEvent) -> Result<()> {
+        write_peer_event(db, self.get_peer(), event)
+    }
+
+    fn request_catchup(&self) -> Result<()> {
+        // only one catchup at a time
+        if IS_CATCHING_UP.swap(true, Ordering::SeqCst) {
             return Ok(());
         }
+        info!("will catchup from {}", self.get_peer());
 
-        match joint_storage::check_new_joint(db, &joint)? {
-            CheckNewResult::New => {
-                // do nothing here, proceed to valide
+        {
+            let db = db::DB_POOL.get_connection();
+            catchup::start_catchup_progress(&db)?;
+        }
+
+        // switch to another peer when the current one fails
+        let mut peer: Option<Arc<HubConn>> = None;
+        let mut tries = 0;
+        let ret = loop {
+            let ret = match peer {
+                Some(ref ws) => ws.catchup(),
+                None => self.catchup(),
+            };
+            let err = match ret {
+                Ok(_) => break Ok(()),
+                Err(e) => e,
+            };
+
+            tries += 1;
+            let next_peer = {
+                let cur_ws: &HubConn = match peer {
+                    Some(ref ws) => ws,
+                    None => self,
+                };
+                error!("catchup from {} failed: {}", cur_ws.get_peer(), err);
+                WSS.get_next_peer_except(cur_ws)
+            };
+            if tries >= config::MAX_CATCHUP_TRIES || next_peer.is_none() {
+                break Err(err);
             }
-            CheckNewResult::Known => {
-                if joint.unsigned == Some(true) {
-                    bail!("known unsigned");
-                }
-                self.send_result(json!({"unit": unit, "result": "known"}))?;
-                self.write_event("know_good")?;
+            peer = next_peer;
+            info!(
+                "switch catchup to peer {}",
+                peer.as_ref().unwrap().get_peer()
+            );
+        };
+
+        // come online
+        catchup::finish_catchup_progress();
+        IS_CATCHING_UP.store(false, Ordering::SeqCst);
+        if ret.is_ok() {
+            info!("catchup done");
+        }
+        ret
+    }
+
+    fn log_catchup_progress(&self) -> Result<()> {
+        let db = db::DB_POOL.get_connection();
+        if let Some(progress) = catchup::get_catchup_progress(&db)? {
+            info!("catchup progress from {}: {:?}", self.get_peer(), progress);
+        }
+        Ok(())
+    }
+
+    fn catchup(&self) -> Result<()> {
+        let db = db::DB_POOL.get_connection();
+        catchup::purge_handled_balls_from_hash_tree(&db)?;
+
+        // the units of last hash tree are still not received
+        let missing_units = read_missing_hash_tree_units(&db)?;
+        if !missing_units.is_empty() {
+            self.request_new_missing_joints(&missing_units)?;
+            self.wait_till_hash_tree_fully_processed()?;
+        }
+
+        let mut stmt = db.prepare_cached("SELECT 1 FROM catchup_chain_balls LIMIT 1")?;
+        if !stmt.exists(&[])? {
+            let witnesses = db.get_my_witnesses()?;
+            let catchup_req = catchup::CatchupReq {
+                last_stable_mci: storage::read_last_stable_mc_index(&db)?,
+                last_known_mci: storage::read_last_main_chain_index(&db)?,
+                witnesses: witnesses.clone(),
+            };
+            let catchup_chain: catchup::CatchupChain = serde_json::from_value(
+                self.send_request("catchup", serde_json::to_value(catchup_req)?)?,
+            )?;
+            catchup::set_catchup_target_mci(catchup_chain.get_last_mci());
+            if catchup::process_catchup_chain(&db, catchup_chain, &witnesses)? {
+                // already current
+                return Ok(());
             }
-            CheckNewResult::KnownBad => {
-                self.send_result(json!({"unit": unit, "result": "known_bad"}))?;
-                self.write_event("know_bad")?;
+        }
+
+        loop {
+            let mut stmt = db.prepare_cached(
+                "SELECT ball FROM catchup_chain_balls ORDER BY member_index LIMIT 2",
+            )?;
+            let chain_balls = stmt
+                .query_map(&[], |row| row.get::<_, String>(0))?
+                .collect::<::std::result::Result<Vec<_>, _>>()?;
+
+            if chain_balls.len() < 2 {
+                // the last chain ball is already stable
+                let mut stmt = db.prepare_cached("DELETE FROM catchup_chain_balls")?;
+                stmt.execute(&[])?;
+                return Ok(());
             }
 
-            CheckNewResult::KnownUnverified => {
-                self.send_result(json!({"unit": unit, "result": "known_unverified"}))?
+            let hash_tree_req = catchup::HashTreeReq {
+                from_ball: chain_balls[0].clone(),
+                to_ball: chain_balls[1].clone(),
+            };
+            let mut hash_tree =
+                self.send_request("get_hash_tree", serde_json::to_value(hash_tree_req)?)?;
+            let balls: Vec<catchup::BallProps> = serde_json::from_value(hash_tree["balls"].take())?;
+            let units = balls.iter().map(|b| b.unit.clone()).collect::<Vec<_>>();
+
+            if let Err(e) = catchup::process_hash_tree(balls) {
+                self.send_error(json!(format!("{}", e)))?;
+                return Err(e);
             }
+            self.request_new_missing_joints(&units)?;
+            self.wait_till_hash_tree_fully_processed()?;
+            self.log_catchup_progress()?;
         }
+    }
 
-        match validation::validate(db, &joint) {
-            Ok(ok) => match ok {
-                ValidationOk::Unsigned => {
-                    if joint.unsigned != Some(true) {
-                        bail!("ifOkUnsigned() signed");
-                    }
+    // wait until all the units of the hash tree are saved
+    fn wait_till_hash_tree_fully_processed(&self) -> Result<()> {
+        let db = db::DB_POOL.get_connection();
+        let mut count_missing = read_missing_hash_tree_units(&db)?.len();
+        let mut last_progress = Instant::now();
+        while count_missing > 0 {
+            coroutine::sleep(Duration::from_millis(100));
+            let missing_units = read_missing_hash_tree_units(&db)?;
+            if missing_units.len() < count_missing {
+                count_missing = missing_units.len();
+                last_progress = Instant::now();
+                continue;
+            }
+
+            let stalled_timeout = config::get_settings().stalled_timeout;
+            if last_progress.elapsed() > Duration::from_secs(stalled_timeout) {
+                bail!("catchup stalled, {} hash tree units missing", count_missing);
+            }
+        }
+        Ok(())
+    }
+
+    fn request_new_missing_joints(&self, units: &[String]) -> Result<()> {
+        use joint_storage::CheckNewResult;
+
+        let db = db::DB_POOL.get_connection();
+        let mut new_units = Vec::new();
+        for unit in units {
+            match joint_storage::check_new_unit(&db, unit)? {
+                CheckNewResult::New => new_units.push(unit.clone()),
+                _ => info!("unit {} is already known", unit),
+            }
+        }
+
+        let ws = match WSS.get_connection_by_name(self.get_peer()) {
+            Some(ws) => ws,
+            None => bail!("connection {} is closed", self.get_peer()),
+        };
+        for unit in new_units {
+            let ws = ws.clone();
+            go!(move || t!(request_joint(ws, unit)));
+        }
+        Ok(())
+    }
+
+    #[allow(dead_code)]
+    fn notify_watchers(&self, joint: &Joint) -> Result<()> {
+        let _ = joint;
+        unimplemented!()
+    }
+}
+
+// handle the joint that was waiting in the unhandled queue for its parents,
+// the peer that sent it may be gone, then nothing is sent back to it
+fn handle_saved_joint(
+    ws: Option<&HubConn>,
+    peer: &str,
+    joint: &Joint,
+    db: &mut Connection,
+) -> Result<bool> {
+    let unit = joint.get_unit_hash();
+    let g = UNIT_IN_WORK.try_lock(vec![unit.clone()]);
+    if g.is_none() {
+        return Ok(false);
+    }
+    let saved = validate_and_save_joint(ws, peer, joint, db)?;
+    joint_storage::remove_unhandled_joint(db, unit)?;
+    Ok(saved)
+}
+
+fn find_and_handle_joints_that_are_ready(unit: &str, db: &mut Connection) -> Result<()> {
+    let mut units = vec![unit.to_owned()];
+    while let Some(unit) = units.pop() {
+        for ready in joint_storage::read_dependent_joints_that_are_ready(db, &unit)? {
+            let conn = WSS.get_connection_by_name(&ready.peer);
+            let ws = conn.as_ref().map(|ws| ws.as_ref());
+            if ws.is_none() {
+                info!(
+                    "peer {} of ready unit {} is gone",
+                    ready.peer,
+                    ready.joint.get_unit_hash()
+                );
+            }
+            if handle_saved_joint(ws, &ready.peer, &ready.joint, db)? {
+                units.push(ready.joint.get_unit_hash().clone());
+            }
+        }
+    }
+    Ok(())
+}
+
+// return true if the joint is saved, the results are sent to the peer if it's still connected
+fn validate_and_save_joint(
+    ws: Option<&HubConn>,
+    peer: &str,
+    joint: &Joint,
+    db: &mut Connection,
+) -> Result<bool> {
+    use validation::{ValidationError, ValidationOk};
+
+    let unit = joint.get_unit_hash();
+    match validation::validate(db, joint) {
+        Ok(ok) => match ok {
+            ValidationOk::Unsigned => {
+                if joint.unsigned != Some(true) {
+                    bail!("ifOkUnsigned() signed");
                 }
-                ValidationOk::Signed(_, _) => {
-                    if joint.unsigned == Some(true) {
-                        bail!("ifOk() unsigned");
+            }
+            ValidationOk::Signed(state, _g) => {
+                if joint.unsigned == Some(true) {
+                    bail!("ifOk() unsigned");
+                }
+                joint.save(state)?;
+                if let Some(ws) = ws {
+                    ws.send_result(json!({"unit": unit, "result": "accepted"}))?;
+                }
+                write_peer_event(db, peer, PeerEvent::NewGood)?;
+                // forward to other peers
+                if !IS_CATCHING_UP.load(Ordering::Relaxed) {
+                    WSS.forward_joint(ws, joint)?;
+                }
+                return Ok(true);
+            }
+        },
+        Err(err) => {
+            let err: ValidationError = err.downcast()?;
+            match err {
+                ValidationError::UnitError { err } => {
+                    warn!("{} validation failed: {}", unit, err);
+                    if let Some(ws) = ws {
+                        ws.send_error_result(unit, &err)?;
+                    }
+                    purge_joint_and_dependencies_and_notify_peers(db, joint, &err)?;
+                    if !err.contains("authentifier verification failed")
+                        && !err.contains("bad merkle proof at path")
+                    {
+                        write_peer_event(db, peer, PeerEvent::Invalid)?;
                     }
-                    joint.save()?;
-                    // self.validation_unlock()?;
-                    // self.send_result(json!({"unit": unit, "result": "accepted"}))?;
-                    // // forward to other peers
-                    // if (!bCatchingUp) {
-                    //     self.forwardJoint(ws, objJoint)?;
-                    // }
-                    // drop(g);
-                    // // wake up other joints that depend on me
-                    // self.findAndHandleJointsThatAreReady(unit);
-                    unimplemented!();
-                    // TODO: forward to other peers
-                    // wake up other joints that depend on me
-                    // self.find_and_handle_joints_that_are_ready(unit)?;
                 }
-            },
-            Err(err) => {
-                let err: ValidationError = err.downcast()?;
-                match err {
-                    ValidationError::UnitError { err } => {
-                        warn!("{} validation failed: {}", unit, err);
-                        self.send_error_result(unit, &err)?;
-                        self.purge_joint_and_dependencies_and_notify_peers(&joint, &err)?;
-                        if !err.contains("authentifier verification failed")
-                            && !err.contains("bad merkle proof at path")
-                        {
-                            self.write_event("invalid")?;
-                        }
+                ValidationError::JointError { err } => {
+                    if let Some(ws) = ws {
+                        ws.send_error_result(unit, &err)?;
                     }
-                    ValidationError::JointError { err } => {
-                        self.send_error_result(unit, &err)?;
-                        self.write_event("invalid")?;
-                        // TODO: insert known_bad_jonts
-                        unimplemented!()
-                        // b.query(
-                        // 	"INSERT INTO known_bad_joints (joint, json, error) VALUES (?,?,?)",
-                        // 	[objectHash.getJointHash(objJoint), JSON.stringify(objJoint), error],
-                        // 	function(){
-                        // 		delete assocUnitsInWork[unit];
-                        // 	}
-                        // );
+                    write_peer_event(db, peer, PeerEvent::Invalid)?;
+                    joint_storage::save_known_bad_joint(db, joint, &err)?;
+                }
+                ValidationError::NeedHashTree => {
+                    info!("need hash tree for unit {}", unit);
+                    if joint.unsigned == Some(true) {
+                        bail!("need hash tree unsigned");
                     }
-                    ValidationError::NeedHashTree => {
-                        info!("need hash tree for unit {}", unit);
-                        if joint.unsigned == Some(true) {
-                            bail!("need hash tree unsigned");
+                    // we are not saving the joint so that it would be requested again
+                    // after the catchup is done
+                    if !IS_CATCHING_UP.load(Ordering::Relaxed) {
+                        let ws = WSS.get_connection_by_name(peer);
+                        if let Some(ws) = ws {
+                            go!(move || t!(ws.request_catchup()));
                         }
-                        unimplemented!()
-                        // if !bCatchingUp && !bWaitingForCatchupChain {
-                        //     self.request_catchup()?;
-                        // }
                     }
-                    ValidationError::NeedParentUnits(missing_units) => {
-                        let info = format!("unresolved dependencies: {}", missing_units.join(", "));
-                        self.send_info(json!({"unit": unit, "info": info}))?;
-                        unimplemented!()
-                        // TODO: need parent units
-                        // joint_storage::save_unhandled_joint_and_dependencies(
-                        //     &joint,
-                        //     &missing_units,
-                        //     self.get_peer(),
-                        // )?;
-                        // drop(g);
-                        // self.request_new_missing_joints(&missing_units)?
+                }
+                ValidationError::NeedParentUnits(missing_units) => {
+                    let info = format!("unresolved dependencies: {}", missing_units.join(", "));
+                    if let Some(ws) = ws {
+                        ws.send_info(json!({"unit": unit, "info": info}))?;
+                    }
+                    joint_storage::save_unhandled_joint_and_dependencies(
+                        db,
+                        joint,
+                        &missing_units,
+                        peer,
+                    )?;
+                    // the missing units are requested from the peer that sent the joint
+                    if let Some(ws) = ws {
+                        ws.request_new_missing_joints(&missing_units)?;
                     }
-                    ValidationError::TransientError { err } => bail!(err),
                 }
+                ValidationError::TransientError { err } => bail!(err),
             }
         }
-
-        Ok(())
     }
 
-    // record peer event in database
-    fn write_event(&self, event: &str) -> Result<()> {
-        // TODO: write event to database to record if the peer is evil
-        let _ = event;
-        Ok(())
-    }
+    Ok(false)
+}
 
-    fn purge_joint_and_dependencies_and_notify_peers(
-        &self,
-        joint: &Joint,
-        err: &str,
-    ) -> Result<()> {
-        let _ = (joint, err);
-        unimplemented!()
+// record peer event in database, disconnect the peer once it's banned
+fn write_peer_event(db: &Connection, peer: &str, event: PeerEvent) -> Result<()> {
+    let host = reputation::get_peer_host(peer);
+    let settings = config::get_settings();
+    if reputation::record_event(db, &host, event, &settings)? {
+        WSS.close_by_host(&host);
     }
+    Ok(())
+}
 
-    #[allow(dead_code)]
-    fn request_catchup(&self) -> Result<()> {
-        unimplemented!()
+fn purge_joint_and_dependencies_and_notify_peers(
+    db: &mut Connection,
+    joint: &Joint,
+    err: &str,
+) -> Result<()> {
+    let purged_units = joint_storage::purge_joint_and_dependencies(db, joint, err)?;
+    let err = format!(
+        "error on (indirect) parent unit {}: {}",
+        joint.get_unit_hash(),
+        err
+    );
+    for (purged_unit, peer) in purged_units {
+        if let Some(ws) = WSS.get_connection_by_name(&peer) {
+            t!(ws.send_error_result(&purged_unit, &err));
+        }
     }
+    Ok(())
+}
 
-    #[allow(dead_code)]
-    fn request_new_missing_joints(&self, units: &Vec<String>) -> Result<()> {
-        let _ = units;
-        unimplemented!()
+// ask the peer for the joint, retry with other peers if the request fails
+fn request_joint(mut ws: Arc<HubConn>, unit: String) -> Result<()> {
+    let mut response = {
+        // don't ask twice for the same unit
+        let _g = match JOINT_IN_REQ.try_lock(vec![unit.clone()]) {
+            Some(g) => g,
+            None => return Ok(()),
+        };
+
+        let mut tries = 0;
+        loop {
+            match ws.send_request("get_joint", json!(unit)) {
+                Ok(response) => break response,
+                Err(e) => {
+                    tries += 1;
+                    warn!("get_joint {} from {} failed: {}", unit, ws.get_peer(), e);
+                    if tries >= config::MAX_GET_JOINT_TRIES {
+                        bail!("failed to get joint {} after {} tries", unit, tries);
+                    }
+                    ws = match WSS.get_next_peer_except(&ws) {
+                        Some(ws) => ws,
+                        None => bail!("no other peer to get joint {}", unit),
+                    };
+                }
+            }
+        }
+    };
+
+    if response["joint_not_found"].as_str() == Some(unit.as_str()) {
+        warn!("peer {} doesn't have joint {}", ws.get_peer(), unit);
+        return Ok(());
     }
-    #[allow(dead_code)]
-    fn notify_watchers(&self, joint: &Joint) -> Result<()> {
-        let _ = joint;
-        unimplemented!()
+
+    let joint: Joint = serde_json::from_value(response["joint"].take())?;
+    if joint.unit.unit.as_ref() != Some(&unit) {
+        let err = format!("I didn't request this unit from you: {:?}", joint.unit.unit);
+        return ws.send_error(json!(err));
     }
+
+    let mut db = db::DB_POOL.get_connection();
+    ws.handle_online_joint(joint, &mut db)
+}
+
+// the units in the hash tree that are not saved yet
+fn read_missing_hash_tree_units(db: &Connection) -> Result<Vec<String>> {
+    let mut stmt = db.prepare_cached(
+        "SELECT hash_tree_balls.unit FROM hash_tree_balls LEFT JOIN units USING(unit) \
+         WHERE units.unit IS NULL ORDER BY ball_index",
+    )?;
+    let units = stmt
+        .query_map(&[], |row| row.get::<_, String>(0))?
+        .collect::<::std::result::Result<Vec<_>, _>>()?;
+    Ok(units)
+}
+
+// purge the unhandled joints that have waited too long for their parents
+pub fn start_purge_junk_unhandled_joints() {
+    go!(|| loop {
+        let timeout = config::get_settings().unhandled_joints_timeout;
+        coroutine::sleep(Duration::from_secs(timeout / 2));
+        let db = db::DB_POOL.get_connection();
+        t!(joint_storage::purge_old_unhandled_joints(&db));
+        let window = config::get_settings().peer_events_window;
+        t!(reputation::purge_old_events(&db, window));
+    });
 }
 
 // the client side impl
 impl HubConn {
     fn send_version(&self) -> Result<()> {
-        // TODO: read these things from config
+        let settings = config::get_settings();
         self.send_just_saying(
             "version",
             json!({
                 "protocol_version": config::VERSION,
                 "alt": config::ALT,
                 "library": "rust-INKC",
-                "library_version": "0.1.0",
-                "program": "rust-INKC-hub",
-                "program_version": "0.1.0"
+                "library_version": env!("CARGO_PKG_VERSION"),
+                "program": settings.program,
+                "program_version": settings.program_version
             }),
         )
     }
 
+    fn send_joint(&self, joint: &Joint) -> Result<()> {
+        self.send_just_saying("joint", serde_json::to_value(joint)?)
+    }
+
     fn send_hub_challenge(&self) -> Result<()> {
         use object_hash;
         let challenge = object_hash::gen_random_string(30);
@@ -429,15 +932,21 @@ impl HubConn {
     }
 
     fn send_subscribe(&self) -> Result<()> {
-        use object_hash;
-        // TODO: this is used to detect self-connect (#63)
-        let subscription_id = object_hash::gen_random_string(30);
-        let db = ::db::DB_POOL.get_connection();
-        let last_mci = storage::read_last_main_chain_index(&db)?;
-        self.send_request(
+        let last_mci = {
+            let db = ::db::DB_POOL.get_connection();
+            storage::read_last_main_chain_index(&db)?
+        };
+        let ret = self.send_request(
             "subscribe",
-            json!({ "subscription_id": subscription_id, "last_mci": last_mci}),
-        )?;
+            json!({ "subscription_id": *MY_SUBSCRIPTION_ID, "last_mci": last_mci}),
+        );
+        if let Err(e) = ret {
+            // the peer rejects the subscription when it finds out to be ourselves
+            if e.to_string().contains(SELF_CONNECT_ERROR) {
+                self.set_self_connection();
+            }
+            return Err(e);
+        }
 
         self.set_source();
         Ok(())
@@ -455,13 +964,31 @@ impl HubConn {
     }
 }
 
-pub fn create_outbound_conn<A: ToSocketAddrs>(address: A) -> Result<Arc<HubConn>> {
-    let stream = TcpStream::connect(address)?;
+// the tls handshake is done for `wss` urls, `ws` is only for local development
+pub fn create_outbound_conn(url: Url) -> Result<Arc<HubConn>> {
+    let host = match url.host_str() {
+        Some(host) => host.to_owned(),
+        None => bail!("no host in peer url {}", url),
+    };
+    let port = match url.port_or_known_default() {
+        Some(port) => port,
+        None => bail!("no port in peer url {}", url),
+    };
+    let stream = TcpStream::connect((host.as_str(), port))?;
     let peer = match stream.peer_addr() {
         Ok(addr) => format!("{}", addr),
         Err(_) => "unknown peer".to_owned(),
     };
-    let url = Url::parse("wss://localhost/")?;
+    let stream = match url.scheme() {
+        "ws" => WsStream::Plain(stream),
+        "wss" => {
+            let settings = config::get_settings();
+            let ca_file = settings.tls_ca_file.as_ref().map(String::as_str);
+            let tls_config = tls::load_client_config(ca_file)?;
+            tls::connect(stream, &host, &tls_config, &settings.tls_pinned_certs)?
+        }
+        scheme => bail!("unsupported scheme {} of peer url {}", scheme, url),
+    };
     let req = Request::from(url);
     let (conn, _) = client(req, stream)?;
     // let ws
@@ -470,3 +997,39 @@ pub fn create_outbound_conn<A: ToSocketAddrs>(address: A) -> Result<Arc<HubConn>
     WSS.add_outbound(ws.clone());
     Ok(ws)
 }
+
+// connect to a peer by its websocket url, e.g. `wss://127.0.0.1:6655`
+pub fn connect_to_peer(peer: &str) -> Result<Arc<HubConn>> {
+    create_outbound_conn(Url::parse(peer)?)
+}
+
+#[test]
+fn test_self_connection() {
+    use super::network::WsServer;
+
+    ::test_utils::init_coroutines();
+    let port = ::std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let _server = WsServer::start(("127.0.0.1", port), None, |c| WSS.add_inbound(c));
+
+    // the server may not be listening yet
+    let url = format!("ws://127.0.0.1:{}", port);
+    let mut ret = Err(format_err!("not connected"));
+    for _ in 0..20 {
+        let url = url.clone();
+        ret = go!(move || connect_to_peer(&url)).join().unwrap();
+        if ret.is_ok() {
+            break;
+        }
+        coroutine::sleep(Duration::from_millis(50));
+    }
+
+    // the client gets the error of subscribe, neither side keeps the connection
+    let ws = ret.unwrap();
+    assert!(ws.is_self_connection());
+    assert!(WSS.get_connection_by_name(ws.get_peer()).is_none());
+    assert!(!WSS.contains_outbound(&ws));
+}
@@ -1,4 +1,7 @@
 pub mod hub;
 mod network;
+pub mod peer_manager;
+pub mod reputation;
+pub mod tls;
 
 pub use self::network::{WsConnection, WsServer};
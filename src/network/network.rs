@@ -5,11 +5,13 @@ use std::sync::atomic::Ordering;
 use std::sync::Arc;
 use std::time::{Duration, Instant};
 
+use super::tls::{self, WsStream};
 use error::Result;
 use may::coroutine::JoinHandle;
-use may::net::{TcpListener, TcpStream};
+use may::net::TcpListener;
 use may::sync::{AtomicOption, RwLock};
 use may_waiter::WaiterMap;
+use rustls::ServerConfig;
 use serde_json::{self, Value};
 use tungstenite::protocol::Role;
 use tungstenite::server::accept;
@@ -27,6 +29,18 @@ macro_rules! t_c {
     };
 }
 
+macro_rules! t_r {
+    ($e:expr) => {
+        match $e {
+            Ok(val) => val,
+            Err(err) => {
+                error!("call = {:?}\nerr = {:?}", stringify!($e), err);
+                return;
+            }
+        }
+    };
+}
+
 // the server part trait
 pub trait Server<T> {
     fn new() -> T;
@@ -74,7 +88,7 @@ pub trait Sender {
 }
 
 struct WsInner {
-    ws: WebSocket<TcpStream>,
+    ws: WebSocket<WsStream>,
     last_recv: Instant,
 }
 
@@ -142,7 +156,7 @@ impl<T> Drop for WsConnection<T> {
 
 impl<T> WsConnection<T> {
     /// create a client from stream socket
-    pub fn new(ws: WebSocket<TcpStream>, data: T, peer: String, role: Role) -> Result<Arc<Self>>
+    pub fn new(ws: WebSocket<WsStream>, data: T, peer: String, role: Role) -> Result<Arc<Self>>
     where
         T: Server<T> + Send + Sync + 'static,
     {
@@ -270,7 +284,8 @@ impl<T> WsConnection<T> {
         let blocker = self.req_map.new_waiter(tag);
         self.send_message("request", request)?;
 
-        let timeout = Some(Duration::from_secs(::config::STALLED_TIMEOUT as u64));
+        let stalled_timeout = ::config::get_settings().stalled_timeout;
+        let timeout = Some(Duration::from_secs(stalled_timeout));
         #[derive(Deserialize)]
         struct Response {
             #[allow(dead_code)]
@@ -297,10 +312,11 @@ pub struct WsServer<T>(PhantomData<T>);
 
 impl<T> WsServer<T> {
     // f is used to save the connection globally
-    pub fn start<A, F>(address: A, f: F) -> JoinHandle<()>
+    // the connections are wss if the tls config is given, or else plain ws
+    pub fn start<A, F>(address: A, tls_config: Option<Arc<ServerConfig>>, f: F) -> JoinHandle<()>
     where
         A: ToSocketAddrs,
-        F: Fn(Arc<WsConnection<T>>) + Send + 'static,
+        F: Fn(Arc<WsConnection<T>>) + Send + Sync + 'static,
         T: Server<T> + Send + Sync + 'static,
     {
         let address = address
@@ -309,6 +325,7 @@ impl<T> WsServer<T> {
             .next()
             .expect("can't resolve address");
 
+        let f = Arc::new(f);
         go!(move || {
             let listener = TcpListener::bind(address).unwrap();
             // for stream in listener.incoming() {
@@ -317,9 +334,18 @@ impl<T> WsServer<T> {
                     Ok(addr) => format!("{}", addr),
                     Err(_) => "unknown peer".to_owned(),
                 };
-                let ws = t_c!(accept(stream));
-                let ws = t_c!(WsConnection::new(ws, T::new(), peer, Role::Server));
-                f(ws);
+                let tls_config = tls_config.clone();
+                let f = f.clone();
+                // don't let a slow handshake block the listener
+                go!(move || {
+                    let stream = match tls_config {
+                        Some(ref config) => t_r!(tls::accept(stream, config)),
+                        None => WsStream::Plain(stream),
+                    };
+                    let ws = t_r!(accept(stream));
+                    let ws = t_r!(WsConnection::new(ws, T::new(), peer, Role::Server));
+                    f(ws);
+                });
             }
         })
     }
@@ -0,0 +1,625 @@
+use std::collections::HashMap;
+use std::net::ToSocketAddrs;
+use std::sync::{Arc, Weak};
+use std::time::{Duration, Instant};
+
+use super::hub::{self, HubConn, WSS};
+use super::reputation;
+use config;
+use db;
+use error::Result;
+use may::coroutine;
+use may::sync::RwLock;
+use rusqlite::Connection;
+use serde_json::{self, Value};
+use url::Url;
+
+// max number of peers returned for get_peers
+const MAX_PEERS_TO_SHARE: u32 = 100;
+// max number of peers taken from a get_peers response
+const MAX_PEERS_TO_LEARN: usize = 100;
+// max number of peers kept in the peers table
+const MAX_PEERS: u32 = 1000;
+// a learnt peer is forgotten once it fails this many times more than it succeeds
+const MAX_PEER_FAILURES: u32 = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PeerStatus {
+    Disconnected,
+    Connecting,
+    Connected,
+    // the peer is ourselves, never connect to it again
+    SelfConnection,
+}
+
+#[derive(Debug, Clone)]
+pub struct PeerState {
+    pub url: String,
+    pub status: PeerStatus,
+    // consecutive failures, decides the reconnect delay
+    pub failures: u32,
+    pub next_retry: Instant,
+    pub last_connected: Option<Instant>,
+}
+
+struct Peer {
+    state: PeerState,
+    conn: Option<Weak<HubConn>>,
+}
+
+lazy_static! {
+    static ref PEERS: RwLock<HashMap<String, Peer>> = RwLock::new(HashMap::new());
+}
+
+fn get_reconnect_delay(failures: u32) -> Duration {
+    let delay = config::MIN_RECONNECT_DELAY
+        .checked_shl(failures.saturating_sub(1))
+        .unwrap_or(config::MAX_RECONNECT_DELAY);
+    Duration::from_secs(::std::cmp::min(delay, config::MAX_RECONNECT_DELAY))
+}
+
+// the resolved socket address is used as the connection name by create_outbound_conn
+fn resolve_peer(url: &str) -> Result<String> {
+    let url = Url::parse(url)?;
+    let host = match url.host_str() {
+        Some(host) => host.to_owned(),
+        None => bail!("no host in peer url {}", url),
+    };
+    let port = match url.port_or_known_default() {
+        Some(port) => port,
+        None => bail!("no port in peer url {}", url),
+    };
+    match (host.as_str(), port).to_socket_addrs()?.next() {
+        Some(addr) => Ok(format!("{}", addr)),
+        None => bail!("can't resolve peer {}", url),
+    }
+}
+
+fn get_peer_host(url: &str) -> Result<String> {
+    let url = Url::parse(url)?;
+    ensure!(
+        url.scheme() == "ws" || url.scheme() == "wss",
+        "peer {} is not a websocket url",
+        url
+    );
+    match url.host_str() {
+        Some(host) => Ok(host.to_owned()),
+        None => bail!("no host in peer url {}", url),
+    }
+}
+
+/// save the peers into the peers table, invalid urls are ignored
+pub fn save_peers(db: &Connection, urls: &[String], learnt_from: Option<&str>) -> Result<()> {
+    let learnt_from_host = match learnt_from {
+        Some(url) => Some(get_peer_host(url)?),
+        None => None,
+    };
+    let mut stmt = db.prepare_cached(
+        "INSERT OR IGNORE INTO peers (peer, peer_host, learnt_from_peer_host) VALUES (?, ?, ?)",
+    )?;
+    for url in urls {
+        let host = match get_peer_host(url) {
+            Ok(host) => host,
+            Err(e) => {
+                warn!("ignore peer {}, err = {}", url, e);
+                continue;
+            }
+        };
+        stmt.execute(&[url, &host, &learnt_from_host])?;
+    }
+    Ok(())
+}
+
+fn save_connect_result(db: &Connection, url: &str, is_success: bool) -> Result<()> {
+    let sql = if is_success {
+        "UPDATE peers SET count_successes=count_successes+1, last_seen=CURRENT_TIMESTAMP \
+         WHERE peer=?"
+    } else {
+        "UPDATE peers SET count_failures=count_failures+1 WHERE peer=?"
+    };
+    let mut stmt = db.prepare_cached(sql)?;
+    stmt.execute(&[&url])?;
+    Ok(())
+}
+
+fn save_self_peer(db: &Connection, url: &str) -> Result<()> {
+    let mut stmt = db.prepare_cached("UPDATE peers SET is_self=1 WHERE peer=?")?;
+    stmt.execute(&[&url])?;
+    Ok(())
+}
+
+/// forget the learnt peers that keep failing and the least reliable ones beyond
+/// `MAX_PEERS`, the configured peers are always kept
+pub fn prune_peers(db: &Connection) -> Result<()> {
+    let mut stmt = db.prepare_cached(
+        "DELETE FROM peers WHERE learnt_from_peer_host IS NOT NULL \
+         AND count_failures-count_successes>=?",
+    )?;
+    stmt.execute(&[&MAX_PEER_FAILURES])?;
+    let mut stmt = db.prepare_cached(
+        "DELETE FROM peers WHERE learnt_from_peer_host IS NOT NULL AND peer NOT IN ( \
+         SELECT peer FROM peers \
+         ORDER BY count_successes-count_failures DESC, last_seen DESC, creation_date DESC \
+         LIMIT ?)",
+    )?;
+    stmt.execute(&[&MAX_PEERS])?;
+    Ok(())
+}
+
+/// the peers that we have successfully connected to, most recently seen first
+pub fn read_good_peers(db: &Connection) -> Result<Vec<String>> {
+    let mut stmt = db.prepare_cached(
+        "SELECT peer FROM peers WHERE is_self=0 AND count_successes>0 \
+         ORDER BY last_seen DESC LIMIT ?",
+    )?;
+    let peers = stmt
+        .query_map(&[&MAX_PEERS_TO_SHARE], |row| row.get(0))?
+        .collect::<::std::result::Result<Vec<String>, _>>()?;
+    Ok(peers)
+}
+
+/// the peers to connect, the more reliable ones come first
+pub fn read_candidate_peers(db: &Connection) -> Result<Vec<String>> {
+    let mut stmt = db.prepare_cached(
+        "SELECT peer FROM peers WHERE is_self=0 \
+         ORDER BY count_successes-count_failures DESC, last_seen DESC",
+    )?;
+    let peers = stmt
+        .query_map(&[], |row| row.get(0))?
+        .collect::<::std::result::Result<Vec<String>, _>>()?;
+    Ok(peers)
+}
+
+// ask a newly connected peer for the peers it knows
+fn discover_peers(ws: &HubConn, url: &str) -> Result<()> {
+    let mut peers: Vec<String> = match ws.send_request("get_peers", Value::Null)? {
+        Value::Null => Vec::new(),
+        v => serde_json::from_value(v)?,
+    };
+    if peers.is_empty() {
+        return Ok(());
+    }
+    peers.truncate(MAX_PEERS_TO_LEARN);
+    info!("learnt {} peers from {}", peers.len(), url);
+    let db = db::DB_POOL.get_connection();
+    save_peers(&db, &peers, Some(url))?;
+    prune_peers(&db)
+}
+
+// sync the peer table with the settings and find out the closed connections
+fn update_peers(urls: &[String]) {
+    let now = Instant::now();
+    let mut g = PEERS.write().unwrap();
+    // the pruned peers are dropped unless we are connected to them
+    g.retain(|url, peer| peer.state.status != PeerStatus::Disconnected || urls.contains(url));
+    for url in urls {
+        g.entry(url.clone()).or_insert_with(|| Peer {
+            state: PeerState {
+                url: url.clone(),
+                status: PeerStatus::Disconnected,
+                failures: 0,
+                next_retry: now,
+                last_connected: None,
+            },
+            conn: None,
+        });
+    }
+
+    for peer in g.values_mut() {
+        if peer.state.status != PeerStatus::Connected {
+            continue;
+        }
+        let is_alive = match peer.conn.as_ref().and_then(|c| c.upgrade()) {
+            Some(ws) => WSS.contains_outbound(&ws),
+            None => false,
+        };
+        if !is_alive {
+            info!("peer {} disconnected", peer.state.url);
+            peer.conn = None;
+            peer.state.status = PeerStatus::Disconnected;
+            peer.state.failures += 1;
+            peer.state.next_retry = now + get_reconnect_delay(peer.state.failures);
+        }
+    }
+}
+
+// pick the peers to connect and mark them as connecting
+fn select_peers_to_connect(max_connections: usize) -> Vec<String> {
+    let now = Instant::now();
+    let mut g = PEERS.write().unwrap();
+    let active = g
+        .values()
+        .filter(|p| p.state.status == PeerStatus::Connecting)
+        .count();
+    let wanted = max_connections.saturating_sub(WSS.get_outbound_count() + active);
+
+    let mut urls = Vec::new();
+    for peer in g.values_mut() {
+        if urls.len() >= wanted {
+            break;
+        }
+        if peer.state.status == PeerStatus::Disconnected && peer.state.next_retry <= now {
+            peer.state.status = PeerStatus::Connecting;
+            urls.push(peer.state.url.clone());
+        }
+    }
+    urls
+}
+
+fn connect(url: &str) -> Result<Arc<HubConn>> {
+    // don't connect to the same peer twice, e.g. through two different urls
+    let addr = resolve_peer(url)?;
+    if WSS.get_connection_by_name(&addr).is_some() {
+        bail!("already connected to {}", addr);
+    }
+    let host = reputation::get_peer_host(&addr);
+    {
+        let db = db::DB_POOL.get_connection();
+        ensure!(
+            !reputation::is_banned(&db, &host)?,
+            "peer {} is banned",
+            host
+        );
+    }
+    hub::connect_to_peer(url)
+}
+
+fn on_connect_result(url: &str, ret: Result<Arc<HubConn>>) {
+    {
+        let db = db::DB_POOL.get_connection();
+        let is_self = match ret {
+            Ok(ref ws) => ws.is_self_connection(),
+            Err(_) => false,
+        };
+        if is_self {
+            t!(save_self_peer(&db, url));
+        } else {
+            t!(save_connect_result(&db, url, ret.is_ok()));
+        }
+    }
+
+    let now = Instant::now();
+    let mut g = PEERS.write().unwrap();
+    let peer = match g.get_mut(url) {
+        Some(peer) => peer,
+        None => return,
+    };
+
+    match ret {
+        Ok(ref ws) if ws.is_self_connection() => {
+            warn!("peer {} is ourselves, will not connect again", url);
+            peer.state.status = PeerStatus::SelfConnection;
+        }
+        Ok(ws) => {
+            info!("connected to peer {}", url);
+            peer.conn = Some(Arc::downgrade(&ws));
+            peer.state.status = PeerStatus::Connected;
+            peer.state.failures = 0;
+            peer.state.last_connected = Some(now);
+            let url = url.to_owned();
+            go!(move || t!(discover_peers(&ws, &url)));
+        }
+        Err(e) => {
+            peer.state.status = PeerStatus::Disconnected;
+            peer.state.failures += 1;
+            let delay = get_reconnect_delay(peer.state.failures);
+            peer.state.next_retry = now + delay;
+            error!(
+                "connect to peer {} failed, retry in {:?}, err = {}",
+                url, delay, e
+            );
+        }
+    }
+}
+
+/// return the state of all the known peers
+pub fn get_peer_states() -> Vec<PeerState> {
+    let g = PEERS.read().unwrap();
+    g.values().map(|p| p.state.clone()).collect()
+}
+
+// configured peers first, then the ones in the peers table
+fn read_peers_to_connect(settings: &config::Settings) -> Result<Vec<String>> {
+    let db = db::DB_POOL.get_connection();
+    save_peers(&db, &settings.peers, None)?;
+    prune_peers(&db)?;
+    let mut urls = settings.peers.clone();
+    for url in read_candidate_peers(&db)? {
+        if !urls.contains(&url) {
+            urls.push(url);
+        }
+    }
+    Ok(urls)
+}
+
+/// keep the outbound connections to the configured and discovered peers
+pub fn start() {
+    go!(|| loop {
+        let settings = config::get_settings();
+        match read_peers_to_connect(&settings) {
+            Ok(urls) => update_peers(&urls),
+            Err(e) => {
+                error!("failed to read peers, err = {}", e);
+                update_peers(&settings.peers);
+            }
+        }
+        for url in select_peers_to_connect(settings.max_outbound_connections) {
+            go!(move || {
+                let ret = connect(&url);
+                on_connect_result(&url, ret);
+            });
+        }
+        coroutine::sleep(Duration::from_secs(config::MIN_RECONNECT_DELAY));
+    });
+}
+
+#[test]
+fn test_reconnect_delay() {
+    assert_eq!(get_reconnect_delay(0), Duration::from_secs(1));
+    assert_eq!(get_reconnect_delay(1), Duration::from_secs(1));
+    assert_eq!(get_reconnect_delay(2), Duration::from_secs(2));
+    assert_eq!(get_reconnect_delay(5), Duration::from_secs(16));
+    assert_eq!(get_reconnect_delay(10), Duration::from_secs(300));
+    assert_eq!(get_reconnect_delay(100), Duration::from_secs(300));
+}
+
+#[test]
+fn test_save_and_read_peers() {
+    let mut db = Connection::open_in_memory().unwrap();
+    db::migrate(&mut db).unwrap();
+
+    let peers = vec![
+        "ws://127.0.0.1:6615".to_owned(),
+        "ws://127.0.0.1:6616".to_owned(),
+        "http://127.0.0.1:6617".to_owned(),
+    ];
+    save_peers(&db, &peers, None).unwrap();
+    save_peers(&db, &peers[..1], Some("ws://127.0.0.1:6618")).unwrap();
+    assert_eq!(read_candidate_peers(&db).unwrap().len(), 2);
+    assert!(read_good_peers(&db).unwrap().is_empty());
+
+    save_connect_result(&db, &peers[0], false).unwrap();
+    save_connect_result(&db, &peers[1], true).unwrap();
+    assert_eq!(
+        read_candidate_peers(&db).unwrap(),
+        vec![peers[1].clone(), peers[0].clone()]
+    );
+    assert_eq!(read_good_peers(&db).unwrap(), vec![peers[1].clone()]);
+
+    save_self_peer(&db, &peers[1]).unwrap();
+    assert_eq!(read_candidate_peers(&db).unwrap(), vec![peers[0].clone()]);
+    assert!(read_good_peers(&db).unwrap().is_empty());
+}
+
+#[test]
+fn test_prune_peers() {
+    let mut db = Connection::open_in_memory().unwrap();
+    db::migrate(&mut db).unwrap();
+
+    let configured = "ws://127.0.0.1:6615".to_owned();
+    let failing = "ws://127.0.0.1:6616".to_owned();
+    let good = "ws://127.0.0.1:6617".to_owned();
+    save_peers(&db, &[configured.clone()], None).unwrap();
+    save_peers(&db, &[failing.clone(), good.clone()], Some(&configured)).unwrap();
+    save_connect_result(&db, &good, true).unwrap();
+    for _ in 0..MAX_PEER_FAILURES {
+        save_connect_result(&db, &configured, false).unwrap();
+        save_connect_result(&db, &failing, false).unwrap();
+    }
+    let learnt = (0..MAX_PEERS)
+        .map(|i| format!("ws://127.0.{}.{}:6655", i / 250, i % 250 + 1))
+        .collect::<Vec<_>>();
+    save_peers(&db, &learnt, Some(&good)).unwrap();
+
+    prune_peers(&db).unwrap();
+    let peers = read_candidate_peers(&db).unwrap();
+    // the configured peer is kept beyond the limit
+    assert_eq!(peers.len() as u32, MAX_PEERS + 1);
+    assert_eq!(peers[0], good);
+    assert!(peers.contains(&configured));
+    assert!(!peers.contains(&failing));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::network::{Server, WsConnection, WsServer};
+    use super::*;
+    use std::thread;
+
+    // a remote node on loopback, it only answers what a new connection asks for
+    struct StandIn;
+
+    lazy_static! {
+        // the connections by the url of the stand-in, they are closed when dropped
+        static ref STAND_IN_CONNS: RwLock<Vec<(String, Arc<WsConnection<StandIn>>)>> =
+            RwLock::new(Vec::new());
+        // the answer of the stand-ins to get_peers
+        static ref STAND_IN_PEERS: RwLock<Vec<String>> = RwLock::new(Vec::new());
+    }
+
+    impl Server<StandIn> for StandIn {
+        fn new() -> StandIn {
+            StandIn
+        }
+
+        fn on_message(_: Arc<WsConnection<StandIn>>, _: String, _: Value) -> Result<()> {
+            Ok(())
+        }
+
+        fn on_request(_: Arc<WsConnection<StandIn>>, command: String, _: Value) -> Result<Value> {
+            match command.as_str() {
+                "subscribe" => Ok(json!("subscribed")),
+                "heartbeat" => Ok(Value::Null),
+                "get_peers" => Ok(json!(*STAND_IN_PEERS.read().unwrap())),
+                command => bail!("stand-in doesn't serve {}", command),
+            }
+        }
+    }
+
+    fn get_free_port() -> u16 {
+        ::std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port()
+    }
+
+    fn start_stand_in() -> String {
+        let port = get_free_port();
+        let url = format!("ws://127.0.0.1:{}", port);
+        let stand_in = url.clone();
+        WsServer::start(("127.0.0.1", port), None, move |c| {
+            STAND_IN_CONNS.write().unwrap().push((stand_in.clone(), c));
+        });
+        url
+    }
+
+    fn get_stand_in_conn(url: &str) -> Arc<WsConnection<StandIn>> {
+        let g = STAND_IN_CONNS.read().unwrap();
+        let (_, ref conn) = *g.iter().rev().find(|&&(ref u, _)| u == url).unwrap();
+        conn.clone()
+    }
+
+    fn start_hub() -> String {
+        let port = get_free_port();
+        WsServer::start(("127.0.0.1", port), None, |c| WSS.add_inbound(c));
+        format!("ws://127.0.0.1:{}", port)
+    }
+
+    // the servers may not be listening yet
+    fn connect_with_retry(url: &str) -> Result<Arc<HubConn>> {
+        let mut tries = 0;
+        loop {
+            let url = url.to_owned();
+            let ret = go!(move || connect(&url)).join().unwrap();
+            tries += 1;
+            if ret.is_ok() || tries >= 20 {
+                return ret;
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+    }
+
+    fn get_state(url: &str) -> PeerState {
+        let g = PEERS.read().unwrap();
+        g[url].state.clone()
+    }
+
+    fn select_from(urls: &[String]) -> Vec<String> {
+        select_peers_to_connect(1000)
+            .into_iter()
+            .filter(|url| urls.contains(url))
+            .collect()
+    }
+
+    #[test]
+    fn test_connect_peers() {
+        ::test_utils::init_coroutines();
+        let self_url = start_hub();
+        let url = start_stand_in();
+        let dead_url = format!("ws://127.0.0.1:{}", get_free_port());
+        let urls = vec![self_url.clone(), url.clone(), dead_url.clone()];
+        update_peers(&urls);
+
+        assert_eq!(select_from(&urls).len(), 3);
+        // not selected again while connecting
+        assert!(select_from(&urls).is_empty());
+        for url in &urls {
+            let ret = connect_with_retry(url);
+            on_connect_result(url, ret);
+        }
+        assert_eq!(get_state(&self_url).status, PeerStatus::SelfConnection);
+        assert_eq!(get_state(&url).status, PeerStatus::Connected);
+        let state = get_state(&dead_url);
+        assert_eq!(state.status, PeerStatus::Disconnected);
+        assert_eq!(state.failures, 1);
+
+        // the same peer through another url
+        let alias = format!("{}/", url);
+        match go!(move || connect(&alias)).join().unwrap() {
+            Err(e) => assert!(e.to_string().contains("already connected")),
+            Ok(_) => panic!("connected to the same peer twice"),
+        }
+
+        // the failed peer is retried after the delay, ourselves never
+        update_peers(&urls);
+        assert!(select_from(&urls).is_empty());
+        thread::sleep(get_reconnect_delay(1) + Duration::from_millis(100));
+        assert_eq!(select_from(&urls), vec![dead_url.clone()]);
+
+        // reconnect when the connection is closed
+        let ws = WSS
+            .get_connection_by_name(&resolve_peer(&url).unwrap())
+            .unwrap();
+        ws.close();
+        drop(ws);
+        update_peers(&urls);
+        let state = get_state(&url);
+        assert_eq!(state.status, PeerStatus::Disconnected);
+        assert_eq!(state.failures, 1);
+        thread::sleep(get_reconnect_delay(1) + Duration::from_millis(100));
+        assert_eq!(select_from(&urls), vec![url.clone()]);
+        let ret = connect_with_retry(&url);
+        on_connect_result(&url, ret);
+        assert_eq!(get_state(&url).status, PeerStatus::Connected);
+        assert_eq!(get_state(&url).failures, 0);
+
+        // a dropped peer is forgotten unless connected
+        on_connect_result(&dead_url, Err(format_err!("connection refused")));
+        update_peers(&[self_url.clone()]);
+        assert!(PEERS.read().unwrap().contains_key(&url));
+        assert!(!PEERS.read().unwrap().contains_key(&dead_url));
+    }
+
+    #[test]
+    fn test_exchange_peers() {
+        ::test_utils::init_coroutines();
+        let self_url = start_hub();
+        let url = start_stand_in();
+        // the stand-in shares more peers than we take, ourselves among them
+        let port = Url::parse(&url).unwrap().port().unwrap();
+        let mut shared = vec![self_url.clone()];
+        shared.extend((2..MAX_PEERS_TO_LEARN + 50).map(|i| format!("ws://127.0.0.{}:{}", i, port)));
+        *STAND_IN_PEERS.write().unwrap() = shared.clone();
+
+        {
+            let db = db::DB_POOL.get_connection();
+            save_peers(&db, &[url.clone()], None).unwrap();
+        }
+        let ws = connect_with_retry(&url).unwrap();
+        {
+            let db = db::DB_POOL.get_connection();
+            save_connect_result(&db, &url, true).unwrap();
+        }
+        let stand_in = url.clone();
+        go!(move || discover_peers(&ws, &stand_in))
+            .join()
+            .unwrap()
+            .unwrap();
+        {
+            let db = db::DB_POOL.get_connection();
+            let candidates = read_candidate_peers(&db).unwrap();
+            let learnt = shared
+                .iter()
+                .filter(|peer| candidates.contains(peer))
+                .collect::<Vec<_>>();
+            assert_eq!(learnt.len(), MAX_PEERS_TO_LEARN);
+            assert_eq!(learnt[0], &self_url);
+        }
+
+        // connecting to the learnt url of ourselves marks it as self
+        let ret = connect_with_retry(&self_url);
+        on_connect_result(&self_url, ret);
+
+        // the stand-in asks back, we share the good peers only
+        let conn = get_stand_in_conn(&url);
+        let peers = go!(move || conn.send_request("get_peers", Value::Null))
+            .join()
+            .unwrap()
+            .unwrap();
+        let peers: Vec<String> = serde_json::from_value(peers).unwrap();
+        assert!(peers.contains(&url));
+        assert!(!peers.contains(&self_url));
+        assert!(peers.len() as u32 <= MAX_PEERS_TO_SHARE);
+    }
+}
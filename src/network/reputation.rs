@@ -0,0 +1,249 @@
+use std::net::SocketAddr;
+
+use config::Settings;
+use error::Result;
+use rusqlite::Connection;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PeerEvent {
+    // the peer sent a new valid joint
+    NewGood,
+    // the peer sent a joint that we already know
+    KnownGood,
+    // the peer sent a joint that we already know to be bad
+    KnownBad,
+    // the peer sent a joint that failed validation
+    Invalid,
+}
+
+impl PeerEvent {
+    fn as_str(&self) -> &'static str {
+        match *self {
+            PeerEvent::NewGood => "new_good",
+            PeerEvent::KnownGood => "know_good",
+            PeerEvent::KnownBad => "know_bad",
+            PeerEvent::Invalid => "invalid",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PeerReputation {
+    pub peer_host: String,
+    pub count_good: u32,
+    pub count_bad: u32,
+}
+
+impl PeerReputation {
+    pub fn get_bad_ratio(&self) -> f64 {
+        let total = self.count_good + self.count_bad;
+        if total == 0 {
+            return 0.0;
+        }
+        f64::from(self.count_bad) / f64::from(total)
+    }
+
+    fn is_bad(&self, settings: &Settings) -> bool {
+        self.count_good + self.count_bad >= settings.min_peer_events
+            && self.get_bad_ratio() >= settings.max_bad_peer_ratio
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct BannedPeer {
+    pub peer_host: String,
+    pub reason: String,
+    pub ban_date: String,
+    pub expiry_date: String,
+}
+
+/// the connection name is the socket address, events are counted per ip
+/// so that reconnecting from another port doesn't reset the reputation
+pub fn get_peer_host(peer: &str) -> String {
+    match peer.parse::<SocketAddr>() {
+        Ok(addr) => addr.ip().to_string(),
+        Err(_) => peer.to_owned(),
+    }
+}
+
+// sqlite datetime modifier for the given seconds, e.g. "-3600 seconds"
+fn get_time_modifier(seconds: i64) -> String {
+    format!("{:+} seconds", seconds)
+}
+
+pub fn write_event(db: &Connection, peer_host: &str, event: PeerEvent) -> Result<()> {
+    let mut stmt = db.prepare_cached("INSERT INTO peer_events (peer_host, event) VALUES (?, ?)")?;
+    stmt.execute(&[&peer_host, &event.as_str()])?;
+    Ok(())
+}
+
+const REPUTATION_COLUMNS: &str = "peer_host, \
+     SUM(CASE WHEN event IN ('new_good', 'know_good') THEN 1 ELSE 0 END), \
+     SUM(CASE WHEN event IN ('know_bad', 'invalid') THEN 1 ELSE 0 END)";
+
+/// count the events of the peer within the last `window` seconds
+pub fn read_reputation(db: &Connection, peer_host: &str, window: u64) -> Result<PeerReputation> {
+    let sql = format!(
+        "SELECT {} FROM peer_events WHERE peer_host=? AND event_date>datetime('now', ?)",
+        REPUTATION_COLUMNS
+    );
+    let mut stmt = db.prepare_cached(&sql)?;
+    let since = get_time_modifier(-(window as i64));
+    let (count_good, count_bad) = stmt.query_row(&[&peer_host, &since], |row| {
+        (
+            row.get::<_, Option<u32>>(1).unwrap_or(0),
+            row.get::<_, Option<u32>>(2).unwrap_or(0),
+        )
+    })?;
+    Ok(PeerReputation {
+        peer_host: peer_host.to_owned(),
+        count_good,
+        count_bad,
+    })
+}
+
+/// the reputation of all the peers that have events within the last `window` seconds
+pub fn read_reputations(db: &Connection, window: u64) -> Result<Vec<PeerReputation>> {
+    let sql = format!(
+        "SELECT {} FROM peer_events WHERE event_date>datetime('now', ?) \
+         GROUP BY peer_host ORDER BY peer_host",
+        REPUTATION_COLUMNS
+    );
+    let mut stmt = db.prepare_cached(&sql)?;
+    let since = get_time_modifier(-(window as i64));
+    let reputations = stmt
+        .query_map(&[&since], |row| PeerReputation {
+            peer_host: row.get(0),
+            count_good: row.get(1),
+            count_bad: row.get(2),
+        })?
+        .collect::<::std::result::Result<Vec<_>, _>>()?;
+    Ok(reputations)
+}
+
+/// record the event and ban the peer if it turns out to be bad,
+/// return true if the peer is banned by this event
+pub fn record_event(
+    db: &Connection,
+    peer_host: &str,
+    event: PeerEvent,
+    settings: &Settings,
+) -> Result<bool> {
+    write_event(db, peer_host, event)?;
+    if event == PeerEvent::NewGood || event == PeerEvent::KnownGood {
+        return Ok(false);
+    }
+
+    let reputation = read_reputation(db, peer_host, settings.peer_events_window)?;
+    if !reputation.is_bad(settings) {
+        return Ok(false);
+    }
+
+    let reason = format!(
+        "{} bad of {} events in {} seconds",
+        reputation.count_bad,
+        reputation.count_good + reputation.count_bad,
+        settings.peer_events_window
+    );
+    warn!("ban peer {}: {}", peer_host, reason);
+    ban_peer(db, peer_host, &reason, settings.peer_ban_duration)?;
+    Ok(true)
+}
+
+pub fn ban_peer(db: &Connection, peer_host: &str, reason: &str, duration: u64) -> Result<()> {
+    let mut stmt = db.prepare_cached(
+        "INSERT OR REPLACE INTO banned_peers (peer_host, reason, expiry_date) \
+         VALUES (?, ?, datetime('now', ?))",
+    )?;
+    let until = get_time_modifier(duration as i64);
+    stmt.execute(&[&peer_host, &reason, &until])?;
+    Ok(())
+}
+
+pub fn is_banned(db: &Connection, peer_host: &str) -> Result<bool> {
+    let mut stmt = db.prepare_cached(
+        "SELECT 1 FROM banned_peers WHERE peer_host=? AND expiry_date>datetime('now')",
+    )?;
+    Ok(stmt.exists(&[&peer_host])?)
+}
+
+/// list the peers that are still banned
+pub fn read_banned_peers(db: &Connection) -> Result<Vec<BannedPeer>> {
+    let mut stmt = db.prepare_cached(
+        "SELECT peer_host, reason, ban_date, expiry_date FROM banned_peers \
+         WHERE expiry_date>datetime('now') ORDER BY ban_date",
+    )?;
+    let peers = stmt
+        .query_map(&[], |row| BannedPeer {
+            peer_host: row.get(0),
+            reason: row.get(1),
+            ban_date: row.get(2),
+            expiry_date: row.get(3),
+        })?
+        .collect::<::std::result::Result<Vec<_>, _>>()?;
+    Ok(peers)
+}
+
+/// lift the ban and forget the past events so that the peer starts over,
+/// return false if the peer is not banned
+pub fn unban_peer(db: &Connection, peer_host: &str) -> Result<bool> {
+    let mut stmt = db.prepare_cached("DELETE FROM banned_peers WHERE peer_host=?")?;
+    let n = stmt.execute(&[&peer_host])?;
+    let mut stmt = db.prepare_cached("DELETE FROM peer_events WHERE peer_host=?")?;
+    stmt.execute(&[&peer_host])?;
+    Ok(n > 0)
+}
+
+/// remove the events out of the window and the expired bans
+pub fn purge_old_events(db: &Connection, window: u64) -> Result<()> {
+    let mut stmt =
+        db.prepare_cached("DELETE FROM peer_events WHERE event_date<=datetime('now', ?)")?;
+    let since = get_time_modifier(-(window as i64));
+    stmt.execute(&[&since])?;
+    let mut stmt =
+        db.prepare_cached("DELETE FROM banned_peers WHERE expiry_date<=datetime('now')")?;
+    stmt.execute(&[])?;
+    Ok(())
+}
+
+#[test]
+fn test_get_peer_host() {
+    assert_eq!(get_peer_host("127.0.0.1:6655"), "127.0.0.1");
+    assert_eq!(get_peer_host("[::1]:6655"), "::1");
+    assert_eq!(get_peer_host("unknown peer"), "unknown peer");
+}
+
+#[test]
+fn test_ban_bad_peer() {
+    let mut db = Connection::open_in_memory().unwrap();
+    ::db::migrate(&mut db).unwrap();
+    let mut settings = Settings::default();
+    settings.min_peer_events = 4;
+    settings.max_bad_peer_ratio = 0.5;
+
+    let host = "127.0.0.1";
+    assert!(!record_event(&db, host, PeerEvent::NewGood, &settings).unwrap());
+    assert!(!record_event(&db, host, PeerEvent::KnownGood, &settings).unwrap());
+    assert!(!record_event(&db, host, PeerEvent::Invalid, &settings).unwrap());
+    assert!(!is_banned(&db, host).unwrap());
+    assert!(record_event(&db, host, PeerEvent::KnownBad, &settings).unwrap());
+    assert!(is_banned(&db, host).unwrap());
+
+    let reputations = read_reputations(&db, settings.peer_events_window).unwrap();
+    assert_eq!(
+        reputations,
+        vec![PeerReputation {
+            peer_host: host.to_owned(),
+            count_good: 2,
+            count_bad: 2,
+        }]
+    );
+    assert_eq!(read_banned_peers(&db).unwrap().len(), 1);
+
+    assert!(unban_peer(&db, host).unwrap());
+    assert!(!unban_peer(&db, host).unwrap());
+    assert!(!is_banned(&db, host).unwrap());
+    assert!(read_reputations(&db, settings.peer_events_window)
+        .unwrap()
+        .is_empty());
+}
@@ -0,0 +1,381 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufReader, Read, Write};
+use std::sync::Arc;
+
+use base64;
+use error::Result;
+use may::net::TcpStream;
+use may::sync::Mutex;
+use rustls::internal::pemfile;
+use rustls::{
+    Certificate, ClientConfig, ClientSession, NoClientAuth, PrivateKey, RootCertStore,
+    ServerCertVerified, ServerCertVerifier, ServerConfig, ServerSession, Session, TLSError,
+};
+use sha2::{Digest, Sha256};
+use webpki;
+use webpki_roots;
+
+/// the stream under a websocket, either plain tcp for `ws://` or tls for `wss://`
+pub enum WsStream {
+    Plain(TcpStream),
+    Tls(TlsStream),
+}
+
+impl WsStream {
+    // the websocket reader and writer work on two handles of the same stream
+    pub fn try_clone(&self) -> io::Result<WsStream> {
+        match *self {
+            WsStream::Plain(ref s) => Ok(WsStream::Plain(s.try_clone()?)),
+            WsStream::Tls(ref s) => Ok(WsStream::Tls(s.try_clone()?)),
+        }
+    }
+}
+
+impl fmt::Debug for WsStream {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            WsStream::Plain(_) => write!(f, "WsStream::Plain"),
+            WsStream::Tls(_) => write!(f, "WsStream::Tls"),
+        }
+    }
+}
+
+impl Read for WsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match *self {
+            WsStream::Plain(ref mut s) => s.read(buf),
+            WsStream::Tls(ref mut s) => s.read(buf),
+        }
+    }
+}
+
+impl Write for WsStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match *self {
+            WsStream::Plain(ref mut s) => s.write(buf),
+            WsStream::Tls(ref mut s) => s.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match *self {
+            WsStream::Plain(ref mut s) => s.flush(),
+            WsStream::Tls(ref mut s) => s.flush(),
+        }
+    }
+}
+
+/// a tls stream that can be cloned, the clones share the tls session
+/// so that one coroutine can read while another one is writing
+pub struct TlsStream {
+    sock: TcpStream,
+    session: Arc<Mutex<Box<Session>>>,
+}
+
+fn tls_to_io_error(e: TLSError) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+impl TlsStream {
+    fn new<S: Session + 'static>(sock: TcpStream, session: S) -> Result<TlsStream> {
+        let session: Box<Session> = Box::new(session);
+        let mut stream = TlsStream {
+            sock,
+            session: Arc::new(Mutex::new(session)),
+        };
+        stream.complete_handshake()?;
+        Ok(stream)
+    }
+
+    // no other clone exists yet, so it's fine to hold the lock on socket io
+    fn complete_handshake(&mut self) -> io::Result<()> {
+        let mut session = self.session.lock().unwrap();
+        while session.is_handshaking() {
+            while session.wants_write() {
+                session.write_tls(&mut self.sock)?;
+            }
+            if session.wants_read() {
+                if session.read_tls(&mut self.sock)? == 0 {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "tls handshake eof",
+                    ));
+                }
+                session.process_new_packets().map_err(tls_to_io_error)?;
+            }
+        }
+        while session.wants_write() {
+            session.write_tls(&mut self.sock)?;
+        }
+        Ok(())
+    }
+
+    fn try_clone(&self) -> io::Result<TlsStream> {
+        Ok(TlsStream {
+            sock: self.sock.try_clone()?,
+            session: self.session.clone(),
+        })
+    }
+}
+
+impl Read for TlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut tls_buf = [0u8; 4096];
+        loop {
+            {
+                let mut session = self.session.lock().unwrap();
+                let n = session.read(buf)?;
+                if n > 0 {
+                    return Ok(n);
+                }
+            }
+
+            // wait for more data without holding the session lock
+            let n = self.sock.read(&mut tls_buf)?;
+            if n == 0 {
+                return Ok(0);
+            }
+
+            let mut session = self.session.lock().unwrap();
+            let mut rd = &tls_buf[..n];
+            while !rd.is_empty() {
+                if session.read_tls(&mut rd)? == 0 {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "tls buffer is full",
+                    ));
+                }
+                session.process_new_packets().map_err(tls_to_io_error)?;
+            }
+            // e.g. the alert or key update that must be answered
+            while session.wants_write() {
+                session.write_tls(&mut self.sock)?;
+            }
+        }
+    }
+}
+
+impl Write for TlsStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut session = self.session.lock().unwrap();
+        let n = session.write(buf)?;
+        while session.wants_write() {
+            session.write_tls(&mut self.sock)?;
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let mut session = self.session.lock().unwrap();
+        session.flush()?;
+        while session.wants_write() {
+            session.write_tls(&mut self.sock)?;
+        }
+        self.sock.flush()
+    }
+}
+
+fn load_certs(file_name: &str) -> Result<Vec<Certificate>> {
+    let mut reader = BufReader::new(File::open(file_name)?);
+    let certs =
+        pemfile::certs(&mut reader).map_err(|_| format_err!("invalid cert file {}", file_name))?;
+    ensure!(!certs.is_empty(), "no cert found in {}", file_name);
+    Ok(certs)
+}
+
+fn load_private_key(file_name: &str) -> Result<PrivateKey> {
+    let mut reader = BufReader::new(File::open(file_name)?);
+    let mut keys = pemfile::pkcs8_private_keys(&mut reader)
+        .map_err(|_| format_err!("invalid key file {}", file_name))?;
+    if keys.is_empty() {
+        let mut reader = BufReader::new(File::open(file_name)?);
+        keys = pemfile::rsa_private_keys(&mut reader)
+            .map_err(|_| format_err!("invalid key file {}", file_name))?;
+    }
+    match keys.pop() {
+        Some(key) => Ok(key),
+        None => bail!("no private key found in {}", file_name),
+    }
+}
+
+/// the server side tls config from the pem encoded cert chain and private key
+pub fn load_server_config(cert_file: &str, key_file: &str) -> Result<Arc<ServerConfig>> {
+    let certs = load_certs(cert_file)?;
+    let key = load_private_key(key_file)?;
+    let mut config = ServerConfig::new(NoClientAuth::new());
+    config.set_single_cert(certs, key)?;
+    Ok(Arc::new(config))
+}
+
+/// the client side tls config, the peer certs are verified against the
+/// given ca file or the well known roots if not set
+pub fn load_client_config(ca_file: Option<&str>) -> Result<Arc<ClientConfig>> {
+    let mut config = ClientConfig::new();
+    match ca_file {
+        Some(file_name) => {
+            let mut reader = BufReader::new(File::open(file_name)?);
+            let (valid, _) = config
+                .root_store
+                .add_pem_file(&mut reader)
+                .map_err(|_| format_err!("invalid ca file {}", file_name))?;
+            ensure!(valid > 0, "no ca cert found in {}", file_name);
+        }
+        None => config
+            .root_store
+            .add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS),
+    }
+    Ok(Arc::new(config))
+}
+
+/// base64 encoded sha256 of the der encoded cert, used to pin the peer cert
+pub fn get_cert_fingerprint(cert: &Certificate) -> String {
+    base64::encode(&Sha256::digest(&cert.0))
+}
+
+// accept the peer only if it presents the pinned cert, the ca and name are not checked
+struct PinnedCertVerifier {
+    fingerprint: String,
+}
+
+impl ServerCertVerifier for PinnedCertVerifier {
+    fn verify_server_cert(
+        &self,
+        _roots: &RootCertStore,
+        presented_certs: &[Certificate],
+        _dns_name: webpki::DNSNameRef,
+        _ocsp_response: &[u8],
+    ) -> ::std::result::Result<ServerCertVerified, TLSError> {
+        match presented_certs.first() {
+            Some(cert) if get_cert_fingerprint(cert) == self.fingerprint => {
+                Ok(ServerCertVerified::assertion())
+            }
+            Some(_) => Err(TLSError::General(
+                "peer cert doesn't match the pin".to_owned(),
+            )),
+            None => Err(TLSError::NoCertificatesPresented),
+        }
+    }
+}
+
+fn pinned_client_config(fingerprint: &str) -> Arc<ClientConfig> {
+    let mut config = ClientConfig::new();
+    config
+        .dangerous()
+        .set_certificate_verifier(Arc::new(PinnedCertVerifier {
+            fingerprint: fingerprint.to_owned(),
+        }));
+    Arc::new(config)
+}
+
+/// do the server side tls handshake on the accepted socket
+pub fn accept(sock: TcpStream, config: &Arc<ServerConfig>) -> Result<WsStream> {
+    let session = ServerSession::new(config);
+    Ok(WsStream::Tls(TlsStream::new(sock, session)?))
+}
+
+/// do the client side tls handshake, a pinned host is verified by its
+/// fingerprint in `pinned_certs`, the others by `config`
+pub fn connect(
+    sock: TcpStream,
+    host: &str,
+    config: &Arc<ClientConfig>,
+    pinned_certs: &HashMap<String, String>,
+) -> Result<WsStream> {
+    let (config, dns_name) = match pinned_certs.get(host) {
+        // the name is not checked for a pinned cert, while ip is not a valid dns name
+        Some(fingerprint) => (pinned_client_config(fingerprint), "localhost"),
+        None => (config.clone(), host),
+    };
+    let dns_name = webpki::DNSNameRef::try_from_ascii_str(dns_name)
+        .map_err(|_| format_err!("{} is not a valid dns name for tls", host))?;
+    let session = ClientSession::new(&config, dns_name);
+    Ok(WsStream::Tls(TlsStream::new(sock, session)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use may::net::TcpListener;
+
+    const CERT_FILE: &str = "src/network/testdata/localhost.crt";
+    const KEY_FILE: &str = "src/network/testdata/localhost.key";
+
+    // start a tls echo server on loopback and return its port
+    fn start_echo_server() -> u16 {
+        let config = load_server_config(CERT_FILE, KEY_FILE).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        go!(move || {
+            let (sock, _) = listener.accept().unwrap();
+            let mut stream = match accept(sock, &config) {
+                Ok(stream) => stream,
+                Err(_) => return,
+            };
+            let mut buf = [0u8; 5];
+            stream.read_exact(&mut buf).unwrap();
+            stream.write_all(&buf).unwrap();
+            stream.flush().unwrap();
+        });
+        port
+    }
+
+    fn send_hello(
+        port: u16,
+        config: &Arc<ClientConfig>,
+        pinned_certs: &HashMap<String, String>,
+    ) -> Result<Vec<u8>> {
+        let sock = TcpStream::connect(("127.0.0.1", port))?;
+        let mut stream = connect(sock, "localhost", config, pinned_certs)?;
+        // write on one handle and read on the other like the websocket does
+        let mut reader = stream.try_clone()?;
+        stream.write_all(b"hello")?;
+        stream.flush()?;
+        let mut buf = vec![0u8; 5];
+        reader.read_exact(&mut buf)?;
+        Ok(buf)
+    }
+
+    // the may sockets only work in coroutine context, like in the hub
+    fn echo(
+        port: u16,
+        config: &Arc<ClientConfig>,
+        pinned_certs: &HashMap<String, String>,
+    ) -> Result<Vec<u8>> {
+        let config = config.clone();
+        let pinned_certs = pinned_certs.clone();
+        go!(move || send_hello(port, &config, &pinned_certs))
+            .join()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_tls_with_ca_file() {
+        ::test_utils::init_coroutines();
+        let config = load_client_config(Some(CERT_FILE)).unwrap();
+        let port = start_echo_server();
+        assert_eq!(echo(port, &config, &HashMap::new()).unwrap(), b"hello");
+
+        // the self-signed cert is not trusted by the well known roots
+        let config = load_client_config(None).unwrap();
+        let port = start_echo_server();
+        assert!(echo(port, &config, &HashMap::new()).is_err());
+    }
+
+    #[test]
+    fn test_tls_with_pinned_cert() {
+        ::test_utils::init_coroutines();
+        let cert = load_certs(CERT_FILE).unwrap().remove(0);
+        let config = load_client_config(None).unwrap();
+
+        let mut pinned_certs = HashMap::new();
+        pinned_certs.insert("localhost".to_owned(), get_cert_fingerprint(&cert));
+        let port = start_echo_server();
+        assert_eq!(echo(port, &config, &pinned_certs).unwrap(), b"hello");
+
+        pinned_certs.insert("localhost".to_owned(), base64::encode(&[0u8; 32]));
+        let port = start_echo_server();
+        assert!(echo(port, &config, &pinned_certs).is_err());
+    }
+}
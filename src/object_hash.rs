@@ -81,7 +81,10 @@ fn get_checksum(data: &[u8]) -> BitVec {
 }
 
 pub fn is_chash_valid(encoded: String) -> Result<bool> {
-    let chash = base32::decode(base32::Alphabet::RFC4648 { padding: true }, &encoded).unwrap();
+    let chash = match base32::decode(base32::Alphabet::RFC4648 { padding: true }, &encoded) {
+        Some(chash) => chash,
+        None => return Ok(false),
+    };
 
     let chash = BitVec::from_bytes(&chash);
     let mut checksum = BitVec::new();
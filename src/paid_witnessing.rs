@@ -40,7 +40,7 @@ pub fn calc_witness_earnings(
     )
 }
 
-fn get_max_spendable_mci_for_last_ball_mci(last_ball_mci: u32) -> Option<u32> {
+pub fn get_max_spendable_mci_for_last_ball_mci(last_ball_mci: u32) -> Option<u32> {
     last_ball_mci.checked_sub(1 + config::COUNT_MC_BALLS_FOR_PAID_WITNESSING)
 }
 
@@ -154,8 +154,7 @@ fn build_paid_witnesses(
         "INSERT INTO paid_witness_events_tmp (unit, address, delay) VALUES {}",
         value_list
     );
-    let mut stmt = db.prepare(&sql)?;
-    stmt.insert(&[])?;
+    db.execute(&sql, &[])?;
 
     //update count paid witnesses
     let mut stmt = db.prepare_cached("UPDATE balls SET count_paid_witnesses=? WHERE unit=?")?;
@@ -245,7 +244,7 @@ fn build_paid_witnesses_for_main_chain_index(db: &Connection, main_chain_index:
             WHERE main_chain_index=? \
             GROUP BY address"
         )?;
-        stmt.insert(&[&main_chain_index])?;
+        stmt.execute(&[&main_chain_index])?;
     }
 
     Ok(())
@@ -261,7 +260,10 @@ fn build_paid_witnesses_till_main_chain_index(
          WHERE count_paid_witnesses IS NULL",
     )?;
 
-    let mut main_chain_index = stmt.query_row(&[], |row| row.get(0))?;
+    let mut main_chain_index = match stmt.query_row(&[], |row| row.get::<_, Option<u32>>(0))? {
+        Some(mci) => mci,
+        None => return Ok(()),
+    };
     while main_chain_index <= to_main_chain_index {
         build_paid_witnesses_for_main_chain_index(db, main_chain_index)?;
         main_chain_index = main_chain_index + 1;
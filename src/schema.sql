@@ -0,0 +1,295 @@
+CREATE TABLE units (
+	unit CHAR(44) NOT NULL PRIMARY KEY,
+	creation_date TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+	version VARCHAR(3) NOT NULL DEFAULT '1.0',
+	alt VARCHAR(3) NOT NULL DEFAULT '1',
+	witness_list_unit CHAR(44) NULL,
+	last_ball_unit CHAR(44) NULL,
+	content_hash CHAR(44) NULL,
+	headers_commission INT NOT NULL,
+	payload_commission INT NOT NULL,
+	is_free TINYINT NOT NULL DEFAULT 1,
+	is_on_main_chain TINYINT NOT NULL DEFAULT 0,
+	main_chain_index INT NULL,
+	latest_included_mc_index INT NULL,
+	level INT NULL,
+	witnessed_level INT NULL,
+	is_stable TINYINT NOT NULL DEFAULT 0,
+	sequence TEXT CHECK (sequence IN('good','temp-bad','final-bad')) NOT NULL DEFAULT 'good',
+	best_parent_unit CHAR(44) NULL,
+	CONSTRAINT unitsByLastBallUnit FOREIGN KEY (last_ball_unit) REFERENCES units(unit),
+	FOREIGN KEY (best_parent_unit) REFERENCES units(unit),
+	CONSTRAINT unitsByWitnessListUnit FOREIGN KEY (witness_list_unit) REFERENCES units(unit)
+);
+CREATE INDEX byLB ON units(last_ball_unit);
+CREATE INDEX byBestParent ON units(best_parent_unit);
+CREATE INDEX byWL ON units(witness_list_unit);
+CREATE INDEX byMainChain ON units(is_on_main_chain);
+CREATE INDEX byMcIndex ON units(main_chain_index);
+CREATE INDEX byLimci ON units(latest_included_mc_index);
+CREATE INDEX byLevel ON units(level);
+CREATE INDEX byFree ON units(is_free);
+CREATE INDEX byStableMci ON units(is_stable, main_chain_index);
+
+CREATE TABLE balls (
+	ball CHAR(44) NOT NULL PRIMARY KEY,
+	creation_date TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+	unit CHAR(44) NOT NULL UNIQUE,
+	count_paid_witnesses TINYINT NULL,
+	FOREIGN KEY (unit) REFERENCES units(unit)
+);
+CREATE INDEX byCountPaidWitnesses ON balls(count_paid_witnesses);
+
+CREATE TABLE skiplist_units (
+	unit CHAR(44) NOT NULL,
+	skiplist_unit CHAR(44) NOT NULL,
+	PRIMARY KEY (unit, skiplist_unit),
+	FOREIGN KEY (unit) REFERENCES units(unit),
+	FOREIGN KEY (skiplist_unit) REFERENCES units(unit)
+);
+CREATE INDEX bySkiplistUnit ON skiplist_units(skiplist_unit);
+
+CREATE TABLE parenthoods (
+	child_unit CHAR(44) NOT NULL,
+	parent_unit CHAR(44) NOT NULL,
+	PRIMARY KEY (parent_unit, child_unit),
+	CONSTRAINT parenthoodsByChild FOREIGN KEY (child_unit) REFERENCES units(unit),
+	CONSTRAINT parenthoodsByParent FOREIGN KEY (parent_unit) REFERENCES units(unit)
+);
+CREATE INDEX byChildUnit ON parenthoods(child_unit);
+
+CREATE TABLE definitions (
+	definition_chash CHAR(32) NOT NULL PRIMARY KEY,
+	definition TEXT NOT NULL,
+	has_references TINYINT NOT NULL
+);
+
+CREATE TABLE addresses (
+	address CHAR(32) NOT NULL PRIMARY KEY,
+	creation_date TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE TABLE unit_authors (
+	unit CHAR(44) NOT NULL,
+	address CHAR(32) NOT NULL,
+	definition_chash CHAR(32) NULL,
+	PRIMARY KEY (unit, address),
+	FOREIGN KEY (unit) REFERENCES units(unit),
+	CONSTRAINT unitAuthorsByAddress FOREIGN KEY (address) REFERENCES addresses(address),
+	FOREIGN KEY (definition_chash) REFERENCES definitions(definition_chash)
+);
+CREATE INDEX byDefinitionChash ON unit_authors(definition_chash);
+CREATE INDEX unitAuthorsIndexByAddress ON unit_authors(address);
+
+CREATE TABLE authentifiers (
+	unit CHAR(44) NOT NULL,
+	address CHAR(32) NOT NULL,
+	path VARCHAR(40) NOT NULL,
+	authentifier VARCHAR(4096) NOT NULL,
+	PRIMARY KEY (unit, address, path),
+	FOREIGN KEY (unit) REFERENCES units(unit),
+	CONSTRAINT authentifiersByAddress FOREIGN KEY (address) REFERENCES addresses(address)
+);
+CREATE INDEX authentifiersIndexByAddress ON authentifiers(address);
+
+CREATE TABLE unit_witnesses (
+	unit CHAR(44) NOT NULL,
+	address VARCHAR(32) NOT NULL,
+	PRIMARY KEY (unit, address),
+	FOREIGN KEY (unit) REFERENCES units(unit)
+);
+CREATE INDEX byAddress ON unit_witnesses(address);
+
+CREATE TABLE earned_headers_commission_recipients (
+	unit CHAR(44) NOT NULL,
+	address VARCHAR(32) NOT NULL,
+	earned_headers_commission_share INT NOT NULL,
+	PRIMARY KEY (unit, address),
+	FOREIGN KEY (unit) REFERENCES units(unit)
+);
+CREATE INDEX earnedbyAddress ON earned_headers_commission_recipients(address);
+
+CREATE TABLE messages (
+	unit CHAR(44) NOT NULL,
+	message_index TINYINT NOT NULL,
+	app VARCHAR(30) NOT NULL,
+	payload_location TEXT CHECK (payload_location IN ('inline','uri','none')) NOT NULL,
+	payload_hash VARCHAR(44) NOT NULL,
+	payload TEXT NULL,
+	payload_uri_hash VARCHAR(44) NULL,
+	payload_uri VARCHAR(500) NULL,
+	PRIMARY KEY (unit, message_index),
+	FOREIGN KEY (unit) REFERENCES units(unit)
+);
+
+CREATE TABLE spend_proofs (
+	unit CHAR(44) NOT NULL,
+	message_index TINYINT NOT NULL,
+	spend_proof_index TINYINT NOT NULL,
+	spend_proof CHAR(44) NOT NULL,
+	address CHAR(32) NOT NULL,
+	PRIMARY KEY (unit, message_index, spend_proof_index),
+	UNIQUE (spend_proof, unit),
+	FOREIGN KEY (unit) REFERENCES units(unit),
+	CONSTRAINT spendProofsByAddress FOREIGN KEY (address) REFERENCES addresses(address)
+);
+CREATE INDEX spendProofsIndexByAddress ON spend_proofs(address);
+
+CREATE TABLE address_definition_changes (
+	unit CHAR(44) NOT NULL,
+	message_index TINYINT NOT NULL,
+	address CHAR(32) NOT NULL,
+	definition_chash CHAR(32) NOT NULL,
+	PRIMARY KEY (unit, address),
+	FOREIGN KEY (unit) REFERENCES units(unit),
+	CONSTRAINT addressDefinitionChangesByAddress FOREIGN KEY (address) REFERENCES addresses(address)
+);
+CREATE INDEX addressDefinitionChangesIndexByAddress ON address_definition_changes(address);
+
+CREATE TABLE data_feeds (
+	unit CHAR(44) NOT NULL,
+	message_index TINYINT NOT NULL,
+	feed_name VARCHAR(64) NOT NULL,
+	value VARCHAR(64) NULL,
+	int_value BIGINT NULL,
+	PRIMARY KEY (unit, feed_name),
+	FOREIGN KEY (unit) REFERENCES units(unit)
+);
+CREATE INDEX byNameStringValue ON data_feeds(feed_name, value);
+CREATE INDEX byNameIntValue ON data_feeds(feed_name, int_value);
+
+CREATE TABLE inputs (
+	unit CHAR(44) NOT NULL,
+	message_index TINYINT NOT NULL,
+	input_index TINYINT NOT NULL,
+	asset CHAR(44) NULL,
+	denomination INT NOT NULL DEFAULT 1,
+	is_unique TINYINT NULL DEFAULT 1,
+	type TEXT CHECK (type IN('transfer','headers_commission','witnessing','issue')) NOT NULL,
+	src_unit CHAR(44) NULL,
+	src_message_index TINYINT NULL,
+	src_output_index TINYINT NULL,
+	from_main_chain_index INT NULL,
+	to_main_chain_index INT NULL,
+	serial_number BIGINT NULL,
+	amount BIGINT NULL,
+	address CHAR(32) NOT NULL,
+	PRIMARY KEY (unit, message_index, input_index),
+	UNIQUE (src_unit, src_message_index, src_output_index, is_unique),
+	UNIQUE (type, from_main_chain_index, address, is_unique),
+	UNIQUE (asset, denomination, serial_number, address, is_unique),
+	FOREIGN KEY (unit) REFERENCES units(unit),
+	CONSTRAINT inputsBySrcUnit FOREIGN KEY (src_unit) REFERENCES units(unit),
+	CONSTRAINT inputsByAddress FOREIGN KEY (address) REFERENCES addresses(address)
+);
+CREATE INDEX inputsIndexByAddress ON inputs(address);
+CREATE INDEX inputsIndexByAddressTypeToMci ON inputs(address, type, to_main_chain_index);
+
+CREATE TABLE outputs (
+	output_id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+	unit CHAR(44) NOT NULL,
+	message_index TINYINT NOT NULL,
+	output_index TINYINT NOT NULL,
+	asset CHAR(44) NULL,
+	denomination INT NOT NULL DEFAULT 1,
+	address CHAR(32) NULL,
+	amount BIGINT NOT NULL,
+	blinding CHAR(16) NULL,
+	output_hash CHAR(44) NULL,
+	is_serial TINYINT NULL,
+	is_spent TINYINT NOT NULL DEFAULT 0,
+	UNIQUE (unit, message_index, output_index),
+	FOREIGN KEY (unit) REFERENCES units(unit)
+);
+CREATE INDEX outputsByAddressSpent ON outputs(address, is_spent);
+CREATE INDEX outputsIndexByAsset ON outputs(asset);
+CREATE INDEX outputsIsSerial ON outputs(is_serial);
+
+CREATE TABLE headers_commission_contributions (
+	unit CHAR(44) NOT NULL,
+	address CHAR(32) NOT NULL,
+	amount BIGINT NOT NULL,
+	creation_date TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+	PRIMARY KEY (unit, address),
+	FOREIGN KEY (unit) REFERENCES units(unit)
+);
+CREATE INDEX hccbyAddress ON headers_commission_contributions(address);
+
+CREATE TABLE headers_commission_outputs (
+	main_chain_index INT NOT NULL,
+	address CHAR(32) NOT NULL,
+	amount BIGINT NOT NULL,
+	is_spent TINYINT NOT NULL DEFAULT 0,
+	creation_date TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+	PRIMARY KEY (main_chain_index, address)
+);
+CREATE INDEX hcobyAddressSpent ON headers_commission_outputs(address, is_spent);
+
+CREATE TABLE paid_witness_events (
+	unit CHAR(44) NOT NULL,
+	address CHAR(32) NOT NULL,
+	delay TINYINT NULL,
+	PRIMARY KEY (unit, address),
+	FOREIGN KEY (unit) REFERENCES units(unit),
+	FOREIGN KEY (address) REFERENCES addresses(address)
+);
+CREATE INDEX pweIndexByAddress ON paid_witness_events(address);
+
+CREATE TABLE witnessing_outputs (
+	main_chain_index INT NOT NULL,
+	address CHAR(32) NOT NULL,
+	amount BIGINT NOT NULL,
+	is_spent TINYINT NOT NULL DEFAULT 0,
+	creation_date TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+	PRIMARY KEY (main_chain_index, address),
+	FOREIGN KEY (address) REFERENCES addresses(address)
+);
+CREATE INDEX byWitnessAddressSpent ON witnessing_outputs(address, is_spent);
+
+-- joints whose parents are not known yet
+CREATE TABLE unhandled_joints (
+	unit CHAR(44) NOT NULL PRIMARY KEY,
+	peer VARCHAR(100) NOT NULL,
+	json TEXT NOT NULL,
+	creation_date TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE TABLE dependencies (
+	unit CHAR(44) NOT NULL,
+	depends_on_unit CHAR(44) NOT NULL,
+	creation_date TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+	UNIQUE (depends_on_unit, unit)
+);
+CREATE INDEX depbyUnit ON dependencies(unit);
+
+CREATE TABLE archived_joints (
+	unit CHAR(44) NOT NULL PRIMARY KEY,
+	reason TEXT CHECK (reason IN('uncovered', 'voided')) NOT NULL,
+	json TEXT NOT NULL,
+	creation_date TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+-- bad joints are recorded by unit, or by joint hash when the unit hash is wrong
+CREATE TABLE known_bad_joints (
+	joint CHAR(44) NULL UNIQUE,
+	unit CHAR(44) NULL UNIQUE,
+	json TEXT NOT NULL,
+	error TEXT NOT NULL,
+	creation_date TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+-- catchup state
+CREATE TABLE hash_tree_balls (
+	ball_index INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+	ball CHAR(44) NOT NULL UNIQUE,
+	unit CHAR(44) NOT NULL UNIQUE
+);
+
+CREATE TABLE catchup_chain_balls (
+	member_index INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+	ball CHAR(44) NOT NULL UNIQUE
+);
+
+CREATE TABLE my_witnesses (
+	address VARCHAR(32) NOT NULL PRIMARY KEY
+);
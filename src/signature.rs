@@ -32,6 +32,13 @@ pub fn sign(hash: &[u8], priv_key: &[u8]) -> Result<String> {
     Ok(base64::encode(&sig[..]))
 }
 
+/// return the base64 string of the compressed public key of the priv_key
+pub fn get_pub_key(priv_key: &[u8]) -> Result<String> {
+    let priv_key = key::SecretKey::from_slice(&SECP256K1, priv_key)?;
+    let pub_key = key::PublicKey::from_secret_key(&SECP256K1, &priv_key)?;
+    Ok(base64::encode(&pub_key.serialize()[..]))
+}
+
 /// verify the bas64 string signiture with the hash and pub key (a bas64 string)
 pub fn verify(hash: &[u8], b64_sig: &str, b64_pub_key: &str) -> Result<()> {
     let msg = Message::from_slice(hash)?;
@@ -42,3 +49,33 @@ pub fn verify(hash: &[u8], b64_sig: &str, b64_pub_key: &str) -> Result<()> {
     let signature = Signature::from_compact(&SECP256K1, sig)?;
     Ok(SECP256K1.verify(&msg, &signature, &pub_key)?)
 }
+
+#[test]
+fn test_sign_and_verify() {
+    let hash = "KLop9582tzXZJbytWjiWLcnpEdvJI7mUymbnUPXweOM=";
+    let priv_key = "jQGnkLnZlX2DjBUd8JKgHgw23zSdRL/Azx3foi/WqvE=";
+    let sig =
+        "YCdh5Q6jOiKQy2R9mQwKJ6tBnq31VFZX2dkb7Ypr+/5z6jj4GLEFT9RtryC4+mSILtKKLeN9YnBmYI4Xa+4tDw==";
+
+    let hash = base64::decode(hash).unwrap();
+    let priv_key = base64::decode(priv_key).unwrap();
+    assert_eq!(sign(&hash, &priv_key).unwrap(), sig);
+
+    let hash = "uPQs4TwLtDGRAdH8sbIJ1ZyWpEmwHWRAhXpamODZ7Kk=";
+    let pub_key = "A0qTjB3ZjHf2yT1EIvLrkVAWY8MPSueNcB4GTlKGo/o6";
+    let sig =
+        "up+2Fjhnu4OjJeesBPCgoZE+6ReqQDdnqcjhbq2iaulHjlwKYLcwRrD3udSWdHS57ceQeZ+LVPWYBMWBloAgpA==";
+
+    assert!(verify(&base64::decode(hash).unwrap(), sig, pub_key).is_ok());
+}
+
+#[test]
+fn test_get_pub_key() {
+    let hash = base64::decode("KLop9582tzXZJbytWjiWLcnpEdvJI7mUymbnUPXweOM=").unwrap();
+    let priv_key = base64::decode("jQGnkLnZlX2DjBUd8JKgHgw23zSdRL/Azx3foi/WqvE=").unwrap();
+
+    let pub_key = get_pub_key(&priv_key).unwrap();
+    assert_eq!(pub_key.len(), 44);
+    let sig = sign(&hash, &priv_key).unwrap();
+    assert!(verify(&hash, &sig, &pub_key).is_ok());
+}
@@ -19,16 +19,25 @@ pub struct Author {
 // TODO: Input struct is from type
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Input {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub address: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub amount: Option<i64>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub from_main_chain_index: Option<u32>,
-    pub message_index: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message_index: Option<u32>,
     #[serde(skip_serializing_if = "Option::is_none")]
     #[serde(rename = "type")]
     pub kind: Option<String>,
-    pub output_index: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub output_index: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub serial_number: Option<u32>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub to_main_chain_index: Option<u32>,
-    pub unit: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub unit: Option<String>,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -52,8 +61,19 @@ pub struct Output {
     pub amount: i64,
 }
 
+/// payload of a message, its shape depends on the app of the message
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Payload {
+    Payment(Payment),
+    // payload of `text`
+    Text(String),
+    // payload of `data_feed`, `address_definition_change` and the other apps
+    Other(Value),
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
-pub struct Payload {
+pub struct Payment {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub address: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -68,8 +88,8 @@ pub struct Payload {
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct HeaderCommissionShare {
-    address: String,
-    earned_headers_commission_share: u32,
+    pub address: String,
+    pub earned_headers_commission_share: u32,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -110,13 +130,13 @@ pub struct Unit {
     pub witness_list_unit: Option<String>,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 /// internally used struct
 pub struct StaticUnitProperty {
     pub level: u32,
     pub witnessed_level: u32,
-    pub best_parent_unit: String,
-    pub witness_list_unit: String,
+    pub best_parent_unit: Option<String>,
+    pub witness_list_unit: Option<String>,
 }
 
 #[derive(Debug)]
@@ -124,7 +144,7 @@ pub struct StaticUnitProperty {
 pub struct UnitProps {
     pub unit: String,
     pub level: u32,
-    pub latest_included_mc_index: u32,
+    pub latest_included_mc_index: Option<u32>,
     pub main_chain_index: u32,
     pub is_on_main_chain: u32,
     pub is_free: u32,
@@ -134,7 +154,7 @@ pub struct UnitProps {
 impl Unit {
     pub fn is_genesis_unit(&self) -> bool {
         match self.unit {
-            Some(ref hash) if hash == ::config::GENESIS_UNIT => true,
+            Some(ref hash) => *hash == ::config::get_genesis_unit(),
             _ => false,
         }
     }
@@ -331,3 +351,68 @@ fn test_unit_hash() {
     assert_eq!(unit.get_header_size(), 344);
     assert_eq!(unit.get_payload_size(), 157);
 }
+
+#[test]
+fn test_author_definition() {
+    use serde_json;
+    let data = r#"
+{
+    "version": "1.0",
+    "alt": "1",
+    "messages": [
+        {
+            "app": "payment",
+            "payload_location": "inline",
+            "payload_hash": "5CYeTTa4VQxgF4b1Tn33NBlKilJadddwBMLvtp1HIus=",
+            "payload": {
+                "outputs": [
+                    {
+                        "address": "7JXBJQPQC3466UPK7C6ABA6VVU6YFYAI",
+                        "amount": 10000
+                    },
+                    {
+                        "address": "JERTY5XNENMHYQW7NVBXUB5CU3IDODA3",
+                        "amount": 99989412
+                    }
+                ],
+                "inputs": [
+                    {
+                        "unit": "lQCxxsMslXLzQKybX2KArOGho8XuNf1Lpds2abdf8O4=",
+                        "message_index": 0,
+                        "output_index": 1
+                    }
+                ]
+            }
+        }
+    ],
+    "authors": [
+        {
+            "address": "JERTY5XNENMHYQW7NVBXUB5CU3IDODA3",
+            "authentifiers": {
+                "r": "tHLxvXNYVwDnQg3N4iNHtHZ4mXvqRW+ZMPkQadev6MpAWbEPVcIpme1Vz1nyskWYgueREZoEbQeEWtC/oCQbxQ=="
+            },
+            "definition": [
+                "sig",
+                {
+                    "pubkey": "A0gKwkLedQgzm32JtEo6KmuRcyZa3beikS3xfrwdXAMU"
+                }
+            ]
+        }
+    ],
+    "parent_units": [
+        "uPbobEuZL+FY1ujTNiYZnM9lgC3xysxuDIpSbvnmbac="
+    ],
+    "last_ball": "oiIA6Y+87fk6/QyrbOlwqsQ/LLr82Rcuzcr1G/GoHlA=",
+    "last_ball_unit": "vxrlKyY517Z+BGMNG35ExiQsYv3ncp/KU414SqXKXTk=",
+    "witness_list_unit": "MtzrZeOHHjqVZheuLylf0DX7zhp10nBsQX5e/+cA3PQ=",
+    "headers_commission": 391,
+    "payload_commission": 197
+}"#;
+
+    let u: Unit = serde_json::from_str(data).unwrap();
+    assert_eq!(u.authors[0].definition[0], json!("sig"));
+    assert_eq!(
+        u.authors[0].definition[1],
+        json!({"pubkey": "A0gKwkLedQgzm32JtEo6KmuRcyZa3beikS3xfrwdXAMU"})
+    );
+}
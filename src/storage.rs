@@ -1,25 +1,38 @@
 use std::collections::{HashMap, HashSet};
 
+use cache::{Cache, CacheStats};
 use error::Result;
+use graph;
 use joint::Joint;
 use may::sync::RwLock;
 use rusqlite::Connection;
+use serde_json::{self, Value};
 use spec::*;
 
 // global data that store unit info
 lazy_static! {
-    static ref CACHED_UNIT: RwLock<HashMap<String, StaticUnitProperty>> = RwLock::new(HashMap::new());
+    static ref CACHED_UNIT: RwLock<Cache<String, StaticUnitProperty>> =
+        RwLock::new(Cache::new(::config::get_settings().max_cached_units));
+    static ref CACHED_AUTHORS: RwLock<Cache<String, Vec<String>>> =
+        RwLock::new(Cache::new(::config::get_settings().max_cached_units));
+    static ref CACHED_WITNESS_LIST: RwLock<Cache<String, Vec<String>>> =
+        RwLock::new(Cache::new(::config::get_settings().max_cached_units));
+    // props of unstable units, only refreshed from committed data after the main chain changed
+    static ref CACHED_UNSTABLE_UNIT: RwLock<Cache<String, graph::UnitProps>> =
+        RwLock::new(Cache::new(::config::get_settings().max_cached_unstable_units));
     static ref KNOWN_UNIT: RwLock<HashSet<String>> = RwLock::new(HashSet::new());
+    // units whose save is not committed yet, they are cached only after the commit
+    static ref UNCOMMITTED_UNIT: RwLock<HashSet<String>> = RwLock::new(HashSet::new());
 }
 
 #[inline]
 pub fn is_genesis_unit(unit: &String) -> bool {
-    unit == ::config::GENESIS_UNIT
+    *unit == ::config::get_genesis_unit()
 }
 
 pub fn is_genesis_ball(ball: &String) -> bool {
-    let _ = ball;
-    unimplemented!()
+    let genesis_unit = ::config::get_genesis_unit();
+    ball == &::object_hash::get_ball_hash(&genesis_unit, &Vec::new(), &Vec::new(), false)
 }
 
 pub fn is_known_unit(unit: &String) -> bool {
@@ -38,22 +51,135 @@ pub fn set_unit_is_known(unit: &String) {
     g.insert(unit.to_owned());
 }
 
+// the unit is being saved, reads within the save transaction must not cache it
+pub fn set_unit_is_uncommitted(unit: &String) {
+    let mut g = UNCOMMITTED_UNIT.write().unwrap();
+    g.insert(unit.to_owned());
+}
+
+fn is_uncommitted_unit(unit: &String) -> bool {
+    let g = UNCOMMITTED_UNIT.read().unwrap();
+    g.contains(unit)
+}
+
+// drop everything cached about the unit, must be called when the unit is purged or archived
 pub fn forget_unit(unit: &String) {
     {
         let mut g = KNOWN_UNIT.write().unwrap();
         g.remove(unit);
     }
-
     {
-        let mut g = CACHED_UNIT.write().unwrap();
+        let mut g = UNCOMMITTED_UNIT.write().unwrap();
+        g.remove(unit);
+    }
+    {
+        let mut g = CACHED_AUTHORS.write().unwrap();
+        g.remove(unit);
+    }
+    {
+        let mut g = CACHED_WITNESS_LIST.write().unwrap();
+        g.remove(unit);
+    }
+    {
+        let mut g = CACHED_UNSTABLE_UNIT.write().unwrap();
         g.remove(unit);
     }
 
-    unimplemented!()
+    let mut g = CACHED_UNIT.write().unwrap();
+    g.remove(unit);
+}
+
+// fill the caches with a newly saved unit, must be called after the transaction is committed,
+// the entries replace whatever was cached for the unit before
+pub fn cache_saved_unit(db: &Connection, unit: &Unit) -> Result<()> {
+    let unit_hash = match unit.unit {
+        Some(ref hash) => hash,
+        None => bail!("can't cache a unit without hash"),
+    };
+    UNCOMMITTED_UNIT.write().unwrap().remove(unit_hash);
+
+    let mut authors = unit
+        .authors
+        .iter()
+        .map(|author| author.address.clone())
+        .collect::<Vec<_>>();
+    authors.sort();
+    CACHED_AUTHORS
+        .write()
+        .unwrap()
+        .insert(unit_hash.clone(), authors);
+
+    if let Some(ref witnesses) = unit.witnesses {
+        let mut witnesses = witnesses.clone();
+        witnesses.sort();
+        CACHED_WITNESS_LIST
+            .write()
+            .unwrap()
+            .insert(unit_hash.clone(), witnesses);
+    }
+
+    // level and witnessed level are only known after saving
+    let props = read_static_unit_property_directly(db, unit_hash)?;
+    CACHED_UNIT
+        .write()
+        .unwrap()
+        .insert(unit_hash.clone(), props);
+    set_unit_is_known(unit_hash);
+    Ok(())
+}
+
+// the main chain is about to change, props of unstable units are no longer valid
+pub fn forget_unstable_units() {
+    let mut g = CACHED_UNSTABLE_UNIT.write().unwrap();
+    g.clear();
+}
+
+// reload props of all unstable units, must be called after the main chain changes are committed
+pub fn refresh_unstable_units(db: &Connection) -> Result<()> {
+    let mut stmt = db.prepare_cached(
+        "SELECT unit, level, latest_included_mc_index, main_chain_index, is_on_main_chain, is_free \
+         FROM units WHERE is_stable=0",
+    )?;
+    let rows = stmt.query_map(&[], |row| graph::UnitProps {
+        unit: row.get(0),
+        level: row.get(1),
+        latest_included_mc_index: row.get(2),
+        main_chain_index: row.get(3),
+        is_on_main_chain: row.get(4),
+        is_free: row.get(5),
+    })?;
+
+    let mut g = CACHED_UNSTABLE_UNIT.write().unwrap();
+    g.clear();
+    for row in rows {
+        let props = row?;
+        g.insert(props.unit.clone(), props);
+    }
+    Ok(())
+}
+
+#[derive(Debug)]
+pub struct UnitCacheStats {
+    pub static_units: CacheStats,
+    pub authors: CacheStats,
+    pub witness_lists: CacheStats,
+    pub unstable_units: CacheStats,
+}
+
+pub fn get_cache_stats() -> UnitCacheStats {
+    UnitCacheStats {
+        static_units: CACHED_UNIT.read().unwrap().get_stats(),
+        authors: CACHED_AUTHORS.read().unwrap().get_stats(),
+        witness_lists: CACHED_WITNESS_LIST.read().unwrap().get_stats(),
+        unstable_units: CACHED_UNSTABLE_UNIT.read().unwrap().get_stats(),
+    }
 }
 
-// TODO: need to cache in memory
 pub fn read_witness_list(db: &Connection, unit_hash: &String) -> Result<Vec<String>> {
+    if let Some(witnesses) = CACHED_WITNESS_LIST.read().unwrap().get(unit_hash) {
+        return Ok(witnesses);
+    }
+
     let mut stmt =
         db.prepare_cached("SELECT address FROM unit_witnesses WHERE unit=? ORDER BY address")?;
     let rows = stmt.query_map(&[unit_hash], |row| row.get(0))?;
@@ -68,9 +194,23 @@ pub fn read_witness_list(db: &Connection, unit_hash: &String) -> Result<Vec<Stri
             unit_hash
         ));
     }
+
+    if !is_uncommitted_unit(unit_hash) {
+        CACHED_WITNESS_LIST
+            .write()
+            .unwrap()
+            .insert(unit_hash.clone(), names.clone());
+    }
     Ok(names)
 }
 
+// read the witness list of a unit, following witness_list_unit if it has one
+pub fn read_witnesses(db: &Connection, unit_hash: &String) -> Result<Vec<String>> {
+    let mut stmt = db.prepare_cached("SELECT witness_list_unit FROM units WHERE unit=?")?;
+    let witness_list_unit = stmt.query_row(&[unit_hash], |row| row.get::<_, Option<String>>(0))?;
+    read_witness_list(db, witness_list_unit.as_ref().unwrap_or(unit_hash))
+}
+
 pub fn read_last_main_chain_index(db: &Connection) -> Result<u32> {
     let mut stmt = db.prepare_cached("SELECT MAX(main_chain_index) AS last_mc_index FROM units")?;
     let ret = stmt.query_row(&[], |row| row.get_checked(0))?;
@@ -98,17 +238,96 @@ pub fn read_unit_props(db: &Connection, unit_hash: &String) -> Result<UnitProps>
 }
 
 pub fn read_props_of_units(
-    _db: &Connection,
-    _unit_hash: &String,
-    _later_unit_hashes: &[String],
-) -> Result<(UnitProps, Vec<UnitProps>)> {
-    unimplemented!();
+    db: &Connection,
+    unit_hash: &String,
+    later_unit_hashes: &[String],
+) -> Result<(graph::UnitProps, Vec<graph::UnitProps>)> {
+    let is_earlier_in_later_units = later_unit_hashes.contains(unit_hash);
+
+    // only use the cache when all the units are unstable
+    {
+        let g = CACHED_UNSTABLE_UNIT.read().unwrap();
+        if g.contains_key(unit_hash) && later_unit_hashes.iter().all(|u| g.contains_key(u)) {
+            let earlier_unit_props = g.get(unit_hash).unwrap();
+            let later_units_props = later_unit_hashes
+                .iter()
+                .filter_map(|u| g.get(u))
+                .collect::<Vec<_>>();
+            return Ok((earlier_unit_props, later_units_props));
+        }
+    }
+
+    let unit_list = later_unit_hashes
+        .iter()
+        .chain(Some(unit_hash))
+        .map(|s| format!("'{}'", s))
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    let sql = format!(
+        "SELECT unit, level, latest_included_mc_index, main_chain_index, is_on_main_chain, is_free \
+         FROM units WHERE unit IN({})",
+        unit_list
+    );
+    let mut stmt = db.prepare(&sql)?;
+    let rows = stmt.query_map(&[], |row| graph::UnitProps {
+        unit: row.get(0),
+        level: row.get(1),
+        latest_included_mc_index: row.get(2),
+        main_chain_index: row.get(3),
+        is_on_main_chain: row.get(4),
+        is_free: row.get(5),
+    })?;
+
+    let mut earlier_unit_props = None;
+    let mut later_units_props = Vec::new();
+    for row in rows {
+        let props = row?;
+        if &props.unit == unit_hash {
+            earlier_unit_props = Some(props);
+        } else {
+            later_units_props.push(props);
+        }
+    }
+
+    let earlier_unit_props = match earlier_unit_props {
+        Some(props) => props,
+        None => bail!("earlier unit {} not found", unit_hash),
+    };
+    ensure!(
+        later_units_props.len() + is_earlier_in_later_units as usize == later_unit_hashes.len(),
+        "wrong number of rows for earlier {}, later {:?}",
+        unit_hash,
+        later_unit_hashes
+    );
+    if is_earlier_in_later_units {
+        later_units_props.push(earlier_unit_props.clone());
+    }
+
+    Ok((earlier_unit_props, later_units_props))
 }
 
-// TODO: need to cache in memory
 pub fn read_static_unit_property(
     db: &Connection,
     unit_hash: &String,
+) -> Result<StaticUnitProperty> {
+    if let Some(props) = CACHED_UNIT.read().unwrap().get(unit_hash) {
+        return Ok(props);
+    }
+
+    let ret = read_static_unit_property_directly(db, unit_hash)?;
+    if !is_uncommitted_unit(unit_hash) {
+        CACHED_UNIT
+            .write()
+            .unwrap()
+            .insert(unit_hash.clone(), ret.clone());
+    }
+    Ok(ret)
+}
+
+fn read_static_unit_property_directly(
+    db: &Connection,
+    unit_hash: &String,
 ) -> Result<StaticUnitProperty> {
     let mut stmt = db.prepare_cached(
         "SELECT level, witnessed_level, best_parent_unit, witness_list_unit \
@@ -120,29 +339,116 @@ pub fn read_static_unit_property(
         best_parent_unit: row.get(2),
         witness_list_unit: row.get(3),
     })?;
-
     Ok(ret)
 }
 
-// TODO: need to cache in memory
 pub fn read_unit_authors(db: &Connection, unit_hash: &String) -> Result<Vec<String>> {
+    if let Some(authors) = CACHED_AUTHORS.read().unwrap().get(unit_hash) {
+        return Ok(authors);
+    }
+
     let mut stmt =
-        db.prepare_cached("SELECT address FROM unit_witnesses WHERE unit=? ORDER BY address")?;
+        db.prepare_cached("SELECT address FROM unit_authors WHERE unit=? ORDER BY address")?;
     let rows = stmt.query_map(&[unit_hash], |row| row.get(0))?;
     let mut names = Vec::new();
     for name_result in rows {
         names.push(name_result?);
     }
 
-    if names.len() != ::config::COUNT_WITNESSES {
-        return Err(format_err!(
-            "wrong number of witnesses in unit {}",
-            unit_hash
-        ));
+    ensure!(!names.is_empty(), "no authors of unit {}", unit_hash);
+    if !is_uncommitted_unit(unit_hash) {
+        CACHED_AUTHORS
+            .write()
+            .unwrap()
+            .insert(unit_hash.clone(), names.clone());
     }
     Ok(names)
 }
 
+// choose best parent among compatible parents only
+pub fn determine_best_parent(
+    db: &Connection,
+    parent_units: &[String],
+    witness_list_unit: Option<&String>,
+    witnesses: &[String],
+) -> Result<Option<String>> {
+    let parent_list = parent_units
+        .iter()
+        .map(|s| format!("'{}'", s))
+        .collect::<Vec<_>>()
+        .join(", ");
+    let witness_list = witnesses
+        .iter()
+        .map(|s| format!("'{}'", s))
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    let sql = format!(
+        "SELECT unit \
+         FROM units AS parent_units \
+         WHERE unit IN({}) \
+         AND (witness_list_unit=? OR ( \
+         SELECT COUNT(*) \
+         FROM unit_witnesses AS parent_witnesses \
+         WHERE parent_witnesses.unit IN(parent_units.unit, parent_units.witness_list_unit) \
+         AND address IN({}) \
+         )>=?) \
+         ORDER BY witnessed_level DESC, \
+         level-witnessed_level ASC, \
+         unit ASC \
+         LIMIT 1",
+        parent_list, witness_list
+    );
+
+    let min_matching_witnesses =
+        (::config::COUNT_WITNESSES - ::config::MAX_WITNESS_LIST_MUTATIONS) as u32;
+    let mut stmt = db.prepare(&sql)?;
+    let mut rows = stmt.query_map(&[&witness_list_unit, &min_matching_witnesses], |row| {
+        row.get::<_, String>(0)
+    })?;
+    match rows.next() {
+        Some(row) => Ok(Some(row?)),
+        None => Ok(None),
+    }
+}
+
+// return the witnessed level and the best parent of a unit with the given parents
+pub fn determine_witnessed_level_and_best_parent(
+    db: &Connection,
+    parent_units: &[String],
+    witnesses: &[String],
+) -> Result<(u32, Option<String>)> {
+    let best_parent_unit = match determine_best_parent(db, parent_units, None, witnesses)? {
+        Some(unit) => unit,
+        None => return Ok((0, None)),
+    };
+
+    let mut collected_witnesses = Vec::new();
+    let mut start_unit = best_parent_unit.clone();
+    loop {
+        let props = read_static_unit_property(db, &start_unit)?;
+        // genesis
+        if props.level == 0 {
+            return Ok((0, Some(best_parent_unit)));
+        }
+
+        for address in read_unit_authors(db, &start_unit)? {
+            if witnesses.contains(&address) && !collected_witnesses.contains(&address) {
+                collected_witnesses.push(address);
+            }
+        }
+
+        if collected_witnesses.len() >= ::config::MAJORITY_OF_WITNESSES {
+            return Ok((props.level, Some(best_parent_unit)));
+        }
+
+        start_unit = match props.best_parent_unit {
+            Some(unit) => unit,
+            None => bail!("no best parent of unit {}", start_unit),
+        };
+    }
+}
+
 // only need part of it.
 pub struct LastStableMcUnitProps {
     pub unit: String,
@@ -151,15 +457,44 @@ pub struct LastStableMcUnitProps {
 }
 
 pub fn read_last_stable_mc_unit_props(db: &Connection) -> Result<LastStableMcUnitProps> {
-    // TODO:
-    let _ = db;
-    unimplemented!()
+    let mut stmt = db.prepare_cached(
+        "SELECT units.unit, ball, main_chain_index FROM units JOIN balls USING(unit) \
+         WHERE is_on_main_chain=1 AND is_stable=1 ORDER BY main_chain_index DESC LIMIT 1",
+    )?;
+    let mut rows = stmt.query_map(&[], |row| LastStableMcUnitProps {
+        unit: row.get(0),
+        ball: row.get(1),
+        main_chain_index: row.get(2),
+    })?;
+
+    match rows.next() {
+        Some(row) => Ok(row?),
+        None => bail!("no units on stable MC?"),
+    }
+}
+
+pub fn read_last_stable_mc_unit(db: &Connection) -> Result<String> {
+    let mut stmt = db.prepare_cached(
+        "SELECT unit FROM units WHERE is_on_main_chain=1 AND is_stable=1 \
+         ORDER BY main_chain_index DESC LIMIT 1",
+    )?;
+    let mut rows = stmt.query_map(&[], |row| row.get(0))?;
+    match rows.next() {
+        Some(row) => Ok(row?),
+        None => bail!("no units on stable MC?"),
+    }
 }
 
 pub fn read_last_stable_mc_index(db: &Connection) -> Result<u32> {
-    // TODO:
-    let _ = db;
-    unimplemented!()
+    let mut stmt = db.prepare_cached(
+        "SELECT main_chain_index FROM units WHERE is_on_main_chain=1 AND is_stable=1 \
+         ORDER BY main_chain_index DESC LIMIT 1",
+    )?;
+    let mut rows = stmt.query_map(&[], |row| row.get(0))?;
+    match rows.next() {
+        Some(row) => Ok(row?),
+        None => Ok(0),
+    }
 }
 
 pub fn determine_if_witness_and_address_definition_have_refs(
@@ -198,21 +533,561 @@ pub fn read_joint_with_ball(db: &Connection, unit: &String) -> Result<Joint> {
     Ok(joint)
 }
 
-pub fn read_joint_directly(_db: &Connection, _unit: &String) -> Result<Joint> {
-    // TODO: #34
-    unimplemented!()
+// reconstruct the joint from database, the unit hash is checked against the saved one
+pub fn read_joint_directly(db: &Connection, unit_hash: &String) -> Result<Joint> {
+    struct UnitRow {
+        version: String,
+        alt: String,
+        witness_list_unit: Option<String>,
+        last_ball_unit: Option<String>,
+        last_ball: Option<String>,
+        content_hash: Option<String>,
+        headers_commission: Option<u32>,
+        payload_commission: Option<u32>,
+        main_chain_index: Option<u32>,
+    }
+
+    let mut stmt = db.prepare_cached(
+        "SELECT version, alt, witness_list_unit, last_ball_unit, balls.ball AS last_ball, \
+         content_hash, headers_commission, payload_commission, main_chain_index \
+         FROM units LEFT JOIN balls ON last_ball_unit=balls.unit WHERE units.unit=?",
+    )?;
+    let mut rows = stmt.query_map(&[unit_hash], |row| UnitRow {
+        version: row.get(0),
+        alt: row.get(1),
+        witness_list_unit: row.get(2),
+        last_ball_unit: row.get(3),
+        last_ball: row.get(4),
+        content_hash: row.get(5),
+        headers_commission: row.get(6),
+        payload_commission: row.get(7),
+        main_chain_index: row.get(8),
+    })?;
+    let unit_row = match rows.next() {
+        Some(row) => row?,
+        None => bail!("unit {} not found", unit_hash),
+    };
+
+    let mut stmt = db.prepare_cached(
+        "SELECT parent_unit FROM parenthoods WHERE child_unit=? ORDER BY parent_unit",
+    )?;
+    let parent_units = stmt
+        .query_map(&[unit_hash], |row| row.get::<_, String>(0))?
+        .collect::<::std::result::Result<Vec<_>, _>>()?;
+
+    let mut stmt =
+        db.prepare_cached("SELECT address FROM unit_witnesses WHERE unit=? ORDER BY address")?;
+    let witnesses = stmt
+        .query_map(&[unit_hash], |row| row.get::<_, String>(0))?
+        .collect::<::std::result::Result<Vec<_>, _>>()?;
+
+    let mut stmt = db.prepare_cached(
+        "SELECT address, earned_headers_commission_share \
+         FROM earned_headers_commission_recipients WHERE unit=? ORDER BY address",
+    )?;
+    let recipients = stmt
+        .query_map(&[unit_hash], |row| HeaderCommissionShare {
+            address: row.get(0),
+            earned_headers_commission_share: row.get(1),
+        })?
+        .collect::<::std::result::Result<Vec<_>, _>>()?;
+
+    let authors = read_authors(db, unit_hash)?;
+    let messages = read_messages(db, unit_hash, authors.len() > 1)?;
+
+    // the unit is received stripped if it has no messages, otherwise the content hash
+    // is set by ourselves when the unit becomes final-bad and the full unit is kept
+    let is_stripped = unit_row.content_hash.is_some() && messages.is_empty();
+    let mut unit = Unit {
+        alt: unit_row.alt,
+        authors,
+        content_hash: None,
+        earned_headers_commission_recipients: some_if!(!recipients.is_empty(), recipients),
+        headers_commission: unit_row.headers_commission,
+        last_ball: unit_row.last_ball,
+        last_ball_unit: unit_row.last_ball_unit,
+        main_chain_index: unit_row.main_chain_index,
+        messages,
+        parent_units,
+        payload_commission: unit_row.payload_commission,
+        timestamp: None,
+        unit: Some(unit_hash.clone()),
+        version: unit_row.version,
+        witnesses: some_if!(!witnesses.is_empty(), witnesses),
+        witness_list_unit: unit_row.witness_list_unit,
+    };
+    if is_stripped {
+        unit.content_hash = unit_row.content_hash;
+        unit.headers_commission = None;
+        unit.payload_commission = None;
+        unit.earned_headers_commission_recipients = None;
+    }
+
+    let mut stmt = db.prepare_cached("SELECT ball FROM balls WHERE unit=?")?;
+    let mut rows = stmt.query_map(&[unit_hash], |row| row.get::<_, String>(0))?;
+    let ball = match rows.next() {
+        Some(row) => Some(row?),
+        None => None,
+    };
+
+    let mut skiplist_units = None;
+    if ball.is_some() {
+        let mut stmt = db.prepare_cached(
+            "SELECT skiplist_unit FROM skiplist_units WHERE unit=? ORDER BY skiplist_unit",
+        )?;
+        let units = stmt
+            .query_map(&[unit_hash], |row| row.get::<_, String>(0))?
+            .collect::<::std::result::Result<Vec<_>, _>>()?;
+        skiplist_units = some_if!(!units.is_empty(), units);
+    }
+
+    // make sure what we read is exactly what we saved
+    ensure!(
+        &unit.get_unit_hash() == unit_hash,
+        "unit hash of read joint {} doesn't match",
+        unit_hash
+    );
+
+    Ok(Joint {
+        ball,
+        skiplist_units,
+        unsigned: None,
+        unit,
+    })
+}
+
+fn read_authors(db: &Connection, unit_hash: &String) -> Result<Vec<Author>> {
+    let mut stmt = db.prepare_cached(
+        "SELECT address, definition_chash FROM unit_authors WHERE unit=? ORDER BY address",
+    )?;
+    let rows = stmt
+        .query_map(&[unit_hash], |row| {
+            (row.get::<_, String>(0), row.get::<_, Option<String>>(1))
+        })?
+        .collect::<::std::result::Result<Vec<_>, _>>()?;
+
+    let mut authors = Vec::new();
+    for (address, definition_chash) in rows {
+        let definition = match definition_chash {
+            Some(ref chash) => serde_json::from_str(&read_definition(db, chash)?)?,
+            None => Value::Null,
+        };
+
+        let mut stmt = db.prepare_cached(
+            "SELECT path, authentifier FROM authentifiers WHERE unit=? AND address=?",
+        )?;
+        let authentifiers = stmt
+            .query_map(&[unit_hash, &address], |row| {
+                (row.get::<_, String>(0), row.get::<_, String>(1))
+            })?
+            .collect::<::std::result::Result<HashMap<_, _>, _>>()?;
+
+        authors.push(Author {
+            address,
+            authentifiers,
+            definition,
+        });
+    }
+    Ok(authors)
+}
+
+fn read_messages(
+    db: &Connection,
+    unit_hash: &String,
+    is_multi_authored: bool,
+) -> Result<Vec<Message>> {
+    struct MessageRow {
+        message_index: u32,
+        app: String,
+        payload_hash: String,
+        payload_location: String,
+        payload: Option<String>,
+        payload_uri: Option<String>,
+        payload_uri_hash: Option<String>,
+    }
+
+    let mut stmt = db.prepare_cached(
+        "SELECT message_index, app, payload_hash, payload_location, payload, \
+         payload_uri, payload_uri_hash \
+         FROM messages WHERE unit=? ORDER BY message_index",
+    )?;
+    let rows = stmt
+        .query_map(&[unit_hash], |row| MessageRow {
+            message_index: row.get(0),
+            app: row.get(1),
+            payload_hash: row.get(2),
+            payload_location: row.get(3),
+            payload: row.get(4),
+            payload_uri: row.get(5),
+            payload_uri_hash: row.get(6),
+        })?
+        .collect::<::std::result::Result<Vec<_>, _>>()?;
+
+    let mut messages = Vec::new();
+    for row in rows {
+        let payload = match row.payload {
+            Some(payload) => match row.app.as_str() {
+                "text" => Some(Payload::Text(payload)),
+                _ => serde_json::from_str(&payload)?,
+            },
+            None if row.payload_location == "inline" => match row.app.as_str() {
+                "payment" => Some(Payload::Payment(read_payment_payload(
+                    db,
+                    unit_hash,
+                    row.message_index,
+                    is_multi_authored,
+                )?)),
+                "address_definition_change" => Some(read_address_definition_change_payload(
+                    db,
+                    unit_hash,
+                    row.message_index,
+                    is_multi_authored,
+                )?),
+                "data_feed" => Some(read_data_feed_payload(db, unit_hash, row.message_index)?),
+                "poll" => Some(read_poll_payload(db, unit_hash)?),
+                "vote" => Some(read_vote_payload(db, unit_hash, row.message_index)?),
+                _ => None,
+            },
+            None => None,
+        };
+
+        messages.push(Message {
+            app: row.app,
+            payload,
+            payload_hash: row.payload_hash,
+            payload_location: row.payload_location,
+            payload_uri: row.payload_uri,
+            payload_uri_hash: row.payload_uri_hash,
+            spend_proofs: None,
+        });
+    }
+    Ok(messages)
+}
+
+fn read_payment_payload(
+    db: &Connection,
+    unit_hash: &String,
+    message_index: u32,
+    is_multi_authored: bool,
+) -> Result<Payment> {
+    struct InputRow {
+        kind: String,
+        src_unit: Option<String>,
+        src_message_index: Option<u32>,
+        src_output_index: Option<u32>,
+        from_main_chain_index: Option<u32>,
+        to_main_chain_index: Option<u32>,
+        serial_number: Option<u32>,
+        amount: Option<i64>,
+        address: String,
+        asset: Option<String>,
+        denomination: u32,
+    }
+
+    let mut stmt = db.prepare_cached(
+        "SELECT type, src_unit, src_message_index, src_output_index, \
+         from_main_chain_index, to_main_chain_index, serial_number, amount, \
+         address, asset, denomination \
+         FROM inputs WHERE unit=? AND message_index=? ORDER BY input_index",
+    )?;
+    let rows = stmt
+        .query_map(&[unit_hash, &message_index], |row| InputRow {
+            kind: row.get(0),
+            src_unit: row.get(1),
+            src_message_index: row.get(2),
+            src_output_index: row.get(3),
+            from_main_chain_index: row.get(4),
+            to_main_chain_index: row.get(5),
+            serial_number: row.get(6),
+            amount: row.get(7),
+            address: row.get(8),
+            asset: row.get(9),
+            denomination: row.get(10),
+        })?
+        .collect::<::std::result::Result<Vec<_>, _>>()?;
+
+    let mut asset = None;
+    let mut denomination = 1;
+    let mut inputs = Vec::new();
+    for row in rows {
+        asset = row.asset;
+        denomination = row.denomination;
+        // the address is explicit only for multi-authored units
+        let address = match row.kind.as_str() {
+            "issue" | "headers_commission" | "witnessing" if is_multi_authored => Some(row.address),
+            _ => None,
+        };
+        let kind = some_if!(row.kind != "transfer", row.kind);
+
+        inputs.push(Input {
+            address,
+            amount: row.amount,
+            from_main_chain_index: row.from_main_chain_index,
+            message_index: row.src_message_index,
+            kind,
+            output_index: row.src_output_index,
+            serial_number: row.serial_number,
+            to_main_chain_index: row.to_main_chain_index,
+            unit: row.src_unit,
+        });
+    }
+
+    let mut stmt = db.prepare_cached(
+        "SELECT address, amount FROM outputs \
+         WHERE unit=? AND message_index=? ORDER BY output_index",
+    )?;
+    let outputs = stmt
+        .query_map(&[unit_hash, &message_index], |row| Output {
+            address: row.get(0),
+            amount: row.get(1),
+        })?
+        .collect::<::std::result::Result<Vec<_>, _>>()?;
+
+    Ok(Payment {
+        address: None,
+        denomination: some_if!(asset.is_some() && denomination != 1, denomination),
+        asset,
+        definition_chash: None,
+        inputs,
+        outputs,
+    })
+}
+
+fn read_address_definition_change_payload(
+    db: &Connection,
+    unit_hash: &String,
+    message_index: u32,
+    is_multi_authored: bool,
+) -> Result<Payload> {
+    let mut stmt = db.prepare_cached(
+        "SELECT address, definition_chash FROM address_definition_changes \
+         WHERE unit=? AND message_index=?",
+    )?;
+    let (address, definition_chash) =
+        stmt.query_row(&[unit_hash, &message_index], |row| {
+            (row.get::<_, String>(0), row.get::<_, String>(1))
+        })?;
+
+    // the address is explicit only for multi-authored units
+    let mut payload = json!({ "definition_chash": definition_chash });
+    if is_multi_authored {
+        payload["address"] = Value::String(address);
+    }
+    Ok(Payload::Other(payload))
+}
+
+fn read_data_feed_payload(
+    db: &Connection,
+    unit_hash: &String,
+    message_index: u32,
+) -> Result<Payload> {
+    let mut stmt = db.prepare_cached(
+        "SELECT feed_name, value, int_value FROM data_feeds WHERE unit=? AND message_index=?",
+    )?;
+    let rows = stmt
+        .query_map(&[unit_hash, &message_index], |row| {
+            (
+                row.get::<_, String>(0),
+                row.get::<_, Option<String>>(1),
+                row.get::<_, Option<i64>>(2),
+            )
+        })?
+        .collect::<::std::result::Result<Vec<_>, _>>()?;
+
+    let mut feeds = serde_json::Map::new();
+    for (feed_name, value, int_value) in rows {
+        let value = match (value, int_value) {
+            (Some(value), _) => Value::String(value),
+            (None, Some(int_value)) => Value::from(int_value),
+            (None, None) => bail!("data feed {} of unit {} has no value", feed_name, unit_hash),
+        };
+        feeds.insert(feed_name, value);
+    }
+    Ok(Payload::Other(Value::Object(feeds)))
+}
+
+fn read_poll_payload(db: &Connection, unit_hash: &String) -> Result<Payload> {
+    let mut stmt = db.prepare_cached("SELECT question FROM polls WHERE unit=?")?;
+    let question = stmt.query_row(&[unit_hash], |row| row.get::<_, String>(0))?;
+
+    let mut stmt =
+        db.prepare_cached("SELECT choice FROM poll_choices WHERE unit=? ORDER BY choice_index")?;
+    let choices = stmt
+        .query_map(&[unit_hash], |row| row.get::<_, String>(0))?
+        .collect::<::std::result::Result<Vec<_>, _>>()?;
+
+    Ok(Payload::Other(
+        json!({ "question": question, "choices": choices }),
+    ))
+}
+
+fn read_vote_payload(db: &Connection, unit_hash: &String, message_index: u32) -> Result<Payload> {
+    let mut stmt =
+        db.prepare_cached("SELECT poll_unit, choice FROM votes WHERE unit=? AND message_index=?")?;
+    let (poll_unit, choice) = stmt.query_row(&[unit_hash, &message_index], |row| {
+        (row.get::<_, String>(0), row.get::<_, String>(1))
+    })?;
+    Ok(Payload::Other(
+        json!({ "unit": poll_unit, "choice": choice }),
+    ))
 }
 
-pub fn read_definition(_db: &Connection, _definition_chash: &String) -> Result<String> {
-    // TODO: #??
-    unimplemented!()
+pub fn read_definition(db: &Connection, definition_chash: &String) -> Result<String> {
+    let mut stmt = db.prepare_cached("SELECT definition FROM definitions WHERE definition_chash=?")?;
+    let mut rows = stmt.query_map(&[definition_chash], |row| row.get(0))?;
+    match rows.next() {
+        Some(row) => Ok(row?),
+        None => bail!("definition {} not found", definition_chash),
+    }
 }
 
+// read the latest definition of the address that is stable at max_mci
 pub fn read_definition_by_address(
-    _db: &Connection,
-    _address: &String,
-    _max_mci: Option<u32>,
+    db: &Connection,
+    address: &String,
+    max_mci: Option<u32>,
 ) -> Result<Option<String>> {
-    // TODO: #??
-    unimplemented!()
+    let max_mci = max_mci.unwrap_or(::std::i32::MAX as u32);
+
+    // try to find last definition change, otherwise definition_chash=address
+    let mut stmt = db.prepare_cached(
+        "SELECT definition_chash FROM address_definition_changes CROSS JOIN units USING(unit) \
+         WHERE address=? AND is_stable=1 AND sequence='good' AND main_chain_index<=? \
+         ORDER BY level DESC LIMIT 1",
+    )?;
+    let mut rows = stmt.query_map(&[address, &max_mci], |row| row.get::<_, String>(0))?;
+    let definition_chash = match rows.next() {
+        Some(row) => row?,
+        None => address.clone(),
+    };
+
+    let mut stmt = db.prepare_cached(
+        "SELECT definition FROM definitions \
+         CROSS JOIN unit_authors USING(definition_chash) CROSS JOIN units USING(unit) \
+         WHERE definition_chash=? AND is_stable=1 AND sequence='good' AND main_chain_index<=?",
+    )?;
+    let mut rows = stmt.query_map(&[&definition_chash, &max_mci], |row| row.get(0))?;
+    match rows.next() {
+        Some(row) => Ok(Some(row?)),
+        None => Ok(None),
+    }
+}
+
+#[test]
+fn test_read_joint_directly() {
+    use test_utils::*;
+
+    fn check_round_trip(db: &Connection, joint: &Joint) {
+        let read = read_joint_directly(db, joint.get_unit_hash()).unwrap();
+        assert_eq!(read.unit.get_unit_hash(), joint.unit.get_unit_hash());
+        assert_eq!(read.ball, joint.ball);
+    }
+
+    let mut dag = TestDag::new("read_joint_directly");
+    let genesis = compose_genesis_joint(&dag.witnesses);
+    check_round_trip(&dag.db, &genesis);
+    let genesis_unit = genesis.get_unit_hash();
+
+    // multi-authored payment with several inputs and outputs
+    let payment = {
+        let authors = [&dag.witnesses[0], &dag.witnesses[1]];
+        let inputs = vec![
+            transfer_input(genesis_unit, 0, 0),
+            transfer_input(genesis_unit, 0, 1),
+        ];
+        let outputs = vec![
+            output(&dag.witnesses[2].address, 1000),
+            output(&dag.witnesses[3].address, 2000),
+            output(&dag.witnesses[3].address, 3000),
+        ];
+        let mut unit = dag
+            .compose(
+                &authors,
+                vec![genesis_unit.clone()],
+                vec![payment_message(inputs, outputs)],
+            )
+            .unit;
+        unit.earned_headers_commission_recipients = Some(vec![HeaderCommissionShare {
+            address: dag.witnesses[0].address.clone(),
+            earned_headers_commission_share: 100,
+        }]);
+        Joint {
+            ball: None,
+            skiplist_units: None,
+            unsigned: None,
+            unit: sign_unit(unit, &authors),
+        }
+    };
+    dag.save(&payment).unwrap();
+    check_round_trip(&dag.db, &payment);
+
+    let data_feed = dag.compose(
+        &[&dag.witnesses[4]],
+        vec![payment.get_unit_hash().clone()],
+        vec![
+            inline_message(
+                "data_feed",
+                Payload::Other(json!({"price": "100", "volume": 5})),
+            ),
+            text_message("hello"),
+        ],
+    );
+    dag.save(&data_feed).unwrap();
+    check_round_trip(&dag.db, &data_feed);
+
+    let definition_change = dag.compose(
+        &[&dag.witnesses[5]],
+        vec![data_feed.get_unit_hash().clone()],
+        vec![inline_message(
+            "address_definition_change",
+            Payload::Other(json!({"definition_chash": dag.witnesses[6].address})),
+        )],
+    );
+    dag.save(&definition_change).unwrap();
+    check_round_trip(&dag.db, &definition_change);
+
+    // the definitions are sent with the first units of the authors
+    let read = read_joint_directly(&dag.db, definition_change.get_unit_hash()).unwrap();
+    assert_eq!(read.unit.authors[0].definition, dag.witnesses[5].definition);
+}
+
+#[test]
+fn test_cache_saved_unit_after_commit() {
+    use test_utils::*;
+
+    fn set_level(db: &Connection, unit: &String, level: u32) {
+        db.execute("UPDATE units SET level=? WHERE unit=?", &[&level, unit])
+            .unwrap();
+    }
+
+    let mut dag = TestDag::new("cache saved unit");
+    dag.add_units(3);
+    let joint = dag.compose(&[&dag.witnesses[3]], dag.read_free_units(), Vec::new());
+    let unit = joint.get_unit_hash().clone();
+    set_unit_is_uncommitted(&unit);
+    dag.save(&joint).unwrap();
+    let level = read_static_unit_property(&dag.db, &unit).unwrap().level;
+
+    // not cached before the commit
+    set_level(&dag.db, &unit, level + 100);
+    assert_eq!(
+        read_static_unit_property(&dag.db, &unit).unwrap().level,
+        level + 100
+    );
+
+    // the committed props replace the cached ones
+    set_level(&dag.db, &unit, level);
+    cache_saved_unit(&dag.db, &joint.unit).unwrap();
+    set_level(&dag.db, &unit, level + 100);
+    assert_eq!(
+        read_static_unit_property(&dag.db, &unit).unwrap().level,
+        level
+    );
+    set_level(&dag.db, &unit, level + 200);
+    cache_saved_unit(&dag.db, &joint.unit).unwrap();
+    assert_eq!(
+        read_static_unit_property(&dag.db, &unit).unwrap().level,
+        level + 200
+    );
+    forget_unit(&unit);
 }
@@ -0,0 +1,419 @@
+//! deterministic keys, signed units and small DAGs in an in-memory database for the tests
+
+use std::collections::HashMap;
+use std::sync::Once;
+
+use config;
+use db;
+use error::Result;
+use joint::Joint;
+use object_hash;
+use rusqlite::Connection;
+use serde_json::Value;
+use signature;
+use spec::*;
+use storage;
+use validation::{self, ValidationOk, ValidationState};
+
+// each witness gets this amount from the genesis, the rest goes to the first witness
+pub const GENESIS_OUTPUT_AMOUNT: i64 = 1_000_000;
+
+// a signature is always 88 chars in base64, used to calc the headers commission before signing
+const SIG_PLACEHOLDER_LENGTH: usize = 88;
+
+pub struct TestKey {
+    pub address: String,
+    pub definition: Value,
+    priv_key: Vec<u8>,
+}
+
+impl TestKey {
+    /// the private key is the seed repeated, so the seed must not be 0
+    pub fn new(seed: u8) -> TestKey {
+        let priv_key = vec![seed; 32];
+        let pub_key = signature::get_pub_key(&priv_key).expect("invalid test key");
+        let definition = json!(["sig", { "pubkey": pub_key }]);
+        let address = object_hash::get_chash(&definition).expect("failed to get address");
+        TestKey {
+            address,
+            definition,
+            priv_key,
+        }
+    }
+
+    fn sign(&self, hash: &[u8]) -> String {
+        signature::sign(hash, &self.priv_key).expect("failed to sign")
+    }
+}
+
+/// the keys of the 12 witnesses, sorted by address
+pub fn get_witness_keys() -> Vec<TestKey> {
+    let mut keys = (1..config::COUNT_WITNESSES as u8 + 1)
+        .map(TestKey::new)
+        .collect::<Vec<_>>();
+    keys.sort_by(|a, b| a.address.cmp(&b.address));
+    keys
+}
+
+/// give the coroutines the stack size of the hub, call it before spawning any coroutine
+/// in the test including the ones of `DB_POOL`, the finished coroutines are reused
+/// by the scheduler with the stack they were created with
+pub fn init_coroutines() {
+    static INIT: Once = Once::new();
+    INIT.call_once(|| {
+        ::may::config().set_stack_size(0x2000);
+    });
+}
+
+pub fn open_db() -> Connection {
+    let mut db = Connection::open_in_memory().unwrap();
+    db::migrate(&mut db).unwrap();
+    db
+}
+
+pub fn inline_message(app: &str, payload: Payload) -> Message {
+    Message {
+        app: app.to_owned(),
+        payload_hash: object_hash::get_base64_hash(&payload).unwrap(),
+        payload: Some(payload),
+        payload_location: String::from("inline"),
+        payload_uri: None,
+        payload_uri_hash: None,
+        spend_proofs: None,
+    }
+}
+
+pub fn text_message(text: &str) -> Message {
+    inline_message("text", Payload::Text(text.to_owned()))
+}
+
+pub fn payment_message(inputs: Vec<Input>, outputs: Vec<Output>) -> Message {
+    let payment = Payment {
+        address: None,
+        asset: None,
+        definition_chash: None,
+        denomination: None,
+        inputs,
+        outputs,
+    };
+    inline_message("payment", Payload::Payment(payment))
+}
+
+fn new_input() -> Input {
+    Input {
+        address: None,
+        amount: None,
+        from_main_chain_index: None,
+        message_index: None,
+        kind: None,
+        output_index: None,
+        serial_number: None,
+        to_main_chain_index: None,
+        unit: None,
+    }
+}
+
+pub fn transfer_input(unit: &str, message_index: u32, output_index: u32) -> Input {
+    Input {
+        unit: Some(unit.to_owned()),
+        message_index: Some(message_index),
+        output_index: Some(output_index),
+        ..new_input()
+    }
+}
+
+pub fn output(address: &str, amount: i64) -> Output {
+    Output {
+        address: address.to_owned(),
+        amount,
+    }
+}
+
+/// fill the commissions and the authentifiers, the unit hash is set at last,
+/// call it again after changing the unit
+pub fn sign_unit(mut unit: Unit, authors: &[&TestKey]) -> Unit {
+    for author in &mut unit.authors {
+        author.authentifiers.insert(
+            String::from("r"),
+            ::std::iter::repeat("-")
+                .take(SIG_PLACEHOLDER_LENGTH)
+                .collect(),
+        );
+    }
+    unit.headers_commission = Some(unit.get_header_size());
+    unit.payload_commission = Some(unit.get_payload_size());
+
+    let hash_to_sign = unit.get_unit_hash_to_sign();
+    for (author, key) in unit.authors.iter_mut().zip(authors) {
+        author
+            .authentifiers
+            .insert(String::from("r"), key.sign(&hash_to_sign));
+    }
+    unit.unit = Some(unit.get_unit_hash());
+    unit
+}
+
+fn new_unit(authors: &[&TestKey], parent_units: Vec<String>, messages: Vec<Message>) -> Unit {
+    Unit {
+        alt: String::from(config::ALT),
+        authors: authors
+            .iter()
+            .map(|key| Author {
+                address: key.address.clone(),
+                authentifiers: HashMap::new(),
+                definition: Value::Null,
+            })
+            .collect(),
+        content_hash: None,
+        earned_headers_commission_recipients: None,
+        headers_commission: None,
+        last_ball: None,
+        last_ball_unit: None,
+        main_chain_index: None,
+        messages,
+        parent_units,
+        payload_commission: None,
+        timestamp: None,
+        unit: None,
+        version: String::from(config::VERSION),
+        witnesses: None,
+        witness_list_unit: None,
+    }
+}
+
+/// the genesis issues all the bytes, the tests run on the network of this genesis
+/// since it can't hash to `config::GENESIS_UNIT` without the mainnet keys
+pub fn compose_genesis_joint(witnesses: &[TestKey]) -> Joint {
+    let issue = Input {
+        amount: Some(config::TOTAL_WHITEBYTES),
+        kind: Some(String::from("issue")),
+        serial_number: Some(1),
+        ..new_input()
+    };
+    let outputs = witnesses
+        .iter()
+        .map(|w| output(&w.address, GENESIS_OUTPUT_AMOUNT))
+        .collect();
+
+    let mut unit = new_unit(
+        &[&witnesses[0]],
+        Vec::new(),
+        vec![payment_message(vec![issue], outputs)],
+    );
+    unit.authors[0].definition = witnesses[0].definition.clone();
+    unit.witnesses = Some(witnesses.iter().map(|w| w.address.clone()).collect());
+    let unit = sign_unit(unit, &[&witnesses[0]]);
+    config::set_genesis_unit(unit.unit.as_ref().unwrap());
+
+    Joint {
+        ball: Some(object_hash::get_ball_hash(
+            unit.unit.as_ref().unwrap(),
+            &Vec::new(),
+            &Vec::new(),
+            false,
+        )),
+        skiplist_units: None,
+        unsigned: None,
+        unit,
+    }
+}
+
+/// a DAG of units by the 12 witnesses on top of the genesis
+pub struct TestDag {
+    pub db: Connection,
+    pub witnesses: Vec<TestKey>,
+    // mixed into the texts to keep the units of different tests apart in the global caches
+    tag: String,
+    count_units: usize,
+}
+
+impl TestDag {
+    pub fn new(tag: &str) -> TestDag {
+        let witnesses = get_witness_keys();
+        let mut dag = TestDag {
+            db: open_db(),
+            witnesses,
+            tag: tag.to_owned(),
+            count_units: 0,
+        };
+        let genesis = compose_genesis_joint(&dag.witnesses);
+        dag.save(&genesis).unwrap();
+        dag
+    }
+
+    pub fn get_witness_addresses(&self) -> Vec<String> {
+        self.witnesses.iter().map(|w| w.address.clone()).collect()
+    }
+
+    pub fn read_free_units(&self) -> Vec<String> {
+        let mut stmt = self
+            .db
+            .prepare("SELECT unit FROM units WHERE is_free=1 ORDER BY unit")
+            .unwrap();
+        let units = stmt
+            .query_map(&[], |row| row.get(0))
+            .unwrap()
+            .collect::<::std::result::Result<Vec<_>, _>>()
+            .unwrap();
+        units
+    }
+
+    /// compose a signed unit on the last stable ball, the definitions are added for
+    /// the authors whose definitions are not stable yet like the wallet does
+    pub fn compose(
+        &self,
+        authors: &[&TestKey],
+        parent_units: Vec<String>,
+        messages: Vec<Message>,
+    ) -> Joint {
+        self.compose_with(authors, parent_units, messages, |_| {})
+    }
+
+    /// like `compose`, the unit can be changed before it is signed
+    pub fn compose_with<F>(
+        &self,
+        authors: &[&TestKey],
+        mut parent_units: Vec<String>,
+        messages: Vec<Message>,
+        modify: F,
+    ) -> Joint
+    where
+        F: Fn(&mut Unit),
+    {
+        let last_stable = storage::read_last_stable_mc_unit_props(&self.db).unwrap();
+        parent_units.sort();
+
+        let mut unit = new_unit(authors, parent_units, messages);
+        unit.last_ball = Some(last_stable.ball);
+        unit.last_ball_unit = Some(last_stable.unit);
+        unit.witness_list_unit = Some(config::get_genesis_unit());
+        for (author, key) in unit.authors.iter_mut().zip(authors) {
+            let definition = storage::read_definition_by_address(
+                &self.db,
+                &key.address,
+                Some(last_stable.main_chain_index),
+            )
+            .unwrap();
+            if definition.is_none() {
+                author.definition = key.definition.clone();
+            }
+        }
+        modify(&mut unit);
+
+        Joint {
+            ball: None,
+            skiplist_units: None,
+            unsigned: None,
+            unit: sign_unit(unit, authors),
+        }
+    }
+
+    /// compose a payment that sends the change back to the author
+    pub fn compose_payment(
+        &self,
+        author: &TestKey,
+        parent_units: Vec<String>,
+        inputs: Vec<Input>,
+        total_input: i64,
+        outputs: Vec<Output>,
+    ) -> Joint {
+        self.compose_payment_with(
+            &[author],
+            parent_units,
+            inputs,
+            total_input,
+            outputs,
+            |_| {},
+        )
+    }
+
+    /// like `compose_payment`, the change goes to the first author
+    pub fn compose_payment_with<F>(
+        &self,
+        authors: &[&TestKey],
+        parent_units: Vec<String>,
+        inputs: Vec<Input>,
+        total_input: i64,
+        outputs: Vec<Output>,
+        modify: F,
+    ) -> Joint
+    where
+        F: Fn(&mut Unit),
+    {
+        let mut all_outputs = outputs.clone();
+        all_outputs.push(output(&authors[0].address, 0));
+        // the commissions don't depend on the amounts
+        let draft = self.compose_with(
+            authors,
+            parent_units.clone(),
+            vec![payment_message(inputs.clone(), all_outputs.clone())],
+            &modify,
+        );
+        let commissions = i64::from(draft.unit.headers_commission.unwrap())
+            + i64::from(draft.unit.payload_commission.unwrap());
+        let total_output: i64 = outputs.iter().map(|o| o.amount).sum();
+
+        all_outputs.last_mut().unwrap().amount = total_input - total_output - commissions;
+        all_outputs.sort_by(|a, b| (&a.address, a.amount).cmp(&(&b.address, b.amount)));
+        self.compose_with(
+            authors,
+            parent_units,
+            vec![payment_message(inputs, all_outputs)],
+            modify,
+        )
+    }
+
+    pub fn save(&mut self, joint: &Joint) -> Result<()> {
+        self.save_with_state(joint, &ValidationState::new())
+    }
+
+    fn save_with_state(&mut self, joint: &Joint, state: &ValidationState) -> Result<()> {
+        let tx = self.db.transaction()?;
+        joint.save_in_tx(&tx, state)?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// validate the joint and save it if it is signed and valid
+    pub fn validate_and_save(&mut self, joint: &Joint) -> Result<()> {
+        match validation::validate(&mut self.db, joint)? {
+            ValidationOk::Signed(state, _lock) => self.save_with_state(joint, &state),
+            ValidationOk::Unsigned => bail!("unsigned unit {}", joint.get_unit_hash()),
+        }
+    }
+
+    /// add a text unit of the witness on top of all the free units
+    pub fn add_unit(&mut self, witness_index: usize) -> String {
+        let parent_units = self.read_free_units();
+        self.add_unit_on(witness_index, parent_units)
+    }
+
+    /// add a text unit of the witness on top of the given parents
+    pub fn add_unit_on(&mut self, witness_index: usize, parent_units: Vec<String>) -> String {
+        self.count_units += 1;
+        let text = format!("{} {}", self.tag, self.count_units);
+        let joint = {
+            let author = &self.witnesses[witness_index];
+            self.compose(&[author], parent_units, vec![text_message(&text)])
+        };
+        self.save(&joint).unwrap();
+        joint.get_unit_hash().clone()
+    }
+
+    /// add the units authored by the witnesses in turn
+    pub fn add_units(&mut self, count: usize) -> Vec<String> {
+        (0..count)
+            .map(|_| {
+                let witness_index = self.count_units % self.witnesses.len();
+                self.add_unit(witness_index)
+            })
+            .collect()
+    }
+}
+
+#[test]
+fn test_genesis_joint() {
+    let joint = compose_genesis_joint(&get_witness_keys());
+    assert!(joint.unit.is_genesis_unit());
+    assert!(joint.has_valid_hashes());
+}
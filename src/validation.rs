@@ -1,9 +1,19 @@
 use config;
+use definition;
 use error::Result;
-use joint::Joint;
+use graph;
+use header_commissions;
+use joint::{self, Joint};
+use main_chain;
 use map_lock::{self, MapLock};
+use mc_outputs;
+use object_hash;
+use paid_witnessing;
+use rusqlite::types::ToSql;
 use rusqlite::{Connection, Transaction};
+use serde_json::{self, Value};
 use spec::*;
+use storage;
 
 const HASH_LENGTH: usize = 44;
 
@@ -21,24 +31,56 @@ macro_rules! err {
 
 #[derive(Debug)]
 pub struct DoubleSpendInput {
-    message_index: u32,
-    input_index: u32,
+    pub message_index: u32,
+    pub input_index: u32,
 }
 
 #[derive(Debug)]
 pub struct ValidationState {
     unsigned: bool,
+    pub last_ball_mci: u32,
+    pub max_known_mci: u32,
+    pub max_parent_limci: u32,
+    pub unit_hash_to_sign: Vec<u8>,
+    pub no_references: bool,
     pub additional_queries: Vec<String>,
     pub double_spend_inputs: Vec<DoubleSpendInput>,
-    // input_keys: // what this?
+    pub sequence: String,
+    // authors that have a conflicting unit not included in our parents
+    addresses_with_forked_path: Vec<String>,
+    conflicting_units: Vec<String>,
+    // keys of the inputs spent by the unit, used to detect inputs listed twice
+    input_keys: Vec<String>,
+    has_base_payment: bool,
+    // addresses whose definition the unit changes, only once per address
+    definition_change_addresses: Vec<String>,
+    has_data_feed: bool,
+    has_poll: bool,
+    has_profile: bool,
+    has_definition_template: bool,
 }
 
 impl ValidationState {
     pub fn new() -> Self {
         ValidationState {
             unsigned: false,
+            last_ball_mci: 0,
+            max_known_mci: 0,
+            max_parent_limci: 0,
+            unit_hash_to_sign: Vec::new(),
+            no_references: false,
             additional_queries: Vec::new(),
             double_spend_inputs: Vec::new(),
+            sequence: String::from("good"),
+            addresses_with_forked_path: Vec::new(),
+            conflicting_units: Vec::new(),
+            input_keys: Vec::new(),
+            has_base_payment: false,
+            definition_change_addresses: Vec::new(),
+            has_data_feed: false,
+            has_poll: false,
+            has_profile: false,
+            has_definition_template: false,
         }
     }
 }
@@ -64,12 +106,26 @@ pub enum ValidationOk {
 }
 
 pub fn validate_author_signature_without_ref(
-    _db: &Connection,
-    _author: &Author,
-    _unit: &Unit,
-    _definition: &String,
+    db: &Connection,
+    author: &Author,
+    unit: &Unit,
+    definition: &String,
 ) -> Result<()> {
-    unimplemented!()
+    let definition: Value = serde_json::from_str(definition)?;
+    let mut state = ValidationState::new();
+    state.unit_hash_to_sign = unit.get_unit_hash_to_sign();
+    state.no_references = true;
+
+    let res = definition::validate_authentifiers(
+        db,
+        &author.address,
+        &definition,
+        unit,
+        &state,
+        &author.authentifiers,
+    )?;
+    ensure!(res, "authentifier verification failed");
+    Ok(())
 }
 
 pub fn validate(db: &mut Connection, joint: &Joint) -> Result<ValidationOk> {
@@ -162,6 +218,8 @@ pub fn validate(db: &mut Connection, joint: &Joint) -> Result<ValidationOk> {
                 err: format!("wrong payload commission, expected {}", payload_size),
             });
         }
+
+        validate_headers_commission_recipients(unit)?;
     }
 
     if unit.authors.is_empty() {
@@ -214,13 +272,125 @@ pub fn validate(db: &mut Connection, joint: &Joint) -> Result<ValidationOk> {
     }
 
     let author_addresses: Vec<String> = unit.authors.iter().map(|a| a.address.clone()).collect();
-    let _g = ADDRESS_LOCK.lock(author_addresses);
+    let g = ADDRESS_LOCK.lock(author_addresses);
+
+    // advancing the stability point to the last ball writes within the validation
+    // transaction, take the writer lock before the transaction starts so that no
+    // other writer changes the db under it
+    let writer_guard = if is_last_ball_unit_stable(db, unit)? {
+        None
+    } else {
+        Some(joint::WRITER_MUTEX.lock()?)
+    };
+
+    let ret = {
+        let tx = db.transaction()?;
+        let ret = validate_in_tx(&tx, joint, &mut validate_state);
+        if ret.is_ok() {
+            tx.commit()?;
+        }
+        ret
+    };
+    // the unstable units are forgotten once the stability point is advanced
+    if writer_guard.is_some() {
+        storage::refresh_unstable_units(db)?;
+    }
+    ret?;
+
+    if validate_state.unsigned {
+        return Ok(ValidationOk::Unsigned);
+    }
+    Ok(ValidationOk::Signed(validate_state, g))
+}
+
+// the genesis has no last ball, an unknown last ball unit may become known at any time
+fn is_last_ball_unit_stable(db: &Connection, unit: &Unit) -> Result<bool> {
+    let last_ball_unit = match unit.last_ball_unit {
+        Some(ref last_ball_unit) => last_ball_unit,
+        None => return Ok(true),
+    };
+    let mut stmt = db.prepare_cached("SELECT 1 FROM units WHERE unit=? AND is_stable=1")?;
+    Ok(stmt.exists(&[last_ball_unit])?)
+}
 
-    let tx = db.transaction()?;
-    check_duplicate(&tx, unit_hash)?;
+fn validate_in_tx(
+    tx: &Transaction,
+    joint: &Joint,
+    validate_state: &mut ValidationState,
+) -> Result<()> {
+    let unit = &joint.unit;
+    check_duplicate(tx, joint.get_unit_hash())?;
+    validate_hash_tree(tx, joint)?;
+    if !unit.is_genesis_unit() {
+        validate_parents(tx, joint, validate_state)?;
+    }
+    if let Some(ref skiplist_units) = joint.skiplist_units {
+        validate_skiplist(tx, skiplist_units)?;
+    }
+    let witnesses = validate_witnesses(tx, unit, validate_state)?;
+    check_witnessed_level_did_not_retreat(tx, unit, &witnesses)?;
+    validate_authors(tx, unit, validate_state)?;
+    if unit.content_hash.is_none() {
+        validate_messages(tx, unit, validate_state)?;
+    } else {
+        validate_state.sequence = String::from("final-bad");
+    }
 
     // TODO: add more checks
-    Ok(ValidationOk::Unsigned)
+    Ok(())
+}
+
+fn validate_headers_commission_recipients(unit: &Unit) -> Result<()> {
+    let recipients = match unit.earned_headers_commission_recipients {
+        Some(ref recipients) => recipients,
+        None => {
+            if unit.authors.len() > 1 {
+                err!(ValidationError::UnitError {
+                    err: "must specify earned_headers_commission_recipients when more than 1 author"
+                        .to_owned(),
+                });
+            }
+            return Ok(());
+        }
+    };
+
+    if recipients.is_empty() {
+        err!(ValidationError::UnitError {
+            err: "empty earned_headers_commission_recipients array".to_owned(),
+        });
+    }
+
+    let mut total_share = 0;
+    let mut prev_address = "";
+    for recipient in recipients {
+        if recipient.earned_headers_commission_share == 0 {
+            err!(ValidationError::UnitError {
+                err: "earned_headers_commission_share must be positive integer".to_owned(),
+            });
+        }
+        if recipient.address.as_str() <= prev_address {
+            err!(ValidationError::UnitError {
+                err: "recipient list must be sorted by address".to_owned(),
+            });
+        }
+        if recipient.address.len() != 32
+            || !object_hash::is_chash_valid(recipient.address.clone())?
+        {
+            err!(ValidationError::UnitError {
+                err: "invalid recipient address checksum".to_owned(),
+            });
+        }
+        total_share += recipient.earned_headers_commission_share;
+        prev_address = recipient.address.as_str();
+    }
+
+    if total_share != 100 {
+        err!(ValidationError::UnitError {
+            err: "sum of earned_headers_commission_share is not 100".to_owned(),
+        });
+    }
+
+    Ok(())
 }
 
 fn check_duplicate(tx: &Transaction, unit: &String) -> Result<()> {
@@ -232,3 +402,2350 @@ fn check_duplicate(tx: &Transaction, unit: &String) -> Result<()> {
     }
     Ok(())
 }
+
+fn validate_hash_tree(tx: &Transaction, joint: &Joint) -> Result<()> {
+    let ball = match joint.ball {
+        Some(ref ball) => ball,
+        None => return Ok(()),
+    };
+    let unit = &joint.unit;
+    let unit_hash = joint.get_unit_hash();
+
+    let mut stmt = tx.prepare_cached("SELECT unit FROM hash_tree_balls WHERE ball=?")?;
+    let hash_tree_units = stmt
+        .query_map(&[ball], |row| row.get::<_, String>(0))?
+        .collect::<::std::result::Result<Vec<_>, _>>()?;
+    if hash_tree_units.is_empty() {
+        err!(ValidationError::NeedHashTree);
+    }
+    if &hash_tree_units[0] != unit_hash {
+        err!(ValidationError::JointError {
+            err: format!("ball {} unit {} contradicts hash tree", ball, unit_hash),
+        });
+    }
+
+    let parent_balls = read_balls_of_units(tx, &unit.parent_units)?;
+    if parent_balls.len() != unit.parent_units.len() {
+        err!(ValidationError::JointError {
+            err: "missing parents in hash tree".to_owned(),
+        });
+    }
+
+    let mut skiplist_balls = Vec::new();
+    if let Some(ref skiplist_units) = joint.skiplist_units {
+        skiplist_balls = read_balls_of_units(tx, skiplist_units)?;
+        if skiplist_balls.len() != skiplist_units.len() {
+            err!(ValidationError::JointError {
+                err: "skiplist balls not found".to_owned(),
+            });
+        }
+    }
+
+    let calc_ball = object_hash::get_ball_hash(
+        unit_hash,
+        &parent_balls,
+        &skiplist_balls,
+        unit.content_hash.is_some(),
+    );
+    if &calc_ball != ball {
+        err!(ValidationError::JointError {
+            err: "ball hash is wrong".to_owned(),
+        });
+    }
+
+    Ok(())
+}
+
+// read the balls of units from both the hash tree and the stable balls, sorted
+fn read_balls_of_units(tx: &Transaction, units: &[String]) -> Result<Vec<String>> {
+    let unit_list = units
+        .iter()
+        .map(|s| format!("'{}'", s))
+        .collect::<Vec<_>>()
+        .join(", ");
+    let sql = format!(
+        "SELECT ball FROM hash_tree_balls WHERE unit IN({}) \
+         UNION \
+         SELECT ball FROM balls WHERE unit IN({}) \
+         ORDER BY ball",
+        unit_list, unit_list
+    );
+
+    let mut stmt = tx.prepare(&sql)?;
+    let rows = stmt.query_map(&[], |row| row.get(0))?;
+    let mut balls = Vec::new();
+    for row in rows {
+        balls.push(row?);
+    }
+    Ok(balls)
+}
+
+fn validate_parents(tx: &Transaction, joint: &Joint, state: &mut ValidationState) -> Result<()> {
+    let unit = &joint.unit;
+    if unit.parent_units.len() > config::MAX_PARENTS_PER_UNIT {
+        err!(ValidationError::UnitError {
+            err: format!("too many parents: {}", unit.parent_units.len()),
+        });
+    }
+
+    // after the hash tree is validated, the parent list is trusted
+    let create_error = |err: String| -> ValidationError {
+        if joint.ball.is_some() {
+            ValidationError::JointError { err }
+        } else {
+            ValidationError::UnitError { err }
+        }
+    };
+
+    let mut missing_parent_units = Vec::new();
+    let mut prev = "";
+    for parent_unit in &unit.parent_units {
+        if parent_unit.as_str() <= prev {
+            err!(create_error("parent units not ordered".to_owned()));
+        }
+        prev = parent_unit.as_str();
+
+        let mut stmt =
+            tx.prepare_cached("SELECT latest_included_mc_index FROM units WHERE unit=?")?;
+        let rows = stmt
+            .query_map(&[parent_unit], |row| row.get::<_, Option<u32>>(0))?
+            .collect::<::std::result::Result<Vec<_>, _>>()?;
+        match rows.first() {
+            None => missing_parent_units.push(parent_unit.clone()),
+            Some(limci) => {
+                let limci = limci.unwrap_or(0);
+                if limci > state.max_parent_limci {
+                    state.max_parent_limci = limci;
+                }
+            }
+        }
+    }
+
+    if !missing_parent_units.is_empty() {
+        let unit_list = missing_parent_units
+            .iter()
+            .map(|s| format!("'{}'", s))
+            .collect::<Vec<_>>()
+            .join(", ");
+        let sql = format!(
+            "SELECT error FROM known_bad_joints WHERE unit IN({})",
+            unit_list
+        );
+        let mut stmt = tx.prepare(&sql)?;
+        let mut rows = stmt.query_map(&[], |row| row.get::<_, String>(0))?;
+        if let Some(row) = rows.next() {
+            err!(ValidationError::UnitError {
+                err: format!("some of the unit's parents are known bad: {}", row?),
+            });
+        }
+        err!(ValidationError::NeedParentUnits(missing_parent_units));
+    }
+
+    for parent_unit in &unit.parent_units {
+        let other_parents = unit.parent_units
+            .iter()
+            .filter(|u| *u != parent_unit)
+            .cloned()
+            .collect::<Vec<_>>();
+        if !other_parents.is_empty()
+            && graph::determine_if_included(tx, parent_unit, &other_parents)?
+        {
+            err!(create_error(format!(
+                "parent unit {} is related to one of the other parent units",
+                parent_unit
+            )));
+        }
+    }
+
+    check_last_ball(tx, joint, state)?;
+    check_no_same_address_in_different_parents(tx, unit)
+}
+
+fn check_last_ball(tx: &Transaction, joint: &Joint, state: &mut ValidationState) -> Result<()> {
+    let unit = &joint.unit;
+    let last_ball = unit.last_ball.as_ref().unwrap();
+    let last_ball_unit = unit.last_ball_unit.as_ref().unwrap();
+
+    struct LastBallUnitProps {
+        is_stable: u32,
+        is_on_main_chain: u32,
+        main_chain_index: Option<u32>,
+        ball: Option<String>,
+        max_known_mci: Option<u32>,
+    }
+
+    let mut stmt = tx.prepare_cached(
+        "SELECT is_stable, is_on_main_chain, main_chain_index, ball, \
+         (SELECT MAX(main_chain_index) FROM units) AS max_known_mci \
+         FROM units LEFT JOIN balls USING(unit) WHERE unit=?",
+    )?;
+    let rows = stmt
+        .query_map(&[last_ball_unit], |row| LastBallUnitProps {
+            is_stable: row.get(0),
+            is_on_main_chain: row.get(1),
+            main_chain_index: row.get(2),
+            ball: row.get(3),
+            max_known_mci: row.get(4),
+        })?
+        .collect::<::std::result::Result<Vec<_>, _>>()?;
+    if rows.len() != 1 {
+        err!(ValidationError::UnitError {
+            err: format!("last ball unit {} not found", last_ball_unit),
+        });
+    }
+    let props = &rows[0];
+
+    ensure!(
+        props.ball.is_some() || props.is_stable == 0,
+        "last ball unit {} is stable but has no ball",
+        last_ball_unit
+    );
+    if props.is_on_main_chain != 1 {
+        err!(ValidationError::UnitError {
+            err: format!("last ball {} is not on MC", last_ball),
+        });
+    }
+    if props.ball.is_some() && props.ball.as_ref() != Some(last_ball) {
+        err!(ValidationError::UnitError {
+            err: format!(
+                "last_ball {} and last_ball_unit {} do not match",
+                last_ball, last_ball_unit
+            ),
+        });
+    }
+
+    state.last_ball_mci = props.main_chain_index.unwrap_or(0);
+    state.max_known_mci = props.max_known_mci.unwrap_or(0);
+    if state.max_parent_limci < state.last_ball_mci {
+        err!(ValidationError::UnitError {
+            err: format!(
+                "last ball unit {} is not included in parents, unit {}",
+                last_ball_unit,
+                joint.get_unit_hash()
+            ),
+        });
+    }
+
+    // the last ball can't be older than the last balls of the parents
+    let sql = format!(
+        "SELECT MAX(lb_units.main_chain_index) FROM units \
+         JOIN units AS lb_units ON units.last_ball_unit=lb_units.unit \
+         WHERE units.unit IN({})",
+        vec!["?"; unit.parent_units.len()].join(",")
+    );
+    let params = unit
+        .parent_units
+        .iter()
+        .map(|u| u as &ToSql)
+        .collect::<Vec<_>>();
+    let max_parent_last_ball_mci =
+        tx.query_row(&sql, &params, |row| row.get::<_, Option<u32>>(0))?;
+    if max_parent_last_ball_mci.unwrap_or(0) > state.last_ball_mci {
+        err!(ValidationError::UnitError {
+            err: format!(
+                "last ball mci must not retreat, parents: {:?}",
+                unit.parent_units
+            ),
+        });
+    }
+
+    if props.is_stable == 1 {
+        return Ok(());
+    }
+
+    // last ball is not stable yet in our view, check if it is stable in view of the parents
+    if !main_chain::determine_if_stable_in_later_units(tx, last_ball_unit, &unit.parent_units)? {
+        err!(ValidationError::UnitError {
+            err: format!(
+                "{}: last ball unit {} is not stable in view of your parents {:?}",
+                joint.get_unit_hash(),
+                last_ball_unit,
+                unit.parent_units
+            ),
+        });
+    }
+
+    // the last ball unit is stable in view of the parents, advance the stability point
+    // to it, validate() holds the writer lock in this case
+    main_chain::mark_stable_up_to_unit(tx, last_ball_unit)?;
+    let ball = tx.query_row(
+        "SELECT ball FROM balls WHERE unit=?",
+        &[last_ball_unit],
+        |row| row.get::<_, String>(0),
+    )?;
+
+    if &ball != last_ball {
+        err!(ValidationError::UnitError {
+            err: format!(
+                "last_ball {} and last_ball_unit {} do not match after advancing stability point",
+                last_ball, last_ball_unit
+            ),
+        });
+    }
+
+    Ok(())
+}
+
+fn check_no_same_address_in_different_parents(tx: &Transaction, unit: &Unit) -> Result<()> {
+    if unit.parent_units.len() == 1 {
+        return Ok(());
+    }
+
+    let parent_list = unit.parent_units
+        .iter()
+        .map(|s| format!("'{}'", s))
+        .collect::<Vec<_>>()
+        .join(", ");
+    let sql = format!(
+        "SELECT address, COUNT(*) AS c FROM unit_authors \
+         WHERE unit IN({}) GROUP BY address HAVING c>1",
+        parent_list
+    );
+    let mut stmt = tx.prepare(&sql)?;
+    let mut rows = stmt.query_map(&[], |row| row.get::<_, String>(0))?;
+    if let Some(row) = rows.next() {
+        err!(ValidationError::UnitError {
+            err: format!(
+                "some addresses found more than once in parents, e.g. {}",
+                row?
+            ),
+        });
+    }
+
+    Ok(())
+}
+
+fn validate_skiplist(tx: &Transaction, skiplist_units: &[String]) -> Result<()> {
+    let mut prev = "";
+    for skiplist_unit in skiplist_units {
+        if skiplist_unit.as_str() <= prev {
+            err!(ValidationError::JointError {
+                err: "skiplist units not ordered".to_owned(),
+            });
+        }
+        prev = skiplist_unit.as_str();
+
+        let mut stmt = tx.prepare_cached(
+            "SELECT is_stable, is_on_main_chain, main_chain_index FROM units WHERE unit=?",
+        )?;
+        let rows = stmt
+            .query_map(&[skiplist_unit], |row| {
+                (
+                    row.get::<_, u32>(0),
+                    row.get::<_, Option<u32>>(1),
+                    row.get::<_, Option<u32>>(2),
+                )
+            })?
+            .collect::<::std::result::Result<Vec<_>, _>>()?;
+        let (is_stable, is_on_main_chain, main_chain_index) = match rows.first() {
+            Some(row) => row.clone(),
+            None => {
+                err!(ValidationError::UnitError {
+                    err: format!("skiplist unit {} not found", skiplist_unit),
+                });
+            }
+        };
+
+        // if not stable, can't check that it is on MC as MC is not stable in its area yet
+        if is_stable == 1 {
+            if is_on_main_chain != Some(1) {
+                err!(ValidationError::UnitError {
+                    err: format!("skiplist unit {} is not on MC", skiplist_unit),
+                });
+            }
+            if main_chain_index.unwrap_or(0) % 10 != 0 {
+                err!(ValidationError::UnitError {
+                    err: format!("skiplist unit {} MCI is not divisible by 10", skiplist_unit),
+                });
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn validate_witnesses(
+    tx: &Transaction,
+    unit: &Unit,
+    state: &ValidationState,
+) -> Result<Vec<String>> {
+    let witnesses = match (&unit.witness_list_unit, &unit.witnesses) {
+        (&Some(ref witness_list_unit), _) => {
+            validate_witness_list_unit(tx, witness_list_unit, state)?
+        }
+        (&None, &Some(ref witnesses)) => {
+            validate_inline_witnesses(tx, unit, witnesses, state)?;
+            witnesses.clone()
+        }
+        (&None, &None) => {
+            err!(ValidationError::UnitError {
+                err: "no witnesses or not enough witnesses".to_owned(),
+            });
+        }
+    };
+
+    if !unit.is_genesis_unit() {
+        validate_witness_list_mutations(tx, unit, &witnesses)?;
+    }
+
+    Ok(witnesses)
+}
+
+fn validate_witness_list_unit(
+    tx: &Transaction,
+    witness_list_unit: &String,
+    state: &ValidationState,
+) -> Result<Vec<String>> {
+    let mut stmt = tx.prepare_cached(
+        "SELECT sequence, is_stable, main_chain_index FROM units WHERE unit=?",
+    )?;
+    let rows = stmt
+        .query_map(&[witness_list_unit], |row| {
+            (
+                row.get::<_, String>(0),
+                row.get::<_, u32>(1),
+                row.get::<_, Option<u32>>(2),
+            )
+        })?
+        .collect::<::std::result::Result<Vec<_>, _>>()?;
+    let (sequence, is_stable, main_chain_index) = match rows.into_iter().next() {
+        Some(row) => row,
+        None => {
+            err!(ValidationError::UnitError {
+                err: format!("referenced witness list unit {} not found", witness_list_unit),
+            });
+        }
+    };
+    if sequence != "good" {
+        err!(ValidationError::UnitError {
+            err: "witness list unit is not serial".to_owned(),
+        });
+    }
+    if is_stable != 1 {
+        err!(ValidationError::UnitError {
+            err: "witness list unit is not stable".to_owned(),
+        });
+    }
+    if main_chain_index.map_or(true, |mci| mci > state.last_ball_mci) {
+        err!(ValidationError::UnitError {
+            err: "witness list unit must come before last ball".to_owned(),
+        });
+    }
+
+    match storage::read_witness_list(tx, witness_list_unit) {
+        Ok(witnesses) => Ok(witnesses),
+        Err(_) => {
+            err!(ValidationError::UnitError {
+                err: format!(
+                    "referenced witness list unit {} has no witnesses",
+                    witness_list_unit
+                ),
+            });
+        }
+    }
+}
+
+fn validate_inline_witnesses(
+    tx: &Transaction,
+    unit: &Unit,
+    witnesses: &[String],
+    state: &ValidationState,
+) -> Result<()> {
+    if witnesses.len() != config::COUNT_WITNESSES {
+        err!(ValidationError::UnitError {
+            err: "wrong number of witnesses".to_owned(),
+        });
+    }
+
+    let mut prev_witness = "";
+    for witness in witnesses {
+        if witness.as_str() <= prev_witness {
+            err!(ValidationError::UnitError {
+                err: "wrong order of witnesses, or duplicates".to_owned(),
+            });
+        }
+        if witness.len() != 32 || !object_hash::is_chash_valid(witness.clone())? {
+            err!(ValidationError::UnitError {
+                err: format!("witness address {} is invalid", witness),
+            });
+        }
+        prev_witness = witness.as_str();
+    }
+
+    // the witnesses of genesis are not known yet
+    if unit.is_genesis_unit() {
+        return Ok(());
+    }
+
+    if storage::determine_if_witness_and_address_definition_have_refs(tx, witnesses)? {
+        err!(ValidationError::UnitError {
+            err: "some witnesses have references in their addresses".to_owned(),
+        });
+    }
+
+    let witness_list = witnesses
+        .iter()
+        .map(|s| format!("'{}'", s))
+        .collect::<Vec<_>>()
+        .join(", ");
+    let sql = format!(
+        "SELECT COUNT(DISTINCT address) FROM unit_authors CROSS JOIN units USING(unit) \
+         WHERE address IN({}) AND +sequence='good' AND is_stable=1 AND main_chain_index<=?",
+        witness_list
+    );
+    let count_stable_good_witnesses =
+        tx.query_row(&sql, &[&state.last_ball_mci], |row| row.get::<_, u32>(0))?;
+    if count_stable_good_witnesses as usize != config::COUNT_WITNESSES {
+        err!(ValidationError::UnitError {
+            err: "some witnesses are not stable, not serial, or don't come before last ball"
+                .to_owned(),
+        });
+    }
+
+    Ok(())
+}
+
+// the witness list may differ from that of each parent by no more than MAX_WITNESS_LIST_MUTATIONS
+fn validate_witness_list_mutations(
+    tx: &Transaction,
+    unit: &Unit,
+    witnesses: &[String],
+) -> Result<()> {
+    let witness_list = witnesses
+        .iter()
+        .map(|s| format!("'{}'", s))
+        .collect::<Vec<_>>()
+        .join(", ");
+    let sql = format!(
+        "SELECT COUNT(*) FROM units CROSS JOIN unit_witnesses \
+         ON unit_witnesses.unit IN(units.unit, units.witness_list_unit) \
+         WHERE units.unit=? AND address IN({})",
+        witness_list
+    );
+    let min_matching_witnesses = config::COUNT_WITNESSES - config::MAX_WITNESS_LIST_MUTATIONS;
+
+    let mut stmt = tx.prepare(&sql)?;
+    for parent_unit in &unit.parent_units {
+        let count_matching_witnesses =
+            stmt.query_row(&[parent_unit], |row| row.get::<_, u32>(0))?;
+        if (count_matching_witnesses as usize) < min_matching_witnesses {
+            err!(ValidationError::UnitError {
+                err: format!(
+                    "witness list of parent {} is incompatible, only {} matching witnesses",
+                    parent_unit, count_matching_witnesses
+                ),
+            });
+        }
+    }
+
+    Ok(())
+}
+
+fn check_witnessed_level_did_not_retreat(
+    tx: &Transaction,
+    unit: &Unit,
+    witnesses: &[String],
+) -> Result<()> {
+    if unit.is_genesis_unit() {
+        return Ok(());
+    }
+
+    let (witnessed_level, best_parent_unit) =
+        storage::determine_witnessed_level_and_best_parent(tx, &unit.parent_units, witnesses)?;
+    let best_parent_unit = match best_parent_unit {
+        Some(unit) => unit,
+        None => {
+            err!(ValidationError::UnitError {
+                err: "no compatible best parent".to_owned(),
+            });
+        }
+    };
+
+    let best_parent_props = storage::read_static_unit_property(tx, &best_parent_unit)?;
+    if witnessed_level < best_parent_props.witnessed_level {
+        err!(ValidationError::UnitError {
+            err: format!(
+                "witnessed level retreats from {} to {}",
+                best_parent_props.witnessed_level, witnessed_level
+            ),
+        });
+    }
+
+    Ok(())
+}
+
+fn validate_authors(tx: &Transaction, unit: &Unit, state: &mut ValidationState) -> Result<()> {
+    if unit.authors.len() > config::MAX_AUTHORS_PER_UNIT {
+        err!(ValidationError::UnitError {
+            err: "too many authors".to_owned()
+        });
+    }
+
+    let mut prev_address = "";
+    for author in &unit.authors {
+        if author.address.as_str() <= prev_address {
+            err!(ValidationError::UnitError {
+                err: "author addresses not sorted".to_owned(),
+            });
+        }
+        prev_address = author.address.as_str();
+    }
+
+    state.unit_hash_to_sign = unit.get_unit_hash_to_sign();
+    for author in &unit.authors {
+        validate_author(tx, author, unit, state)?;
+    }
+
+    Ok(())
+}
+
+fn validate_author(
+    tx: &Transaction,
+    author: &Author,
+    unit: &Unit,
+    state: &mut ValidationState,
+) -> Result<()> {
+    if author.address.len() != 32 {
+        err!(ValidationError::UnitError {
+            err: "wrong address length".to_owned(),
+        });
+    }
+
+    if author.authentifiers.is_empty() && unit.content_hash.is_none() {
+        err!(ValidationError::UnitError {
+            err: "no authentifiers".to_owned()
+        });
+    }
+
+    for authentifier in author.authentifiers.values() {
+        if authentifier.is_empty() {
+            err!(ValidationError::UnitError {
+                err: "authentifiers must be nonempty strings".to_owned(),
+            });
+        }
+        if authentifier.len() > config::MAX_AUTHENTIFIER_LENGTH {
+            err!(ValidationError::UnitError {
+                err: "authentifier too long".to_owned(),
+            });
+        }
+    }
+
+    let definition = if author.definition.is_null() {
+        if !object_hash::is_chash_valid(author.address.clone())? {
+            err!(ValidationError::UnitError {
+                err: "address checksum invalid".to_owned(),
+            });
+        }
+        // nothing else to check for the stripped units
+        if unit.content_hash.is_some() {
+            return Ok(());
+        }
+
+        // we check signatures using the latest address definition before last ball
+        match storage::read_definition_by_address(tx, &author.address, Some(state.last_ball_mci))
+            .map_err(to_transient_error)?
+        {
+            Some(definition) => serde_json::from_str(&definition).map_err(to_transient_error)?,
+            None => {
+                err!(ValidationError::UnitError {
+                    err: format!(
+                        "definition bound to address {} is not defined",
+                        author.address
+                    ),
+                });
+            }
+        }
+    } else if author.definition.is_array() {
+        let definition_chash = object_hash::get_chash(&author.definition)?;
+        if storage::read_definition_by_address(tx, &author.address, Some(state.last_ball_mci))
+            .map_err(to_transient_error)?
+            .is_some()
+        {
+            err!(ValidationError::UnitError {
+                err: format!("duplicate definition of address {}", author.address),
+            });
+        }
+        // first use of the address, the definition must hash to it
+        if definition_chash != author.address {
+            err!(ValidationError::UnitError {
+                err: format!(
+                    "wrong definition: {} != {}",
+                    definition_chash, author.address
+                ),
+            });
+        }
+        author.definition.clone()
+    } else {
+        err!(ValidationError::UnitError {
+            err: "bad type of definition".to_owned(),
+        });
+    };
+
+    let res = match definition::validate_authentifiers(
+        tx,
+        &author.address,
+        &definition,
+        unit,
+        state,
+        &author.authentifiers,
+    ) {
+        Ok(res) => res,
+        // the referenced units and addresses are read while evaluating the definition
+        Err(ref e) if e.downcast_ref::<::rusqlite::Error>().is_some() => {
+            err!(ValidationError::TransientError { err: e.to_string() });
+        }
+        Err(e) => {
+            err!(ValidationError::UnitError { err: e.to_string() });
+        }
+    };
+    if !res {
+        err!(ValidationError::UnitError {
+            err: "authentifier verification failed".to_owned(),
+        });
+    }
+
+    check_serial_address_use(tx, &author.address, unit, state).map_err(to_transient_error)
+}
+
+// failing to read the db says nothing about the unit, it may pass when tried again
+fn to_transient_error<E: Into<::failure::Error>>(e: E) -> ::failure::Error {
+    let e = e.into();
+    if e.downcast_ref::<ValidationError>().is_some() {
+        return e;
+    }
+    ValidationError::TransientError { err: e.to_string() }.into()
+}
+
+// units of the same author that are not included in our parents are conflicting,
+// the unit is temp-bad until all the conflicts are stable, then it becomes final-bad
+fn check_serial_address_use(
+    tx: &Transaction,
+    address: &String,
+    unit: &Unit,
+    state: &mut ValidationState,
+) -> Result<()> {
+    let mut stmt = tx.prepare_cached(
+        "SELECT unit, is_stable FROM units CROSS JOIN unit_authors USING(unit) \
+         WHERE address=? AND (main_chain_index>? OR main_chain_index IS NULL) AND unit != ?",
+    )?;
+    let rows = stmt
+        .query_map(
+            &[
+                address,
+                &state.max_parent_limci,
+                unit.unit.as_ref().unwrap(),
+            ],
+            |row| (row.get::<_, String>(0), row.get::<_, u32>(1)),
+        )?
+        .collect::<::std::result::Result<Vec<_>, _>>()?;
+
+    let mut conflicting_units = Vec::new();
+    let mut has_stable_conflict = false;
+    for (conflicting_unit, is_stable) in rows {
+        if graph::determine_if_included_or_equal(tx, &conflicting_unit, &unit.parent_units)? {
+            continue;
+        }
+        if is_stable == 1 {
+            has_stable_conflict = true;
+        }
+        conflicting_units.push(conflicting_unit);
+    }
+
+    if conflicting_units.is_empty() {
+        return Ok(());
+    }
+
+    warn!("found conflicting units {:?}", conflicting_units);
+    if !state.addresses_with_forked_path.contains(address) {
+        state.addresses_with_forked_path.push(address.clone());
+    }
+    if state.sequence != "final-bad" {
+        state.sequence = if has_stable_conflict {
+            String::from("final-bad")
+        } else {
+            String::from("temp-bad")
+        };
+    }
+    if !has_stable_conflict {
+        // the conflicting units that are still good become temp-bad as well
+        let units_list = conflicting_units
+            .iter()
+            .map(|s| format!("'{}'", s))
+            .collect::<Vec<_>>()
+            .join(", ");
+        state.additional_queries.push(format!(
+            "UPDATE units SET sequence='temp-bad' WHERE unit IN({}) AND +sequence='good'",
+            units_list
+        ));
+    }
+    for conflicting_unit in conflicting_units {
+        if !state.conflicting_units.contains(&conflicting_unit) {
+            state.conflicting_units.push(conflicting_unit);
+        }
+    }
+
+    Ok(())
+}
+
+fn validate_messages(tx: &Transaction, unit: &Unit, state: &mut ValidationState) -> Result<()> {
+    for (i, message) in unit.messages.iter().enumerate() {
+        validate_message(tx, message, i as u32, unit, state)?;
+    }
+
+    if !state.has_base_payment {
+        err!(ValidationError::UnitError {
+            err: "no base payment message".to_owned(),
+        });
+    }
+
+    Ok(())
+}
+
+fn validate_message(
+    tx: &Transaction,
+    message: &Message,
+    message_index: u32,
+    unit: &Unit,
+    state: &mut ValidationState,
+) -> Result<()> {
+    if message.payload_hash.len() != HASH_LENGTH {
+        err!(ValidationError::UnitError {
+            err: "wrong payload hash size".to_owned(),
+        });
+    }
+
+    let location = message.payload_location.as_str();
+    if location != "inline" && location != "uri" && location != "none" {
+        err!(ValidationError::UnitError {
+            err: format!("wrong payload location: {}", location),
+        });
+    }
+
+    if location == "none" && message.payload.is_some() {
+        err!(ValidationError::UnitError {
+            err: "must be no payload".to_owned(),
+        });
+    }
+
+    if location == "uri" {
+        if message.payload.is_some() {
+            err!(ValidationError::UnitError {
+                err: "must not contain payload".to_owned(),
+            });
+        }
+        if message.payload_uri.is_none() {
+            err!(ValidationError::UnitError {
+                err: "no payload uri".to_owned(),
+            });
+        }
+        if message.payload_uri_hash.as_ref().map(|s| s.len()) != Some(HASH_LENGTH) {
+            err!(ValidationError::UnitError {
+                err: "wrong length of payload uri hash".to_owned(),
+            });
+        }
+    } else if message.payload_uri.is_some() || message.payload_uri_hash.is_some() {
+        err!(ValidationError::UnitError {
+            err: "must not contain payload_uri and payload_uri_hash".to_owned(),
+        });
+    }
+
+    // these apps are saved into their own tables
+    let inline_only_apps = [
+        "address_definition_change",
+        "data_feed",
+        "definition_template",
+        "asset",
+        "asset_attestors",
+        "attestation",
+        "poll",
+        "vote",
+    ];
+    if location != "inline" && inline_only_apps.contains(&message.app.as_str()) {
+        err!(ValidationError::UnitError {
+            err: format!("{} must be inline", message.app),
+        });
+    }
+
+    if message.app == "payment" {
+        if location != "inline" && location != "none" {
+            err!(ValidationError::UnitError {
+                err: "payment location must be inline or none".to_owned(),
+            });
+        }
+        if location == "none" && message.spend_proofs.is_none() {
+            err!(ValidationError::UnitError {
+                err: "private payment must come with spend proof(s)".to_owned(),
+            });
+        }
+    }
+
+    if location != "inline" {
+        return Ok(());
+    }
+
+    let payload = match message.payload {
+        Some(ref payload) => payload,
+        None => {
+            err!(ValidationError::UnitError {
+                err: "no inline payload".to_owned(),
+            });
+        }
+    };
+
+    let payload_hash = object_hash::get_base64_hash(payload)?;
+    if payload_hash != message.payload_hash {
+        err!(ValidationError::UnitError {
+            err: format!(
+                "wrong payload hash: expected {}, got {}",
+                payload_hash, message.payload_hash
+            ),
+        });
+    }
+
+    validate_inline_payload(tx, message, payload, message_index, unit, state)
+}
+
+fn validate_inline_payload(
+    tx: &Transaction,
+    message: &Message,
+    payload: &Payload,
+    message_index: u32,
+    unit: &Unit,
+    state: &mut ValidationState,
+) -> Result<()> {
+    let app = message.app.as_str();
+    match (app, payload) {
+        ("payment", &Payload::Payment(ref payment)) => {
+            return validate_payment(tx, payment, message_index, unit, state);
+        }
+        ("payment", _) => {
+            err!(ValidationError::UnitError {
+                err: "payment payload must be an object with inputs and outputs".to_owned(),
+            });
+        }
+        ("text", &Payload::Text(_)) => return Ok(()),
+        ("text", _) => {
+            err!(ValidationError::UnitError {
+                err: "text payload must be string".to_owned(),
+            });
+        }
+        _ => {}
+    }
+
+    // the other apps are checked on the plain json
+    let payload = serde_json::to_value(payload)?;
+    match app {
+        "address_definition_change" => validate_address_definition_change(&payload, unit, state),
+        "data_feed" => validate_data_feed(&payload, state),
+        "poll" => validate_poll(&payload, state),
+        "vote" => validate_vote(tx, &payload, state),
+        "profile" | "data" => {
+            if app == "profile" {
+                if unit.authors.len() != 1 {
+                    err!(ValidationError::UnitError {
+                        err: "profile must be single-authored".to_owned(),
+                    });
+                }
+                if state.has_profile {
+                    err!(ValidationError::UnitError {
+                        err: "can be only one profile".to_owned(),
+                    });
+                }
+                state.has_profile = true;
+            }
+            if !payload.is_object() {
+                err!(ValidationError::UnitError {
+                    err: format!("{} payload must be object", app),
+                });
+            }
+            Ok(())
+        }
+        "definition_template" => {
+            if state.has_definition_template {
+                err!(ValidationError::UnitError {
+                    err: "can be only one definition template".to_owned(),
+                });
+            }
+            state.has_definition_template = true;
+            if payload.as_array().map(|a| a.len()) != Some(2) {
+                err!(ValidationError::UnitError {
+                    err: "definition_template payload must be array of two elements".to_owned(),
+                });
+            }
+            Ok(())
+        }
+        "attestation" => {
+            if unit.authors.len() != 1 {
+                err!(ValidationError::UnitError {
+                    err: "attestation must be single-authored".to_owned(),
+                });
+            }
+            if has_fields_except(&payload, &["address", "profile"]) {
+                err!(ValidationError::UnitError {
+                    err: "unknown fields in attestation".to_owned(),
+                });
+            }
+            // it's fine to attest an address that is not used yet, or oneself
+            if !is_valid_address(&payload["address"]) {
+                err!(ValidationError::UnitError {
+                    err: "attesting an invalid address".to_owned(),
+                });
+            }
+            if !payload["profile"].is_object() {
+                err!(ValidationError::UnitError {
+                    err: "attested profile must be object".to_owned(),
+                });
+            }
+            Ok(())
+        }
+        "asset" | "asset_attestors" => {
+            err!(ValidationError::UnitError {
+                err: format!("{} is not supported", app),
+            });
+        }
+        _ => {
+            err!(ValidationError::UnitError {
+                err: format!("unknown app: {}", app),
+            });
+        }
+    }
+}
+
+fn has_fields_except(payload: &Value, fields: &[&str]) -> bool {
+    match payload.as_object() {
+        Some(map) => map.keys().any(|key| !fields.contains(&key.as_str())),
+        None => false,
+    }
+}
+
+fn is_valid_address(address: &Value) -> bool {
+    match address.as_str() {
+        Some(address) => {
+            address.len() == 32 && object_hash::is_chash_valid(address.to_owned()).unwrap_or(false)
+        }
+        None => false,
+    }
+}
+
+fn validate_address_definition_change(
+    payload: &Value,
+    unit: &Unit,
+    state: &mut ValidationState,
+) -> Result<()> {
+    if !payload.is_object() || has_fields_except(payload, &["definition_chash", "address"]) {
+        err!(ValidationError::UnitError {
+            err: "unknown fields in address_definition_change".to_owned(),
+        });
+    }
+
+    // the address is explicit only when multi-authored
+    let address = if unit.authors.len() > 1 {
+        let address = payload["address"].as_str().unwrap_or("");
+        if !is_valid_address(&payload["address"]) {
+            err!(ValidationError::UnitError {
+                err: "when multi-authored, must indicate address".to_owned(),
+            });
+        }
+        if !unit.authors.iter().any(|a| a.address == address) {
+            err!(ValidationError::UnitError {
+                err: "foreign address".to_owned(),
+            });
+        }
+        address.to_owned()
+    } else {
+        if payload.get("address").is_some() {
+            err!(ValidationError::UnitError {
+                err: "when single-authored, must not indicate address".to_owned(),
+            });
+        }
+        unit.authors[0].address.clone()
+    };
+
+    if state.definition_change_addresses.contains(&address) {
+        err!(ValidationError::UnitError {
+            err: "can be only one definition change per address".to_owned(),
+        });
+    }
+    state.definition_change_addresses.push(address);
+
+    if !is_valid_address(&payload["definition_chash"]) {
+        err!(ValidationError::UnitError {
+            err: "bad new definition_chash".to_owned(),
+        });
+    }
+    Ok(())
+}
+
+fn validate_data_feed(payload: &Value, state: &mut ValidationState) -> Result<()> {
+    if state.has_data_feed {
+        err!(ValidationError::UnitError {
+            err: "can be only one data feed".to_owned(),
+        });
+    }
+    state.has_data_feed = true;
+
+    let feeds = match payload.as_object() {
+        Some(feeds) if !feeds.is_empty() => feeds,
+        _ => {
+            err!(ValidationError::UnitError {
+                err: "data feed payload must be non-empty object".to_owned(),
+            });
+        }
+    };
+    for (feed_name, value) in feeds {
+        if feed_name.len() > config::MAX_DATA_FEED_NAME_LENGTH {
+            err!(ValidationError::UnitError {
+                err: format!("feed name {} too long", feed_name),
+            });
+        }
+        match *value {
+            Value::String(ref s) if s.len() > config::MAX_DATA_FEED_VALUE_LENGTH => {
+                err!(ValidationError::UnitError {
+                    err: format!("value {} too long", s),
+                });
+            }
+            Value::String(_) => {}
+            Value::Number(ref n) if !n.is_i64() => {
+                err!(ValidationError::UnitError {
+                    err: "fractional numbers not allowed in data feeds".to_owned(),
+                });
+            }
+            Value::Number(_) => {}
+            _ => {
+                err!(ValidationError::UnitError {
+                    err: format!("data feed {} must be string or number", feed_name),
+                });
+            }
+        }
+    }
+    Ok(())
+}
+
+fn validate_poll(payload: &Value, state: &mut ValidationState) -> Result<()> {
+    if state.has_poll {
+        err!(ValidationError::UnitError {
+            err: "can be only one poll".to_owned(),
+        });
+    }
+    state.has_poll = true;
+
+    if !payload.is_object() {
+        err!(ValidationError::UnitError {
+            err: "poll payload must be object".to_owned(),
+        });
+    }
+    if has_fields_except(payload, &["question", "choices"]) {
+        err!(ValidationError::UnitError {
+            err: "unknown fields in poll".to_owned(),
+        });
+    }
+    if !payload["question"].is_string() {
+        err!(ValidationError::UnitError {
+            err: "no question in poll".to_owned(),
+        });
+    }
+    let choices = match payload["choices"].as_array() {
+        Some(choices) if !choices.is_empty() => choices,
+        _ => {
+            err!(ValidationError::UnitError {
+                err: "no choices in poll".to_owned(),
+            });
+        }
+    };
+    if choices.len() > config::MAX_CHOICES_PER_POLL {
+        err!(ValidationError::UnitError {
+            err: "too many choices in poll".to_owned(),
+        });
+    }
+    let mut seen = Vec::new();
+    for choice in choices {
+        let choice = match choice.as_str() {
+            Some(choice) if choice.len() <= config::MAX_CHOICE_LENGTH => choice,
+            _ => {
+                err!(ValidationError::UnitError {
+                    err: "all choices must be short strings".to_owned(),
+                });
+            }
+        };
+        if seen.contains(&choice) {
+            err!(ValidationError::UnitError {
+                err: format!("duplicate choice {} in poll", choice),
+            });
+        }
+        seen.push(choice);
+    }
+    Ok(())
+}
+
+// the poll must be serial and before the last ball
+fn validate_vote(tx: &Transaction, payload: &Value, state: &ValidationState) -> Result<()> {
+    let poll_unit = match payload["unit"].as_str() {
+        Some(poll_unit) if poll_unit.len() == HASH_LENGTH => poll_unit,
+        _ => {
+            err!(ValidationError::UnitError {
+                err: "invalid unit in vote".to_owned(),
+            });
+        }
+    };
+    let choice = match payload["choice"].as_str() {
+        Some(choice) => choice,
+        None => {
+            err!(ValidationError::UnitError {
+                err: "choice must be string".to_owned(),
+            });
+        }
+    };
+    if has_fields_except(payload, &["unit", "choice"]) {
+        err!(ValidationError::UnitError {
+            err: "unknown fields in vote".to_owned(),
+        });
+    }
+
+    let mut stmt = tx.prepare_cached(
+        "SELECT main_chain_index, sequence FROM polls \
+         JOIN poll_choices USING(unit) JOIN units USING(unit) WHERE unit=? AND choice=?",
+    )?;
+    let rows = stmt
+        .query_map(&[&poll_unit, &choice], |row| {
+            (row.get::<_, Option<u32>>(0), row.get::<_, String>(1))
+        })?
+        .collect::<::std::result::Result<Vec<_>, _>>()?;
+    let (main_chain_index, sequence) = match rows.into_iter().next() {
+        Some(row) => row,
+        None => {
+            err!(ValidationError::UnitError {
+                err: format!("invalid choice {} or poll {}", choice, poll_unit),
+            });
+        }
+    };
+    match main_chain_index {
+        Some(mci) if mci <= state.last_ball_mci => {}
+        _ => {
+            err!(ValidationError::UnitError {
+                err: "poll unit must be before last ball".to_owned(),
+            });
+        }
+    }
+    if sequence != "good" {
+        err!(ValidationError::UnitError {
+            err: "poll unit is not serial".to_owned(),
+        });
+    }
+    Ok(())
+}
+
+fn validate_payment(
+    tx: &Transaction,
+    payload: &Payment,
+    message_index: u32,
+    unit: &Unit,
+    state: &mut ValidationState,
+) -> Result<()> {
+    if payload.asset.is_some() {
+        err!(ValidationError::UnitError {
+            err: "asset payments are not supported".to_owned(),
+        });
+    }
+
+    // base currency
+    if payload.address.is_some() || payload.definition_chash.is_some()
+        || payload.denomination.is_some()
+    {
+        err!(ValidationError::UnitError {
+            err: "unknown fields in payment message".to_owned(),
+        });
+    }
+    if state.has_base_payment {
+        err!(ValidationError::UnitError {
+            err: "can have only one base payment".to_owned(),
+        });
+    }
+    state.has_base_payment = true;
+
+    validate_payment_inputs_and_outputs(tx, payload, message_index, unit, state)
+}
+
+fn validate_payment_inputs_and_outputs(
+    tx: &Transaction,
+    payload: &Payment,
+    message_index: u32,
+    unit: &Unit,
+    state: &mut ValidationState,
+) -> Result<()> {
+    let denomination = payload.denomination.unwrap_or(1);
+    let author_addresses = unit.authors
+        .iter()
+        .map(|a| a.address.clone())
+        .collect::<Vec<_>>();
+
+    if payload.inputs.len() > config::MAX_INPUTS_PER_PAYMENT_MESSAGE {
+        err!(ValidationError::UnitError {
+            err: "too many inputs".to_owned()
+        });
+    }
+    if payload.outputs.len() > config::MAX_OUTPUTS_PER_PAYMENT_MESSAGE {
+        err!(ValidationError::UnitError {
+            err: "too many outputs".to_owned()
+        });
+    }
+
+    let mut total_output = 0i64;
+    let mut prev_address = "";
+    let mut prev_amount = 0;
+    for output in &payload.outputs {
+        if output.amount <= 0 {
+            err!(ValidationError::UnitError {
+                err: format!("amount must be positive integer, found {}", output.amount),
+            });
+        }
+        if output.address.len() != 32 || !object_hash::is_chash_valid(output.address.clone())? {
+            err!(ValidationError::UnitError {
+                err: format!("output address {} invalid", output.address),
+            });
+        }
+        if prev_address > output.address.as_str() {
+            err!(ValidationError::UnitError {
+                err: "output addresses not sorted".to_owned(),
+            });
+        } else if prev_address == output.address.as_str() && prev_amount > output.amount {
+            err!(ValidationError::UnitError {
+                err: "output amounts for same address not sorted".to_owned(),
+            });
+        }
+        prev_address = output.address.as_str();
+        prev_amount = output.amount;
+        total_output += output.amount;
+    }
+
+    let mut total_input = 0i64;
+    let mut is_issue = false;
+    let mut have_headers_commissions = false;
+    let mut have_witnessings = false;
+    for (input_index, input) in payload.inputs.iter().enumerate() {
+        let default_kind = String::from("transfer");
+        let kind = input.kind.as_ref().unwrap_or(&default_kind);
+
+        if input.amount.is_some() && kind != "issue" {
+            err!(ValidationError::UnitError {
+                err: "amount in base input".to_owned(),
+            });
+        }
+
+        match kind.as_str() {
+            "issue" => {
+                if input_index != 0 {
+                    err!(ValidationError::UnitError {
+                        err: "issue must come first".to_owned(),
+                    });
+                }
+                if input.unit.is_some() || input.message_index.is_some()
+                    || input.output_index.is_some()
+                    || input.from_main_chain_index.is_some()
+                    || input.to_main_chain_index.is_some()
+                {
+                    err!(ValidationError::UnitError {
+                        err: "unknown fields in issue input".to_owned(),
+                    });
+                }
+                if input.amount.unwrap_or(0) <= 0 {
+                    err!(ValidationError::UnitError {
+                        err: "amount must be positive".to_owned(),
+                    });
+                }
+                if input.serial_number != Some(1) {
+                    err!(ValidationError::UnitError {
+                        err: "for capped asset serial_number must be 1".to_owned(),
+                    });
+                }
+                if is_issue {
+                    err!(ValidationError::UnitError {
+                        err: "only one issue per message allowed".to_owned(),
+                    });
+                }
+                is_issue = true;
+
+                let address = get_input_address(input, kind, &author_addresses)?;
+                if !unit.is_genesis_unit() {
+                    err!(ValidationError::UnitError {
+                        err: "only genesis can issue base asset".to_owned(),
+                    });
+                }
+                if input.amount != Some(config::TOTAL_WHITEBYTES) {
+                    err!(ValidationError::UnitError {
+                        err: "issue must be equal to cap".to_owned(),
+                    });
+                }
+
+                let input_key = format!("base-{}-{}", address, input.serial_number.unwrap());
+                check_input_key(state, input_key)?;
+                total_input += input.amount.unwrap();
+            }
+            "transfer" => {
+                if have_headers_commissions || have_witnessings {
+                    err!(ValidationError::UnitError {
+                        err: "all transfers must come before hc and witnessings".to_owned(),
+                    });
+                }
+                if input.address.is_some() || input.serial_number.is_some()
+                    || input.from_main_chain_index.is_some()
+                    || input.to_main_chain_index.is_some()
+                {
+                    err!(ValidationError::UnitError {
+                        err: "unknown fields in payment input".to_owned(),
+                    });
+                }
+
+                let src_unit = match input.unit {
+                    Some(ref unit) if unit.len() == HASH_LENGTH => unit,
+                    _ => {
+                        err!(ValidationError::UnitError {
+                            err: "wrong unit length in payment input".to_owned(),
+                        });
+                    }
+                };
+                let src_message_index = match input.message_index {
+                    Some(index) => index,
+                    None => {
+                        err!(ValidationError::UnitError {
+                            err: "no message_index in payment input".to_owned(),
+                        });
+                    }
+                };
+                let src_output_index = match input.output_index {
+                    Some(index) => index,
+                    None => {
+                        err!(ValidationError::UnitError {
+                            err: "no output_index in payment input".to_owned(),
+                        });
+                    }
+                };
+
+                let input_key = format!(
+                    "base-{}-{}-{}",
+                    src_unit, src_message_index, src_output_index
+                );
+                check_input_key(state, input_key)?;
+
+                struct SrcOutput {
+                    amount: i64,
+                    sequence: String,
+                    address: String,
+                    main_chain_index: Option<u32>,
+                    denomination: u32,
+                    asset: Option<String>,
+                }
+
+                let mut stmt = tx.prepare_cached(
+                    "SELECT amount, sequence, address, main_chain_index, denomination, asset \
+                     FROM outputs JOIN units USING(unit) \
+                     WHERE outputs.unit=? AND message_index=? AND output_index=?",
+                )?;
+                let rows = stmt
+                    .query_map(&[src_unit, &src_message_index, &src_output_index], |row| {
+                        SrcOutput {
+                            amount: row.get(0),
+                            sequence: row.get(1),
+                            address: row.get(2),
+                            main_chain_index: row.get(3),
+                            denomination: row.get(4),
+                            asset: row.get(5),
+                        }
+                    })?
+                    .collect::<::std::result::Result<Vec<_>, _>>()?;
+                ensure!(rows.len() <= 1, "more than 1 src output");
+                let src_output = match rows.into_iter().next() {
+                    Some(src_output) => src_output,
+                    None => {
+                        err!(ValidationError::UnitError {
+                            err: format!("input unit {} not found", src_unit),
+                        });
+                    }
+                };
+
+                if src_output.asset.is_some() {
+                    err!(ValidationError::UnitError {
+                        err: "asset mismatch".to_owned(),
+                    });
+                }
+                // for public payments, you can't spend unconfirmed transactions
+                match src_output.main_chain_index {
+                    Some(mci) if mci <= state.last_ball_mci => {}
+                    _ => {
+                        err!(ValidationError::UnitError {
+                            err: "src output must be before last ball".to_owned(),
+                        });
+                    }
+                }
+                if src_output.sequence != "good" {
+                    err!(ValidationError::UnitError {
+                        err: format!("input unit {} is not serial", src_unit),
+                    });
+                }
+                if !author_addresses.contains(&src_output.address) {
+                    err!(ValidationError::UnitError {
+                        err: "output owner is not among authors".to_owned(),
+                    });
+                }
+                if src_output.denomination != denomination {
+                    err!(ValidationError::UnitError {
+                        err: "denomination mismatch".to_owned(),
+                    });
+                }
+
+                let double_spend_where = format!(
+                    "type='{}' AND src_unit='{}' AND src_message_index={} AND src_output_index={}",
+                    kind, src_unit, src_message_index, src_output_index
+                );
+                check_input_double_spend(
+                    tx,
+                    unit,
+                    state,
+                    &author_addresses,
+                    double_spend_where,
+                    message_index,
+                    input_index as u32,
+                )?;
+
+                total_input += src_output.amount;
+            }
+            "headers_commission" | "witnessing" => {
+                if kind == "headers_commission" {
+                    if have_witnessings {
+                        err!(ValidationError::UnitError {
+                            err: "all headers commissions must come before witnessings"
+                                .to_owned(),
+                        });
+                    }
+                    have_headers_commissions = true;
+                } else {
+                    have_witnessings = true;
+                }
+
+                if input.unit.is_some() || input.message_index.is_some()
+                    || input.output_index.is_some()
+                    || input.serial_number.is_some()
+                {
+                    err!(ValidationError::UnitError {
+                        err: "unknown fields in witnessing input".to_owned(),
+                    });
+                }
+
+                if input.from_main_chain_index.is_none() || input.to_main_chain_index.is_none() {
+                    err!(ValidationError::UnitError {
+                        err: "no from_main_chain_index or to_main_chain_index".to_owned(),
+                    });
+                }
+                let from_mci = input.from_main_chain_index.unwrap();
+                let to_mci = input.to_main_chain_index.unwrap();
+                if from_mci > to_mci {
+                    err!(ValidationError::UnitError {
+                        err: "from_main_chain_index > input.to_main_chain_index".to_owned(),
+                    });
+                }
+                if to_mci > state.last_ball_mci {
+                    err!(ValidationError::UnitError {
+                        err: "to_main_chain_index > last_ball_mci".to_owned(),
+                    });
+                }
+
+                let address = get_input_address(input, kind, &author_addresses)?;
+                let input_key = format!("{}-{}-{}", kind, address, from_mci);
+                check_input_key(state, input_key)?;
+
+                let next_spendable_mc_index = mc_outputs::read_next_spendable_mc_index(
+                    tx,
+                    kind,
+                    &address,
+                    &state.conflicting_units,
+                )?;
+                // gaps allowed, in case a unit becomes bad due to another address being nonserial
+                if from_mci < next_spendable_mc_index {
+                    err!(ValidationError::UnitError {
+                        err: format!("{} ranges must not overlap", kind),
+                    });
+                }
+
+                let max_mci = if kind == "headers_commission" {
+                    header_commissions::get_max_spendable_mci_for_last_ball_mci(
+                        state.last_ball_mci,
+                    )
+                } else {
+                    paid_witnessing::get_max_spendable_mci_for_last_ball_mci(state.last_ball_mci)
+                };
+                if max_mci.map_or(true, |max_mci| to_mci > max_mci) {
+                    err!(ValidationError::UnitError {
+                        err: format!("{} to_main_chain_index is too large", kind),
+                    });
+                }
+
+                let commission = if kind == "headers_commission" {
+                    mc_outputs::calc_earnings(tx, kind, from_mci, to_mci, &address)?
+                } else {
+                    paid_witnessing::calc_witness_earnings(tx, kind, from_mci, to_mci, &address)?
+                };
+                if commission == 0 {
+                    err!(ValidationError::UnitError {
+                        err: format!("zero {} commission", kind),
+                    });
+                }
+
+                let double_spend_where = format!(
+                    "type='{}' AND from_main_chain_index={} AND address='{}'",
+                    kind, from_mci, address
+                );
+                check_input_double_spend(
+                    tx,
+                    unit,
+                    state,
+                    &author_addresses,
+                    double_spend_where,
+                    message_index,
+                    input_index as u32,
+                )?;
+
+                total_input += i64::from(commission);
+            }
+            _ => {
+                err!(ValidationError::UnitError {
+                    err: format!("unrecognized input type: {}", kind),
+                });
+            }
+        }
+    }
+
+    let commissions = i64::from(unit.headers_commission.unwrap_or(0))
+        + i64::from(unit.payload_commission.unwrap_or(0));
+    if total_input != total_output + commissions {
+        err!(ValidationError::UnitError {
+            err: format!(
+                "inputs and outputs do not balance: {} !== {} + {} + {}",
+                total_input,
+                total_output,
+                unit.headers_commission.unwrap_or(0),
+                unit.payload_commission.unwrap_or(0)
+            ),
+        });
+    }
+
+    Ok(())
+}
+
+// the address of issue and commission inputs, it must be explicit when multi-authored
+fn get_input_address(input: &Input, kind: &str, author_addresses: &[String]) -> Result<String> {
+    if author_addresses.len() == 1 {
+        if input.address.is_some() {
+            err!(ValidationError::UnitError {
+                err: format!(
+                    "when single-authored, must not put address in {} input",
+                    kind
+                ),
+            });
+        }
+        return Ok(author_addresses[0].clone());
+    }
+
+    match input.address {
+        Some(ref address) if author_addresses.contains(address) => Ok(address.clone()),
+        Some(ref address) => {
+            err!(ValidationError::UnitError {
+                err: format!("{} input address {} is not an author", kind, address),
+            });
+        }
+        None => {
+            err!(ValidationError::UnitError {
+                err: format!("when multi-authored, must put address in {} input", kind),
+            });
+        }
+    }
+}
+
+// an input spent by another unit that is not included in our parents is a double spend,
+// it's accepted only when the author has a forked path, the unit is then not serial
+fn check_input_double_spend(
+    tx: &Transaction,
+    unit: &Unit,
+    state: &mut ValidationState,
+    author_addresses: &[String],
+    double_spend_where: String,
+    message_index: u32,
+    input_index: u32,
+) -> Result<()> {
+    let unit_hash = unit.unit.as_ref().unwrap();
+    let double_spend_where = format!(
+        "{} AND unit != '{}' AND asset IS NULL",
+        double_spend_where, unit_hash
+    );
+    let sql = format!(
+        "SELECT unit, address, main_chain_index, sequence \
+         FROM inputs JOIN units USING(unit) WHERE {}",
+        double_spend_where
+    );
+    let mut stmt = tx.prepare(&sql)?;
+    let rows = stmt
+        .query_map(&[], |row| {
+            (
+                row.get::<_, String>(0),
+                row.get::<_, String>(1),
+                row.get::<_, Option<u32>>(2),
+                row.get::<_, String>(3),
+            )
+        })?
+        .collect::<::std::result::Result<Vec<_>, _>>()?;
+    let mut has_double_spend = false;
+    for (conflicting_unit, address, main_chain_index, sequence) in rows {
+        ensure!(
+            author_addresses.contains(&address),
+            "conflicting input in unit {} spent from another address {}",
+            conflicting_unit,
+            address
+        );
+        if graph::determine_if_included_or_equal(tx, &conflicting_unit, &unit.parent_units)? {
+            // the other spend is already known to us
+            let spent_before_last_ball = match main_chain_index {
+                Some(mci) => mci <= state.last_ball_mci,
+                None => false,
+            };
+            if !spent_before_last_ball || sequence == "good" {
+                err!(ValidationError::UnitError {
+                    err: format!(
+                        "{}: conflicting input in inner unit {}",
+                        unit_hash, conflicting_unit
+                    ),
+                });
+            }
+            // the other spend is final-bad, the input is still unique
+            if sequence == "final-bad" {
+                continue;
+            }
+            bail!(
+                "unexpected sequence {} of conflicting unit {}",
+                sequence,
+                conflicting_unit
+            );
+        }
+
+        if !state.addresses_with_forked_path.contains(&address) {
+            err!(ValidationError::UnitError {
+                err: format!(
+                    "double spending input in unit {} without double spending address {}",
+                    conflicting_unit, address
+                ),
+            });
+        }
+        has_double_spend = true;
+    }
+    if !has_double_spend {
+        return Ok(());
+    }
+
+    state.additional_queries.push(format!(
+        "UPDATE inputs SET is_unique=NULL WHERE {} \
+         AND (SELECT is_stable FROM units WHERE units.unit=inputs.unit)=0",
+        double_spend_where
+    ));
+    state.double_spend_inputs.push(DoubleSpendInput {
+        message_index,
+        input_index,
+    });
+
+    Ok(())
+}
+
+fn check_input_key(state: &mut ValidationState, input_key: String) -> Result<()> {
+    if state.input_keys.contains(&input_key) {
+        err!(ValidationError::UnitError {
+            err: format!("input {} already used", input_key),
+        });
+    }
+    state.input_keys.push(input_key);
+    Ok(())
+}
+
+#[cfg(test)]
+fn is_stable(db: &Connection, unit: &str) -> bool {
+    db.query_row(
+        "SELECT is_stable FROM units WHERE unit=?",
+        &[&unit],
+        |row| row.get::<_, u32>(0) == 1,
+    )
+    .unwrap()
+}
+
+#[test]
+fn test_check_last_ball_advances_stability_point() {
+    use test_utils::*;
+
+    let mut dag = TestDag::new("validation last ball");
+    dag.add_units(20);
+    let parent_units = dag.read_free_units();
+    let joint = dag.compose_payment(
+        &dag.witnesses[0],
+        parent_units,
+        vec![transfer_input(&config::get_genesis_unit(), 0, 0)],
+        GENESIS_OUTPUT_AMOUNT,
+        vec![output(&dag.witnesses[1].address, 1000)],
+    );
+    let mut bad_unit = joint.unit.clone();
+    bad_unit.last_ball = Some(object_hash::get_base64_hash(&"wrong ball").unwrap());
+    let bad_joint = Joint {
+        ball: None,
+        skiplist_units: None,
+        unsigned: None,
+        unit: sign_unit(bad_unit, &[&dag.witnesses[0]]),
+    };
+
+    // roll back the last stability advance as if we had not seen it yet
+    let last_ball_unit = joint.unit.last_ball_unit.clone().unwrap();
+    let mci = storage::read_last_stable_mc_index(&dag.db).unwrap();
+    dag.db
+        .execute(
+            "DELETE FROM balls WHERE unit IN(SELECT unit FROM units WHERE main_chain_index=?)",
+            &[&mci],
+        )
+        .unwrap();
+    dag.db
+        .execute(
+            "UPDATE units SET is_stable=0 WHERE main_chain_index=?",
+            &[&mci],
+        )
+        .unwrap();
+    assert!(!is_stable(&dag.db, &last_ball_unit));
+
+    // nothing is written when the unit is invalid
+    assert!(validate(&mut dag.db, &bad_joint).is_err());
+    assert!(!is_stable(&dag.db, &last_ball_unit));
+
+    dag.validate_and_save(&joint).unwrap();
+    assert!(is_stable(&dag.db, &last_ball_unit));
+    let ball: String = dag
+        .db
+        .query_row(
+            "SELECT ball FROM balls WHERE unit=?",
+            &[&last_ball_unit],
+            |row| row.get(0),
+        )
+        .unwrap();
+    assert_eq!(Some(ball), joint.unit.last_ball);
+}
+
+#[test]
+fn test_validate_author_errors() {
+    use test_utils::*;
+
+    let mut dag = TestDag::new("validation author");
+    dag.add_units(20);
+    let parent_units = dag.read_free_units();
+    let joint = dag.compose_payment(
+        &dag.witnesses[0],
+        parent_units,
+        vec![transfer_input(&config::get_genesis_unit(), 0, 0)],
+        GENESIS_OUTPUT_AMOUNT,
+        vec![output(&dag.witnesses[1].address, 1000)],
+    );
+    // the definition is stable, the signature is checked against the stored one
+    assert!(joint.unit.authors[0].definition.is_null());
+
+    // signed by another key
+    let bad_joint = Joint {
+        ball: None,
+        skiplist_units: None,
+        unsigned: None,
+        unit: sign_unit(joint.unit.clone(), &[&dag.witnesses[1]]),
+    };
+    match validate(&mut dag.db, &bad_joint).unwrap_err().downcast() {
+        Ok(ValidationError::UnitError { err }) => assert!(err.contains("bad signature")),
+        e => panic!("unexpected error {:?}", e),
+    }
+
+    // the unit is fine, but the definition can't be read
+    dag.db
+        .execute_batch("ALTER TABLE definitions RENAME TO definitions_moved")
+        .unwrap();
+    match validate(&mut dag.db, &joint).unwrap_err().downcast() {
+        Ok(ValidationError::TransientError { .. }) => {}
+        e => panic!("unexpected error {:?}", e),
+    }
+}
+
+#[cfg(test)]
+fn expect_unit_error(db: &mut Connection, joint: &Joint, expected: &str) {
+    match validate(db, joint).unwrap_err().downcast() {
+        Ok(ValidationError::UnitError { err }) => {
+            assert!(err.contains(expected), "unexpected error: {}", err)
+        }
+        e => panic!("unexpected error {:?}", e),
+    }
+}
+
+#[cfg(test)]
+fn read_payment(joint: &Joint) -> Payment {
+    match joint.unit.messages[0].payload {
+        Some(Payload::Payment(ref payment)) => payment.clone(),
+        _ => panic!("not a payment"),
+    }
+}
+
+#[test]
+fn test_validate_payment_balance() {
+    use test_utils::*;
+
+    let mut dag = TestDag::new("validation payment");
+    dag.add_units(20);
+    let parent_units = dag.read_free_units();
+    let inputs = vec![transfer_input(&config::get_genesis_unit(), 0, 0)];
+    let joint = dag.compose_payment(
+        &dag.witnesses[0],
+        parent_units.clone(),
+        inputs.clone(),
+        GENESIS_OUTPUT_AMOUNT,
+        vec![output(&dag.witnesses[1].address, 1000)],
+    );
+    let payment = read_payment(&joint);
+
+    // the change is one byte off either way
+    for delta in &[-1, 1] {
+        let outputs = payment
+            .outputs
+            .iter()
+            .map(|o| {
+                if o.address == dag.witnesses[0].address {
+                    output(&o.address, o.amount + delta)
+                } else {
+                    o.clone()
+                }
+            })
+            .collect();
+        let bad_joint = dag.compose(
+            &[&dag.witnesses[0]],
+            parent_units.clone(),
+            vec![payment_message(inputs.clone(), outputs)],
+        );
+        expect_unit_error(&mut dag.db, &bad_joint, "do not balance");
+    }
+
+    let mut outputs = payment.outputs.clone();
+    outputs.reverse();
+    let bad_joint = dag.compose(
+        &[&dag.witnesses[0]],
+        parent_units.clone(),
+        vec![payment_message(inputs.clone(), outputs)],
+    );
+    expect_unit_error(&mut dag.db, &bad_joint, "output addresses not sorted");
+
+    // the output of the genesis that belongs to the second witness
+    let bad_joint = dag.compose_payment(
+        &dag.witnesses[0],
+        parent_units.clone(),
+        vec![transfer_input(&config::get_genesis_unit(), 0, 1)],
+        GENESIS_OUTPUT_AMOUNT,
+        vec![output(&dag.witnesses[1].address, 1000)],
+    );
+    expect_unit_error(&mut dag.db, &bad_joint, "output owner is not among authors");
+
+    dag.validate_and_save(&joint).unwrap();
+    let total_output: i64 = dag
+        .db
+        .query_row(
+            "SELECT SUM(amount) FROM outputs WHERE unit=?",
+            &[joint.get_unit_hash()],
+            |row| row.get(0),
+        )
+        .unwrap();
+    let commissions =
+        joint.unit.headers_commission.unwrap() + joint.unit.payload_commission.unwrap();
+    assert_eq!(total_output + i64::from(commissions), GENESIS_OUTPUT_AMOUNT);
+}
+
+#[cfg(test)]
+fn read_sequence_and_is_unique(db: &Connection, unit: &str) -> (String, Option<u32>) {
+    db.query_row(
+        "SELECT sequence, is_unique FROM units JOIN inputs USING(unit) WHERE unit=?",
+        &[&unit],
+        |row| (row.get(0), row.get(1)),
+    )
+    .unwrap()
+}
+
+#[test]
+fn test_validate_double_spend_sequence() {
+    use test_utils::*;
+
+    let mut dag = TestDag::new("validation double spend");
+    dag.add_units(20);
+    let parent_units = dag.read_free_units();
+    let input = transfer_input(&config::get_genesis_unit(), 0, 0);
+    let first = dag.compose_payment(
+        &dag.witnesses[0],
+        parent_units.clone(),
+        vec![input.clone()],
+        GENESIS_OUTPUT_AMOUNT,
+        vec![output(&dag.witnesses[1].address, 1000)],
+    );
+    let second = dag.compose_payment(
+        &dag.witnesses[0],
+        parent_units.clone(),
+        vec![input.clone()],
+        GENESIS_OUTPUT_AMOUNT,
+        vec![output(&dag.witnesses[2].address, 2000)],
+    );
+
+    let bad_joint = dag.compose_payment(
+        &dag.witnesses[0],
+        parent_units.clone(),
+        vec![input.clone(), input.clone()],
+        2 * GENESIS_OUTPUT_AMOUNT,
+        vec![output(&dag.witnesses[1].address, 1000)],
+    );
+    expect_unit_error(&mut dag.db, &bad_joint, "already used");
+
+    // the second spend doesn't see the first one, both become temp-bad
+    dag.validate_and_save(&first).unwrap();
+    assert_eq!(
+        read_sequence_and_is_unique(&dag.db, first.get_unit_hash()),
+        (String::from("good"), Some(1))
+    );
+    dag.validate_and_save(&second).unwrap();
+    for unit in &[first.get_unit_hash(), second.get_unit_hash()] {
+        assert_eq!(
+            read_sequence_and_is_unique(&dag.db, unit),
+            (String::from("temp-bad"), None)
+        );
+    }
+
+    // spending it again on top of a known spend is never accepted
+    let bad_joint = dag.compose_payment(
+        &dag.witnesses[0],
+        vec![first.get_unit_hash().clone()],
+        vec![input],
+        GENESIS_OUTPUT_AMOUNT,
+        vec![output(&dag.witnesses[3].address, 3000)],
+    );
+    expect_unit_error(&mut dag.db, &bad_joint, "conflicting input in inner unit");
+
+    // the spend that gets stable first wins
+    dag.add_units(30);
+    let mut results = [first.get_unit_hash(), second.get_unit_hash()]
+        .iter()
+        .map(|unit| {
+            assert!(is_stable(&dag.db, unit));
+            read_sequence_and_is_unique(&dag.db, unit)
+        })
+        .collect::<Vec<_>>();
+    results.sort();
+    assert_eq!(
+        results,
+        vec![
+            (String::from("final-bad"), None),
+            (String::from("good"), Some(1))
+        ]
+    );
+}
+
+#[test]
+fn test_validate_witness_list() {
+    use test_utils::*;
+
+    let mut dag = TestDag::new("validation witnesses");
+    // the replacement witnesses need stable units to be accepted in a list
+    let new_witnesses = vec![TestKey::new(13), TestKey::new(14)];
+    for (i, key) in new_witnesses.iter().enumerate() {
+        let parent_units = dag.read_free_units();
+        let text = format!("validation witnesses new {}", i);
+        let joint = dag.compose(&[key], parent_units, vec![text_message(&text)]);
+        dag.save(&joint).unwrap();
+    }
+    // enough for every witness to have a stable unit
+    dag.add_units(40);
+    let parent_units = dag.read_free_units();
+    let witnesses = dag.get_witness_addresses();
+
+    let compose_with_witnesses = |witnesses: Vec<String>| {
+        dag.compose_payment_with(
+            &[&dag.witnesses[0]],
+            parent_units.clone(),
+            vec![transfer_input(&config::get_genesis_unit(), 0, 0)],
+            GENESIS_OUTPUT_AMOUNT,
+            vec![output(&dag.witnesses[1].address, 1000)],
+            |unit| {
+                unit.witness_list_unit = None;
+                unit.witnesses = Some(witnesses.clone());
+            },
+        )
+    };
+    let mutate = |count: usize| {
+        let mut mutated = witnesses.clone();
+        mutated.truncate(config::COUNT_WITNESSES - count);
+        mutated.extend(new_witnesses[..count].iter().map(|k| k.address.clone()));
+        mutated.sort();
+        compose_with_witnesses(mutated)
+    };
+
+    let joint = compose_with_witnesses(witnesses.clone());
+    let mutated_joint = mutate(1);
+    let bad_joints = vec![
+        (mutate(2), "incompatible"),
+        (
+            compose_with_witnesses(witnesses[1..].to_vec()),
+            "wrong number of witnesses",
+        ),
+        (
+            compose_with_witnesses(witnesses.iter().rev().cloned().collect()),
+            "wrong order of witnesses",
+        ),
+    ];
+    for (bad_joint, expected) in bad_joints {
+        expect_unit_error(&mut dag.db, &bad_joint, expected);
+    }
+
+    // a witness list unit must be a stable unit that has a witness list
+    let no_list_unit = dag.compose_payment_with(
+        &[&dag.witnesses[0]],
+        parent_units.clone(),
+        vec![transfer_input(&config::get_genesis_unit(), 0, 0)],
+        GENESIS_OUTPUT_AMOUNT,
+        vec![output(&dag.witnesses[1].address, 1000)],
+        |unit| unit.witness_list_unit = unit.last_ball_unit.clone(),
+    );
+    expect_unit_error(&mut dag.db, &no_list_unit, "has no witnesses");
+
+    validate(&mut dag.db, &mutated_joint).unwrap();
+    dag.validate_and_save(&joint).unwrap();
+    let count_witnesses: u32 = dag
+        .db
+        .query_row(
+            "SELECT COUNT(*) FROM unit_witnesses WHERE unit=?",
+            &[joint.get_unit_hash()],
+            |row| row.get(0),
+        )
+        .unwrap();
+    assert_eq!(count_witnesses as usize, config::COUNT_WITNESSES);
+}
+
+#[test]
+fn test_validate_headers_commission_recipients() {
+    use test_utils::*;
+
+    let mut dag = TestDag::new("validation recipients");
+    dag.add_units(20);
+    let parent_units = dag.read_free_units();
+    let compose_with_recipients = |recipients: Option<Vec<(usize, u32)>>| {
+        let recipients = recipients.map(|recipients| {
+            recipients
+                .into_iter()
+                .map(|(i, share)| HeaderCommissionShare {
+                    address: dag.witnesses[i].address.clone(),
+                    earned_headers_commission_share: share,
+                })
+                .collect::<Vec<_>>()
+        });
+        dag.compose_payment_with(
+            &[&dag.witnesses[0], &dag.witnesses[1]],
+            parent_units.clone(),
+            vec![
+                transfer_input(&config::get_genesis_unit(), 0, 0),
+                transfer_input(&config::get_genesis_unit(), 0, 1),
+            ],
+            2 * GENESIS_OUTPUT_AMOUNT,
+            vec![output(&dag.witnesses[2].address, 1000)],
+            |unit| unit.earned_headers_commission_recipients = recipients.clone(),
+        )
+    };
+
+    // the witness keys are sorted by address
+    let joint = compose_with_recipients(Some(vec![(0, 40), (1, 60)]));
+    let bad_joints = vec![
+        (None, "must specify earned_headers_commission_recipients"),
+        (Some(vec![]), "empty earned_headers_commission_recipients"),
+        (Some(vec![(0, 40), (1, 50)]), "is not 100"),
+        (Some(vec![(1, 60), (0, 40)]), "must be sorted by address"),
+        (Some(vec![(0, 50), (0, 50)]), "must be sorted by address"),
+        (Some(vec![(0, 0), (1, 100)]), "must be positive"),
+    ]
+    .into_iter()
+    .map(|(recipients, expected)| (compose_with_recipients(recipients), expected))
+    .collect::<Vec<_>>();
+    for (bad_joint, expected) in bad_joints {
+        expect_unit_error(&mut dag.db, &bad_joint, expected);
+    }
+
+    dag.validate_and_save(&joint).unwrap();
+    let recipients = dag
+        .db
+        .prepare(
+            "SELECT address, earned_headers_commission_share \
+             FROM earned_headers_commission_recipients WHERE unit=? ORDER BY address",
+        )
+        .unwrap()
+        .query_map(&[joint.get_unit_hash()], |row| {
+            (row.get::<_, String>(0), row.get::<_, u32>(1))
+        })
+        .unwrap()
+        .collect::<::std::result::Result<Vec<_>, _>>()
+        .unwrap();
+    assert_eq!(
+        recipients,
+        vec![
+            (dag.witnesses[0].address.clone(), 40),
+            (dag.witnesses[1].address.clone(), 60),
+        ]
+    );
+}
+
+#[cfg(test)]
+fn compose_payment_and_messages(
+    dag: &::test_utils::TestDag,
+    author: usize,
+    messages: Vec<Message>,
+) -> Joint {
+    use test_utils::*;
+
+    // each witness spends its own output of the genesis
+    dag.compose_payment_with(
+        &[&dag.witnesses[author]],
+        dag.read_free_units(),
+        vec![transfer_input(
+            &config::get_genesis_unit(),
+            0,
+            author as u32,
+        )],
+        GENESIS_OUTPUT_AMOUNT,
+        vec![output(&dag.witnesses[2].address, 1000)],
+        |unit| unit.messages.extend(messages.clone()),
+    )
+}
+
+#[test]
+fn test_validate_inline_payloads() {
+    use test_utils::*;
+
+    let mut dag = TestDag::new("validation inline payloads");
+    dag.add_units(20);
+    let other = |app: &str, payload: Value| inline_message(app, Payload::Other(payload));
+    let address = dag.witnesses[0].address.clone();
+
+    let joint = compose_payment_and_messages(
+        &dag,
+        0,
+        vec![
+            text_message("validation inline payloads"),
+            other("data_feed", json!({ "price": 100, "name": "inkc" })),
+            other("data", json!({ "key": ["value"] })),
+            other(
+                "address_definition_change",
+                json!({ "definition_chash": address }),
+            ),
+            other(
+                "definition_template",
+                json!(["sig", { "pubkey": "$pubkey" }]),
+            ),
+            other(
+                "poll",
+                json!({ "question": "yes?", "choices": ["yes", "no"] }),
+            ),
+        ],
+    );
+    let bad_joints = vec![
+        (vec![other("unknown", json!({}))], "unknown app"),
+        (
+            vec![other("data_feed", json!({ "price": 1.5 }))],
+            "fractional numbers",
+        ),
+        (vec![other("data_feed", json!({}))], "non-empty object"),
+        (
+            vec![
+                other("data_feed", json!({ "a": 1 })),
+                other("data_feed", json!({ "b": 1 })),
+            ],
+            "only one data feed",
+        ),
+        (
+            vec![inline_message("text", Payload::Other(json!({})))],
+            "must be string",
+        ),
+        (
+            vec![other(
+                "address_definition_change",
+                json!({ "definition_chash": address, "address": address }),
+            )],
+            "must not indicate address",
+        ),
+        (
+            vec![other("poll", json!({ "question": "yes?", "choices": [] }))],
+            "no choices",
+        ),
+        (vec![other("asset", json!({}))], "not supported"),
+    ]
+    .into_iter()
+    .map(|(messages, expected)| (compose_payment_and_messages(&dag, 0, messages), expected))
+    .collect::<Vec<_>>();
+    for (bad_joint, expected) in bad_joints {
+        expect_unit_error(&mut dag.db, &bad_joint, expected);
+    }
+    dag.validate_and_save(&joint).unwrap();
+
+    let vote = |choice: &str| {
+        let vote = other(
+            "vote",
+            json!({ "unit": joint.get_unit_hash(), "choice": choice }),
+        );
+        vec![vote]
+    };
+    let early_vote = compose_payment_and_messages(&dag, 1, vote("yes"));
+    expect_unit_error(&mut dag.db, &early_vote, "before last ball");
+
+    // the poll gets stable
+    dag.add_units(30);
+    let bad_vote = compose_payment_and_messages(&dag, 1, vote("maybe"));
+    expect_unit_error(&mut dag.db, &bad_vote, "invalid choice");
+    let vote_joint = compose_payment_and_messages(&dag, 1, vote("yes"));
+    dag.validate_and_save(&vote_joint).unwrap();
+
+    // the payloads kept in their own tables are read back
+    for joint in &[&joint, &vote_joint] {
+        let saved = storage::read_joint_directly(&dag.db, joint.get_unit_hash()).unwrap();
+        assert_eq!(
+            serde_json::to_value(&saved.unit.messages).unwrap(),
+            serde_json::to_value(&joint.unit.messages).unwrap()
+        );
+    }
+}
+
+#[test]
+fn test_check_last_ball_must_not_retreat() {
+    use test_utils::*;
+
+    let mut dag = TestDag::new("validation last ball retreat");
+    dag.add_units(20);
+    let old_last_stable = storage::read_last_stable_mc_unit_props(&dag.db).unwrap();
+    dag.add_units(10);
+    let parent_units = dag.read_free_units();
+    let joint = dag.compose_payment_with(
+        &[&dag.witnesses[0]],
+        parent_units,
+        vec![transfer_input(&config::get_genesis_unit(), 0, 0)],
+        GENESIS_OUTPUT_AMOUNT,
+        vec![output(&dag.witnesses[1].address, 1000)],
+        |unit| {
+            unit.last_ball = Some(old_last_stable.ball.clone());
+            unit.last_ball_unit = Some(old_last_stable.unit.clone());
+        },
+    );
+    // the parents already refer to a later last ball
+    let parent_last_ball_units = joint
+        .unit
+        .parent_units
+        .iter()
+        .map(|unit| {
+            dag.db
+                .query_row(
+                    "SELECT last_ball_unit FROM units WHERE unit=?",
+                    &[unit],
+                    |row| row.get::<_, String>(0),
+                )
+                .unwrap()
+        })
+        .collect::<Vec<_>>();
+    assert!(!parent_last_ball_units.contains(&old_last_stable.unit));
+
+    expect_unit_error(&mut dag.db, &joint, "last ball mci must not retreat");
+}
@@ -1,8 +1,8 @@
 use error::{Result, INKCError};
 use joint::Joint;
-use my_witness::MY_WITNESSES;
 use object_hash;
 use rusqlite::Connection;
+use serde_json::{self, Value};
 use spec::*;
 use std::collections::HashMap;
 use storage;
@@ -136,8 +136,10 @@ pub struct ProcessWitnessProof {
     pub assoc_last_ball_by_last_ball_unit: HashMap<String, String>,
 }
 
+/// check the proof against our `witnesses`
 pub fn process_witness_proof(
     db: &Connection,
+    witnesses: &[String],
     unstable_mc_joints: Vec<Joint>,
     witness_change_and_definition: Vec<Joint>,
     from_current: bool,
@@ -160,7 +162,7 @@ pub fn process_witness_proof(
         let mut added_joint = false;
         for author in unit.authors.iter() {
             let address = &author.address;
-            if MY_WITNESSES.contains(address) {
+            if witnesses.contains(address) {
                 if !found_witnesses.contains(address) {
                     found_witnesses.push(address.clone());
                 }
@@ -175,7 +177,7 @@ pub fn process_witness_proof(
         {
             let last_ball_unit = unit.last_ball_unit.as_ref().unwrap().clone();
             let last_ball = unit.last_ball.as_ref().unwrap().clone();
-            last_ball_units.push(last_ball.clone());
+            last_ball_units.push(last_ball_unit.clone());
             assoc_last_ball_by_last_ball_unit.insert(last_ball_unit, last_ball);
         }
     }
@@ -204,7 +206,7 @@ pub fn process_witness_proof(
         let mut author_by_witness = false;
         for author in unit.authors.iter() {
             let address = &author.address;
-            if MY_WITNESSES.contains(address) {
+            if witnesses.contains(address) {
                 author_by_witness = true;
                 break;
             }
@@ -215,17 +217,16 @@ pub fn process_witness_proof(
     let mut assoc_definitions = HashMap::<String, String>::new();
     let mut assoc_definition_chashes = HashMap::<String, String>::new();
 
-    if !from_current {
-        for address in MY_WITNESSES.iter() {
+    for address in witnesses {
+        if !from_current {
             assoc_definition_chashes.insert(address.clone(), address.clone());
+            continue;
         }
-    }
-
-    for address in MY_WITNESSES.iter() {
         match storage::read_definition_by_address(db, address, None)? {
             // if found
             Some(definition) => {
-                let definition_chash = object_hash::get_chash(&definition)?;
+                let definition_chash =
+                    object_hash::get_chash(&serde_json::from_str::<Value>(&definition)?)?;
                 assoc_definitions.insert(definition_chash.clone(), definition);
                 assoc_definition_chashes.insert(address.clone(), definition_chash);
             }
@@ -239,7 +240,7 @@ pub fn process_witness_proof(
         let mut b_found = false;
         for author in unit.authors.iter() {
             let address = &author.address;
-            if !MY_WITNESSES.contains(address) {
+            if !witnesses.contains(address) {
                 continue;
             }
 
@@ -253,13 +254,15 @@ pub fn process_witness_proof(
                 chash.unwrap().clone()
             };
 
-            let chash = object_hash::get_chash(&author.definition)?;
-            ensure!(
-                chash == *definition_chash,
-                "definition doesn't hash to the expected value"
-            );
-            assoc_definitions.insert(definition_chash.clone(), author.definition.to_string());
-            b_found = true;
+            if !author.definition.is_null() {
+                let chash = object_hash::get_chash(&author.definition)?;
+                ensure!(
+                    chash == definition_chash,
+                    "definition doesn't hash to the expected value"
+                );
+                assoc_definitions.insert(definition_chash.clone(), author.definition.to_string());
+                b_found = true;
+            }
 
             if assoc_definitions.get(&definition_chash).is_none() {
                 let definition = storage::read_definition(db, &definition_chash)?;
@@ -274,17 +277,21 @@ pub fn process_witness_proof(
                 assoc_definitions.get(&definition_chash).unwrap(),
             )?;
             for message in unit.messages.iter() {
-                let payload_address = message.payload.as_ref().and_then(|p| p.address.as_ref());
-                if message.app == "address_definition_change"
-                    && (payload_address == Some(address)
-                        || (unit.authors.len() == 1 && &unit.authors[0].address == address))
+                if message.app != "address_definition_change" {
+                    continue;
+                }
+                let payload = match message.payload {
+                    Some(Payload::Other(ref payload)) => payload,
+                    _ => bail!("address_definition_change payload must be an object"),
+                };
+                let payload_address = payload["address"].as_str();
+                if payload_address == Some(address.as_str())
+                    || (unit.authors.len() == 1 && &unit.authors[0].address == address)
                 {
-                    let payload = message.payload.as_ref().unwrap();
-                    let chash = payload
-                        .definition_chash
-                        .as_ref()
-                        .expect("no chash in payload")
-                        .clone();
+                    let chash = match payload["definition_chash"].as_str() {
+                        Some(chash) => chash.to_owned(),
+                        None => bail!("no definition_chash in payload"),
+                    };
                     assoc_definition_chashes.insert(address.clone(), chash);
                     b_found = true;
                 }